@@ -0,0 +1,233 @@
+use bytes::BytesMut;
+use common::chunking;
+use common::framing::{self, FRAME_DELIMITER};
+use common::message::{DeviceInfoPayload, HelloPayload, Message, PROTOCOL_VERSION};
+use futures_util::stream::{self, Stream};
+use futures_util::{SinkExt, StreamExt};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::sync::Mutex;
+use tokio_serial::{FlowControl, SerialPortBuilderExt, SerialStream};
+use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
+
+use crate::messages::MessageError;
+
+/// Bytes buffered without a frame delimiter before the receiver gives up on them
+const MAX_BUFFERED: usize = 4096;
+
+/// Async serial message handler, sending and receiving messages like MessageHandler without polling the port
+///
+/// Sending and receiving lock separate halves of the port, so a task waiting in recv or on the messages stream
+/// doesn't hold up send. Frames are sent as they are, without MessageHandler's presentation delay pacing.
+pub struct AsyncMessageHandler {
+    writer: Mutex<FramedWrite<WriteHalf<SerialStream>, MessageCodec>>,
+    reader: Mutex<FramedRead<ReadHalf<SerialStream>, MessageCodec>>,
+    /// Largest frame the firmware accepts, from its DeviceInfo, larger messages are sent in chunks
+    max_frame_size: Arc<AtomicUsize>,
+    resyncs: Arc<AtomicUsize>,
+}
+
+impl AsyncMessageHandler {
+    /// Create a new AsyncMessageHandler by opening a serial port, with RTS/CTS flow control if hardware_flow_control
+    /// is set
+    /// Must be called from within a tokio runtime
+    pub fn new(port_path: &str, baud_rate: u32, hardware_flow_control: bool) -> Result<Self, MessageError> {
+        let flow_control = if hardware_flow_control {
+            FlowControl::Hardware
+        } else {
+            FlowControl::None
+        };
+        let port = tokio_serial::new(port_path, baud_rate)
+            .flow_control(flow_control)
+            .open_native_async()
+            .map_err(|e| MessageError::PortError(format!("Failed to open serial port: {}", e)))?;
+
+        let max_frame_size = Arc::new(AtomicUsize::new(usize::MAX));
+        let resyncs = Arc::new(AtomicUsize::new(0));
+        let codec = MessageCodec {
+            max_frame_size: max_frame_size.clone(),
+            resyncs: resyncs.clone(),
+        };
+        let (read_half, write_half) = tokio::io::split(port);
+        Ok(Self {
+            writer: Mutex::new(FramedWrite::new(write_half, codec.clone())),
+            reader: Mutex::new(FramedRead::new(read_half, codec)),
+            max_frame_size,
+            resyncs,
+        })
+    }
+
+    /// Send a message, split into FrameChunks if it's larger than the firmware's max_frame_size
+    pub async fn send(&self, message: &Message) -> Result<(), MessageError> {
+        self.writer.lock().await.send(message).await
+    }
+
+    /// Wait for the next message from the firmware
+    /// Frames that fail to decode are skipped, counted by take_resyncs
+    pub async fn recv(&self) -> Result<Message, MessageError> {
+        match self.reader.lock().await.next().await {
+            Some(result) => result,
+            None => Err(MessageError::ReadError("Serial port closed".to_string())),
+        }
+    }
+
+    /// Stream of the messages received from the firmware, ending once the port closes
+    /// Only one task should receive at a time, recv and every stream share the port's receiving half
+    pub fn messages(&self) -> impl Stream<Item = Result<Message, MessageError>> + '_ {
+        stream::unfold(self, |handler| async move {
+            let result = handler.reader.lock().await.next().await;
+            result.map(|result| (result, handler))
+        })
+    }
+
+    /// Exchange Hello messages with the firmware
+    /// Returns the firmware's Hello, or an error if it speaks an incompatible protocol version
+    /// Messages received before the firmware's Hello are discarded
+    pub async fn handshake(&self, timeout: Duration) -> Result<HelloPayload, MessageError> {
+        self.send(&Message::Hello(HelloPayload {
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: String::new(),
+        }))
+        .await?;
+        let hello = self
+            .wait_for(timeout, |message| match message {
+                Message::Hello(hello) => Some(hello),
+                _ => None,
+            })
+            .await?;
+        if hello.protocol_version != PROTOCOL_VERSION {
+            return Err(MessageError::IncompatibleProtocol {
+                firmware: hello.protocol_version,
+                server: PROTOCOL_VERSION,
+            });
+        }
+        Ok(hello)
+    }
+
+    /// Ask the firmware for its DeviceInfo
+    /// Larger messages are sent in chunks from then on, so no frame exceeds the firmware's max_frame_size
+    pub async fn device_info(&self, timeout: Duration) -> Result<DeviceInfoPayload, MessageError> {
+        self.send(&Message::GetDeviceInfo).await?;
+        let info = self
+            .wait_for(timeout, |message| match message {
+                Message::DeviceInfo(info) => Some(info),
+                _ => None,
+            })
+            .await?;
+        self.max_frame_size.store(info.max_frame_size as usize, Ordering::Relaxed);
+        Ok(info)
+    }
+
+    /// Take the number of frames the receiver discarded since the last call
+    pub fn take_resyncs(&self) -> usize {
+        self.resyncs.swap(0, Ordering::Relaxed)
+    }
+
+    /// Receive until matches accepts a message, discarding the messages before it
+    async fn wait_for<T>(&self, timeout: Duration, matches: impl Fn(Message) -> Option<T>) -> Result<T, MessageError> {
+        tokio::time::timeout(timeout, async {
+            loop {
+                if let Some(accepted) = matches(self.recv().await?) {
+                    return Ok(accepted);
+                }
+            }
+        })
+        .await
+        .map_err(|_| MessageError::Timeout)?
+    }
+}
+
+/// Codec framing messages like MessageHandler: postcard bytes and a CRC16, COBS encoded and delimited by 0x00
+#[derive(Clone)]
+pub struct MessageCodec {
+    max_frame_size: Arc<AtomicUsize>,
+    resyncs: Arc<AtomicUsize>,
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = MessageError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, MessageError> {
+        while let Some(frame_end) = src.iter().position(|&b| b == FRAME_DELIMITER) {
+            let mut frame = src.split_to(frame_end + 1);
+            // Empty frames are the delimiters sent to wake the firmware, not corruption
+            if frame.len() == 1 {
+                continue;
+            }
+            match framing::decode(&mut frame) {
+                Ok(message) => return Ok(Some(message)),
+                // Corrupted frames are dropped, the next delimiter starts a fresh one
+                Err(_) => {
+                    self.resyncs.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        if src.len() > MAX_BUFFERED {
+            src.clear();
+            return Err(MessageError::BufferOverflow);
+        }
+        Ok(None)
+    }
+}
+
+impl Encoder<&Message> for MessageCodec {
+    type Error = MessageError;
+
+    fn encode(&mut self, message: &Message, dst: &mut BytesMut) -> Result<(), MessageError> {
+        let frames = chunking::encode(message, self.max_frame_size.load(Ordering::Relaxed))
+            .map_err(|e| MessageError::Serialization(format!("Frame encoding error: {}", e)))?;
+        for frame in frames {
+            dst.extend_from_slice(&frame);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codec() -> MessageCodec {
+        MessageCodec {
+            max_frame_size: Arc::new(AtomicUsize::new(usize::MAX)),
+            resyncs: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut codec = codec();
+        let mut buffer = BytesMut::new();
+        codec.encode(&Message::Heartbeat, &mut buffer).unwrap();
+        codec.encode(&Message::GetTime, &mut buffer).unwrap();
+
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(Message::Heartbeat));
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(Message::GetTime));
+        assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+    }
+
+    #[test]
+    fn skips_corrupted_frames() {
+        let mut codec = codec();
+        let mut buffer = BytesMut::from(&[0x03, 0x42, 0x17, FRAME_DELIMITER, FRAME_DELIMITER][..]);
+        codec.encode(&Message::Heartbeat, &mut buffer).unwrap();
+
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(Message::Heartbeat));
+        assert_eq!(codec.resyncs.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn waits_for_the_delimiter() {
+        let mut codec = codec();
+        let mut buffer = BytesMut::new();
+        codec.encode(&Message::Heartbeat, &mut buffer).unwrap();
+        let mut partial = buffer.split_to(buffer.len() - 1);
+
+        assert_eq!(codec.decode(&mut partial).unwrap(), None);
+        partial.extend_from_slice(&buffer);
+        assert_eq!(codec.decode(&mut partial).unwrap(), Some(Message::Heartbeat));
+    }
+}
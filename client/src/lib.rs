@@ -0,0 +1,97 @@
+//! Host-side client for the christmas tree firmware's serial protocol
+//!
+//! [`connect`] opens the serial port, wakes the firmware and checks it speaks this protocol version, leaving a
+//! [`MessageHandler`] that sends frames and commands and receives the firmware's messages. Messages are the
+//! [`message::Message`] enum shared with the firmware, framed with a CRC16 and COBS.
+//!
+//! ```no_run
+//! use christmas_tree_client::message::Rgb;
+//! use christmas_tree_client::{connect, DEFAULT_BAUD_RATE};
+//! use std::time::Duration;
+//!
+//! let connection = connect("/dev/ttyACM0", DEFAULT_BAUD_RATE, false, Duration::from_secs(2))?;
+//! let logs = connection.handler.subscribe_logs()?;
+//! connection.handler.send_frame(0, vec![Rgb::new(255, 0, 0); 100])?;
+//! while let Ok(message) = connection.handler.receive(Duration::from_secs(1)) {
+//!     println!("{:?}", message);
+//!     for log in logs.try_iter() {
+//!         println!("{}", log.content);
+//!     }
+//! }
+//! # Ok::<(), christmas_tree_client::MessageError>(())
+//! ```
+//!
+//! MessageHandler talks to a serial port by default, [`MessageHandler::with_transport`] runs the same protocol over
+//! any other [`Transport`], such as a TCP socket, a [`UdpTransport`] to a firmware on WiFi or an in-memory
+//! [`Loopback`]. [`Simulator`] answers on the other end of a loopback, or any other transport, like the firmware, for
+//! testing hosts without a tree attached.
+//!
+//! With the `tokio` feature, [`AsyncMessageHandler`] does the same without blocking, for use in async applications.
+
+#[cfg(feature = "tokio")]
+mod async_messages;
+mod messages;
+mod pacing;
+mod simulator;
+mod transport;
+
+#[cfg(feature = "tokio")]
+pub use async_messages::{AsyncMessageHandler, MessageCodec};
+pub use common::message;
+pub use messages::{DEFAULT_BAUD_RATE, MessageError, MessageHandler};
+pub use simulator::{SIMULATOR_VERSION, Simulator, SimulatorState};
+pub use transport::{Loopback, Transport, UdpTransport};
+
+use common::message::HelloPayload;
+use std::time::Duration;
+
+/// Link to the firmware opened by connect
+pub struct Connection {
+    pub handler: MessageHandler,
+    /// The firmware's Hello, None if it didn't answer the handshake and the link was left at DEFAULT_BAUD_RATE
+    pub hello: Option<HelloPayload>,
+    /// Baud rate the link runs at
+    pub baud_rate: u32,
+}
+
+/// Open the serial port at port_path, with RTS/CTS flow control if hardware_flow_control is set, and bring the link
+/// up to baud_rate
+///
+/// The firmware is woken from light sleep and sent a handshake at DEFAULT_BAUD_RATE, then at baud_rate in case it's
+/// still at the rate of a previous connection. Once it answers, baud_rate is negotiated, falling back to
+/// DEFAULT_BAUD_RATE if either end can't keep up.
+/// Returns an error if the firmware speaks an incompatible protocol version
+pub fn connect(
+    port_path: &str,
+    baud_rate: u32,
+    hardware_flow_control: bool,
+    timeout: Duration,
+) -> Result<Connection, MessageError> {
+    let handler = MessageHandler::new(port_path, DEFAULT_BAUD_RATE, hardware_flow_control)?;
+    handler.wake()?;
+    let mut handshake = handler.handshake(timeout);
+    if let Err(MessageError::Timeout) = handshake {
+        // The firmware stays at the last connection's baud rate until it notices the host is gone
+        handler.set_baud_rate(baud_rate)?;
+        handshake = handler.handshake(timeout);
+        if handshake.is_err() {
+            handler.set_baud_rate(DEFAULT_BAUD_RATE)?;
+        }
+    }
+    match handshake {
+        Ok(hello) => {
+            let baud_rate = handler.negotiate_baud_rate(baud_rate, timeout)?;
+            Ok(Connection {
+                handler,
+                hello: Some(hello),
+                baud_rate,
+            })
+        }
+        Err(MessageError::Timeout) => Ok(Connection {
+            handler,
+            hello: None,
+            baud_rate: DEFAULT_BAUD_RATE,
+        }),
+        Err(e) => Err(e),
+    }
+}
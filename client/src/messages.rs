@@ -0,0 +1,579 @@
+use common::chunking;
+use common::framing::{self, FrameError, FRAME_DELIMITER};
+use common::message::{
+    BaudRatePayload, BeginUpdatePayload, ConfigPayload, DeviceInfoPayload, FirmwareChunkPayload, GetLedsPayload,
+    HelloPayload, LogPayload, Message, ResetReportPayload, Rgb, SchedulePayload, SegmentsPayload, SetLedsPayload,
+    SoundReactivePayload, UpdateError, UpdateStatusPayload, PROTOCOL_VERSION,
+};
+use serialport::{FlowControl, SerialPort};
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+use crate::pacing::FramePacer;
+use crate::transport::Transport;
+
+/// Delimiters sent to wake the firmware, enough rising edges to trigger its UART wakeup
+const WAKE_DELIMITERS: usize = 4;
+
+/// Time the firmware takes to come out of light sleep
+const WAKE_TIME: Duration = Duration::from_millis(20);
+
+/// Time the firmware takes to switch baud rate after answering SetBaudRate
+const BAUD_SWITCH_TIME: Duration = Duration::from_millis(10);
+
+/// Time after which the firmware has fallen back to its default baud rate if it heard nothing at a new one
+const BAUD_FALLBACK_TIME: Duration = Duration::from_millis(1500);
+
+/// Baud rate the firmware starts at and falls back to
+pub const DEFAULT_BAUD_RATE: u32 = 115_200;
+
+/// Message handler for sending and receiving messages over a transport using COBS framing with a CRC16,
+/// a serial port unless created with_transport
+pub struct MessageHandler<T: Transport = Box<dyn SerialPort>> {
+    port: Mutex<T>,
+    receive_buffer: Mutex<Vec<u8>>,
+    last_read_time: Mutex<Option<std::time::Instant>>,
+    resyncs: AtomicUsize,
+    crc_failures: AtomicUsize,
+    /// Largest frame the firmware accepts, from its DeviceInfo, larger messages are sent in chunks
+    max_frame_size: AtomicUsize,
+    /// Paces frames on the firmware's clock when a presentation delay is set
+    pacer: Mutex<Option<FramePacer>>,
+    /// Receivers of the firmware's logs from subscribe_logs, dropped once they hang up
+    log_subscribers: Mutex<Vec<Sender<LogPayload>>>,
+}
+
+impl MessageHandler {
+    /// Create a new MessageHandler by opening a serial port, with RTS/CTS flow control if hardware_flow_control is set
+    pub fn new(port_path: &str, baud_rate: u32, hardware_flow_control: bool) -> Result<Self, MessageError> {
+        let flow_control = if hardware_flow_control {
+            FlowControl::Hardware
+        } else {
+            FlowControl::None
+        };
+        let port = serialport::new(port_path, baud_rate)
+            .flow_control(flow_control)
+            .timeout(Duration::from_millis(10))
+            .open()
+            .map_err(|e| MessageError::PortError(format!("Failed to open serial port: {}", e)))?;
+        Ok(Self::with_transport(port))
+    }
+}
+
+impl<T: Transport> MessageHandler<T> {
+    /// Create a new MessageHandler sending and receiving over transport
+    pub fn with_transport(transport: T) -> Self {
+        Self {
+            port: Mutex::new(transport),
+            receive_buffer: Mutex::new(Vec::new()),
+            last_read_time: Mutex::new(None),
+            resyncs: AtomicUsize::new(0),
+            crc_failures: AtomicUsize::new(0),
+            max_frame_size: AtomicUsize::new(usize::MAX),
+            pacer: Mutex::new(None),
+            log_subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Send a message over serial using COBS encoding with frame delimiter
+    /// Messages larger than the firmware's max_frame_size are split into FrameChunks,
+    /// and frames are sent as TimedFrames while a presentation delay is set
+    pub fn send(&self, message: &Message) -> Result<(), MessageError> {
+        let paced = match &*self.pacer.lock().map_err(|_| MessageError::LockError)? {
+            Some(pacer) if message.frame_seq().is_some() => Some(pacer.pace(message.clone(), Instant::now())),
+            _ => None,
+        };
+        let message = paced.as_ref().unwrap_or(message);
+
+        // Serialize, append CRC16 and COBS encode message (includes 0x00 delimiter at the end)
+        let frames = chunking::encode(message, self.max_frame_size.load(Ordering::Relaxed))
+            .map_err(|e| MessageError::Serialization(format!("Frame encoding error: {}", e)))?;
+
+        // Write to serial port - handle partial writes
+        if let Ok(mut port) = self.port.lock() {
+            for encoded in &frames {
+                log::trace!("Sending message: {:?}", encoded);
+                let mut remaining = &encoded[..];
+                while !remaining.is_empty() {
+                    match port.write(remaining) {
+                        Ok(0) => return Err(MessageError::WriteError("No progress on write".to_string())),
+                        Ok(n) => remaining = &remaining[n..],
+                        Err(e) => return Err(MessageError::WriteError(format!("Serial write error: {}", e))),
+                    }
+                }
+            }
+            port.flush()
+                .map_err(|e| MessageError::WriteError(format!("Serial flush error: {}", e)))?;
+            Ok(())
+        } else {
+            Err(MessageError::LockError)
+        }
+    }
+
+    /// Try to receive a message from serial
+    /// Returns Ok(Some(message)) if a complete frame was received (ending with byte 0)
+    /// Returns Ok(None) if no complete frame is available yet
+    /// Returns Err if an error occurred
+    /// 
+    /// If bytes are received, continues reading until a complete frame is found or no more data is available
+    pub fn try_receive(&self) -> Result<Option<Message>, MessageError> {
+        let mut any_bytes_received = false;
+
+        // Keep reading until we have a complete frame or no more data is available
+        loop {
+            // Read from serial port
+            let bytes_read = {
+                if let Ok(mut port) = self.port.lock() {
+                    let mut buffer = [0u8; 256];
+
+                    // Read available bytes from serial (non-blocking due to timeout)
+                    match port.read(&mut buffer) {
+                        Ok(n) if n > 0 => {
+                            any_bytes_received = true;
+                            Some(buffer[..n].to_vec())
+                        }
+                        Ok(_) => None, // No data available
+                        // No data available
+                        Err(ref e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => None,
+                        Err(e) => return Err(MessageError::ReadError(format!("Serial read error: {}", e))),
+                    }
+                } else {
+                    return Err(MessageError::LockError);
+                }
+            };
+
+            // Append new data to receive buffer if any was read
+            if let Some(new_data) = bytes_read {
+                if let Ok(mut recv_buf) = self.receive_buffer.lock() {
+                    recv_buf.extend_from_slice(&new_data);
+                } else {
+                    return Err(MessageError::LockError);
+                }
+            } else {
+                // No more data available, break out of read loop
+                break;
+            }
+        }
+
+        // Update last read time if we received any bytes
+        if any_bytes_received && let Ok(mut last_read) = self.last_read_time.lock() {
+            *last_read = Some(std::time::Instant::now());
+        }
+
+        // Look for complete frame (ending with byte 0)
+        if let Ok(mut recv_buf) = self.receive_buffer.lock() {
+            if recv_buf.is_empty() {
+                return Ok(None);
+            }
+
+            // Keep trying to find and decode valid frames
+            loop {
+                // Find frame delimiter (byte 0)
+                if let Some(frame_end) = recv_buf.iter().position(|&b| b == FRAME_DELIMITER) {
+                    // Found a potential complete frame (including delimiter at frame_end)
+                    // Extract frame data (need mutable for from_bytes_cobs)
+                    let mut frame_data = recv_buf[..=frame_end].to_vec();
+
+                    // Try to decode COBS, verify the CRC and deserialize message
+                    match framing::decode(&mut frame_data) {
+                        Ok(message) => {
+                            // Success! Remove the frame (including delimiter) from buffer
+                            recv_buf.drain(..=frame_end);
+                            if let Message::Time(time) = &message
+                                && let Some(pacer) = self.pacer.lock().map_err(|_| MessageError::LockError)?.as_mut()
+                            {
+                                pacer.time_received(time.uptime_us, Instant::now());
+                            }
+                            if let Message::Log(payload) = &message {
+                                let mut subscribers =
+                                    self.log_subscribers.lock().map_err(|_| MessageError::LockError)?;
+                                subscribers.retain(|subscriber| subscriber.send(payload.clone()).is_ok());
+                            }
+                            return Ok(Some(message));
+                        }
+                        Err(e) => {
+                            // Deserialization failed - this might be corrupted data
+                            // Discard the first byte and continue searching for another delimiter
+                            if let FrameError::CrcMismatch { .. } = e {
+                                self.crc_failures.fetch_add(1, Ordering::Relaxed);
+                            }
+                            self.resyncs.fetch_add(1, Ordering::Relaxed);
+                            if recv_buf.len() > 1 {
+                                recv_buf.remove(0);
+                                // Continue loop to look for another delimiter
+                            } else {
+                                recv_buf.clear();
+                                break;
+                            }
+                        }
+                    }
+                } else {
+                    // No delimiter found - frame is incomplete or buffer is empty
+                    // If buffer is getting too large, clear it to prevent memory issues
+                    if recv_buf.len() > 4096 {
+                        recv_buf.clear();
+                        return Err(MessageError::BufferOverflow);
+                    }
+                    break;
+                }
+            }
+        }
+
+        // Only return None if we didn't receive any new bytes
+        // If we received bytes but no delimiter, the frame is incomplete
+        if any_bytes_received {
+            // We received bytes but no complete frame - wait for more data
+            Ok(None)
+        } else {
+            // No bytes received at all
+            Ok(None)
+        }
+    }
+
+    /// Send a frame covering every strip in order, acknowledged by the firmware with seq once it's displayed
+    pub fn send_frame(&self, seq: u32, leds: Vec<Rgb>) -> Result<(), MessageError> {
+        self.send(&Message::SetLeds(SetLedsPayload { seq, strip: None, leds }))
+    }
+
+    /// Receive a copy of every log message from the firmware
+    /// Logs reach the receiver as they're received by try_receive or receive, which still return them
+    pub fn subscribe_logs(&self) -> Result<Receiver<LogPayload>, MessageError> {
+        let (sender, receiver) = mpsc::channel();
+        self.log_subscribers.lock().map_err(|_| MessageError::LockError)?.push(sender);
+        Ok(receiver)
+    }
+
+    /// Send a run of frame delimiters, waking the firmware from light sleep
+    /// The firmware ignores empty frames, so this is harmless while it's awake
+    pub fn wake(&self) -> Result<(), MessageError> {
+        if let Ok(mut port) = self.port.lock() {
+            port.write_all(&[FRAME_DELIMITER; WAKE_DELIMITERS])
+                .map_err(|e| MessageError::WriteError(format!("Serial write error: {}", e)))?;
+            port.flush()
+                .map_err(|e| MessageError::WriteError(format!("Serial flush error: {}", e)))?;
+        } else {
+            return Err(MessageError::LockError);
+        }
+        // Give the firmware time to come out of sleep before the next message
+        std::thread::sleep(WAKE_TIME);
+        Ok(())
+    }
+
+    /// Present frames delay after they're sent, paced on the firmware's clock, or as they arrive for None
+    /// Frames are paced once the firmware has answered request_time
+    pub fn set_presentation_delay(&self, delay: Option<Duration>) -> Result<(), MessageError> {
+        *self.pacer.lock().map_err(|_| MessageError::LockError)? = delay.map(FramePacer::new);
+        Ok(())
+    }
+
+    /// Ask for the firmware's clock while frames are paced, refining the estimate of it with each answer
+    pub fn request_time(&self) -> Result<(), MessageError> {
+        if self.pacer.lock().map_err(|_| MessageError::LockError)?.is_none() {
+            return Ok(());
+        }
+        self.send(&Message::GetTime)?;
+        if let Some(pacer) = self.pacer.lock().map_err(|_| MessageError::LockError)?.as_mut() {
+            pacer.time_requested(Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Switch the serial port to a baud rate, discarding anything received at the old one
+    pub fn set_baud_rate(&self, baud_rate: u32) -> Result<(), MessageError> {
+        let mut port = self.port.lock().map_err(|_| MessageError::LockError)?;
+        port.set_baud_rate(baud_rate)
+            .map_err(|e| MessageError::PortError(format!("Failed to set baud rate: {}", e)))?;
+        self.receive_buffer.lock().map_err(|_| MessageError::LockError)?.clear();
+        Ok(())
+    }
+
+    /// Ask the firmware to switch to a higher baud rate, and switch with it if it agrees
+    /// A handshake at the new rate confirms the link works, otherwise both ends fall back to DEFAULT_BAUD_RATE
+    /// Returns the baud rate the link is running at
+    pub fn negotiate_baud_rate(&self, baud_rate: u32, timeout: Duration) -> Result<u32, MessageError> {
+        self.send(&Message::SetBaudRate(BaudRatePayload { baud_rate }))?;
+        let agreed = self.wait_for(timeout, |message| match message {
+            Message::BaudRate(payload) => Some(payload.baud_rate),
+            _ => None,
+        })?;
+        if agreed != baud_rate {
+            return Ok(agreed);
+        }
+
+        std::thread::sleep(BAUD_SWITCH_TIME);
+        self.set_baud_rate(baud_rate)?;
+        match self.handshake(timeout.min(BAUD_FALLBACK_TIME)) {
+            Ok(_) => Ok(baud_rate),
+            Err(MessageError::Timeout) => {
+                // Wait out the firmware's fallback, then make sure the link works at the default rate
+                std::thread::sleep(BAUD_FALLBACK_TIME);
+                self.set_baud_rate(DEFAULT_BAUD_RATE)?;
+                self.handshake(timeout)?;
+                Ok(DEFAULT_BAUD_RATE)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Exchange Hello messages with the firmware
+    /// Returns the firmware's Hello, or an error if it speaks an incompatible protocol version
+    /// Messages received before the firmware's Hello are discarded
+    pub fn handshake(&self, timeout: Duration) -> Result<HelloPayload, MessageError> {
+        self.send(&Message::Hello(HelloPayload {
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: String::new(),
+        }))?;
+
+        let hello = self.wait_for(timeout, |message| match message {
+            Message::Hello(hello) => Some(hello),
+            _ => None,
+        })?;
+        if hello.protocol_version != PROTOCOL_VERSION {
+            return Err(MessageError::IncompatibleProtocol {
+                firmware: hello.protocol_version,
+                server: PROTOCOL_VERSION,
+            });
+        }
+        Ok(hello)
+    }
+
+    /// Ask the firmware to describe itself
+    /// Messages received before the firmware's DeviceInfo are discarded
+    /// Larger messages are sent in chunks from then on, so no frame exceeds the firmware's max_frame_size
+    pub fn device_info(&self, timeout: Duration) -> Result<DeviceInfoPayload, MessageError> {
+        self.send(&Message::GetDeviceInfo)?;
+        let info = self.wait_for(timeout, |message| match message {
+            Message::DeviceInfo(info) => Some(info),
+            _ => None,
+        })?;
+        self.max_frame_size.store(info.max_frame_size as usize, Ordering::Relaxed);
+        Ok(info)
+    }
+
+    /// Ask the firmware why it last reset
+    /// Messages received before the firmware's ResetReport are discarded
+    pub fn reset_report(&self, timeout: Duration) -> Result<ResetReportPayload, MessageError> {
+        self.send(&Message::GetResetReport)?;
+        self.wait_for(timeout, |message| match message {
+            Message::ResetReport(report) => Some(report),
+            _ => None,
+        })
+    }
+
+    /// Read the firmware's persistent settings
+    /// Messages received before the firmware's Config are discarded
+    pub fn config(&self, timeout: Duration) -> Result<ConfigPayload, MessageError> {
+        self.send(&Message::GetConfig)?;
+        self.wait_for_config(timeout)
+    }
+
+    /// Replace the firmware's persistent settings
+    /// Returns the settings the firmware stored, which it may have limited to what it supports
+    pub fn set_config(&self, config: ConfigPayload, timeout: Duration) -> Result<ConfigPayload, MessageError> {
+        self.send(&Message::SetConfig(config))?;
+        self.wait_for_config(timeout)
+    }
+
+    /// Wait for the firmware's Config
+    fn wait_for_config(&self, timeout: Duration) -> Result<ConfigPayload, MessageError> {
+        self.wait_for(timeout, |message| match message {
+            Message::Config(config) => Some(config),
+            _ => None,
+        })
+    }
+
+    /// Read the firmware's schedule
+    pub fn schedule(&self, timeout: Duration) -> Result<SchedulePayload, MessageError> {
+        self.send(&Message::GetSchedule)?;
+        self.wait_for_schedule(timeout)
+    }
+
+    /// Replace the firmware's schedule
+    /// Returns the schedule the firmware follows, the previous one if it rejected this one
+    pub fn set_schedule(&self, schedule: SchedulePayload, timeout: Duration) -> Result<SchedulePayload, MessageError> {
+        self.send(&Message::SetSchedule(schedule))?;
+        self.wait_for_schedule(timeout)
+    }
+
+    /// Wait for the firmware's Schedule
+    fn wait_for_schedule(&self, timeout: Duration) -> Result<SchedulePayload, MessageError> {
+        self.wait_for(timeout, |message| match message {
+            Message::Schedule(schedule) => Some(schedule),
+            _ => None,
+        })
+    }
+
+    /// Read the LEDs the firmware is displaying
+    pub fn leds(&self, timeout: Duration) -> Result<Vec<Rgb>, MessageError> {
+        self.send(&Message::GetLeds(GetLedsPayload {
+            offset: 0,
+            count: u16::MAX,
+        }))?;
+        self.wait_for(timeout, |message| match message {
+            Message::Leds(payload) => Some(payload.leds),
+            _ => None,
+        })
+    }
+
+    /// Read the firmware's segments
+    pub fn segments(&self, timeout: Duration) -> Result<SegmentsPayload, MessageError> {
+        self.send(&Message::GetSegments)?;
+        self.wait_for_segments(timeout)
+    }
+
+    /// Replace the firmware's segments
+    /// Returns the segments the firmware runs, the previous ones if it rejected these
+    pub fn set_segments(&self, segments: SegmentsPayload, timeout: Duration) -> Result<SegmentsPayload, MessageError> {
+        self.send(&Message::SetSegments(segments))?;
+        self.wait_for_segments(timeout)
+    }
+
+    /// Wait for the firmware's Segments
+    fn wait_for_segments(&self, timeout: Duration) -> Result<SegmentsPayload, MessageError> {
+        self.wait_for(timeout, |message| match message {
+            Message::Segments(segments) => Some(segments),
+            _ => None,
+        })
+    }
+
+    /// Turn the firmware's microphone-driven effects on or off
+    /// Returns the sound reactivity the firmware applied
+    pub fn set_sound_reactive(
+        &self,
+        payload: SoundReactivePayload,
+        timeout: Duration,
+    ) -> Result<SoundReactivePayload, MessageError> {
+        self.send(&Message::SetSoundReactive(payload))?;
+        self.wait_for(timeout, |message| match message {
+            Message::SoundReactive(payload) => Some(payload),
+            _ => None,
+        })
+    }
+
+    /// Upload a firmware image to the inactive OTA partition and reboot the firmware into it
+    /// Each chunk waits for the firmware's UpdateStatus, progress is called with the bytes written so far
+    pub fn update_firmware(
+        &self,
+        image: &[u8],
+        chunk_size: usize,
+        timeout: Duration,
+        mut progress: impl FnMut(usize),
+    ) -> Result<(), MessageError> {
+        self.send(&Message::BeginUpdate(BeginUpdatePayload {
+            size: image.len() as u32,
+            crc: framing::crc32(image),
+        }))?;
+        self.update_status(timeout)?;
+
+        for (index, chunk) in image.chunks(chunk_size).enumerate() {
+            self.send(&Message::FirmwareChunk(FirmwareChunkPayload {
+                offset: (index * chunk_size) as u32,
+                data: chunk.to_vec(),
+            }))?;
+            progress(self.update_status(timeout)?.written as usize);
+        }
+
+        self.send(&Message::FinishUpdate)?;
+        self.update_status(timeout).map(|_| ())
+    }
+
+    /// Wait for the firmware's UpdateStatus, turning a reported failure into an error
+    fn update_status(&self, timeout: Duration) -> Result<UpdateStatusPayload, MessageError> {
+        let status = self.wait_for(timeout, |message| match message {
+            Message::UpdateStatus(status) => Some(status),
+            _ => None,
+        })?;
+        match status.error {
+            Some(error) => Err(MessageError::UpdateFailed(error)),
+            None => Ok(status),
+        }
+    }
+
+    /// Receive messages until one is accepted by f or timeout occurs
+    fn wait_for<R>(&self, timeout: Duration, mut f: impl FnMut(Message) -> Option<R>) -> Result<R, MessageError> {
+        let start = std::time::Instant::now();
+        loop {
+            let remaining = timeout.saturating_sub(start.elapsed());
+            if let Some(accepted) = f(self.receive(remaining)?) {
+                return Ok(accepted);
+            }
+        }
+    }
+
+    /// Take the number of times the receiver discarded data to resynchronize since the last call
+    pub fn take_resyncs(&self) -> usize {
+        self.resyncs.swap(0, Ordering::Relaxed)
+    }
+
+    /// Take the number of frames that failed their CRC check since the last call
+    pub fn take_crc_failures(&self) -> usize {
+        self.crc_failures.swap(0, Ordering::Relaxed)
+    }
+
+    /// Blocking receive that waits for a message
+    /// This will block until a complete message is received or timeout occurs
+    pub fn receive(&self, timeout: Duration) -> Result<Message, MessageError> {
+        let start = std::time::Instant::now();
+
+        loop {
+            match self.try_receive() {
+                Ok(Some(message)) => return Ok(message),
+                Ok(None) => {
+                    if start.elapsed() >= timeout {
+                        return Err(MessageError::Timeout);
+                    }
+                    // Small delay to avoid busy waiting
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Errors that can occur when handling messages
+#[derive(Debug)]
+pub enum MessageError {
+    Serialization(String),
+    Deserialization(String),
+    WriteError(String),
+    ReadError(String),
+    PortError(String),
+    LockError,
+    Timeout,
+    BufferOverflow,
+    IncompatibleProtocol { firmware: u16, server: u16 },
+    UpdateFailed(UpdateError),
+}
+
+impl std::fmt::Display for MessageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MessageError::Serialization(e) => write!(f, "Serialization error: {}", e),
+            MessageError::Deserialization(e) => write!(f, "Deserialization error: {}", e),
+            MessageError::WriteError(e) => write!(f, "Write error: {}", e),
+            MessageError::ReadError(e) => write!(f, "Read error: {}", e),
+            MessageError::PortError(e) => write!(f, "Port error: {}", e),
+            MessageError::LockError => write!(f, "Failed to acquire lock"),
+            MessageError::Timeout => write!(f, "Receive timeout"),
+            MessageError::BufferOverflow => write!(f, "Receive buffer overflow"),
+            MessageError::IncompatibleProtocol { firmware, server } => write!(
+                f,
+                "Firmware speaks protocol version {}, server speaks version {}",
+                firmware, server
+            ),
+            MessageError::UpdateFailed(e) => write!(f, "Firmware update failed: {:?}", e),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+impl From<std::io::Error> for MessageError {
+    fn from(e: std::io::Error) -> Self {
+        MessageError::PortError(format!("Serial port error: {}", e))
+    }
+}
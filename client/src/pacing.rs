@@ -0,0 +1,129 @@
+use common::message::{Message, TimedFramePayload};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Clock readings kept, the one with the shortest round trip is trusted
+/// Old readings age out so the estimate follows drift between the clocks
+const MAX_SAMPLES: usize = 8;
+
+/// Reading of the firmware's clock
+struct Sample {
+    /// Host time halfway through the round trip, when the firmware is assumed to have read its clock
+    host: Instant,
+    firmware_us: u64,
+    round_trip: Duration,
+}
+
+/// Frame pacer stamping frames with a presentation time on the firmware's clock
+///
+/// The firmware's clock is estimated from Time replies to GetTime, and frames are presented a fixed delay
+/// after they're sent, so jitter in the link and the host's scheduling doesn't reach the LEDs.
+pub struct FramePacer {
+    delay: Duration,
+    samples: VecDeque<Sample>,
+    requested_at: Option<Instant>,
+}
+
+impl FramePacer {
+    /// Create a new FramePacer presenting frames delay after they're sent
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            samples: VecDeque::new(),
+            requested_at: None,
+        }
+    }
+
+    /// Record that GetTime was sent at
+    pub fn time_requested(&mut self, at: Instant) {
+        self.requested_at = Some(at);
+    }
+
+    /// Record the firmware's Time reply, received at
+    /// Replies without a matching request are ignored
+    pub fn time_received(&mut self, firmware_us: u64, at: Instant) {
+        let Some(requested_at) = self.requested_at.take() else {
+            return;
+        };
+        let round_trip = at.saturating_duration_since(requested_at);
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample {
+            host: requested_at + round_trip / 2,
+            firmware_us,
+            round_trip,
+        });
+    }
+
+    /// Firmware uptime in microseconds at a host time, None until the firmware's clock has been read
+    pub fn firmware_time(&self, at: Instant) -> Option<u64> {
+        let best = self.samples.iter().min_by_key(|sample| sample.round_trip)?;
+        let time = if at >= best.host {
+            best.firmware_us + (at - best.host).as_micros() as u64
+        } else {
+            best.firmware_us.saturating_sub((best.host - at).as_micros() as u64)
+        };
+        Some(time)
+    }
+
+    /// Wrap a frame sent at now in a TimedFrame presented delay later
+    /// Other messages, and frames sent before the firmware's clock is known, are left as they are
+    pub fn pace(&self, message: Message, now: Instant) -> Message {
+        match self.firmware_time(now + self.delay) {
+            Some(present_at_us) if message.frame_seq().is_some() => Message::TimedFrame(TimedFramePayload {
+                present_at_us,
+                frame: Box::new(message),
+            }),
+            _ => message,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trusts_the_shortest_round_trip() {
+        let start = Instant::now();
+        let ms = |ms: u64| start + Duration::from_millis(ms);
+        let mut pacer = FramePacer::new(Duration::from_millis(50));
+        assert_eq!(pacer.firmware_time(start), None);
+
+        // Firmware read 1 s at host 5 ms, with a 10 ms round trip
+        pacer.time_requested(ms(0));
+        pacer.time_received(1_000_000, ms(10));
+        // A slow reply would put the firmware 20 ms behind
+        pacer.time_requested(ms(100));
+        pacer.time_received(1_060_000, ms(190));
+        // A reply nobody asked for
+        pacer.time_received(0, ms(200));
+
+        assert_eq!(pacer.firmware_time(ms(105)), Some(1_100_000));
+        assert_eq!(pacer.firmware_time(ms(0)), Some(995_000));
+    }
+
+    #[test]
+    fn paces_only_frames() {
+        let start = Instant::now();
+        let mut pacer = FramePacer::new(Duration::from_millis(50));
+        let frame = Message::SetLeds(common::message::SetLedsPayload {
+            seq: 1,
+            strip: None,
+            leds: Vec::new(),
+        });
+        assert_eq!(pacer.pace(frame.clone(), start), frame);
+
+        pacer.time_requested(start);
+        pacer.time_received(0, start);
+        assert_eq!(
+            pacer.pace(frame.clone(), start),
+            Message::TimedFrame(TimedFramePayload {
+                present_at_us: 50_000,
+                frame: Box::new(frame),
+            })
+        );
+        assert_eq!(pacer.pace(Message::Heartbeat, start), Message::Heartbeat);
+    }
+}
@@ -0,0 +1,372 @@
+use common::chunking::Reassembler;
+use common::framing;
+use common::message::{
+    AckPayload, BaudRatePayload, BeginUpdatePayload, DeviceInfoPayload, HelloPayload, LedsPayload, LogPayload, Message,
+    NackPayload, NackReason, Rgb, TimePayload, UpdateError, UpdateStatusPayload, PROTOCOL_VERSION,
+};
+use log::Level;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::messages::{MessageError, MessageHandler};
+use crate::transport::{Loopback, Transport};
+
+/// Firmware version the simulator answers the handshake with
+pub const SIMULATOR_VERSION: &str = "simulator";
+
+/// Largest frame the simulator accepts, small enough that large frames arrive in FrameChunks
+const MAX_FRAME_SIZE: u32 = 1024;
+
+/// Largest message the simulator reassembles from FrameChunks
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Time the simulator waits for a message before checking whether it's been stopped
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Largest firmware image the simulator takes, the size of an OTA slot in firmware/partitions.csv
+const UPDATE_PARTITION_SIZE: u32 = 0x1e0000;
+
+/// What the simulated firmware has received and is displaying
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimulatorState {
+    /// LEDs on display, black until the first frame
+    pub leds: Vec<Rgb>,
+    /// Frames displayed since the simulator started
+    pub frames: u32,
+    /// Heartbeats answered since the simulator started
+    pub heartbeats: u32,
+    /// Image of the last firmware update that was verified
+    pub firmware: Option<Vec<u8>>,
+    /// Messages the simulator doesn't handle, which the real firmware may
+    pub unhandled: Vec<Message>,
+}
+
+/// Simulated firmware speaking the protocol over a Loopback, or another transport, on its own thread, for testing
+/// the host without an ESP32
+///
+/// It answers the handshake, heartbeats, DeviceInfo, GetTime, SetBaudRate and GetLeds, displays and acknowledges
+/// SetLeds frames, reassembling them from FrameChunks, takes firmware updates like the firmware's inactive OTA
+/// partition, and logs what it receives like the firmware.
+pub struct Simulator<T: Transport = Loopback> {
+    device: Arc<MessageHandler<T>>,
+    state: Arc<Mutex<SimulatorState>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Simulator {
+    /// Start a simulated tree with a single strip of num_leds LEDs
+    /// Returns the simulator and a MessageHandler for the host's end of the link
+    pub fn start(num_leds: u16) -> (Simulator, MessageHandler<Loopback>) {
+        let (host, device) = Loopback::pair();
+        (Simulator::with_transport(device, num_leds), MessageHandler::with_transport(host))
+    }
+}
+
+impl<T: Transport + 'static> Simulator<T> {
+    /// Start a simulated tree with a single strip of num_leds LEDs, answering over the device's end of a link,
+    /// such as a pseudo-terminal a host opens like a serial port
+    pub fn with_transport(device: T, num_leds: u16) -> Simulator<T> {
+        let device = Arc::new(MessageHandler::with_transport(device));
+        let state = Arc::new(Mutex::new(SimulatorState {
+            leds: vec![Rgb::default(); num_leds as usize],
+            ..SimulatorState::default()
+        }));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let device = device.clone();
+            let state = state.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || run(&device, &state, &stop, num_leds))
+        };
+        Simulator {
+            device,
+            state,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// What the simulator has received and is displaying
+    pub fn state(&self) -> SimulatorState {
+        self.state.lock().map(|state| state.clone()).unwrap_or_default()
+    }
+
+    /// Send a log message to the host, as the firmware's logger would
+    pub fn log(&self, level: Level, content: &str) -> Result<(), MessageError> {
+        self.device.send(&Message::Log(LogPayload::new(level, content.to_string())))
+    }
+}
+
+impl<T: Transport> Drop for Simulator<T> {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Answer the host's messages until stop is set
+fn run<T: Transport>(device: &MessageHandler<T>, state: &Mutex<SimulatorState>, stop: &AtomicBool, num_leds: u16) {
+    let started = Instant::now();
+    let mut reassembler = Reassembler::new(MAX_MESSAGE_SIZE);
+    // Update in progress and the image received so far
+    let mut update: Option<(BeginUpdatePayload, Vec<u8>)> = None;
+    let log = |level: Level, content: String| {
+        let uptime_ms = started.elapsed().as_millis() as u64;
+        device.send(&Message::Log(LogPayload::new(level, content).at(uptime_ms))).ok();
+    };
+
+    while !stop.load(Ordering::Relaxed) {
+        let message = match device.receive(POLL_INTERVAL) {
+            Ok(Message::FrameChunk(chunk)) => match reassembler.push(chunk) {
+                Ok(Some(message)) => message,
+                Ok(None) => continue,
+                Err(e) => {
+                    log(Level::Warn, format!("Dropped chunked message: {:?}", e));
+                    let nack = NackPayload {
+                        seq: Some(e.seq()),
+                        reason: NackReason::DecodeFailed,
+                    };
+                    device.send(&Message::Nack(nack)).ok();
+                    continue;
+                }
+            },
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+        let Ok(mut state) = state.lock() else {
+            return;
+        };
+
+        let reply = match message {
+            Message::Hello(_) => Some(Message::Hello(HelloPayload {
+                protocol_version: PROTOCOL_VERSION,
+                firmware_version: SIMULATOR_VERSION.to_string(),
+            })),
+            Message::Heartbeat => {
+                state.heartbeats += 1;
+                Some(Message::Heartbeat)
+            }
+            Message::GetDeviceInfo => Some(Message::DeviceInfo(DeviceInfoPayload {
+                num_leds,
+                strips: vec![num_leds],
+                chip: SIMULATOR_VERSION.to_string(),
+                fw_version: SIMULATOR_VERSION.to_string(),
+                max_frame_size: MAX_FRAME_SIZE,
+            })),
+            Message::GetTime => Some(Message::Time(TimePayload {
+                uptime_us: started.elapsed().as_micros() as u64,
+            })),
+            // The loopback has no baud rate, so any rate works
+            Message::SetBaudRate(payload) => Some(Message::BaudRate(BaudRatePayload {
+                baud_rate: payload.baud_rate,
+            })),
+            Message::SetLeds(payload) => {
+                log(Level::Debug, format!("Received SetLeds command with {} LEDs", payload.leds.len()));
+                if payload.strip.is_none() && payload.leds.len() == num_leds as usize {
+                    state.leds = payload.leds;
+                    state.frames += 1;
+                    Some(Message::Ack(AckPayload { seq: payload.seq }))
+                } else {
+                    Some(Message::Nack(NackPayload {
+                        seq: Some(payload.seq),
+                        reason: NackReason::WrongLength,
+                    }))
+                }
+            }
+            Message::GetLeds(payload) => {
+                let start = (payload.offset as usize).min(state.leds.len());
+                let end = (start + payload.count as usize).min(state.leds.len());
+                Some(Message::Leds(LedsPayload {
+                    offset: payload.offset,
+                    leds: state.leds[start..end].to_vec(),
+                }))
+            }
+            Message::BeginUpdate(payload) => {
+                let error = (payload.size > UPDATE_PARTITION_SIZE).then_some(UpdateError::TooLarge);
+                if error.is_none() {
+                    update = Some((payload, Vec::new()));
+                }
+                Some(Message::UpdateStatus(UpdateStatusPayload { written: 0, error }))
+            }
+            Message::FirmwareChunk(chunk) => {
+                let status = match &mut update {
+                    Some((begin, image)) => {
+                        let error = if chunk.offset as usize != image.len() {
+                            Some(UpdateError::OutOfOrder)
+                        } else if image.len() + chunk.data.len() > begin.size as usize {
+                            Some(UpdateError::TooLarge)
+                        } else {
+                            image.extend_from_slice(&chunk.data);
+                            None
+                        };
+                        UpdateStatusPayload {
+                            written: image.len() as u32,
+                            error,
+                        }
+                    }
+                    None => UpdateStatusPayload {
+                        written: 0,
+                        error: Some(UpdateError::NotStarted),
+                    },
+                };
+                if status.error.is_some() {
+                    update = None;
+                }
+                Some(Message::UpdateStatus(status))
+            }
+            Message::FinishUpdate => {
+                let status = match update.take() {
+                    Some((begin, image)) => {
+                        let error = if image.len() != begin.size as usize {
+                            Some(UpdateError::Incomplete)
+                        } else if framing::crc32(&image) != begin.crc {
+                            Some(UpdateError::CrcMismatch)
+                        } else {
+                            None
+                        };
+                        let written = image.len() as u32;
+                        if error.is_none() {
+                            state.firmware = Some(image);
+                        }
+                        UpdateStatusPayload { written, error }
+                    }
+                    None => UpdateStatusPayload {
+                        written: 0,
+                        error: Some(UpdateError::NotStarted),
+                    },
+                };
+                Some(Message::UpdateStatus(status))
+            }
+            message => {
+                log(Level::Warn, format!("Received unexpected message: {:?}", message));
+                state.unhandled.push(message);
+                None
+            }
+        };
+        drop(state);
+        if let Some(reply) = reply {
+            device.send(&reply).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::message::FirmwareChunkPayload;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Receive the simulator's next message that isn't a log
+    fn reply(host: &MessageHandler<Loopback>) -> Message {
+        loop {
+            match host.receive(TIMEOUT).unwrap() {
+                Message::Log(_) => {}
+                message => return message,
+            }
+        }
+    }
+
+    #[test]
+    fn handshake_and_device_info() {
+        let (_simulator, host) = Simulator::start(50);
+        assert_eq!(host.handshake(TIMEOUT).unwrap().firmware_version, SIMULATOR_VERSION);
+        let info = host.device_info(TIMEOUT).unwrap();
+        assert_eq!(info.num_leds, 50);
+        assert_eq!(info.strips, vec![50]);
+    }
+
+    #[test]
+    fn frames_are_displayed_and_acknowledged() {
+        let (simulator, host) = Simulator::start(4);
+        let leds = vec![Rgb::new(255, 0, 0), Rgb::new(0, 255, 0), Rgb::new(0, 0, 255), Rgb::new(1, 2, 3)];
+        host.send_frame(7, leds.clone()).unwrap();
+        assert_eq!(reply(&host), Message::Ack(AckPayload { seq: 7 }));
+        assert_eq!(simulator.state().leds, leds);
+        assert_eq!(simulator.state().frames, 1);
+    }
+
+    #[test]
+    fn frames_of_the_wrong_length_are_rejected() {
+        let (simulator, host) = Simulator::start(4);
+        host.send_frame(3, vec![Rgb::default(); 5]).unwrap();
+        let nack = Message::Nack(NackPayload {
+            seq: Some(3),
+            reason: NackReason::WrongLength,
+        });
+        assert_eq!(reply(&host), nack);
+        assert_eq!(simulator.state().frames, 0);
+    }
+
+    #[test]
+    fn large_frames_are_chunked() {
+        let (simulator, host) = Simulator::start(1000);
+        host.device_info(TIMEOUT).unwrap();
+        let leds: Vec<Rgb> = (0..1000).map(|i| Rgb::new(i as u8, (i / 4) as u8, 0)).collect();
+        host.send_frame(1, leds.clone()).unwrap();
+        assert_eq!(reply(&host), Message::Ack(AckPayload { seq: 1 }));
+        assert_eq!(simulator.state().leds, leds);
+    }
+
+    #[test]
+    fn displayed_leds_are_read_back() {
+        let (_simulator, host) = Simulator::start(3);
+        let leds = vec![Rgb::new(1, 2, 3), Rgb::new(4, 5, 6), Rgb::new(7, 8, 9)];
+        host.send_frame(1, leds.clone()).unwrap();
+        assert_eq!(host.leds(TIMEOUT).unwrap(), leds);
+    }
+
+    #[test]
+    fn firmware_updates_are_written_and_verified() {
+        let (simulator, host) = Simulator::start(10);
+        let image: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let mut progress = Vec::new();
+        host.update_firmware(&image, 256, TIMEOUT, |written| progress.push(written)).unwrap();
+        assert_eq!(progress, [256, 512, 768, 1000]);
+        assert_eq!(simulator.state().firmware, Some(image));
+    }
+
+    #[test]
+    fn firmware_updates_are_checked() {
+        let (simulator, host) = Simulator::start(10);
+        let image = vec![0; UPDATE_PARTITION_SIZE as usize + 1];
+        let result = host.update_firmware(&image, 256, TIMEOUT, |_| {});
+        assert!(matches!(result, Err(MessageError::UpdateFailed(UpdateError::TooLarge))));
+
+        // A chunk is only taken after a BeginUpdate
+        let chunk = FirmwareChunkPayload {
+            offset: 0,
+            data: vec![1, 2, 3],
+        };
+        host.send(&Message::FirmwareChunk(chunk)).unwrap();
+        let status = UpdateStatusPayload {
+            written: 0,
+            error: Some(UpdateError::NotStarted),
+        };
+        assert_eq!(reply(&host), Message::UpdateStatus(status));
+        assert_eq!(simulator.state().firmware, None);
+    }
+
+    #[test]
+    fn heartbeats_are_answered() {
+        let (simulator, host) = Simulator::start(10);
+        host.send(&Message::Heartbeat).unwrap();
+        assert_eq!(reply(&host), Message::Heartbeat);
+        assert_eq!(simulator.state().heartbeats, 1);
+    }
+
+    #[test]
+    fn logs_reach_subscribers() {
+        let (simulator, host) = Simulator::start(10);
+        let logs = host.subscribe_logs().unwrap();
+        simulator.log(Level::Info, "System initialized").unwrap();
+        assert!(matches!(host.receive(TIMEOUT).unwrap(), Message::Log(_)));
+        assert_eq!(logs.try_recv().unwrap().content, "System initialized");
+    }
+}
@@ -0,0 +1,184 @@
+use serialport::SerialPort;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// Time a loopback read waits for bytes before timing out, matching the serial port's read timeout
+const LOOPBACK_READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Time a UDP read waits for a datagram before timing out, short since the firmware answers over its UART instead
+const UDP_READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Byte stream MessageHandler frames messages over
+///
+/// Reads should give up after a short wait with TimedOut or WouldBlock rather than block until bytes arrive,
+/// like the serial port's 10 ms timeout, so MessageHandler can poll the transport.
+pub trait Transport: Read + Write + Send {
+    /// Switch the link to a baud rate, transports without one ignore it
+    fn set_baud_rate(&mut self, _baud_rate: u32) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Box<dyn SerialPort> {
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        SerialPort::set_baud_rate(self.as_mut(), baud_rate).map_err(io::Error::from)
+    }
+}
+
+/// TCP link to a firmware bridged onto the network, give it a read timeout with TcpStream::set_read_timeout
+impl Transport for TcpStream {}
+
+/// UDP link to a firmware on the network, sending each message as one datagram to its UDP_PORT
+///
+/// The firmware only receives over UDP and answers over its UART, so reads time out: this suits streaming frames with
+/// send_frame rather than anything that waits for a reply, such as the handshake.
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    /// Send to the firmware at addr, such as ("192.168.1.50", common::framing::UDP_PORT)
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<UdpTransport> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.connect(addr)?;
+        socket.set_read_timeout(Some(UDP_READ_TIMEOUT))?;
+        Ok(UdpTransport { socket })
+    }
+}
+
+impl Read for UdpTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket.recv(buf)
+    }
+}
+
+impl Write for UdpTransport {
+    /// MessageHandler writes each framed message whole, so it arrives as one datagram
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for UdpTransport {}
+
+/// Unix socket or pipe to a local process speaking the protocol, give it a read timeout with
+/// UnixStream::set_read_timeout
+#[cfg(unix)]
+impl Transport for UnixStream {}
+
+/// Bytes written to one end of a loopback, waiting to be read from the other
+#[derive(Default)]
+struct Pipe {
+    bytes: Mutex<VecDeque<u8>>,
+    written: Condvar,
+}
+
+/// One end of an in-memory link, for testing without hardware
+/// Bytes written to it are read from the other end
+pub struct Loopback {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+}
+
+impl Loopback {
+    /// Create both ends of a link
+    pub fn pair() -> (Loopback, Loopback) {
+        let a = Arc::new(Pipe::default());
+        let b = Arc::new(Pipe::default());
+        (
+            Loopback {
+                incoming: a.clone(),
+                outgoing: b.clone(),
+            },
+            Loopback {
+                incoming: b,
+                outgoing: a,
+            },
+        )
+    }
+}
+
+impl Read for Loopback {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = self.incoming.bytes.lock().map_err(|_| io::Error::other("loopback poisoned"))?;
+        let (mut bytes, _) = self
+            .incoming
+            .written
+            .wait_timeout_while(bytes, LOOPBACK_READ_TIMEOUT, |bytes| bytes.is_empty())
+            .map_err(|_| io::Error::other("loopback poisoned"))?;
+        if bytes.is_empty() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        let n = buf.len().min(bytes.len());
+        for (byte, received) in buf.iter_mut().zip(bytes.drain(..n)) {
+            *byte = received;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Loopback {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut bytes = self.outgoing.bytes.lock().map_err(|_| io::Error::other("loopback poisoned"))?;
+        bytes.extend(buf);
+        self.outgoing.written.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Loopback {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MessageHandler;
+    use common::framing;
+    use common::message::Message;
+
+    #[test]
+    fn loopback_carries_messages_both_ways() {
+        let (host, device) = Loopback::pair();
+        let host = MessageHandler::with_transport(host);
+        let device = MessageHandler::with_transport(device);
+
+        host.send(&Message::Heartbeat).unwrap();
+        assert_eq!(device.receive(Duration::from_secs(1)).unwrap(), Message::Heartbeat);
+        device.send(&Message::GetTime).unwrap();
+        assert_eq!(host.receive(Duration::from_secs(1)).unwrap(), Message::GetTime);
+    }
+
+    #[test]
+    fn udp_sends_a_message_per_datagram() {
+        let firmware = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        firmware.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let host = MessageHandler::with_transport(UdpTransport::connect(firmware.local_addr().unwrap()).unwrap());
+
+        host.send(&Message::Heartbeat).unwrap();
+        host.send(&Message::GetTime).unwrap();
+        let mut datagram = [0; 64];
+        let len = firmware.recv(&mut datagram).unwrap();
+        assert_eq!(framing::decode(&mut datagram[..len]).unwrap(), Message::Heartbeat);
+        let len = firmware.recv(&mut datagram).unwrap();
+        assert_eq!(framing::decode(&mut datagram[..len]).unwrap(), Message::GetTime);
+    }
+
+    #[test]
+    fn loopback_read_times_out() {
+        let (mut host, _device) = Loopback::pair();
+        let error = host.read(&mut [0; 16]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,180 @@
+/// UDP port Art-Net is sent to and answered from
+pub const ARTNET_PORT: u16 = 6454;
+
+/// Size of an ArtPollReply
+pub const POLL_REPLY_SIZE: usize = 239;
+
+/// Most ports described by one ArtPollReply, nodes with more send a reply for each group of ports
+pub const PORTS_PER_REPLY: usize = 4;
+
+/// Starts every Art-Net packet
+const ARTNET_ID: &[u8; 8] = b"Art-Net\0";
+
+/// Protocol revision sent and required of received packets
+const PROTOCOL_VERSION: u16 = 14;
+
+const OP_POLL: u16 = 0x2000;
+const OP_POLL_REPLY: u16 = 0x2100;
+const OP_DMX: u16 = 0x5000;
+
+/// Offset of the DMX channel data in an ArtDmx packet
+const DMX_DATA_OFFSET: usize = 18;
+
+/// Port type of an output port taking DMX512 from Art-Net
+const PORT_TYPE_OUTPUT: u8 = 0x80;
+
+/// Good output bit of a port transmitting DMX
+const GOOD_OUTPUT_TRANSMITTING: u8 = 0x80;
+
+/// Status2 bit of a node supporting 15-bit port-addresses
+const STATUS2_PORT_ADDRESS_15_BIT: u8 = 0x08;
+
+/// Art-Net packet a node acts on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Packet<'a> {
+    /// A controller discovering nodes, answered with ArtPollReplies
+    Poll,
+    /// DMX channel data of one universe
+    Dmx(DmxPacket<'a>),
+}
+
+/// DMX channel data of one universe from an ArtDmx packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmxPacket<'a> {
+    /// 15-bit universe, the net in the high 7 bits, then the sub-net and universe nibbles
+    pub port_address: u16,
+    /// Sequence number from 1, 0 when the controller doesn't sequence packets
+    pub sequence: u8,
+    /// Channel values, starting at channel 1
+    pub data: &'a [u8],
+}
+
+/// Parse an Art-Net packet
+/// Returns None for other opcodes, older protocol revisions and malformed packets
+pub fn parse(packet: &[u8]) -> Option<Packet<'_>> {
+    if packet.get(..8)? != ARTNET_ID {
+        return None;
+    }
+    let opcode = u16::from_le_bytes([*packet.get(8)?, *packet.get(9)?]);
+    let version = u16::from_be_bytes([*packet.get(10)?, *packet.get(11)?]);
+    if version < PROTOCOL_VERSION {
+        return None;
+    }
+    match opcode {
+        OP_POLL => Some(Packet::Poll),
+        OP_DMX => {
+            let length = u16::from_be_bytes([*packet.get(16)?, *packet.get(17)?]) as usize;
+            Some(Packet::Dmx(DmxPacket {
+                port_address: u16::from_le_bytes([packet[14], packet[15] & 0x7f]),
+                sequence: packet[12],
+                data: packet.get(DMX_DATA_OFFSET..DMX_DATA_OFFSET + length)?,
+            }))
+        }
+        _ => None,
+    }
+}
+
+/// ArtPollReply describing up to PORTS_PER_REPLY output ports of a node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PollReply<'a> {
+    /// IPv4 address controllers send the node's ArtDmx to
+    pub ip: [u8; 4],
+    /// Name shown in node lists, truncated to 17 bytes
+    pub short_name: &'a str,
+    /// Description, truncated to 63 bytes
+    pub long_name: &'a str,
+    /// Port-addresses of the ports, which must share their net and sub-net
+    pub port_addresses: &'a [u16],
+    /// Which of the node's replies this is, from 1
+    pub bind_index: u8,
+}
+
+impl PollReply<'_> {
+    /// Encode the reply as sent to the controller
+    pub fn encode(&self) -> [u8; POLL_REPLY_SIZE] {
+        let mut reply = [0u8; POLL_REPLY_SIZE];
+        let ports = &self.port_addresses[..self.port_addresses.len().min(PORTS_PER_REPLY)];
+        let first = ports.first().copied().unwrap_or_default();
+
+        reply[..8].copy_from_slice(ARTNET_ID);
+        reply[8..10].copy_from_slice(&OP_POLL_REPLY.to_le_bytes());
+        reply[10..14].copy_from_slice(&self.ip);
+        reply[14..16].copy_from_slice(&ARTNET_PORT.to_le_bytes());
+        reply[18] = (first >> 8) as u8 & 0x7f;
+        reply[19] = (first >> 4) as u8 & 0x0f;
+        copy_name(&mut reply[26..44], self.short_name);
+        copy_name(&mut reply[44..108], self.long_name);
+        reply[172..174].copy_from_slice(&(ports.len() as u16).to_be_bytes());
+        for (index, port_address) in ports.iter().enumerate() {
+            reply[174 + index] = PORT_TYPE_OUTPUT;
+            reply[182 + index] = GOOD_OUTPUT_TRANSMITTING;
+            reply[190 + index] = *port_address as u8 & 0x0f;
+        }
+        reply[207..211].copy_from_slice(&self.ip);
+        reply[211] = self.bind_index;
+        reply[212] = STATUS2_PORT_ADDRESS_15_BIT;
+        reply
+    }
+}
+
+/// Copy a name into a null-terminated field, truncating it to fit
+fn copy_name(field: &mut [u8], name: &str) {
+    let len = name.len().min(field.len() - 1);
+    field[..len].copy_from_slice(&name.as_bytes()[..len]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn header(opcode: u16) -> Vec<u8> {
+        let mut packet = ARTNET_ID.to_vec();
+        packet.extend_from_slice(&opcode.to_le_bytes());
+        packet.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn parses_dmx_and_poll_packets() {
+        let mut packet = header(OP_DMX);
+        packet.extend_from_slice(&[3, 0, 0x21, 0x01, 0, 4, 255, 0, 128, 7]);
+        assert_eq!(
+            parse(&packet),
+            Some(Packet::Dmx(DmxPacket {
+                port_address: 0x0121,
+                sequence: 3,
+                data: &[255, 0, 128, 7],
+            }))
+        );
+        assert_eq!(parse(&packet[..packet.len() - 1]), None);
+
+        let mut poll = header(OP_POLL);
+        poll.extend_from_slice(&[0, 0]);
+        assert_eq!(parse(&poll), Some(Packet::Poll));
+        poll[11] = 13;
+        assert_eq!(parse(&poll), None);
+    }
+
+    #[test]
+    fn encodes_poll_replies() {
+        let reply = PollReply {
+            ip: [192, 168, 1, 20],
+            short_name: "Christmas tree",
+            long_name: "A name far longer than the sixty-four bytes the long name field holds, which is truncated",
+            port_addresses: &[0x0120, 0x0121],
+            bind_index: 1,
+        }
+        .encode();
+        assert_eq!(parse(&reply), None);
+        assert_eq!(&reply[..8], ARTNET_ID);
+        assert_eq!(u16::from_le_bytes([reply[8], reply[9]]), OP_POLL_REPLY);
+        assert_eq!(&reply[10..14], &[192, 168, 1, 20]);
+        assert_eq!((reply[18], reply[19]), (0x01, 0x02));
+        assert_eq!(&reply[26..41], b"Christmas tree\0");
+        assert_eq!(reply[107], 0);
+        assert_eq!(&reply[172..174], &[0, 2]);
+        assert_eq!(&reply[174..178], &[PORT_TYPE_OUTPUT, PORT_TYPE_OUTPUT, 0, 0]);
+        assert_eq!(&reply[190..192], &[0x00, 0x01]);
+    }
+}
@@ -0,0 +1,160 @@
+use crate::framing::{self, FrameError};
+use crate::message::{FrameChunkPayload, Message};
+use alloc::vec::Vec;
+
+/// Bytes a FrameChunk adds around its data once encoded: the variant, header fields and data length,
+/// the CRC16, COBS overhead for small frames and the delimiter
+const CHUNK_OVERHEAD: usize = 24;
+
+/// Encode a message into frames no larger than max_frame_size
+/// Messages that don't fit in one frame are split into FrameChunks, which are numbered by the message's frame
+/// sequence number, or 0 if it isn't a frame
+pub fn encode(message: &Message, max_frame_size: usize) -> Result<Vec<Vec<u8>>, FrameError> {
+    let encoded = framing::encode(message)?;
+    if encoded.len() <= max_frame_size {
+        return Ok(alloc::vec![encoded]);
+    }
+
+    let data = postcard::to_allocvec(message).map_err(FrameError::Serialization)?;
+    // COBS adds a byte for every 254
+    let chunk_size = max_frame_size.saturating_sub(max_frame_size / 254 + CHUNK_OVERHEAD).max(1);
+    let chunks = data.chunks(chunk_size);
+    let count = chunks.len() as u16;
+    let seq = message.frame_seq().unwrap_or(0);
+    chunks
+        .enumerate()
+        .map(|(index, chunk)| {
+            framing::encode(&Message::FrameChunk(FrameChunkPayload {
+                seq,
+                index: index as u16,
+                count,
+                data: chunk.to_vec(),
+            }))
+        })
+        .collect()
+}
+
+/// Error reassembling a chunked message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkError {
+    /// A chunk arrived out of order, so one before it was lost and the message is dropped
+    Missing { seq: u32 },
+    /// The message would exceed the reassembler's maximum size
+    TooLarge { seq: u32 },
+    /// The reassembled message could not be deserialized
+    DecodeFailed { seq: u32 },
+}
+
+impl ChunkError {
+    /// Sequence number of the dropped message
+    pub fn seq(&self) -> u32 {
+        match *self {
+            ChunkError::Missing { seq } | ChunkError::TooLarge { seq } | ChunkError::DecodeFailed { seq } => seq,
+        }
+    }
+}
+
+/// Reassembles messages from FrameChunks arriving in order
+pub struct Reassembler {
+    max_size: usize,
+    seq: u32,
+    next_index: u16,
+    data: Vec<u8>,
+}
+
+impl Reassembler {
+    /// Create a reassembler for messages of up to max_size serialized bytes
+    pub const fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            seq: 0,
+            next_index: 0,
+            data: Vec::new(),
+        }
+    }
+
+    /// Add a chunk, returning the message once its last chunk has arrived
+    /// A first chunk starts a new message, abandoning any incomplete one
+    pub fn push(&mut self, chunk: FrameChunkPayload) -> Result<Option<Message>, ChunkError> {
+        let seq = chunk.seq;
+        if chunk.index == 0 {
+            self.seq = seq;
+            self.data.clear();
+        } else if chunk.index != self.next_index || seq != self.seq {
+            self.reset();
+            return Err(ChunkError::Missing { seq });
+        }
+        if self.data.len() + chunk.data.len() > self.max_size {
+            self.reset();
+            return Err(ChunkError::TooLarge { seq });
+        }
+        self.data.extend_from_slice(&chunk.data);
+        self.next_index = chunk.index + 1;
+
+        if self.next_index < chunk.count {
+            return Ok(None);
+        }
+        let message = Message::from_bytes(&self.data).map_err(|_| ChunkError::DecodeFailed { seq });
+        self.reset();
+        message.map(Some)
+    }
+
+    /// Drop the message being reassembled, releasing its memory
+    fn reset(&mut self) {
+        self.next_index = 0;
+        self.data = Vec::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Rgb, SetLedsPayload};
+
+    fn large_frame(seq: u32) -> Message {
+        Message::SetLeds(SetLedsPayload {
+            seq,
+            strip: None,
+            leds: (0..1000).map(|i| Rgb::new(i as u8, (i >> 8) as u8, 7)).collect(),
+        })
+    }
+
+    #[test]
+    fn chunked_round_trip() {
+        let message = large_frame(42);
+        let frames = encode(&message, 512).unwrap();
+        assert!(frames.len() > 1);
+        assert!(frames.iter().all(|frame| frame.len() <= 512));
+
+        let mut reassembler = Reassembler::new(4096);
+        let mut reassembled = None;
+        for mut frame in frames {
+            let Message::FrameChunk(chunk) = framing::decode(&mut frame).unwrap() else {
+                panic!("expected a chunk");
+            };
+            assert_eq!(chunk.seq, 42);
+            reassembled = reassembler.push(chunk).unwrap();
+        }
+        assert_eq!(reassembled, Some(message));
+
+        // Small messages aren't chunked
+        assert_eq!(encode(&Message::Heartbeat, 512).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn lost_chunks_drop_the_message() {
+        let chunks: Vec<FrameChunkPayload> = encode(&large_frame(7), 512)
+            .unwrap()
+            .into_iter()
+            .map(|mut frame| match framing::decode(&mut frame).unwrap() {
+                Message::FrameChunk(chunk) => chunk,
+                _ => panic!("expected a chunk"),
+            })
+            .collect();
+
+        let mut reassembler = Reassembler::new(4096);
+        assert_eq!(reassembler.push(chunks[0].clone()), Ok(None));
+        assert_eq!(reassembler.push(chunks[2].clone()), Err(ChunkError::Missing { seq: 7 }));
+        assert_eq!(Reassembler::new(100).push(chunks[0].clone()), Err(ChunkError::TooLarge { seq: 7 }));
+    }
+}
@@ -0,0 +1,150 @@
+use alloc::vec::Vec;
+
+/// UDP port DDP is sent to
+pub const DDP_PORT: u16 = 4048;
+
+/// Most pixel data sent in one packet, 480 RGB pixels, so packets fit in an Ethernet frame
+pub const MAX_DATA_SIZE: usize = 1440;
+
+/// Version 1 in the top two bits of the flags, the only version there is
+const FLAGS_VERSION_MASK: u8 = 0xc0;
+const FLAGS_VERSION_1: u8 = 0x40;
+
+/// Flags bit marking a timecode between the header and the data
+const FLAGS_TIMECODE: u8 = 0x10;
+
+/// Flags bit marking a query, asking the display for its status or config rather than showing pixels
+const FLAGS_QUERY: u8 = 0x02;
+
+/// Flags bit marking the last packet of a frame, which is displayed once it arrives
+const FLAGS_PUSH: u8 = 0x01;
+
+/// Data type of RGB pixels with 8 bits per channel
+const TYPE_RGB8: u8 = 0x0b;
+
+/// Destination ID of the default output device
+const ID_DISPLAY: u8 = 1;
+
+/// Destination ID of every device
+const ID_ALL: u8 = 255;
+
+/// Size of the header without a timecode
+const HEADER_SIZE: usize = 10;
+
+/// Size of the timecode following the header when present
+const TIMECODE_SIZE: usize = 4;
+
+/// Pixel data from a DDP packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataPacket<'a> {
+    /// Sequence number from 1 to 15, 0 when the sender doesn't sequence packets
+    pub sequence: u8,
+    /// Byte offset of the data in the display's RGB pixels
+    pub offset: u32,
+    /// Display the frame once this packet's data is written
+    pub push: bool,
+    /// RGB bytes, three per pixel
+    pub data: &'a [u8],
+}
+
+/// Parse a DDP packet carrying pixel data for the display
+/// Returns None for queries, replies, other destinations and malformed packets
+pub fn parse(packet: &[u8]) -> Option<DataPacket<'_>> {
+    let flags = *packet.first()?;
+    if flags & FLAGS_VERSION_MASK != FLAGS_VERSION_1 || flags & FLAGS_QUERY != 0 {
+        return None;
+    }
+    let destination = *packet.get(3)?;
+    if destination != ID_DISPLAY && destination != ID_ALL {
+        return None;
+    }
+    let offset = u32::from_be_bytes(packet.get(4..8)?.try_into().ok()?);
+    let length = u16::from_be_bytes(packet.get(8..10)?.try_into().ok()?) as usize;
+    let start = HEADER_SIZE + if flags & FLAGS_TIMECODE != 0 { TIMECODE_SIZE } else { 0 };
+    Some(DataPacket {
+        sequence: packet[1] & 0x0f,
+        offset,
+        push: flags & FLAGS_PUSH != 0,
+        data: packet.get(start..start + length)?,
+    })
+}
+
+/// Encode RGB bytes for the default display as DDP packets, the last of which pushes the frame to display
+/// Every packet carries sequence, from 1 to 15, or 0 to leave them unsequenced
+pub fn encode(data: &[u8], sequence: u8) -> Vec<Vec<u8>> {
+    let chunks: Vec<&[u8]> = if data.is_empty() {
+        alloc::vec![&[]]
+    } else {
+        data.chunks(MAX_DATA_SIZE).collect()
+    };
+    let last = chunks.len() - 1;
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let flags = if index == last {
+                FLAGS_VERSION_1 | FLAGS_PUSH
+            } else {
+                FLAGS_VERSION_1
+            };
+            let mut packet = Vec::with_capacity(HEADER_SIZE + chunk.len());
+            packet.extend_from_slice(&[flags, sequence & 0x0f, TYPE_RGB8, ID_DISPLAY]);
+            packet.extend_from_slice(&((index * MAX_DATA_SIZE) as u32).to_be_bytes());
+            packet.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            packet.extend_from_slice(chunk);
+            packet
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(flags: u8, destination: u8, offset: u32, data: &[u8]) -> Vec<u8> {
+        let mut packet = alloc::vec![flags, 0x05, 0x0b, destination];
+        packet.extend_from_slice(&offset.to_be_bytes());
+        packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        if flags & FLAGS_TIMECODE != 0 {
+            packet.extend_from_slice(&[0; TIMECODE_SIZE]);
+        }
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    #[test]
+    fn parses_data_packets() {
+        let data = packet(FLAGS_VERSION_1 | FLAGS_PUSH, ID_DISPLAY, 30, &[1, 2, 3]);
+        assert_eq!(
+            parse(&data),
+            Some(DataPacket {
+                sequence: 5,
+                offset: 30,
+                push: true,
+                data: &[1, 2, 3],
+            })
+        );
+        let timecoded = packet(FLAGS_VERSION_1 | FLAGS_TIMECODE, ID_ALL, 0, &[4, 5, 6]);
+        assert_eq!(parse(&timecoded).map(|packet| (packet.push, packet.data)), Some((false, &[4, 5, 6][..])));
+        assert_eq!(parse(&data[..data.len() - 1]), None);
+    }
+
+    #[test]
+    fn encodes_frames_across_packets() {
+        let data: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+        let packets = encode(&data, 3);
+        let parsed: Vec<DataPacket> = packets.iter().map(|packet| parse(packet).unwrap()).collect();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!((parsed[0].sequence, parsed[0].offset, parsed[0].push), (3, 0, false));
+        assert_eq!((parsed[1].offset, parsed[1].push), (MAX_DATA_SIZE as u32, true));
+        assert_eq!([parsed[0].data, parsed[1].data].concat(), data);
+        assert_eq!(parse(&encode(&[], 0)[0]).map(|packet| packet.push), Some(true));
+    }
+
+    #[test]
+    fn ignores_queries_and_other_destinations() {
+        assert_eq!(parse(&packet(FLAGS_VERSION_1 | FLAGS_QUERY, ID_DISPLAY, 0, &[])), None);
+        assert_eq!(parse(&packet(FLAGS_VERSION_1, 251, 0, &[1, 2, 3])), None);
+        assert_eq!(parse(&packet(0x80, ID_DISPLAY, 0, &[1, 2, 3])), None);
+    }
+}
@@ -0,0 +1,109 @@
+/// UDP port E1.31 (sACN) data is sent to
+pub const E131_PORT: u16 = 5568;
+
+/// RGB pixels in each universe, 510 of the 512 channels as lighting software packs them by default
+pub const PIXELS_PER_UNIVERSE: usize = 170;
+
+/// Identifies an ACN packet, after the preamble and postamble sizes
+const ACN_PACKET_IDENTIFIER: &[u8; 12] = b"ASC-E1.17\0\0\0";
+
+/// Root layer vector of an E1.31 data packet
+const VECTOR_ROOT_E131_DATA: u32 = 0x0000_0004;
+
+/// Framing layer vector of an E1.31 data packet
+const VECTOR_E131_DATA_PACKET: u32 = 0x0000_0002;
+
+/// DMP layer vector of a set property message
+const VECTOR_DMP_SET_PROPERTY: u8 = 0x02;
+
+/// Options bit marking data for visualizers only, not to be output
+const OPTION_PREVIEW_DATA: u8 = 0x80;
+
+/// Options bit marking the last packet of a source that stopped sending
+const OPTION_STREAM_TERMINATED: u8 = 0x40;
+
+/// Offset of the DMX start code, followed by the channel data
+const START_CODE_OFFSET: usize = 125;
+
+/// DMX channel data of one universe from an E1.31 data packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataPacket<'a> {
+    pub universe: u16,
+    pub sequence: u8,
+    /// The source stopped sending this universe
+    pub terminated: bool,
+    /// Channel values, starting at channel 1
+    pub data: &'a [u8],
+}
+
+/// Multicast group a universe is sent to
+pub fn multicast_group(universe: u16) -> [u8; 4] {
+    let [high, low] = universe.to_be_bytes();
+    [239, 255, high, low]
+}
+
+/// Parse an E1.31 data packet
+/// Returns None for other ACN packets, preview data, non-zero start codes and malformed packets
+pub fn parse(packet: &[u8]) -> Option<DataPacket<'_>> {
+    let u16_at = |offset: usize| Some(u16::from_be_bytes(packet.get(offset..offset + 2)?.try_into().ok()?));
+    let u32_at = |offset: usize| Some(u32::from_be_bytes(packet.get(offset..offset + 4)?.try_into().ok()?));
+
+    if packet.get(4..16)? != ACN_PACKET_IDENTIFIER
+        || u32_at(18)? != VECTOR_ROOT_E131_DATA
+        || u32_at(40)? != VECTOR_E131_DATA_PACKET
+        || *packet.get(117)? != VECTOR_DMP_SET_PROPERTY
+    {
+        return None;
+    }
+    let options = *packet.get(112)?;
+    // The property value count includes the start code
+    let channels = (u16_at(123)? as usize).checked_sub(1)?;
+    if options & OPTION_PREVIEW_DATA != 0 || *packet.get(START_CODE_OFFSET)? != 0 {
+        return None;
+    }
+    Some(DataPacket {
+        universe: u16_at(113)?,
+        sequence: packet[111],
+        terminated: options & OPTION_STREAM_TERMINATED != 0,
+        data: packet.get(START_CODE_OFFSET + 1..START_CODE_OFFSET + 1 + channels)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn data_packet(universe: u16, options: u8, data: &[u8]) -> Vec<u8> {
+        let mut packet = alloc::vec![0u8; START_CODE_OFFSET + 1];
+        packet[1] = 0x10;
+        packet[4..16].copy_from_slice(ACN_PACKET_IDENTIFIER);
+        packet[18..22].copy_from_slice(&VECTOR_ROOT_E131_DATA.to_be_bytes());
+        packet[40..44].copy_from_slice(&VECTOR_E131_DATA_PACKET.to_be_bytes());
+        packet[111] = 7;
+        packet[112] = options;
+        packet[113..115].copy_from_slice(&universe.to_be_bytes());
+        packet[117] = VECTOR_DMP_SET_PROPERTY;
+        packet[118] = 0xa1;
+        packet[123..125].copy_from_slice(&(data.len() as u16 + 1).to_be_bytes());
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    #[test]
+    fn parses_data_packets() {
+        let packet = data_packet(3, 0, &[255, 0, 128]);
+        assert_eq!(
+            parse(&packet),
+            Some(DataPacket {
+                universe: 3,
+                sequence: 7,
+                terminated: false,
+                data: &[255, 0, 128],
+            })
+        );
+        assert_eq!(parse(&data_packet(3, OPTION_PREVIEW_DATA, &[1])), None);
+        assert_eq!(parse(&packet[..packet.len() - 1]), None);
+        assert_eq!(multicast_group(0x0102), [239, 255, 1, 2]);
+    }
+}
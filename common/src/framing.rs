@@ -0,0 +1,239 @@
+use crate::message::Message;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Frame delimiter byte (0x00) - COBS ensures this never appears in encoded data
+pub const FRAME_DELIMITER: u8 = 0x00;
+
+/// UDP port the firmware receives messages on over WiFi, one framed message per datagram
+pub const UDP_PORT: u16 = 7777;
+
+/// Errors that can occur when encoding or decoding a frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    Serialization(postcard::Error),
+    Deserialization(postcard::Error),
+    /// The frame is not valid COBS
+    Cobs,
+    /// The frame is too short to hold a CRC
+    TooShort,
+    /// The CRC in the frame does not match its contents
+    CrcMismatch { expected: u16, actual: u16 },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Serialization(e) => write!(f, "Serialization error: {}", e),
+            FrameError::Deserialization(e) => write!(f, "Deserialization error: {}", e),
+            FrameError::Cobs => write!(f, "Invalid COBS encoding"),
+            FrameError::TooShort => write!(f, "Frame too short"),
+            FrameError::CrcMismatch { expected, actual } => {
+                write!(f, "CRC mismatch: expected {:#06x}, got {:#06x}", expected, actual)
+            }
+        }
+    }
+}
+
+/// Compute the CRC-16/CCITT-FALSE of data
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xffff, |crc, &byte| {
+        let mut crc = crc ^ ((byte as u16) << 8);
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+/// Incremental CRC-32 (IEEE), for data too large to checksum in one piece
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Self(0xffff_ffff)
+    }
+
+    /// Add data to the checksum
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 ^= byte as u32;
+            for _ in 0..8 {
+                self.0 = if self.0 & 1 != 0 { (self.0 >> 1) ^ 0xedb8_8320 } else { self.0 >> 1 };
+            }
+        }
+    }
+
+    /// Get the checksum of all data added so far
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Compute the CRC-32 (IEEE) of data
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/// Encode a message into a frame: postcard bytes followed by their little endian CRC16,
+/// COBS encoded and terminated with the frame delimiter
+pub fn encode(message: &Message) -> Result<Vec<u8>, FrameError> {
+    let mut data = postcard::to_allocvec(message).map_err(FrameError::Serialization)?;
+    let crc = crc16(&data);
+    data.extend_from_slice(&crc.to_le_bytes());
+
+    let mut encoded = cobs_encode(&data);
+    encoded.push(FRAME_DELIMITER);
+    Ok(encoded)
+}
+
+/// Decode a frame in place, with or without its trailing delimiter, verifying its CRC
+pub fn decode(frame: &mut [u8]) -> Result<Message, FrameError> {
+    let frame = match frame.split_last_mut() {
+        Some((&mut FRAME_DELIMITER, rest)) => rest,
+        _ => frame,
+    };
+    let len = cobs_decode_in_place(frame)?;
+    if len < 2 {
+        return Err(FrameError::TooShort);
+    }
+
+    let (data, crc) = frame[..len].split_at(len - 2);
+    let expected = u16::from_le_bytes([crc[0], crc[1]]);
+    let actual = crc16(data);
+    if expected != actual {
+        return Err(FrameError::CrcMismatch { expected, actual });
+    }
+    postcard::from_bytes(data).map_err(FrameError::Deserialization)
+}
+
+/// COBS encode data, without a trailing delimiter
+fn cobs_encode(data: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(data.len() + data.len() / 254 + 2);
+    let mut code_index = 0;
+    let mut code = 1u8;
+    encoded.push(0);
+
+    for &byte in data {
+        if byte != 0 {
+            encoded.push(byte);
+            code += 1;
+        }
+        // Close the block on a zero, or when it reaches the maximum length of 254 bytes
+        if byte == 0 || code == 0xff {
+            encoded[code_index] = code;
+            code_index = encoded.len();
+            encoded.push(0);
+            code = 1;
+        }
+    }
+    encoded[code_index] = code;
+    encoded
+}
+
+/// COBS decode data in place, without a trailing delimiter
+/// Returns the length of the decoded data at the start of data
+fn cobs_decode_in_place(data: &mut [u8]) -> Result<usize, FrameError> {
+    let mut read = 0;
+    let mut write = 0;
+
+    while read < data.len() {
+        let code = data[read] as usize;
+        if code == 0 || read + code > data.len() {
+            return Err(FrameError::Cobs);
+        }
+        read += 1;
+
+        data.copy_within(read..read + code - 1, write);
+        read += code - 1;
+        write += code - 1;
+
+        // Every block except maximum length ones and the last one was followed by a zero
+        if code != 0xff && read < data.len() {
+            data[write] = 0;
+            write += 1;
+        }
+    }
+    Ok(write)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Rgb, SetLedsPayload};
+
+    fn round_trip(message: Message) {
+        let mut encoded = encode(&message).unwrap();
+        assert_eq!(encoded.last(), Some(&FRAME_DELIMITER));
+        assert!(!encoded[..encoded.len() - 1].contains(&FRAME_DELIMITER));
+        assert_eq!(decode(&mut encoded).unwrap(), message);
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        round_trip(Message::Heartbeat);
+        // Long frames span several maximum length COBS blocks
+        round_trip(Message::SetLeds(SetLedsPayload {
+            seq: 1,
+            strip: None,
+            leds: (0..=255).map(|i| Rgb::new(i as u8, 0, 255 - i as u8)).collect(),
+        }));
+    }
+
+    #[test]
+    fn cobs_block_boundaries() {
+        for len in [0, 1, 253, 254, 255, 508, 509] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 7) as u8).collect();
+            let mut encoded = cobs_encode(&data);
+            assert!(!encoded.contains(&0));
+            let decoded_len = cobs_decode_in_place(&mut encoded).unwrap();
+            assert_eq!(&encoded[..decoded_len], &data[..]);
+
+            let data = vec![0x5a; len];
+            let mut encoded = cobs_encode(&data);
+            let decoded_len = cobs_decode_in_place(&mut encoded).unwrap();
+            assert_eq!(&encoded[..decoded_len], &data[..]);
+        }
+    }
+
+    #[test]
+    fn corrupted_frame_fails_crc() {
+        let mut encoded = encode(&Message::SetLeds(SetLedsPayload {
+            seq: 9,
+            strip: None,
+            leds: vec![Rgb::new(10, 20, 30)],
+        }))
+        .unwrap();
+        // Flip a bit in the payload without creating a zero byte
+        let index = encoded.len() - 4;
+        encoded[index] ^= if encoded[index] == 1 { 0x02 } else { 0x01 };
+        assert!(matches!(
+            decode(&mut encoded),
+            Err(FrameError::CrcMismatch { .. } | FrameError::Cobs)
+        ));
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xcbf4_3926);
+    }
+}
@@ -1,5 +1,16 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
+pub mod artnet;
+pub mod chunking;
+pub mod ddp;
+pub mod e131;
+pub mod framing;
 pub mod message;
+pub mod mirror;
+pub mod opc;
+pub mod palette;
+pub mod schedule;
+pub mod segments;
+pub mod sntp;
 
 extern crate alloc;
\ No newline at end of file
@@ -1,11 +1,14 @@
 use serde::{Deserialize, Serialize};
+use alloc::boxed::Box;
 use alloc::string::String;
 use alloc::vec::Vec;
 use alloc::format;
+use core::fmt;
+use core::ops::Range;
 use log::Level;
 
 /// RGB color value
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Rgb {
     pub r: u8,
     pub g: u8,
@@ -13,17 +16,413 @@ pub struct Rgb {
 }
 
 impl Rgb {
-    pub fn new(r: u8, g: u8, b: u8) -> Self {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+}
+
+/// RGB color value with 16 bits per channel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rgb16 {
+    pub r: u16,
+    pub g: u16,
+    pub b: u16,
+}
+
+impl Rgb16 {
+    pub const fn new(r: u16, g: u16, b: u16) -> Self {
         Self { r, g, b }
     }
 }
 
+impl From<Rgb> for Rgb16 {
+    /// Widen each channel so 0xff maps to 0xffff
+    fn from(rgb: Rgb) -> Self {
+        Self::new(rgb.r as u16 * 257, rgb.g as u16 * 257, rgb.b as u16 * 257)
+    }
+}
+
+impl From<Rgb16> for Rgb {
+    /// Round each channel to the nearest 8-bit value
+    fn from(rgb: Rgb16) -> Self {
+        let narrow = |channel: u16| ((channel as u32 + 128) / 257) as u8;
+        Self::new(narrow(rgb.r), narrow(rgb.g), narrow(rgb.b))
+    }
+}
+
+/// RGBW color value for strips with a dedicated white channel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rgbw {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub w: u8,
+}
+
+impl Rgbw {
+    pub const fn new(r: u8, g: u8, b: u8, w: u8) -> Self {
+        Self { r, g, b, w }
+    }
+}
+
+impl From<Rgb> for Rgbw {
+    /// Leave the white channel off
+    fn from(rgb: Rgb) -> Self {
+        Self::new(rgb.r, rgb.g, rgb.b, 0)
+    }
+}
+
+impl From<Rgbw> for Rgb {
+    /// Mix the white channel into each color channel, for strips without one
+    fn from(rgbw: Rgbw) -> Self {
+        Self::new(
+            rgbw.r.saturating_add(rgbw.w),
+            rgbw.g.saturating_add(rgbw.w),
+            rgbw.b.saturating_add(rgbw.w),
+        )
+    }
+}
+
+/// Sequence numbers above this are kept for frames the firmware generates itself, like sACN and mirroring
+pub const MAX_HOST_SEQ: u32 = 0x7fff_ffff;
+
+/// Get the host frame sequence number after the given one, wrapping before the numbers kept for the firmware
+pub fn next_host_seq(seq: u32) -> u32 {
+    seq.wrapping_add(1) & MAX_HOST_SEQ
+}
+
 /// Payload for SetLeds message
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SetLedsPayload {
+    /// Sequence number echoed back in the Ack or Nack for this frame
+    pub seq: u32,
+    /// Strip the LEDs are for, or None for a frame covering every strip in order
+    pub strip: Option<u8>,
+    pub leds: Vec<Rgb>,
+}
+
+impl SetLedsPayload {
+    /// Get the checksum of the frame carried by this payload
+    pub fn checksum(&self) -> u32 {
+        frame_checksum(&self.leds)
+    }
+}
+
+/// Payload for SetLedsRgbw message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SetLedsRgbwPayload {
+    /// Sequence number echoed back in the Ack or Nack for this frame
+    pub seq: u32,
+    pub leds: Vec<Rgbw>,
+}
+
+impl From<SetLedsPayload> for SetLedsRgbwPayload {
+    fn from(payload: SetLedsPayload) -> Self {
+        Self {
+            seq: payload.seq,
+            leds: payload.leds.into_iter().map(Rgbw::from).collect(),
+        }
+    }
+}
+
+impl From<SetLedsRgbwPayload> for SetLedsPayload {
+    fn from(payload: SetLedsRgbwPayload) -> Self {
+        Self {
+            seq: payload.seq,
+            strip: None,
+            leds: payload.leds.into_iter().map(Rgb::from).collect(),
+        }
+    }
+}
+
+/// Run of consecutive LEDs with the same color
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LedRun {
+    pub len: u16,
+    pub color: Rgb,
+}
+
+/// Payload for SetLedsRle message, a run-length encoded SetLeds frame
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SetLedsRlePayload {
+    /// Sequence number echoed back in the Ack or Nack for this frame
+    pub seq: u32,
+    pub runs: Vec<LedRun>,
+}
+
+impl SetLedsRlePayload {
+    /// Run-length encode a frame
+    pub fn encode(seq: u32, leds: &[Rgb]) -> Self {
+        let mut runs: Vec<LedRun> = Vec::new();
+        for &color in leds {
+            match runs.last_mut() {
+                Some(run) if run.color == color && run.len < u16::MAX => run.len += 1,
+                _ => runs.push(LedRun { len: 1, color }),
+            }
+        }
+        Self { seq, runs }
+    }
+
+    /// Number of LEDs in the decoded frame
+    pub fn len(&self) -> usize {
+        self.runs.iter().map(|run| run.len as usize).sum()
+    }
+
+    /// Whether the decoded frame has no LEDs
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl From<SetLedsRlePayload> for SetLedsPayload {
+    fn from(payload: SetLedsRlePayload) -> Self {
+        let mut leds = Vec::with_capacity(payload.len());
+        for run in &payload.runs {
+            leds.extend(core::iter::repeat_n(run.color, run.len as usize));
+        }
+        Self {
+            seq: payload.seq,
+            strip: None,
+            leds,
+        }
+    }
+}
+
+/// Largest number of colors in the palette used by indexed frames
+pub const MAX_PALETTE_COLORS: usize = 256;
+
+/// Payload for SetPalette message
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PalettePayload {
+    /// Colors referenced by index from SetLedsIndexed frames, at most MAX_PALETTE_COLORS
+    pub colors: Vec<Rgb>,
+}
+
+impl PalettePayload {
+    /// Collect the distinct colors of a frame in order of first appearance
+    /// Returns None if the frame has more than MAX_PALETTE_COLORS colors
+    pub fn from_frame(leds: &[Rgb]) -> Option<Self> {
+        let mut colors: Vec<Rgb> = Vec::new();
+        for color in leds {
+            if !colors.contains(color) {
+                if colors.len() == MAX_PALETTE_COLORS {
+                    return None;
+                }
+                colors.push(*color);
+            }
+        }
+        Some(Self { colors })
+    }
+}
+
+/// Payload for SetLedsIndexed message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SetLedsIndexedPayload {
+    /// Sequence number echoed back in the Ack or Nack for this frame
+    pub seq: u32,
+    /// Index into the palette of each LED's color
+    pub indices: Vec<u8>,
+}
+
+impl SetLedsIndexedPayload {
+    /// Encode a frame as indices into a palette
+    /// Returns None if a color of the frame is missing from the palette
+    pub fn encode(seq: u32, leds: &[Rgb], palette: &PalettePayload) -> Option<Self> {
+        let indices = leds
+            .iter()
+            .map(|color| palette.colors.iter().position(|c| c == color).and_then(|i| u8::try_from(i).ok()))
+            .collect::<Option<Vec<u8>>>()?;
+        Some(Self { seq, indices })
+    }
+
+    /// Look up the color of each LED in a palette
+    /// Returns None if an index is outside the palette
+    pub fn decode(&self, palette: &PalettePayload) -> Option<SetLedsPayload> {
+        let leds = self
+            .indices
+            .iter()
+            .map(|&index| palette.colors.get(index as usize).copied())
+            .collect::<Option<Vec<Rgb>>>()?;
+        Some(SetLedsPayload {
+            seq: self.seq,
+            strip: None,
+            leds,
+        })
+    }
+}
+
+/// Payload for SetLedRange message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SetLedRangePayload {
+    /// Sequence number echoed back in the Ack or Nack for this update
+    pub seq: u32,
+    /// Index of the first LED in leds
+    pub start: u16,
     pub leds: Vec<Rgb>,
 }
 
+impl SetLedRangePayload {
+    /// Copy the range into a frame, leaving the LEDs outside it untouched
+    /// Returns false without modifying the frame if the range doesn't fit in it
+    pub fn apply<C: From<Rgb>>(&self, frame: &mut [C]) -> bool {
+        let start = self.start as usize;
+        match frame.get_mut(start..start + self.leds.len()) {
+            Some(range) => {
+                for (led, &color) in range.iter_mut().zip(&self.leds) {
+                    *led = color.into();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Payload for SetLedsSparse message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SetLedsSparsePayload {
+    /// Sequence number echoed back in the Ack or Nack for this update
+    pub seq: u32,
+    /// Index and new color of every changed LED
+    pub leds: Vec<(u16, Rgb)>,
+}
+
+impl SetLedsSparsePayload {
+    /// Set each listed LED in a frame, leaving the others untouched
+    /// Returns false without modifying the frame if any index is outside it
+    pub fn apply<C: From<Rgb>>(&self, frame: &mut [C]) -> bool {
+        apply_indexed(&self.leds, frame)
+    }
+}
+
+/// Payload for FrameDelta message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrameDeltaPayload {
+    /// Sequence number echoed back in the Ack or Nack for this frame
+    pub seq: u32,
+    /// Sequence number of the frame the delta was computed against
+    pub base_seq: u32,
+    /// Index and new color of every LED that changed since the base frame
+    pub leds: Vec<(u16, Rgb)>,
+}
+
+impl FrameDeltaPayload {
+    /// Patch the changed LEDs onto the base frame
+    /// Returns false without modifying the frame if any index is outside it
+    pub fn apply<C: From<Rgb>>(&self, frame: &mut [C]) -> bool {
+        apply_indexed(&self.leds, frame)
+    }
+}
+
+fn apply_indexed<C: From<Rgb>>(leds: &[(u16, Rgb)], frame: &mut [C]) -> bool {
+    if leds.iter().any(|&(index, _)| index as usize >= frame.len()) {
+        return false;
+    }
+    for &(index, color) in leds {
+        frame[index as usize] = color.into();
+    }
+    true
+}
+
+/// Incremental 32-bit FNV-1a checksum, for frames checksummed as they're produced
+#[derive(Debug, Clone, Copy)]
+pub struct Fnv1a(u32);
+
+impl Fnv1a {
+    pub const fn new() -> Self {
+        Self(0x811c_9dc5)
+    }
+
+    /// Add data to the checksum
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 = (self.0 ^ byte as u32).wrapping_mul(0x0100_0193);
+        }
+    }
+
+    /// Get the checksum of all data added so far
+    pub fn finish(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Compute a 32-bit FNV-1a checksum over the RGB bytes of a frame
+pub fn frame_checksum(leds: &[Rgb]) -> u32 {
+    let mut checksum = Fnv1a::new();
+    for rgb in leds {
+        checksum.update(&[rgb.r, rgb.g, rgb.b]);
+    }
+    checksum.finish()
+}
+
+/// Payload for Ack message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AckPayload {
+    /// Sequence number of the frame that was applied
+    pub seq: u32,
+}
+
+/// Reason a frame was not applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NackReason {
+    /// The firmware's receive channel was full, or a partial update arrived while a frame was still pending
+    ChannelFull,
+    /// The frame could not be decoded
+    DecodeFailed,
+    /// The frame had the wrong number of LEDs
+    WrongLength,
+    /// Writing the frame to the LEDs failed
+    WriteFailed,
+    /// The frame's CRC did not match its contents
+    CrcMismatch,
+    /// The frame exceeded the firmware's maximum frame size
+    TooLarge,
+    /// The delta was computed against a frame the firmware isn't displaying
+    BaseMismatch,
+    /// The indexed frame referenced a color missing from the palette
+    InvalidIndex,
+    /// A newer whole frame arrived before this one was displayed, so it was skipped
+    Superseded,
+    /// The LEDs are powered off until PowerOn
+    PoweredOff,
+    /// A chunk of the frame was lost, so the rest of it was dropped
+    MissingChunk,
+}
+
+/// Payload for Nack message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NackPayload {
+    /// Sequence number of the rejected frame, None if it couldn't be decoded
+    pub seq: Option<u32>,
+    pub reason: NackReason,
+}
+
+/// Payload for SetVerification message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerificationPayload {
+    /// Report the checksum of every Nth frame written to the LEDs (0 disables verification)
+    pub interval: u16,
+}
+
+/// Payload for FrameChecksum message, sent once a frame has been written to the LEDs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrameChecksumPayload {
+    /// Sequence number of the frame
+    pub seq: u32,
+    /// Checksum of the frame as decoded, comparable to frame_checksum of the frame the host sent
+    pub checksum: u32,
+    /// Checksum of the bytes written to each configured strip, after segments, transitions, status LEDs, gamma, the
+    /// brightness cap, dithering and color order
+    pub output_checksums: Vec<u32>,
+}
+
 /// Serializable wrapper for log::Level
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct SerializableLogLevel(Level);
@@ -85,10 +484,24 @@ impl<'de> Deserialize<'de> for SerializableLogLevel {
     }
 }
 
+/// Source code location a log message was emitted from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: u32,
+    pub module: String,
+}
+
 /// Payload for Log message
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct LogPayload {
     pub level: SerializableLogLevel,
+    /// Firmware uptime in milliseconds when the message was logged
+    pub timestamp_ms: u64,
+    /// Target of the log record, usually the module path
+    pub target: String,
+    /// Source location, only included when verbose diagnostics are enabled
+    pub location: Option<SourceLocation>,
     pub content: String,
 }
 
@@ -97,29 +510,665 @@ impl LogPayload {
     pub fn new(level: Level, content: String) -> Self {
         Self {
             level: SerializableLogLevel::from(level),
+            timestamp_ms: 0,
+            target: String::new(),
+            location: None,
             content,
         }
     }
 
+    /// Set the uptime in milliseconds when the message was logged
+    pub fn at(mut self, timestamp_ms: u64) -> Self {
+        self.timestamp_ms = timestamp_ms;
+        self
+    }
+
+    /// Set the target of the log record
+    pub fn with_target(mut self, target: String) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Set the source location the message was emitted from
+    pub fn with_location(mut self, location: SourceLocation) -> Self {
+        self.location = Some(location);
+        self
+    }
+
     /// Get the log level
     pub fn level(&self) -> Level {
         self.level.level()
     }
 }
 
+/// Payload for SetVerboseDiagnostics message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerboseDiagnosticsPayload {
+    /// Include source locations in log messages
+    pub enabled: bool,
+}
+
+/// Payload for DeviceInfo message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceInfoPayload {
+    /// Number of LEDs every frame must contain
+    pub num_leds: u16,
+    /// Number of LEDs on each strip, in the order they appear in frames
+    pub strips: Vec<u16>,
+    pub chip: String,
+    pub fw_version: String,
+    /// Largest encoded frame the firmware accepts, in bytes
+    pub max_frame_size: u32,
+}
+
+/// Animation built into the firmware
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Effect {
+    /// Palette colors flowing along the strip
+    Rainbow,
+    /// A short segment running along the strip
+    Chase,
+    /// Random LEDs lighting up and fading out
+    Twinkle,
+    /// The whole strip fading in and out
+    Breathe,
+}
+
+/// Payload for StartEffect message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StartEffectPayload {
+    pub effect: Effect,
+    /// Animation speed in percent of the effect's normal speed
+    pub speed: u16,
+    /// Colors the effect cycles through, the effect's own colors are used when empty
+    pub palette: Vec<Rgb>,
+}
+
+/// What the firmware displays once the host stops sending messages
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdleBehavior {
+    /// Keep displaying the last frame
+    Hold,
+    /// Turn every LED off
+    Blackout,
+    /// Run a built-in animation
+    Effect(StartEffectPayload),
+}
+
+/// Order the strip expects the color channels of each LED in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorOrder {
+    Rgb,
+    Rbg,
+    Grb,
+    Gbr,
+    Brg,
+    Bgr,
+}
+
+impl ColorOrder {
+    /// Arrange a color's channels in this order
+    pub fn arrange(self, rgb: Rgb) -> [u8; 3] {
+        let Rgb { r, g, b } = rgb;
+        match self {
+            ColorOrder::Rgb => [r, g, b],
+            ColorOrder::Rbg => [r, b, g],
+            ColorOrder::Grb => [g, r, b],
+            ColorOrder::Gbr => [g, b, r],
+            ColorOrder::Brg => [b, r, g],
+            ColorOrder::Bgr => [b, g, r],
+        }
+    }
+}
+
+/// Number of entries in an uploaded gamma table, one for each channel level
+pub const GAMMA_TABLE_SIZE: usize = 256;
+
+/// Correction applied to every channel before it's sent to the strips
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Gamma {
+    /// Send levels unchanged, for hosts that correct their colors themselves
+    Off,
+    /// The firmware's built-in curve, a gamma of 2.8
+    Standard,
+    /// Level sent for each channel level, GAMMA_TABLE_SIZE entries
+    Table(Vec<u8>),
+}
+
+/// LED chip a strip is built from, setting the bit timing it's driven with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedChip {
+    Ws2812b,
+    /// The WS2811 in its 400 kHz mode
+    Ws2811,
+    Sk6812,
+}
+
+/// Settings of a strip driven from its own output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StripConfig {
+    pub num_leds: u16,
+    pub color_order: ColorOrder,
+    pub chip: LedChip,
+}
+
+/// Part a tree plays in mirroring frames to other trees over ESP-NOW
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MirrorRole {
+    Off,
+    /// Broadcast every frame displayed
+    Primary,
+    /// Display the frames broadcast by the primary
+    Secondary,
+}
+
+/// Payload for SetConfig and Config messages, the firmware's persistent settings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfigPayload {
+    /// Highest level any channel is driven at, out of 255
+    pub brightness_cap: u8,
+    pub gamma: Gamma,
+    /// Alternate levels between adjacent 8-bit values from frame to frame, smoothing dim fades
+    pub dithering: bool,
+    /// Fade between received frames, rendering intermediate frames in between
+    pub interpolation: bool,
+    /// Strips in the order they appear in frames, each on its own output
+    pub strips: Vec<StripConfig>,
+    /// Seconds without any message after which the host is considered lost
+    pub idle_timeout_s: u16,
+    /// What to display once the host is lost
+    pub idle_behavior: IdleBehavior,
+    /// Wipe red, green and blue along the strips at boot, before clearing them
+    pub self_test: bool,
+    /// Number of LEDs at the start of the frame showing the firmware's status instead of the frame
+    pub status_leds: u8,
+    /// Scale the brightness cap with the room's brightness, measured by the light sensor
+    pub auto_brightness: bool,
+    /// Brightness cap in the dark when auto_brightness is enabled, out of 255
+    pub min_brightness: u8,
+    /// First E1.31 (sACN) universe received over WiFi, the frame continuing into the universes after it
+    /// 0 disables sACN
+    pub sacn_universe: u16,
+    /// Whether frames are mirrored to or from other trees over ESP-NOW
+    pub mirror: MirrorRole,
+    /// WiFi channel mirrored frames are sent on when not joined to a network, every tree must use the same one
+    pub mirror_channel: u8,
+    /// Use RTS/CTS hardware flow control on the UART from the next boot, the host must enable it too
+    pub uart_flow_control: bool,
+    /// Save what's on display to flash once it settles, and show it again after a reboot
+    pub restore_state: bool,
+    /// Milliseconds to crossfade from what's on display to a new scene, a newly started effect or a frame after a
+    /// pause, 0 cuts straight to it
+    pub transition_ms: u16,
+}
+
+impl ConfigPayload {
+    /// Number of LEDs every frame must contain, across all strips
+    pub fn num_leds(&self) -> usize {
+        self.strips.iter().map(|strip| strip.num_leds as usize).sum()
+    }
+
+    /// Indices of a strip's LEDs within the frame
+    /// Returns None if there is no such strip
+    pub fn strip_range(&self, strip: usize) -> Option<Range<usize>> {
+        let start = self.strips.get(..strip)?.iter().map(|strip| strip.num_leds as usize).sum();
+        Some(start..start + self.strips.get(strip)?.num_leds as usize)
+    }
+}
+
+/// Payload for BeginUpdate message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BeginUpdatePayload {
+    /// Size of the firmware image in bytes
+    pub size: u32,
+    /// CRC-32 of the whole firmware image
+    pub crc: u32,
+}
+
+/// Payload for FirmwareChunk message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FirmwareChunkPayload {
+    /// Offset of data in the firmware image, chunks must be sent in order
+    pub offset: u32,
+    pub data: Vec<u8>,
+}
+
+/// Reason a firmware update failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateError {
+    /// A chunk or FinishUpdate was received without a BeginUpdate
+    NotStarted,
+    /// The image doesn't fit in the update partition
+    TooLarge,
+    /// A chunk didn't continue where the previous one ended
+    OutOfOrder,
+    /// Reading or writing flash failed
+    Flash,
+    /// FinishUpdate was received before the whole image
+    Incomplete,
+    /// The written image doesn't match the CRC from BeginUpdate
+    CrcMismatch,
+}
+
+/// Payload for UpdateStatus message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpdateStatusPayload {
+    /// Number of bytes of the image written so far
+    pub written: u32,
+    /// Why the update was aborted, None while it's progressing
+    pub error: Option<UpdateError>,
+}
+
+/// Payload for Status message, firmware health sent along with each heartbeat reply
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatusPayload {
+    /// Time since the firmware booted
+    pub uptime_ms: u64,
+    /// Free heap in bytes
+    pub free_heap: u32,
+    /// Messages waiting in the firmware's receive channel
+    pub rx_queued: u8,
+    /// Capacity of the firmware's receive channel
+    pub rx_capacity: u8,
+    /// Frames written to the LEDs since boot, including animation frames
+    pub frames_rendered: u32,
+    /// LED writes that failed or stalled since boot, after which the RMT drivers were recreated
+    pub led_write_errors: u32,
+    /// Log messages dropped since boot
+    pub dropped_logs: u32,
+    /// Light sensor reading from 0 in the dark to 4095 in daylight, when auto brightness is enabled
+    pub ambient_light: Option<u16>,
+    /// Built-in effect being shown, None while showing frames from the host or a stored frame
+    pub effect: Option<StartEffectPayload>,
+}
+
+/// Payload for Panic message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PanicPayload {
+    pub message: String,
+    pub location: Option<SourceLocation>,
+    /// Program counters of the panicking call stack, innermost first
+    pub backtrace: Vec<u32>,
+}
+
+/// Payload for PowerOff message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PowerOffPayload {
+    /// Put the firmware in light sleep between messages, woken by activity on the UART
+    pub light_sleep: bool,
+}
+
+/// Payload for SetWifiCredentials message
+/// An empty SSID clears the stored credentials
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WifiCredentialsPayload {
+    pub ssid: String,
+    pub password: String,
+}
+
+impl fmt::Debug for WifiCredentialsPayload {
+    /// The password is left out, so it never ends up in logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WifiCredentialsPayload")
+            .field("ssid", &self.ssid)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Most entries a schedule can hold
+pub const MAX_SCHEDULE_ENTRIES: usize = 16;
+
+/// Time of day a schedule entry comes due
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleTime {
+    /// Minutes after local midnight
+    At(u16),
+    /// Minutes after sunrise, negative for before
+    Sunrise(i16),
+    /// Minutes after sunset, negative for before
+    Sunset(i16),
+}
+
+/// What a schedule entry does when it comes due
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleAction {
+    /// Power the LEDs on, running the idle behavior if the host isn't connected
+    On,
+    /// Power the LEDs off
+    Off,
+}
+
+/// Entry of a daily schedule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduleEntry {
+    pub time: ScheduleTime,
+    pub action: ScheduleAction,
+}
+
+/// Payload for SetSchedule and Schedule messages, the daily routine the firmware follows once it knows the time
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SchedulePayload {
+    /// Offset of local time from UTC in minutes
+    pub utc_offset_min: i16,
+    /// Location sunrise and sunset are calculated for, in hundredths of a degree, north and east positive
+    pub latitude: i16,
+    pub longitude: i16,
+    pub entries: Vec<ScheduleEntry>,
+}
+
+/// How long a button was held
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ButtonPress {
+    Short,
+    Long,
+}
+
+/// Payload for ButtonEvent message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ButtonEventPayload {
+    /// Index of the button on the device
+    pub button: u8,
+    pub press: ButtonPress,
+}
+
+/// Payload for GetLeds message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GetLedsPayload {
+    /// Index of the first LED to read
+    pub offset: u16,
+    /// Number of LEDs to read
+    pub count: u16,
+}
+
+/// Payload for Leds message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LedsPayload {
+    /// Index of the first LED in leds
+    pub offset: u16,
+    pub leds: Vec<Rgb>,
+}
+
+/// Payload for FrameChunk message, part of a message too large for one frame
+/// Chunks are sent in order, their data concatenated is the postcard serialization of the message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrameChunkPayload {
+    /// Sequence number of the frame being sent
+    pub seq: u32,
+    pub index: u16,
+    /// Number of chunks the message was split into
+    pub count: u16,
+    pub data: Vec<u8>,
+}
+
+/// Payload for TimedFrame message, a frame to display at a presentation time
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimedFramePayload {
+    /// Firmware uptime in microseconds at which the frame is latched, earlier times are displayed right away
+    pub present_at_us: u64,
+    /// Frame message, handled like any other frame
+    pub frame: Box<Message>,
+}
+
+/// Payload for Time message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimePayload {
+    /// Firmware uptime in microseconds when the message was sent
+    pub uptime_us: u64,
+}
+
+/// Most segments the firmware can run at once
+pub const MAX_SEGMENTS: usize = 16;
+
+/// What a segment displays, independently of the frames around it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SegmentMode {
+    /// Run a built-in effect on the segment's LEDs
+    Effect(StartEffectPayload),
+    /// Hold the LEDs the segment was showing when it was set
+    Frozen,
+}
+
+/// Contiguous range of LEDs treated independently of the rest of the frame
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Segment {
+    /// Index of the segment's first LED in the frame
+    pub start: u16,
+    pub len: u16,
+    pub mode: SegmentMode,
+}
+
+/// Payload for SetSegments and Segments messages
+/// Frames from the host only light the LEDs outside every segment
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentsPayload {
+    pub segments: Vec<Segment>,
+}
+
+/// Payload for SetSoundReactive and SoundReactive messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SoundReactivePayload {
+    /// Drive the running effects with the microphone, brightness following the sound level and flashing on beats
+    pub enabled: bool,
+    /// How readily a rise in the sound level counts as a beat, out of 255
+    pub sensitivity: u8,
+}
+
+/// Payload for Telemetry message, the firmware's memory use sent periodically
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TelemetryPayload {
+    /// Free heap in bytes
+    pub free_heap: u32,
+    /// Largest block the heap can allocate in one piece, well below free_heap once the heap fragments
+    pub largest_free_block: u32,
+    /// Lowest free heap seen since boot
+    pub min_free_heap: u32,
+    /// Size of the stack every task runs on, in bytes
+    pub stack_size: u32,
+    /// Most of the stack used since boot, in bytes
+    pub stack_high_water: u32,
+}
+
+/// Cause of the firmware's last reset, as reported by the chip
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResetReason {
+    PowerOn,
+    /// Reset by the firmware itself, after a panic, an update or a Reboot
+    Software,
+    Watchdog,
+    /// The supply voltage dropped too low
+    Brownout,
+    DeepSleep,
+    Other,
+}
+
+/// Payload for ResetReport message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResetReportPayload {
+    pub reason: ResetReason,
+    /// Panic that caused the reset, kept in RTC memory across it
+    pub panic: Option<PanicPayload>,
+    /// Tasks that stopped responding before a watchdog reset
+    pub wedged_tasks: Vec<String>,
+}
+
+/// Payload for SetBaudRate and BaudRate messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BaudRatePayload {
+    pub baud_rate: u32,
+}
+
+/// Version of the message protocol, bumped on every incompatible change to Message
+pub const PROTOCOL_VERSION: u16 = 18;
+
+/// Payload for Hello message
+///
+/// The layout of this payload must never change, so both ends can always read each other's version.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HelloPayload {
+    pub protocol_version: u16,
+    /// Firmware version, empty when sent by the host
+    pub firmware_version: String,
+}
+
 /// Message type enum
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum Message {
+    /// Version handshake sent by the host at connection start and answered by the firmware
+    /// Must remain the first variant so it decodes the same in every protocol version
+    Hello(HelloPayload),
     /// Heartbeat message with no payload
     Heartbeat,
     /// Set LED values message with RGB array payload
     SetLeds(SetLedsPayload),
     /// Log message with log level and string content
     Log(LogPayload),
+    /// Enable or disable reporting the checksums of frames written to the LEDs
+    SetVerification(VerificationPayload),
+    /// Checksum of a frame displayed by the firmware
+    FrameChecksum(FrameChecksumPayload),
+    /// Request a range of the firmware's current frame buffer
+    GetLeds(GetLedsPayload),
+    /// Contents of the firmware's current frame buffer, sent in response to GetLeds
+    Leds(LedsPayload),
+    /// Enable or disable verbose diagnostics in firmware log messages
+    SetVerboseDiagnostics(VerboseDiagnosticsPayload),
+    /// Frame with the given sequence number was applied
+    Ack(AckPayload),
+    /// Frame was not applied
+    Nack(NackPayload),
+    /// Request the firmware's DeviceInfo
+    GetDeviceInfo,
+    /// Description of the device, sent in response to GetDeviceInfo
+    DeviceInfo(DeviceInfoPayload),
+    /// Set a contiguous range of LEDs, leaving the rest of the frame unchanged
+    SetLedRange(SetLedRangePayload),
+    /// Set individual LEDs by index, leaving the rest of the frame unchanged
+    SetLedsSparse(SetLedsSparsePayload),
+    /// Set LED values message with RGBW array payload
+    SetLedsRgbw(SetLedsRgbwPayload),
+    /// LEDs changed since the frame with the given sequence number, applied only if it's the frame on display
+    FrameDelta(FrameDeltaPayload),
+    /// Set LED values message with a run-length encoded RGB array payload
+    SetLedsRle(SetLedsRlePayload),
+    /// Replace the palette used by indexed frames
+    SetPalette(PalettePayload),
+    /// Set LED values message with one palette index per LED
+    SetLedsIndexed(SetLedsIndexedPayload),
+    /// Run a built-in animation on the firmware until the next frame is received
+    StartEffect(StartEffectPayload),
+    /// Request the firmware's Config
+    GetConfig,
+    /// Apply and persist new settings, answered with the resulting Config
+    SetConfig(ConfigPayload),
+    /// Settings in use by the firmware, sent in response to GetConfig and SetConfig
+    Config(ConfigPayload),
+    /// Start a firmware update, erasing the inactive OTA partition
+    BeginUpdate(BeginUpdatePayload),
+    /// Part of the firmware image, answered with an UpdateStatus
+    FirmwareChunk(FirmwareChunkPayload),
+    /// Verify the firmware image and reboot into it, answered with an UpdateStatus first
+    FinishUpdate,
+    /// Progress of a firmware update, sent in response to each update message
+    UpdateStatus(UpdateStatusPayload),
+    /// Restart the firmware
+    Reboot,
+    /// Clear the persisted config and restart the firmware with default settings
+    FactoryReset,
+    /// Firmware health, sent after every Heartbeat reply
+    Status(StatusPayload),
+    /// The firmware panicked and is about to reset
+    Panic(PanicPayload),
+    /// The firmware is falling behind, the host should stop sending frames until Ready
+    Busy,
+    /// The firmware has caught up after a Busy, the host may send frames again
+    Ready,
+    /// A button on the device was pressed and released
+    ButtonEvent(ButtonEventPayload),
+    /// Blank the LEDs and stop driving them, frames are rejected until PowerOn
+    PowerOff(PowerOffPayload),
+    /// Drive the LEDs again after PowerOff
+    PowerOn,
+    /// Store the WiFi network the firmware joins at boot when built with WiFi support
+    SetWifiCredentials(WifiCredentialsPayload),
+    /// Ask for the firmware's schedule, answered with Schedule
+    GetSchedule,
+    /// Replace the firmware's schedule, answered with Schedule
+    SetSchedule(SchedulePayload),
+    /// The firmware's schedule, sent in response to GetSchedule and SetSchedule
+    Schedule(SchedulePayload),
+    /// The firmware's schedule powered the LEDs on or off
+    ScheduleEvent(ScheduleAction),
+    /// Ask the firmware to switch the UART's baud rate, answered with BaudRate
+    SetBaudRate(BaudRatePayload),
+    /// The baud rate the firmware switches to once this is sent, its current rate if it refused the request
+    /// It falls back to 115200 baud unless a message arrives at the new rate within a second
+    BaudRate(BaudRatePayload),
+    /// Part of a frame larger than the firmware's max_frame_size, reassembled before it's handled
+    FrameChunk(FrameChunkPayload),
+    /// A frame paced to be displayed at a time on the firmware's clock
+    TimedFrame(TimedFramePayload),
+    /// Ask for the firmware's clock, answered with Time
+    GetTime,
+    /// The firmware's clock, sent in response to GetTime
+    Time(TimePayload),
+    /// Ask why the firmware last reset, answered with ResetReport
+    GetResetReport,
+    /// Why the firmware last reset, sent in response to GetResetReport
+    ResetReport(ResetReportPayload),
+    /// Heap and stack usage, sent by the firmware periodically
+    Telemetry(TelemetryPayload),
+    /// Ask for the firmware's segments, answered with Segments
+    GetSegments,
+    /// Replace the firmware's segments, answered with Segments, an empty list removes them all
+    SetSegments(SegmentsPayload),
+    /// The firmware's segments, sent in response to GetSegments and SetSegments
+    Segments(SegmentsPayload),
+    /// Turn the microphone-driven effects on or off, answered with SoundReactive
+    SetSoundReactive(SoundReactivePayload),
+    /// Whether the microphone-driven effects are on, sent in response to SetSoundReactive
+    SoundReactive(SoundReactivePayload),
 }
 
 impl Message {
+    /// Get the sequence number of a message that updates the LEDs
+    /// Returns None for every other message
+    pub fn frame_seq(&self) -> Option<u32> {
+        match self {
+            Message::SetLeds(payload) => Some(payload.seq),
+            Message::SetLedRange(payload) => Some(payload.seq),
+            Message::SetLedsSparse(payload) => Some(payload.seq),
+            Message::SetLedsRgbw(payload) => Some(payload.seq),
+            Message::FrameDelta(payload) => Some(payload.seq),
+            Message::SetLedsRle(payload) => Some(payload.seq),
+            Message::SetLedsIndexed(payload) => Some(payload.seq),
+            Message::TimedFrame(payload) => payload.frame.frame_seq(),
+            _ => None,
+        }
+    }
+
+    /// Whether this message replaces the whole frame, rather than patching the frame before it
+    pub fn is_whole_frame(&self) -> bool {
+        match self {
+            Message::SetLeds(payload) => payload.strip.is_none(),
+            Message::SetLedsRgbw(_) | Message::SetLedsRle(_) | Message::SetLedsIndexed(_) => true,
+            Message::TimedFrame(payload) => payload.frame.is_whole_frame(),
+            _ => false,
+        }
+    }
+
     /// Serialize message to bytes using postcard
     pub fn to_bytes(&self) -> Result<Vec<u8>, postcard::Error> {
         postcard::to_allocvec(self)
@@ -135,6 +1184,18 @@ impl Message {
 mod tests {
     use super::*;
 
+    #[test]
+    fn hello_is_stable() {
+        let msg = Message::Hello(HelloPayload {
+            protocol_version: 0x0102,
+            firmware_version: "1.0".to_string(),
+        });
+        let bytes = msg.to_bytes().unwrap();
+        // Variant 0, varint protocol version, then the length-prefixed version string
+        assert_eq!(bytes, [0, 0x82, 0x02, 3, b'1', b'.', b'0']);
+        assert_eq!(Message::from_bytes(&bytes).unwrap(), msg);
+    }
+
     #[test]
     fn heartbeat_serialization() {
         let msg = Message::Heartbeat;
@@ -146,6 +1207,8 @@ mod tests {
     #[test]
     fn set_leds_serialization() {
         let payload = SetLedsPayload {
+            seq: 7,
+            strip: Some(1),
             leds: vec![
                 Rgb::new(255, 0, 0),
                 Rgb::new(0, 255, 0),
@@ -160,10 +1223,432 @@ mod tests {
 
     #[test]
     fn log_serialization() {
-        let payload = LogPayload::new(Level::Info, "System initialized".to_string());
+        let payload = LogPayload::new(Level::Info, "System initialized".to_string())
+            .at(1234)
+            .with_target("firmware".to_string())
+            .with_location(SourceLocation {
+                file: "src/main.rs".to_string(),
+                line: 42,
+                module: "firmware".to_string(),
+            });
         let msg = Message::Log(payload);
         let bytes = msg.to_bytes().unwrap();
         let deserialized = Message::from_bytes(&bytes).unwrap();
         assert_eq!(msg, deserialized);
     }
+
+    #[test]
+    fn ack_nack_serialization() {
+        for msg in [
+            Message::Ack(AckPayload { seq: u32::MAX }),
+            Message::Nack(NackPayload {
+                seq: Some(3),
+                reason: NackReason::ChannelFull,
+            }),
+            Message::Nack(NackPayload {
+                seq: None,
+                reason: NackReason::DecodeFailed,
+            }),
+        ] {
+            let bytes = msg.to_bytes().unwrap();
+            let deserialized = Message::from_bytes(&bytes).unwrap();
+            assert_eq!(msg, deserialized);
+        }
+    }
+
+    #[test]
+    fn frame_checksum_serialization() {
+        let msg = Message::FrameChecksum(FrameChecksumPayload {
+            seq: 42,
+            checksum: 0xdead_beef,
+            output_checksums: vec![0x1234_5678, 0x9abc_def0],
+        });
+        let bytes = msg.to_bytes().unwrap();
+        let deserialized = Message::from_bytes(&bytes).unwrap();
+        assert_eq!(msg, deserialized);
+    }
+
+    #[test]
+    fn rgb16_round_trip() {
+        for value in 0..=255 {
+            let rgb = Rgb::new(value, 255 - value, value / 2);
+            assert_eq!(Rgb::from(Rgb16::from(rgb)), rgb);
+        }
+        assert_eq!(Rgb16::from(Rgb::new(255, 0, 0)), Rgb16::new(0xffff, 0, 0));
+        assert_eq!(Rgb::from(Rgb16::new(0x80ff, 0x0081, 0x0080)), Rgb::new(0x80, 1, 0));
+    }
+
+    #[test]
+    fn device_info_serialization() {
+        let msg = Message::DeviceInfo(DeviceInfoPayload {
+            num_leds: 513,
+            strips: vec![171, 171, 171],
+            chip: "esp32c6".to_string(),
+            fw_version: "0.1.0".to_string(),
+            max_frame_size: 4096,
+        });
+        let bytes = msg.to_bytes().unwrap();
+        let deserialized = Message::from_bytes(&bytes).unwrap();
+        assert_eq!(msg, deserialized);
+    }
+
+    #[test]
+    fn leds_serialization() {
+        let msg = Message::Leds(LedsPayload {
+            offset: 10,
+            leds: vec![Rgb::new(1, 2, 3), Rgb::new(4, 5, 6)],
+        });
+        let bytes = msg.to_bytes().unwrap();
+        let deserialized = Message::from_bytes(&bytes).unwrap();
+        assert_eq!(msg, deserialized);
+    }
+
+    #[test]
+    fn frame_checksum_detects_changes() {
+        let frame = vec![Rgb::new(255, 0, 0), Rgb::new(0, 255, 0)];
+        let mut corrupted = frame.clone();
+        corrupted[1].g ^= 0x01;
+
+        assert_eq!(frame_checksum(&frame), frame_checksum(&frame.clone()));
+        assert_ne!(frame_checksum(&frame), frame_checksum(&corrupted));
+        assert_eq!(frame_checksum(&[]), 0x811c_9dc5);
+    }
+
+    #[test]
+    fn set_led_range_apply() {
+        let payload = SetLedRangePayload {
+            seq: 3,
+            start: 2,
+            leds: vec![Rgb::new(1, 1, 1), Rgb::new(2, 2, 2)],
+        };
+        let msg = Message::SetLedRange(payload.clone());
+        assert_eq!(Message::from_bytes(&msg.to_bytes().unwrap()).unwrap(), msg);
+        assert_eq!(msg.frame_seq(), Some(3));
+
+        let mut frame = vec![Rgb::default(); 4];
+        assert!(payload.apply(&mut frame));
+        assert_eq!(frame[1..], [Rgb::default(), Rgb::new(1, 1, 1), Rgb::new(2, 2, 2)]);
+
+        // Ranges running past the end of the frame are rejected
+        let mut short = vec![Rgb::default(); 3];
+        assert!(!payload.apply(&mut short));
+        assert_eq!(short, vec![Rgb::default(); 3]);
+    }
+
+    #[test]
+    fn set_leds_sparse_apply() {
+        let payload = SetLedsSparsePayload {
+            seq: 5,
+            leds: vec![(3, Rgb::new(1, 1, 1)), (0, Rgb::new(2, 2, 2))],
+        };
+        let msg = Message::SetLedsSparse(payload.clone());
+        assert_eq!(Message::from_bytes(&msg.to_bytes().unwrap()).unwrap(), msg);
+        assert_eq!(msg.frame_seq(), Some(5));
+
+        let mut frame = vec![Rgb::default(); 4];
+        assert!(payload.apply(&mut frame));
+        assert_eq!(frame, [Rgb::new(2, 2, 2), Rgb::default(), Rgb::default(), Rgb::new(1, 1, 1)]);
+
+        // A single out of range index rejects the whole update
+        let mut short = vec![Rgb::default(); 3];
+        assert!(!payload.apply(&mut short));
+        assert_eq!(short, vec![Rgb::default(); 3]);
+    }
+
+    #[test]
+    fn rgbw_conversions() {
+        assert_eq!(Rgbw::from(Rgb::new(1, 2, 3)), Rgbw::new(1, 2, 3, 0));
+        assert_eq!(Rgb::from(Rgbw::new(1, 2, 3, 0)), Rgb::new(1, 2, 3));
+        assert_eq!(Rgb::from(Rgbw::new(10, 250, 0, 20)), Rgb::new(30, 255, 20));
+
+        let msg = Message::SetLedsRgbw(SetLedsRgbwPayload {
+            seq: 8,
+            leds: vec![Rgbw::new(1, 2, 3, 4)],
+        });
+        assert_eq!(Message::from_bytes(&msg.to_bytes().unwrap()).unwrap(), msg);
+        assert_eq!(msg.frame_seq(), Some(8));
+        assert!(msg.is_whole_frame());
+    }
+
+    #[test]
+    fn frame_delta_serialization() {
+        let msg = Message::FrameDelta(FrameDeltaPayload {
+            seq: 11,
+            base_seq: 10,
+            leds: vec![(512, Rgb::new(9, 8, 7))],
+        });
+        assert_eq!(Message::from_bytes(&msg.to_bytes().unwrap()).unwrap(), msg);
+        assert_eq!(msg.frame_seq(), Some(11));
+        assert!(!msg.is_whole_frame());
+    }
+
+    #[test]
+    fn rle_round_trip() {
+        let mut leds = vec![Rgb::new(255, 0, 0); 300];
+        leds.extend([Rgb::new(0, 255, 0), Rgb::new(0, 0, 255), Rgb::new(0, 0, 255)]);
+
+        let payload = SetLedsRlePayload::encode(4, &leds);
+        assert_eq!(payload.runs.len(), 3);
+        assert_eq!(payload.len(), leds.len());
+
+        let msg = Message::SetLedsRle(payload.clone());
+        assert_eq!(Message::from_bytes(&msg.to_bytes().unwrap()).unwrap(), msg);
+        assert_eq!(SetLedsPayload::from(payload), SetLedsPayload { seq: 4, strip: None, leds });
+    }
+
+    #[test]
+    fn indexed_round_trip() {
+        let leds = vec![Rgb::new(1, 0, 0), Rgb::new(0, 1, 0), Rgb::new(1, 0, 0)];
+        let palette = PalettePayload::from_frame(&leds).unwrap();
+        assert_eq!(palette.colors, [Rgb::new(1, 0, 0), Rgb::new(0, 1, 0)]);
+
+        let payload = SetLedsIndexedPayload::encode(2, &leds, &palette).unwrap();
+        assert_eq!(payload.indices, [0, 1, 0]);
+        let msg = Message::SetLedsIndexed(payload.clone());
+        assert_eq!(Message::from_bytes(&msg.to_bytes().unwrap()).unwrap(), msg);
+        assert_eq!(payload.decode(&palette), Some(SetLedsPayload { seq: 2, strip: None, leds }));
+
+        // Colors and indices missing from the palette are rejected
+        assert!(SetLedsIndexedPayload::encode(2, &[Rgb::new(0, 0, 1)], &palette).is_none());
+        let stale = SetLedsIndexedPayload { seq: 3, indices: vec![2] };
+        assert!(stale.decode(&palette).is_none());
+
+        let gradient: Vec<Rgb> = (0..=256).map(|i| Rgb::new((i % 256) as u8, (i / 256) as u8, 0)).collect();
+        assert!(PalettePayload::from_frame(&gradient).is_none());
+    }
+
+    #[test]
+    fn start_effect_serialization() {
+        let msg = Message::StartEffect(StartEffectPayload {
+            effect: Effect::Twinkle,
+            speed: 150,
+            palette: vec![Rgb::new(255, 255, 255)],
+        });
+        assert_eq!(Message::from_bytes(&msg.to_bytes().unwrap()).unwrap(), msg);
+        assert_eq!(msg.frame_seq(), None);
+    }
+
+    #[test]
+    fn config_serialization() {
+        let msg = Message::SetConfig(ConfigPayload {
+            brightness_cap: 128,
+            gamma: Gamma::Table((0..=255).rev().collect()),
+            dithering: true,
+            interpolation: true,
+            strips: vec![
+                StripConfig {
+                    num_leds: 200,
+                    color_order: ColorOrder::Grb,
+                    chip: LedChip::Ws2812b,
+                },
+                StripConfig {
+                    num_leds: 313,
+                    color_order: ColorOrder::Rgb,
+                    chip: LedChip::Ws2811,
+                },
+            ],
+            idle_timeout_s: 10,
+            idle_behavior: IdleBehavior::Effect(StartEffectPayload {
+                effect: Effect::Rainbow,
+                speed: 50,
+                palette: vec![],
+            }),
+            self_test: true,
+            status_leds: 3,
+            auto_brightness: true,
+            min_brightness: 16,
+            sacn_universe: 1,
+            mirror: MirrorRole::Secondary,
+            mirror_channel: 6,
+            uart_flow_control: true,
+            restore_state: true,
+            transition_ms: 500,
+        });
+        assert_eq!(Message::from_bytes(&msg.to_bytes().unwrap()).unwrap(), msg);
+        assert_eq!(ColorOrder::Grb.arrange(Rgb::new(1, 2, 3)), [2, 1, 3]);
+    }
+
+    #[test]
+    fn strips_split_the_frame() {
+        let config = ConfigPayload {
+            brightness_cap: 255,
+            gamma: Gamma::Off,
+            dithering: false,
+            interpolation: false,
+            strips: [200, 313]
+                .map(|num_leds| StripConfig {
+                    num_leds,
+                    color_order: ColorOrder::Grb,
+                    chip: LedChip::Ws2812b,
+                })
+                .to_vec(),
+            idle_timeout_s: 10,
+            idle_behavior: IdleBehavior::Hold,
+            self_test: false,
+            status_leds: 0,
+            auto_brightness: false,
+            min_brightness: 0,
+            sacn_universe: 0,
+            mirror: MirrorRole::Off,
+            mirror_channel: 1,
+            uart_flow_control: false,
+            restore_state: false,
+            transition_ms: 0,
+        };
+        assert_eq!(config.num_leds(), 513);
+        assert_eq!(config.strip_range(0), Some(0..200));
+        assert_eq!(config.strip_range(1), Some(200..513));
+        assert_eq!(config.strip_range(2), None);
+    }
+
+    #[test]
+    fn firmware_update_serialization() {
+        let messages = [
+            Message::BeginUpdate(BeginUpdatePayload { size: 4, crc: 0xdead_beef }),
+            Message::FirmwareChunk(FirmwareChunkPayload {
+                offset: 0,
+                data: vec![0xe9, 0, 1, 2],
+            }),
+            Message::FinishUpdate,
+            Message::UpdateStatus(UpdateStatusPayload {
+                written: 4,
+                error: Some(UpdateError::CrcMismatch),
+            }),
+        ];
+        for msg in messages {
+            assert_eq!(Message::from_bytes(&msg.to_bytes().unwrap()).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn status_serialization() {
+        let msg = Message::Status(StatusPayload {
+            uptime_ms: 86_400_000,
+            free_heap: 40_000,
+            rx_queued: 3,
+            rx_capacity: 16,
+            frames_rendered: 123_456,
+            led_write_errors: 1,
+            dropped_logs: 2,
+            ambient_light: Some(1800),
+            effect: Some(StartEffectPayload {
+                effect: Effect::Twinkle,
+                speed: 120,
+                palette: vec![Rgb::new(255, 0, 0)],
+            }),
+        });
+        assert_eq!(Message::from_bytes(&msg.to_bytes().unwrap()).unwrap(), msg);
+    }
+
+    #[test]
+    fn panic_serialization() {
+        let msg = Message::Panic(PanicPayload {
+            message: "index out of bounds".to_string(),
+            location: Some(SourceLocation {
+                file: "src/main.rs".to_string(),
+                line: 42,
+                module: String::new(),
+            }),
+            backtrace: vec![0x4200_1234, 0x4200_5678],
+        });
+        assert_eq!(Message::from_bytes(&msg.to_bytes().unwrap()).unwrap(), msg);
+    }
+
+    #[test]
+    fn power_serialization() {
+        let msg = Message::PowerOff(PowerOffPayload { light_sleep: true });
+        assert_eq!(Message::from_bytes(&msg.to_bytes().unwrap()).unwrap(), msg);
+        assert_eq!(Message::from_bytes(&Message::PowerOn.to_bytes().unwrap()).unwrap(), Message::PowerOn);
+    }
+
+    #[test]
+    fn wifi_credentials_hide_password() {
+        let msg = Message::SetWifiCredentials(WifiCredentialsPayload {
+            ssid: "tree".to_string(),
+            password: "hunter2".to_string(),
+        });
+        assert_eq!(Message::from_bytes(&msg.to_bytes().unwrap()).unwrap(), msg);
+        assert!(!format!("{:?}", msg).contains("hunter2"));
+    }
+
+    #[test]
+    fn button_event_serialization() {
+        let msg = Message::ButtonEvent(ButtonEventPayload {
+            button: 0,
+            press: ButtonPress::Long,
+        });
+        assert_eq!(Message::from_bytes(&msg.to_bytes().unwrap()).unwrap(), msg);
+        assert_eq!(msg.frame_seq(), None);
+    }
+
+    #[test]
+    fn schedule_serialization() {
+        let msg = Message::SetSchedule(SchedulePayload {
+            utc_offset_min: -300,
+            latitude: 4071,
+            longitude: -7401,
+            entries: vec![
+                ScheduleEntry {
+                    time: ScheduleTime::Sunset(-15),
+                    action: ScheduleAction::On,
+                },
+                ScheduleEntry {
+                    time: ScheduleTime::At(0),
+                    action: ScheduleAction::Off,
+                },
+            ],
+        });
+        assert_eq!(Message::from_bytes(&msg.to_bytes().unwrap()).unwrap(), msg);
+    }
+
+    #[test]
+    fn timed_frames_are_frames() {
+        let frame = Message::SetLeds(SetLedsPayload {
+            seq: 12,
+            strip: None,
+            leds: vec![Rgb::new(1, 2, 3)],
+        });
+        let msg = Message::TimedFrame(TimedFramePayload {
+            present_at_us: 5_000_000,
+            frame: Box::new(frame),
+        });
+        assert_eq!(Message::from_bytes(&msg.to_bytes().unwrap()).unwrap(), msg);
+        assert_eq!(msg.frame_seq(), Some(12));
+        assert!(msg.is_whole_frame());
+    }
+
+    #[test]
+    fn telemetry_serialization() {
+        let msg = Message::Telemetry(TelemetryPayload {
+            free_heap: 40_000,
+            largest_free_block: 12_000,
+            min_free_heap: 35_000,
+            stack_size: 32_768,
+            stack_high_water: 9_000,
+        });
+        assert_eq!(Message::from_bytes(&msg.to_bytes().unwrap()).unwrap(), msg);
+    }
+
+    #[test]
+    fn sound_reactive_serialization() {
+        let msg = Message::SetSoundReactive(SoundReactivePayload {
+            enabled: true,
+            sensitivity: 160,
+        });
+        assert_eq!(Message::from_bytes(&msg.to_bytes().unwrap()).unwrap(), msg);
+    }
+
+    #[test]
+    fn reset_report_serialization() {
+        let msg = Message::ResetReport(ResetReportPayload {
+            reason: ResetReason::Software,
+            panic: Some(PanicPayload {
+                message: "attempt to divide by zero".to_string(),
+                location: None,
+                backtrace: vec![0x4200_1234],
+            }),
+            wedged_tasks: vec!["Renderer".to_string()],
+        });
+        assert_eq!(Message::from_bytes(&msg.to_bytes().unwrap()).unwrap(), msg);
+    }
 }
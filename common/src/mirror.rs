@@ -0,0 +1,86 @@
+use crate::message::Rgb;
+use alloc::vec::Vec;
+
+/// Largest ESP-NOW payload
+pub const MAX_PACKET_SIZE: usize = 250;
+
+/// Marks a mirrored frame packet
+const MAGIC: u8 = b'M';
+
+/// Size of the header before the LEDs: magic, frame number, packet index and packet count
+const HEADER_SIZE: usize = 4;
+
+/// RGB LEDs carried by each packet
+pub const LEDS_PER_PACKET: usize = (MAX_PACKET_SIZE - HEADER_SIZE) / 3;
+
+/// Part of a frame broadcast by the primary tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MirrorPacket<'a> {
+    /// Frame the packet belongs to, wrapping around
+    pub frame: u8,
+    /// Index of the packet in the frame, each covering LEDS_PER_PACKET LEDs
+    pub index: u8,
+    /// Number of packets in the frame
+    pub count: u8,
+    /// RGB bytes of the packet's LEDs
+    pub data: &'a [u8],
+}
+
+impl MirrorPacket<'_> {
+    /// Copy the packet's LEDs into their place in leds
+    pub fn apply(&self, leds: &mut [Rgb]) {
+        let start = self.index as usize * LEDS_PER_PACKET;
+        let (channels, _) = self.data.as_chunks::<3>();
+        for (led, &[r, g, b]) in leds.iter_mut().skip(start).zip(channels) {
+            *led = Rgb::new(r, g, b);
+        }
+    }
+}
+
+/// Split a frame into packets
+/// Frames longer than 255 packets are cut short
+pub fn packets(frame: u8, leds: &[Rgb]) -> impl Iterator<Item = Vec<u8>> + '_ {
+    let chunks = leds.chunks(LEDS_PER_PACKET).take(u8::MAX as usize);
+    let count = chunks.len() as u8;
+    chunks.enumerate().map(move |(index, chunk)| {
+        let mut packet = Vec::with_capacity(HEADER_SIZE + chunk.len() * 3);
+        packet.extend_from_slice(&[MAGIC, frame, index as u8, count]);
+        packet.extend(chunk.iter().flat_map(|led| [led.r, led.g, led.b]));
+        packet
+    })
+}
+
+/// Parse a packet, returning None if it isn't a mirrored frame packet
+pub fn parse(packet: &[u8]) -> Option<MirrorPacket<'_>> {
+    match packet {
+        [MAGIC, frame, index, count, data @ ..] if index < count && data.len() % 3 == 0 => Some(MirrorPacket {
+            frame: *frame,
+            index: *index,
+            count: *count,
+            data,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_round_trip() {
+        let leds: Vec<Rgb> = (0..200).map(|i| Rgb::new(i as u8, 0, 255 - i as u8)).collect();
+        let packets: Vec<Vec<u8>> = packets(9, &leds).collect();
+        assert_eq!(packets.len(), 3);
+        assert!(packets.iter().all(|packet| packet.len() <= MAX_PACKET_SIZE));
+
+        let mut mirrored = alloc::vec![Rgb::default(); leds.len()];
+        for packet in &packets {
+            let packet = parse(packet).unwrap();
+            assert_eq!((packet.frame, packet.count), (9, 3));
+            packet.apply(&mut mirrored);
+        }
+        assert_eq!(mirrored, leds);
+        assert_eq!(parse(&[MAGIC, 0, 3, 3]), None);
+    }
+}
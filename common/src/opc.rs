@@ -0,0 +1,60 @@
+/// TCP port OPC clients connect to
+pub const OPC_PORT: u16 = 7890;
+
+/// Size of the header before each message's data
+const HEADER_SIZE: usize = 4;
+
+/// Command setting 8-bit RGB pixel colors
+const COMMAND_SET_PIXELS: u8 = 0;
+
+/// Channel addressing every output
+pub const BROADCAST_CHANNEL: u8 = 0;
+
+/// One message from an OPC client's stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Message<'a> {
+    pub channel: u8,
+    pub command: u8,
+    pub data: &'a [u8],
+}
+
+impl<'a> Message<'a> {
+    /// RGB bytes of a set pixel colors message, three per pixel from the first
+    pub fn pixels(&self) -> Option<&'a [u8]> {
+        (self.command == COMMAND_SET_PIXELS).then_some(self.data)
+    }
+}
+
+/// Parse the message at the start of a client's stream
+/// Returns the message and the number of bytes it takes up, or None until all of it has arrived
+pub fn parse(buffer: &[u8]) -> Option<(Message<'_>, usize)> {
+    let header = buffer.get(..HEADER_SIZE)?;
+    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+    let message = Message {
+        channel: header[0],
+        command: header[1],
+        data: buffer.get(HEADER_SIZE..HEADER_SIZE + length)?,
+    };
+    Some((message, HEADER_SIZE + length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_messages_from_a_stream() {
+        let stream = [1, 0, 0, 3, 255, 128, 0, 0, 255, 0, 2, 9, 9];
+        let (message, size) = parse(&stream).unwrap();
+        assert_eq!(message.channel, 1);
+        assert_eq!(message.pixels(), Some(&[255, 128, 0][..]));
+        assert_eq!(size, 7);
+
+        let (message, size) = parse(&stream[size..]).unwrap();
+        assert_eq!((message.channel, message.command, message.pixels()), (0, 255, None));
+        assert_eq!(size, 6);
+
+        assert_eq!(parse(&stream[..6]), None);
+        assert_eq!(parse(&stream[..3]), None);
+    }
+}
@@ -0,0 +1,92 @@
+use crate::message::Rgb;
+
+/// Named set of colors for effects to draw from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    pub name: &'static str,
+    pub colors: &'static [Rgb],
+}
+
+impl Palette {
+    /// Get the color at index, wrapping around the end of the palette
+    pub fn color(&self, index: usize) -> Rgb {
+        self.colors[index % self.colors.len()]
+    }
+}
+
+/// Okabe-Ito palette, distinguishable under all common forms of color vision deficiency
+pub const OKABE_ITO: Palette = Palette {
+    name: "okabe-ito",
+    colors: &[
+        Rgb::new(230, 159, 0),
+        Rgb::new(86, 180, 233),
+        Rgb::new(0, 158, 115),
+        Rgb::new(240, 228, 66),
+        Rgb::new(0, 114, 178),
+        Rgb::new(213, 94, 0),
+        Rgb::new(204, 121, 167),
+    ],
+};
+
+/// Paul Tol's bright qualitative palette, safe for red-green color blindness
+pub const TOL_BRIGHT: Palette = Palette {
+    name: "tol-bright",
+    colors: &[
+        Rgb::new(68, 119, 170),
+        Rgb::new(102, 204, 238),
+        Rgb::new(34, 136, 51),
+        Rgb::new(204, 187, 68),
+        Rgb::new(238, 102, 119),
+        Rgb::new(170, 51, 119),
+    ],
+};
+
+/// Samples of the viridis colormap, perceptually uniform and readable in grayscale
+pub const VIRIDIS: Palette = Palette {
+    name: "viridis",
+    colors: &[
+        Rgb::new(68, 1, 84),
+        Rgb::new(59, 82, 139),
+        Rgb::new(33, 145, 140),
+        Rgb::new(94, 201, 98),
+        Rgb::new(253, 231, 37),
+    ],
+};
+
+/// Samples of the cividis colormap, optimized for deuteranopia and protanopia
+pub const CIVIDIS: Palette = Palette {
+    name: "cividis",
+    colors: &[
+        Rgb::new(0, 34, 78),
+        Rgb::new(65, 77, 107),
+        Rgb::new(124, 123, 120),
+        Rgb::new(187, 175, 113),
+        Rgb::new(254, 232, 56),
+    ],
+};
+
+/// Blue and orange pair, distinguishable under every form of color vision deficiency
+pub const BLUE_ORANGE: Palette = Palette {
+    name: "blue-orange",
+    colors: &[Rgb::new(0, 90, 181), Rgb::new(220, 50, 32)],
+};
+
+/// All color-vision-friendly palettes
+pub const ACCESSIBLE_PALETTES: &[Palette] = &[OKABE_ITO, TOL_BRIGHT, VIRIDIS, CIVIDIS, BLUE_ORANGE];
+
+/// Find an accessible palette by name
+pub fn accessible_palette(name: &str) -> Option<Palette> {
+    ACCESSIBLE_PALETTES.iter().copied().find(|palette| palette.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palette_lookup() {
+        assert_eq!(accessible_palette("viridis"), Some(VIRIDIS));
+        assert_eq!(accessible_palette("rainbow"), None);
+        assert_eq!(OKABE_ITO.color(OKABE_ITO.colors.len()), OKABE_ITO.colors[0]);
+    }
+}
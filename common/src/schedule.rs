@@ -0,0 +1,149 @@
+use crate::message::{ScheduleAction, ScheduleEntry, SchedulePayload, ScheduleTime};
+use core::str::FromStr;
+
+pub const SECONDS_PER_DAY: i64 = 86_400;
+
+const MINUTES_PER_DAY: i64 = 1_440;
+
+/// Julian day of the Unix epoch
+const UNIX_EPOCH_JULIAN_DAY: f64 = 2_440_587.5;
+
+/// Julian day of the J2000 epoch
+const J2000_JULIAN_DAY: f64 = 2_451_545.0;
+
+/// Sunrise and sunset of a day in seconds since the Unix epoch, using the sunrise equation
+/// Returns None when the sun doesn't rise or doesn't set that day
+pub fn sun_times(unix_day: i64, latitude: f64, longitude: f64) -> Option<(i64, i64)> {
+    use libm::{acos, asin, cos, sin};
+
+    let days = unix_day as f64 + 0.5 + UNIX_EPOCH_JULIAN_DAY - J2000_JULIAN_DAY + 0.0008;
+    let mean_solar_time = days - longitude / 360.0;
+    // Angles are left unwrapped, they only go through sin
+    let anomaly = (357.5291 + 0.985_600_28 * mean_solar_time).to_radians();
+    let center = 1.9148 * sin(anomaly) + 0.0200 * sin(2.0 * anomaly) + 0.0003 * sin(3.0 * anomaly);
+    let ecliptic_longitude = (anomaly.to_degrees() + center + 180.0 + 102.9372).to_radians();
+    let transit = J2000_JULIAN_DAY + mean_solar_time + 0.0053 * sin(anomaly) - 0.0069 * sin(2.0 * ecliptic_longitude);
+
+    let declination = asin(sin(ecliptic_longitude) * sin(23.4397f64.to_radians()));
+    let latitude = latitude.to_radians();
+    // The sun's center 0.833 degrees below the horizon, for refraction and the sun's size
+    let cos_hour_angle = (sin((-0.833f64).to_radians()) - sin(latitude) * sin(declination))
+        / (cos(latitude) * cos(declination));
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let hour_angle = acos(cos_hour_angle).to_degrees() / 360.0;
+    let unix = |julian_day: f64| ((julian_day - UNIX_EPOCH_JULIAN_DAY) * SECONDS_PER_DAY as f64) as i64;
+    Some((unix(transit - hour_angle), unix(transit + hour_angle)))
+}
+
+impl SchedulePayload {
+    /// Action of the entry that came due last at unix seconds, looking back as far as the day before
+    /// Returns None for an empty schedule
+    pub fn action_at(&self, unix: i64) -> Option<ScheduleAction> {
+        let offset = self.utc_offset_min as i64 * 60;
+        let today = (unix + offset).div_euclid(SECONDS_PER_DAY);
+        let now = (unix + offset).div_euclid(60);
+        (today - 1..=today)
+            .flat_map(|day| self.entries.iter().filter_map(move |entry| Some((self.due(entry, day)?, entry.action))))
+            .filter(|&(due, _)| due <= now)
+            .max_by_key(|&(due, _)| due)
+            .map(|(_, action)| action)
+    }
+
+    /// Local minute, counted from the Unix epoch, at which entry comes due on a local day
+    fn due(&self, entry: &ScheduleEntry, day: i64) -> Option<i64> {
+        let midnight = day * MINUTES_PER_DAY;
+        let sun = || {
+            sun_times(day, self.latitude as f64 / 100.0, self.longitude as f64 / 100.0)
+                .map(|(rise, set)| (rise.div_euclid(60), set.div_euclid(60)))
+        };
+        let offset = self.utc_offset_min as i64;
+        match entry.time {
+            ScheduleTime::At(minute) => Some(midnight + minute as i64),
+            ScheduleTime::Sunrise(after) => Some(sun()?.0 + offset + after as i64),
+            ScheduleTime::Sunset(after) => Some(sun()?.1 + offset + after as i64),
+        }
+    }
+}
+
+/// Error parsing a schedule entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseEntryError;
+
+impl FromStr for ScheduleEntry {
+    type Err = ParseEntryError;
+
+    /// Parse an entry like `on@dusk`, `off@00:00` or `on@dawn-30`, where dusk and dawn take an offset in minutes
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (action, time) = s.split_once('@').ok_or(ParseEntryError)?;
+        let action = match action {
+            "on" => ScheduleAction::On,
+            "off" => ScheduleAction::Off,
+            _ => return Err(ParseEntryError),
+        };
+        let offset = |rest: &str| match rest {
+            "" => Ok(0),
+            rest if rest.starts_with(['+', '-']) => rest.parse().map_err(|_| ParseEntryError),
+            _ => Err(ParseEntryError),
+        };
+        let time = if let Some(rest) = time.strip_prefix("dawn") {
+            ScheduleTime::Sunrise(offset(rest)?)
+        } else if let Some(rest) = time.strip_prefix("dusk") {
+            ScheduleTime::Sunset(offset(rest)?)
+        } else {
+            let (hours, minutes) = time.split_once(':').ok_or(ParseEntryError)?;
+            let hours: u16 = hours.parse().map_err(|_| ParseEntryError)?;
+            let minutes: u16 = minutes.parse().map_err(|_| ParseEntryError)?;
+            if hours >= 24 || minutes >= 60 {
+                return Err(ParseEntryError);
+            }
+            ScheduleTime::At(hours * 60 + minutes)
+        };
+        Ok(ScheduleEntry { time, action })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-12-21, the winter solstice
+    const SOLSTICE: i64 = 20_078;
+
+    #[test]
+    fn sun_times_match_almanac() {
+        // London rises at 08:04 and sets at 15:53 UTC
+        let (rise, set) = sun_times(SOLSTICE, 51.5, -0.13).unwrap();
+        assert!((rise - (SOLSTICE * SECONDS_PER_DAY + 8 * 3600 + 4 * 60)).abs() < 120);
+        assert!((set - (SOLSTICE * SECONDS_PER_DAY + 15 * 3600 + 53 * 60)).abs() < 120);
+        // The sun doesn't rise above the arctic circle
+        assert_eq!(sun_times(SOLSTICE, 78.2, 15.6), None);
+    }
+
+    #[test]
+    fn on_at_dusk_off_at_midnight() {
+        let schedule = SchedulePayload {
+            utc_offset_min: 0,
+            latitude: 5150,
+            longitude: -13,
+            entries: alloc::vec!["on@dusk".parse().unwrap(), "off@00:00".parse().unwrap()],
+        };
+        let at = |hour: i64| schedule.action_at(SOLSTICE * SECONDS_PER_DAY + hour * 3600);
+        assert_eq!(at(1), Some(ScheduleAction::Off));
+        assert_eq!(at(12), Some(ScheduleAction::Off));
+        assert_eq!(at(17), Some(ScheduleAction::On));
+        assert_eq!(at(23), Some(ScheduleAction::On));
+        assert_eq!(SchedulePayload::default().action_at(0), None);
+    }
+
+    #[test]
+    fn parses_entries() {
+        let entry = |time, action| Ok(ScheduleEntry { time, action });
+        assert_eq!("on@dusk".parse(), entry(ScheduleTime::Sunset(0), ScheduleAction::On));
+        assert_eq!("off@dawn-30".parse(), entry(ScheduleTime::Sunrise(-30), ScheduleAction::Off));
+        assert_eq!("off@23:45".parse(), entry(ScheduleTime::At(1425), ScheduleAction::Off));
+        assert_eq!("on@24:00".parse::<ScheduleEntry>(), Err(ParseEntryError));
+        assert_eq!("on@dusk30".parse::<ScheduleEntry>(), Err(ParseEntryError));
+    }
+}
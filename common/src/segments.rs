@@ -0,0 +1,108 @@
+use crate::message::{Effect, Segment, SegmentMode, SegmentsPayload, StartEffectPayload, MAX_SEGMENTS};
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::str::FromStr;
+
+/// Speed effects run at when a segment doesn't give one, in percent
+const DEFAULT_SPEED: u16 = 100;
+
+impl Segment {
+    /// LEDs of the segment in the frame
+    pub fn range(&self) -> Range<usize> {
+        self.start as usize..self.start as usize + self.len as usize
+    }
+}
+
+impl SegmentsPayload {
+    /// Whether the segments fit in a frame of num_leds without overlapping, and aren't too many
+    pub fn is_valid(&self, num_leds: usize) -> bool {
+        if self.segments.len() > MAX_SEGMENTS {
+            return false;
+        }
+        let mut ranges: Vec<Range<usize>> = self.segments.iter().map(Segment::range).collect();
+        ranges.sort_by_key(|range| range.start);
+        ranges.iter().all(|range| !range.is_empty() && range.end <= num_leds)
+            && ranges.windows(2).all(|pair| pair[0].end <= pair[1].start)
+    }
+}
+
+/// Error parsing a segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseSegmentError;
+
+impl FromStr for Segment {
+    type Err = ParseSegmentError;
+
+    /// Parse a segment like `0+20:frozen`, `20+5:rainbow` or `25+100:twinkle@50`,
+    /// its first LED and length followed by what it displays, effects taking a speed in percent
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (range, mode) = s.split_once(':').ok_or(ParseSegmentError)?;
+        let (start, len) = range.split_once('+').ok_or(ParseSegmentError)?;
+        let start = start.parse().map_err(|_| ParseSegmentError)?;
+        let len = len.parse().map_err(|_| ParseSegmentError)?;
+        if mode == "frozen" {
+            return Ok(Segment {
+                start,
+                len,
+                mode: SegmentMode::Frozen,
+            });
+        }
+        let (name, speed) = match mode.split_once('@') {
+            Some((name, speed)) => (name, speed.parse().map_err(|_| ParseSegmentError)?),
+            None => (mode, DEFAULT_SPEED),
+        };
+        let effect = match name {
+            "rainbow" => Effect::Rainbow,
+            "chase" => Effect::Chase,
+            "twinkle" => Effect::Twinkle,
+            "breathe" => Effect::Breathe,
+            _ => return Err(ParseSegmentError),
+        };
+        let mode = SegmentMode::Effect(StartEffectPayload {
+            effect,
+            speed,
+            palette: Vec::new(),
+        });
+        Ok(Segment { start, len, mode })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_segments() {
+        assert_eq!(
+            "0+20:frozen".parse::<Segment>(),
+            Ok(Segment {
+                start: 0,
+                len: 20,
+                mode: SegmentMode::Frozen
+            })
+        );
+        assert_eq!(
+            "25+100:twinkle@50".parse::<Segment>().map(|segment| segment.mode),
+            Ok(SegmentMode::Effect(StartEffectPayload {
+                effect: Effect::Twinkle,
+                speed: 50,
+                palette: Vec::new(),
+            }))
+        );
+        assert_eq!("25+100:sparkle".parse::<Segment>(), Err(ParseSegmentError));
+        assert_eq!("25:rainbow".parse::<Segment>(), Err(ParseSegmentError));
+        assert_eq!("0+20:frozen@50".parse::<Segment>(), Err(ParseSegmentError));
+    }
+
+    #[test]
+    fn segments_must_fit_without_overlapping() {
+        let payload = |segments: &[&str]| SegmentsPayload {
+            segments: segments.iter().map(|segment| segment.parse().unwrap()).collect(),
+        };
+        assert!(payload(&["20+5:rainbow", "0+20:frozen"]).is_valid(25));
+        assert!(payload(&[]).is_valid(0));
+        assert!(!payload(&["20+6:rainbow"]).is_valid(25));
+        assert!(!payload(&["0+10:frozen", "9+5:chase"]).is_valid(25));
+        assert!(!payload(&["0+0:frozen"]).is_valid(25));
+    }
+}
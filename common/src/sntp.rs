@@ -0,0 +1,68 @@
+/// UDP port SNTP servers listen on
+pub const SNTP_PORT: u16 = 123;
+
+/// Size of an SNTP packet without extensions
+pub const PACKET_SIZE: usize = 48;
+
+/// Seconds from the NTP epoch in 1900 to the Unix epoch
+const NTP_TO_UNIX_SECONDS: u64 = 2_208_988_800;
+
+/// Protocol version 4
+const VERSION: u8 = 4;
+
+/// Client and server association modes
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+
+/// Leap indicator of a server whose clock isn't synchronized
+const LEAP_UNSYNCHRONIZED: u8 = 3;
+
+/// Offset of the transmit timestamp, the server's time when it replied
+const TRANSMIT_TIMESTAMP_OFFSET: usize = 40;
+
+/// Build a client request
+pub fn request() -> [u8; PACKET_SIZE] {
+    let mut packet = [0u8; PACKET_SIZE];
+    packet[0] = VERSION << 3 | MODE_CLIENT;
+    packet
+}
+
+/// Parse a server's reply, returning its time in seconds since the Unix epoch
+/// Returns None for other packets and servers that don't know the time
+pub fn parse(packet: &[u8]) -> Option<u64> {
+    let header = *packet.first()?;
+    let stratum = *packet.get(1)?;
+    if header & 0x07 != MODE_SERVER || header >> 6 == LEAP_UNSYNCHRONIZED || stratum == 0 {
+        return None;
+    }
+    let seconds = packet.get(TRANSMIT_TIMESTAMP_OFFSET..TRANSMIT_TIMESTAMP_OFFSET + 4)?;
+    let seconds = u32::from_be_bytes(seconds.try_into().ok()?) as u64;
+    // Timestamps wrap in 2036, later times count from the start of the next era
+    if seconds >= NTP_TO_UNIX_SECONDS {
+        Some(seconds - NTP_TO_UNIX_SECONDS)
+    } else {
+        Some(seconds + (1 << 32) - NTP_TO_UNIX_SECONDS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_server_time() {
+        assert_eq!(request()[0], 0x23);
+
+        let mut reply = [0u8; PACKET_SIZE];
+        reply[0] = VERSION << 3 | MODE_SERVER;
+        reply[1] = 2;
+        reply[TRANSMIT_TIMESTAMP_OFFSET..TRANSMIT_TIMESTAMP_OFFSET + 4]
+            .copy_from_slice(&((1_734_739_200 + NTP_TO_UNIX_SECONDS) as u32).to_be_bytes());
+        assert_eq!(parse(&reply), Some(1_734_739_200));
+
+        // Kiss-o'-death replies have stratum 0
+        reply[1] = 0;
+        assert_eq!(parse(&reply), None);
+        assert_eq!(parse(&request()), None);
+    }
+}
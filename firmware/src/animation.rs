@@ -0,0 +1,157 @@
+use alloc::vec::Vec;
+use common::message::{Effect, Rgb, Rgbw, StartEffectPayload};
+use embassy_time::Instant;
+
+/// Palette used when StartEffect doesn't include one, interpolating between these gives the full hue wheel
+const DEFAULT_PALETTE: [Rgb; 6] = [
+    Rgb::new(255, 0, 0),
+    Rgb::new(255, 255, 0),
+    Rgb::new(0, 255, 0),
+    Rgb::new(0, 255, 255),
+    Rgb::new(0, 0, 255),
+    Rgb::new(255, 0, 255),
+];
+
+/// Time for the rainbow to flow once through the palette at normal speed
+const RAINBOW_PERIOD_MS: u64 = 5000;
+/// Time for the chase to advance one LED at normal speed
+const CHASE_STEP_MS: u64 = 20;
+/// Number of LEDs in the chase segment, including its fading tail
+const CHASE_LENGTH: usize = 8;
+/// Fraction of its brightness, out of 256, a twinkle keeps each frame
+const TWINKLE_FADE: u16 = 230;
+/// LEDs per new twinkle each frame at normal speed
+const TWINKLE_SPACING: usize = 64;
+/// Time for one breath at normal speed
+const BREATHE_PERIOD_MS: u64 = 4000;
+
+/// Effects cycled through by the button while the host is absent
+const BUTTON_EFFECTS: [Effect; 4] = [Effect::Rainbow, Effect::Chase, Effect::Twinkle, Effect::Breathe];
+
+/// Effect the button switches to after current, starting from the first
+pub fn next_effect(current: Option<Effect>) -> Effect {
+    let next = current
+        .and_then(|current| BUTTON_EFFECTS.iter().position(|&effect| effect == current))
+        .map_or(0, |index| index + 1);
+    BUTTON_EFFECTS[next % BUTTON_EFFECTS.len()]
+}
+
+/// Firmware-resident animation started by a StartEffect message
+pub struct Animation {
+    effect: Effect,
+    speed: u16,
+    palette: Vec<Rgb>,
+    start: Instant,
+    rng: u32,
+}
+
+impl Animation {
+    /// Start the animation described by payload
+    pub fn new(payload: StartEffectPayload) -> Self {
+        let palette = if payload.palette.is_empty() {
+            DEFAULT_PALETTE.to_vec()
+        } else {
+            payload.palette
+        };
+        let start = Instant::now();
+        Self {
+            effect: payload.effect,
+            speed: payload.speed,
+            palette,
+            start,
+            // Xorshift state must never be zero
+            rng: (start.as_ticks() as u32) | 1,
+        }
+    }
+
+    /// Effect being animated
+    pub fn effect(&self) -> Effect {
+        self.effect
+    }
+
+    /// StartEffect payload starting this animation over
+    pub fn payload(&self) -> StartEffectPayload {
+        StartEffectPayload {
+            effect: self.effect,
+            speed: self.speed,
+            palette: self.palette.clone(),
+        }
+    }
+
+    /// Render the next frame into leds, which holds the previous frame
+    pub fn render(&mut self, leds: &mut [Rgbw]) {
+        if leds.is_empty() {
+            return;
+        }
+        // Animation time, scaled by the speed
+        let t = self.start.elapsed().as_millis() * self.speed as u64 / 100;
+        let cycle = self.palette.len() as u64 * 256;
+        let len = leds.len();
+
+        match self.effect {
+            Effect::Rainbow => {
+                let offset = t * cycle / RAINBOW_PERIOD_MS;
+                for (i, led) in leds.iter_mut().enumerate() {
+                    let position = (i as u64 * cycle / len as u64 + offset) % cycle;
+                    *led = self.blend(position).into();
+                }
+            }
+            Effect::Chase => {
+                let step = (t / CHASE_STEP_MS) as usize;
+                let head = step % len;
+                let color = self.palette[(step / len) % self.palette.len()];
+                leds.fill(Rgbw::default());
+                for k in 0..CHASE_LENGTH.min(len) {
+                    let brightness = 255 - (k * 255 / CHASE_LENGTH) as u8;
+                    leds[(head + len - k) % len] = scale(color, brightness).into();
+                }
+            }
+            Effect::Twinkle => {
+                for led in leds.iter_mut() {
+                    let fade = |channel: u8| (channel as u16 * TWINKLE_FADE / 256) as u8;
+                    *led = Rgbw::new(fade(led.r), fade(led.g), fade(led.b), fade(led.w));
+                }
+                let twinkles = (len * self.speed as usize / (100 * TWINKLE_SPACING)).max(1);
+                for _ in 0..twinkles {
+                    let index = self.random() as usize % len;
+                    let pick = self.random() as usize % self.palette.len();
+                    let color = self.palette[pick];
+                    leds[index] = color.into();
+                }
+            }
+            Effect::Breathe => {
+                let phase = t % BREATHE_PERIOD_MS;
+                let half = BREATHE_PERIOD_MS / 2;
+                let triangle = (if phase < half { phase } else { BREATHE_PERIOD_MS - phase }) * 255 / half;
+                // Squaring the triangle wave makes the fade look even to the eye
+                let brightness = (triangle * triangle / 255) as u8;
+                let color = self.palette[(t / BREATHE_PERIOD_MS) as usize % self.palette.len()];
+                leds.fill(scale(color, brightness).into());
+            }
+        }
+    }
+
+    /// Color at position along the palette, 256 steps between adjacent colors, wrapping around
+    fn blend(&self, position: u64) -> Rgb {
+        let index = (position / 256) as usize % self.palette.len();
+        let from = self.palette[index];
+        let to = self.palette[(index + 1) % self.palette.len()];
+        let frac = (position % 256) as i32;
+        let lerp = |a: u8, b: u8| (a as i32 + (b as i32 - a as i32) * frac / 256) as u8;
+        Rgb::new(lerp(from.r, to.r), lerp(from.g, to.g), lerp(from.b, to.b))
+    }
+
+    /// Next value of the xorshift32 generator
+    fn random(&mut self) -> u32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.rng
+    }
+}
+
+/// Scale a color by brightness out of 255
+fn scale(color: Rgb, brightness: u8) -> Rgb {
+    let scale = |channel: u8| (channel as u16 * brightness as u16 / 255) as u8;
+    Rgb::new(scale(color.r), scale(color.g), scale(color.b))
+}
@@ -0,0 +1,61 @@
+use common::message::LedChip;
+use esp_hal::Async;
+use esp_hal::spi::master::Spi;
+
+use crate::strip::{LedDriver, StripError};
+
+/// SPI clock of the strip, APA102 and SK9822 LEDs take up to about 20 MHz but long cable runs need it slower
+pub const SPI_FREQUENCY_MHZ: u32 = 4;
+
+/// Bytes of the start frame, and of the reset frame SK9822 LEDs need after the last LED
+const START_FRAME_SIZE: usize = 4;
+
+/// First byte of each LED, three marker bits followed by the 5-bit global brightness, left at full
+/// so brightness is handled by the frame itself
+const LED_HEADER: u8 = 0xFF;
+
+/// Bytes of the end frame for a number of LEDs, each LED delays the data by half a clock
+const fn end_frame_size(leds: usize) -> usize {
+    START_FRAME_SIZE + leds.div_ceil(16)
+}
+
+/// Number of bytes needed to send a frame of LEDs: the start frame, 4 bytes per LED and the end frame
+pub const fn buffer_size(leds: usize) -> usize {
+    START_FRAME_SIZE + leds * 4 + end_frame_size(leds)
+}
+
+/// APA102 or SK9822 strip clocked from an SPI bus, taking its color bytes in the order blue, green, red
+/// Strips are configured with the Bgr color order to match
+pub struct Apa102Driver<'d, const BUFFER_SIZE: usize> {
+    spi: Spi<'d, Async>,
+    buffer: &'d mut [u8; BUFFER_SIZE],
+}
+
+impl<'d, const BUFFER_SIZE: usize> Apa102Driver<'d, BUFFER_SIZE> {
+    /// Drive a strip from an SPI bus with its clock and data pins attached
+    pub fn new(spi: Spi<'d, Async>, buffer: &'d mut [u8; BUFFER_SIZE]) -> Self {
+        Self { spi, buffer }
+    }
+}
+
+impl<const BUFFER_SIZE: usize> LedDriver for Apa102Driver<'_, BUFFER_SIZE> {
+    /// Send bytes to the strip, 3 for each LED, the chip's timing doesn't apply to clocked LEDs
+    async fn write(&mut self, mut bytes: impl Iterator<Item = u8>, _chip: LedChip) -> Result<(), StripError> {
+        self.buffer[..START_FRAME_SIZE].fill(0);
+        let mut len = START_FRAME_SIZE;
+        let mut leds = 0;
+        while let (Some(first), Some(second), Some(third)) = (bytes.next(), bytes.next(), bytes.next()) {
+            // Leave room for the end frame
+            if len + 4 + end_frame_size(leds + 1) > BUFFER_SIZE {
+                return Err(StripError::Overflow);
+            }
+            self.buffer[len..len + 4].copy_from_slice(&[LED_HEADER, first, second, third]);
+            len += 4;
+            leds += 1;
+        }
+        // Zeros rather than the usual ones, so the end frame can't be taken for another LED
+        let end = len + end_frame_size(leds);
+        self.buffer[len..end].fill(0);
+        self.spi.write_async(&self.buffer[..end]).await.map_err(StripError::Spi)
+    }
+}
@@ -0,0 +1,177 @@
+//! Pins and peripherals that differ between boards, selected by cargo features:
+//! a chip feature (esp32c6, esp32c3 or esp32s3) and a board feature (devkit or custom-pcb)
+
+use esp_hal::gpio::AnyPin;
+use esp_hal::peripherals::{GPIO4, UART0};
+
+use crate::MAX_STRIPS;
+
+#[cfg(not(any(feature = "esp32c6", feature = "esp32c3", feature = "esp32s3")))]
+compile_error!("Select the chip with one of the esp32c6, esp32c3 or esp32s3 features");
+
+#[cfg(not(any(feature = "devkit", feature = "custom-pcb")))]
+compile_error!("Select the board with the devkit or custom-pcb feature");
+
+#[cfg(all(feature = "devkit", feature = "custom-pcb"))]
+compile_error!("The devkit and custom-pcb features select different boards, enable only one");
+
+/// Chip the firmware is built for, reported in DeviceInfo
+#[cfg(feature = "esp32c6")]
+pub const CHIP: &str = "esp32c6";
+#[cfg(feature = "esp32c3")]
+pub const CHIP: &str = "esp32c3";
+#[cfg(feature = "esp32s3")]
+pub const CHIP: &str = "esp32s3";
+
+/// LEDs in the single strip configured until a config is stored
+#[cfg(feature = "devkit")]
+pub const DEFAULT_NUM_LEDS: u16 = 513;
+#[cfg(feature = "custom-pcb")]
+pub const DEFAULT_NUM_LEDS: u16 = 300;
+
+/// UART carrying frames to and from the host, UART0 so it can wake the chip from light sleep
+pub type HostUart = UART0<'static>;
+
+/// ADC1 pin the light sensor's photoresistor divider is wired to
+pub type LightSensorPin = GPIO4<'static>;
+
+/// ADC1 pin an analog microphone's output is wired to, for sound-reactive effects
+#[cfg(any(all(feature = "devkit", feature = "esp32c6"), feature = "custom-pcb"))]
+pub type MicrophonePin = esp_hal::peripherals::GPIO1<'static>;
+#[cfg(all(feature = "devkit", feature = "esp32c3"))]
+pub type MicrophonePin = esp_hal::peripherals::GPIO3<'static>;
+#[cfg(all(feature = "devkit", feature = "esp32s3"))]
+pub type MicrophonePin = esp_hal::peripherals::GPIO5<'static>;
+
+/// Pins and peripherals of the board, taken from the chip's peripherals with take!
+pub struct Board {
+    /// Data pin of each strip output, in order
+    pub led_pins: [AnyPin<'static>; MAX_STRIPS],
+    /// Clock pin of the first strip when it's an APA102 strip
+    #[cfg(feature = "apa102")]
+    pub led_clock: AnyPin<'static>,
+    pub uart: HostUart,
+    pub uart_rx: AnyPin<'static>,
+    pub uart_tx: AnyPin<'static>,
+    /// Flow control pins, only connected when uart_flow_control is enabled
+    pub uart_rts: AnyPin<'static>,
+    pub uart_cts: AnyPin<'static>,
+    pub light_sensor: LightSensorPin,
+    pub microphone: MicrophonePin,
+    /// Active low button that changes effects without the host
+    pub button: AnyPin<'static>,
+}
+
+/// UART0's RX and TX pins, which the chip's boot ROM and devkits' USB-serial bridges use
+#[cfg(feature = "esp32c6")]
+macro_rules! uart_pins {
+    ($peripherals:ident) => {
+        ($peripherals.GPIO17.into(), $peripherals.GPIO16.into())
+    };
+}
+#[cfg(feature = "esp32c3")]
+macro_rules! uart_pins {
+    ($peripherals:ident) => {
+        ($peripherals.GPIO20.into(), $peripherals.GPIO21.into())
+    };
+}
+#[cfg(feature = "esp32s3")]
+macro_rules! uart_pins {
+    ($peripherals:ident) => {
+        ($peripherals.GPIO44.into(), $peripherals.GPIO43.into())
+    };
+}
+pub(crate) use uart_pins;
+
+/// Take the board's pins and peripherals, leaving the rest of peripherals to the caller
+/// ESP32-C6-DevKitC-1: strips on GPIO10 and GPIO11, APA102 clock on GPIO18, RTS/CTS on GPIO2 and GPIO3,
+/// microphone on GPIO1, boot button on GPIO9
+#[cfg(all(feature = "devkit", feature = "esp32c6"))]
+macro_rules! take {
+    ($peripherals:ident) => {{
+        let (uart_rx, uart_tx) = $crate::board::uart_pins!($peripherals);
+        $crate::board::Board {
+            led_pins: [$peripherals.GPIO10.into(), $peripherals.GPIO11.into()],
+            #[cfg(feature = "apa102")]
+            led_clock: $peripherals.GPIO18.into(),
+            uart: $peripherals.UART0,
+            uart_rx,
+            uart_tx,
+            uart_rts: $peripherals.GPIO2.into(),
+            uart_cts: $peripherals.GPIO3.into(),
+            light_sensor: $peripherals.GPIO4,
+            microphone: $peripherals.GPIO1,
+            button: $peripherals.GPIO9.into(),
+        }
+    }};
+}
+
+/// Take the board's pins and peripherals, leaving the rest of peripherals to the caller
+/// ESP32-C3-DevKitM-1: strips on GPIO6 and GPIO7, APA102 clock on GPIO10, RTS/CTS on GPIO0 and GPIO1,
+/// microphone on GPIO3, boot button on GPIO9
+#[cfg(all(feature = "devkit", feature = "esp32c3"))]
+macro_rules! take {
+    ($peripherals:ident) => {{
+        let (uart_rx, uart_tx) = $crate::board::uart_pins!($peripherals);
+        $crate::board::Board {
+            led_pins: [$peripherals.GPIO6.into(), $peripherals.GPIO7.into()],
+            #[cfg(feature = "apa102")]
+            led_clock: $peripherals.GPIO10.into(),
+            uart: $peripherals.UART0,
+            uart_rx,
+            uart_tx,
+            uart_rts: $peripherals.GPIO0.into(),
+            uart_cts: $peripherals.GPIO1.into(),
+            light_sensor: $peripherals.GPIO4,
+            microphone: $peripherals.GPIO3,
+            button: $peripherals.GPIO9.into(),
+        }
+    }};
+}
+
+/// Take the board's pins and peripherals, leaving the rest of peripherals to the caller
+/// ESP32-S3-DevKitC-1: strips on GPIO10 and GPIO11, APA102 clock on GPIO12, RTS/CTS on GPIO15 and GPIO16,
+/// microphone on GPIO5, boot button on GPIO0
+#[cfg(all(feature = "devkit", feature = "esp32s3"))]
+macro_rules! take {
+    ($peripherals:ident) => {{
+        let (uart_rx, uart_tx) = $crate::board::uart_pins!($peripherals);
+        $crate::board::Board {
+            led_pins: [$peripherals.GPIO10.into(), $peripherals.GPIO11.into()],
+            #[cfg(feature = "apa102")]
+            led_clock: $peripherals.GPIO12.into(),
+            uart: $peripherals.UART0,
+            uart_rx,
+            uart_tx,
+            uart_rts: $peripherals.GPIO15.into(),
+            uart_cts: $peripherals.GPIO16.into(),
+            light_sensor: $peripherals.GPIO4,
+            microphone: $peripherals.GPIO5,
+            button: $peripherals.GPIO0.into(),
+        }
+    }};
+}
+
+/// Take the board's pins and peripherals, leaving the rest of peripherals to the caller
+/// Custom PCB, on pins every supported chip has: strips on GPIO5 and GPIO6, APA102 clock on GPIO7,
+/// RTS/CTS on GPIO2 and GPIO3, microphone on GPIO1, button on GPIO9, change them to match the PCB's wiring
+#[cfg(feature = "custom-pcb")]
+macro_rules! take {
+    ($peripherals:ident) => {{
+        let (uart_rx, uart_tx) = $crate::board::uart_pins!($peripherals);
+        $crate::board::Board {
+            led_pins: [$peripherals.GPIO5.into(), $peripherals.GPIO6.into()],
+            #[cfg(feature = "apa102")]
+            led_clock: $peripherals.GPIO7.into(),
+            uart: $peripherals.UART0,
+            uart_rx,
+            uart_tx,
+            uart_rts: $peripherals.GPIO2.into(),
+            uart_cts: $peripherals.GPIO3.into(),
+            light_sensor: $peripherals.GPIO4,
+            microphone: $peripherals.GPIO1,
+            button: $peripherals.GPIO9.into(),
+        }
+    }};
+}
+pub(crate) use take;
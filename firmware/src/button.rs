@@ -0,0 +1,41 @@
+use common::message::{ButtonEventPayload, ButtonPress, Message};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::gpio::{AnyPin, Input, InputConfig, Pull};
+
+use crate::messages::RX_CHANNEL;
+
+/// Time a button's contacts are left to settle after it changes, changes shorter than this are ignored
+const DEBOUNCE_TIME: Duration = Duration::from_millis(30);
+
+/// Time a button must be held for a long press
+const LONG_PRESS_TIME: Duration = Duration::from_secs(1);
+
+/// Button task reporting presses of an active low button to the main loop as ButtonEvent messages,
+/// which forwards them to the host
+#[embassy_executor::task]
+pub async fn button_task(pin: AnyPin<'static>, button: u8) {
+    let mut input = Input::new(pin, InputConfig::default().with_pull(Pull::Up));
+
+    loop {
+        input.wait_for_falling_edge().await;
+        Timer::after(DEBOUNCE_TIME).await;
+        // Released again before settling, so it was noise
+        if input.is_high() {
+            continue;
+        }
+        let pressed_at = Instant::now();
+
+        input.wait_for_high().await;
+        let press = if pressed_at.elapsed() >= LONG_PRESS_TIME {
+            ButtonPress::Long
+        } else {
+            ButtonPress::Short
+        };
+        Timer::after(DEBOUNCE_TIME).await;
+
+        let event = ButtonEventPayload { button, press };
+        if RX_CHANNEL.try_send(Message::ButtonEvent(event)).is_err() {
+            log::warn!("Dropped {:?} press of button {}, receive channel full", press, button);
+        }
+    }
+}
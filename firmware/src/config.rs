@@ -0,0 +1,249 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use common::framing::crc16;
+use common::message::{
+    ColorOrder, ConfigPayload, Effect, Gamma, IdleBehavior, LedChip, Message, MirrorRole, SchedulePayload,
+    StartEffectPayload, StripConfig, WifiCredentialsPayload, GAMMA_TABLE_SIZE,
+};
+use embedded_storage::{ReadStorage, Storage};
+use esp_bootloader_esp_idf::partitions::{self, DataPartitionSubType, PARTITION_TABLE_MAX_LEN, PartitionType};
+use esp_storage::{FlashStorage, FlashStorageError};
+
+use crate::indicator::STATUS_INDICATORS;
+use crate::{MAX_LEDS, MAX_STRIPS, board};
+
+/// Size of a flash sector, the unit flash is erased in
+const SECTOR_SIZE: u32 = 0x1000;
+
+/// Offset of the config in the NVS partition, which firmware/partitions.csv sizes for every record below
+const CONFIG_OFFSET: u32 = 0;
+
+/// Offset of the WiFi credentials, in the flash sector after the config
+const WIFI_OFFSET: u32 = CONFIG_OFFSET + SECTOR_SIZE;
+
+/// Offset of the schedule, in the flash sector after the WiFi credentials
+const SCHEDULE_OFFSET: u32 = WIFI_OFFSET + SECTOR_SIZE;
+
+/// Offset of the display state, in the two flash sectors after the schedule
+const STATE_OFFSET: u32 = SCHEDULE_OFFSET + SECTOR_SIZE;
+
+/// Marks a flash sector holding a config, erased flash reads as all ones
+const CONFIG_MAGIC: [u8; 4] = *b"TREE";
+
+/// Largest serialized config that can be stored, room for an uploaded gamma table
+const MAX_CONFIG_SIZE: usize = 512;
+
+/// Largest serialized display state that can be stored, room for a whole RGBW frame
+const MAX_STATE_SIZE: usize = MAX_LEDS * MAX_STRIPS * 4 + 16;
+
+/// Size of the header before the serialized config: magic, length and CRC16
+const HEADER_SIZE: usize = 8;
+
+/// WiFi channels trees can mirror frames on
+const MIRROR_CHANNELS: core::ops::RangeInclusive<u8> = 1..=13;
+
+/// Settings of a strip not in the config
+pub const DEFAULT_STRIP: StripConfig = StripConfig {
+    num_leds: 0,
+    color_order: ColorOrder::Grb,
+    chip: LedChip::Ws2812b,
+};
+
+/// Settings used until a config is stored
+pub fn default_config() -> ConfigPayload {
+    ConfigPayload {
+        brightness_cap: 255,
+        gamma: Gamma::Standard,
+        dithering: false,
+        interpolation: false,
+        strips: vec![StripConfig {
+            num_leds: board::DEFAULT_NUM_LEDS,
+            ..DEFAULT_STRIP
+        }],
+        idle_timeout_s: 10,
+        idle_behavior: IdleBehavior::Effect(StartEffectPayload {
+            effect: Effect::Breathe,
+            speed: 100,
+            palette: Vec::new(),
+        }),
+        self_test: false,
+        status_leds: 0,
+        auto_brightness: false,
+        min_brightness: 16,
+        sacn_universe: 0,
+        mirror: MirrorRole::Off,
+        mirror_channel: 1,
+        uart_flow_control: false,
+        restore_state: false,
+        transition_ms: 0,
+    }
+}
+
+/// Restrict a config to the strips the firmware can drive and a usable gamma table
+pub fn limit(config: &mut ConfigPayload) {
+    if let Gamma::Table(levels) = &config.gamma
+        && levels.len() != GAMMA_TABLE_SIZE
+    {
+        log::warn!("Config has a gamma table of {} entries, expected {}", levels.len(), GAMMA_TABLE_SIZE);
+        config.gamma = Gamma::Standard;
+    }
+    if config.strips.len() > MAX_STRIPS {
+        log::warn!("Config has {} strips, at most {} are supported", config.strips.len(), MAX_STRIPS);
+        config.strips.truncate(MAX_STRIPS);
+    }
+    for strip in &mut config.strips {
+        if strip.num_leds as usize > MAX_LEDS {
+            log::warn!("Config has a strip of {} LEDs, at most {} are supported", strip.num_leds, MAX_LEDS);
+            strip.num_leds = MAX_LEDS as u16;
+        }
+    }
+    if config.status_leds as usize > STATUS_INDICATORS {
+        log::warn!("Config has {} status LEDs, at most {} are supported", config.status_leds, STATUS_INDICATORS);
+        config.status_leds = STATUS_INDICATORS as u8;
+    }
+    if !MIRROR_CHANNELS.contains(&config.mirror_channel) {
+        log::warn!("Config has ESP-NOW channel {}, using channel 1", config.mirror_channel);
+        config.mirror_channel = 1;
+    }
+}
+
+/// Errors that can occur when storing the config
+#[derive(Debug)]
+pub enum ConfigError {
+    Serialization(postcard::Error),
+    Flash(FlashStorageError),
+    /// The partition table couldn't be read or has no NVS partition
+    NoPartition,
+    /// The record would run past the end of the NVS partition, into the partition after it
+    OutOfSpace,
+}
+
+/// Load the config stored in flash
+/// Returns None if no config is stored or it is corrupted
+pub fn load(flash: &mut FlashStorage<'_>) -> Option<ConfigPayload> {
+    let mut data = [0u8; MAX_CONFIG_SIZE];
+    let len = read_record(flash, CONFIG_OFFSET, &mut data)?;
+    postcard::from_bytes(&data[..len]).ok()
+}
+
+/// Erase the config, WiFi credentials, schedule and display state stored in flash,
+/// so the defaults are used from the next boot
+pub fn clear(flash: &mut FlashStorage<'_>) -> Result<(), ConfigError> {
+    for offset in [CONFIG_OFFSET, WIFI_OFFSET, SCHEDULE_OFFSET, STATE_OFFSET] {
+        let address = locate(flash, offset, HEADER_SIZE)?;
+        flash.write(address, &[0u8; HEADER_SIZE]).map_err(ConfigError::Flash)?;
+    }
+    Ok(())
+}
+
+/// Store a config in flash so it survives power cycles, replacing the previous one
+pub fn save(flash: &mut FlashStorage<'_>, config: &ConfigPayload) -> Result<(), ConfigError> {
+    let mut data = [0u8; MAX_CONFIG_SIZE];
+    let len = postcard::to_slice(config, &mut data)
+        .map_err(ConfigError::Serialization)?
+        .len();
+    write_record(flash, CONFIG_OFFSET, &data[..len])
+}
+
+/// Load the WiFi credentials stored in flash
+/// Returns None if none are stored or they are corrupted
+pub fn load_wifi(flash: &mut FlashStorage<'_>) -> Option<WifiCredentialsPayload> {
+    let mut data = [0u8; MAX_CONFIG_SIZE];
+    let len = read_record(flash, WIFI_OFFSET, &mut data)?;
+    postcard::from_bytes(&data[..len]).ok()
+}
+
+/// Store WiFi credentials in flash, replacing the previous ones
+pub fn save_wifi(flash: &mut FlashStorage<'_>, credentials: &WifiCredentialsPayload) -> Result<(), ConfigError> {
+    let mut data = [0u8; MAX_CONFIG_SIZE];
+    let len = postcard::to_slice(credentials, &mut data)
+        .map_err(ConfigError::Serialization)?
+        .len();
+    write_record(flash, WIFI_OFFSET, &data[..len])
+}
+
+/// Load the schedule stored in flash
+/// Returns None if none is stored or it is corrupted
+pub fn load_schedule(flash: &mut FlashStorage<'_>) -> Option<SchedulePayload> {
+    let mut data = [0u8; MAX_CONFIG_SIZE];
+    let len = read_record(flash, SCHEDULE_OFFSET, &mut data)?;
+    postcard::from_bytes(&data[..len]).ok()
+}
+
+/// Store a schedule in flash, replacing the previous one
+pub fn save_schedule(flash: &mut FlashStorage<'_>, schedule: &SchedulePayload) -> Result<(), ConfigError> {
+    let mut data = [0u8; MAX_CONFIG_SIZE];
+    let len = postcard::to_slice(schedule, &mut data)
+        .map_err(ConfigError::Serialization)?
+        .len();
+    write_record(flash, SCHEDULE_OFFSET, &data[..len])
+}
+
+/// Load the display state stored in flash, a SetLedsRgbw frame or a StartEffect
+/// Returns None if none is stored or it is corrupted
+pub fn load_state(flash: &mut FlashStorage<'_>) -> Option<Message> {
+    let mut data = vec![0u8; MAX_STATE_SIZE];
+    let len = read_record(flash, STATE_OFFSET, &mut data)?;
+    Message::from_bytes(&data[..len]).ok()
+}
+
+/// Store the display state in flash, replacing the previous one
+pub fn save_state(flash: &mut FlashStorage<'_>, state: &Message) -> Result<(), ConfigError> {
+    let mut data = vec![0u8; MAX_STATE_SIZE];
+    let len = postcard::to_slice(state, &mut data)
+        .map_err(ConfigError::Serialization)?
+        .len();
+    write_record(flash, STATE_OFFSET, &data[..len])
+}
+
+/// Find the flash address of size bytes at offset in the NVS partition
+/// Fails rather than return an address past the partition's end, where another partition like otadata starts
+fn locate(flash: &mut FlashStorage<'_>, offset: u32, size: usize) -> Result<u32, ConfigError> {
+    let mut buffer = [0u8; PARTITION_TABLE_MAX_LEN];
+    let table = partitions::read_partition_table(flash, &mut buffer).map_err(|_| ConfigError::NoPartition)?;
+    let nvs = table
+        .find_partition(PartitionType::Data(DataPartitionSubType::Nvs))
+        .ok()
+        .flatten()
+        .ok_or(ConfigError::NoPartition)?;
+    if offset as usize + size > nvs.len() as usize {
+        return Err(ConfigError::OutOfSpace);
+    }
+    Ok(nvs.offset() + offset)
+}
+
+/// Read the record stored at offset in the NVS partition into data
+/// Returns its length, or None if no record is stored there, it is corrupted or it doesn't fit in data
+fn read_record(flash: &mut FlashStorage<'_>, offset: u32, data: &mut [u8]) -> Option<usize> {
+    let offset = locate(flash, offset, HEADER_SIZE).ok()?;
+    let mut header = [0u8; HEADER_SIZE];
+    flash.read(offset, &mut header).ok()?;
+    if header[..4] != CONFIG_MAGIC {
+        return None;
+    }
+    let len = u16::from_le_bytes([header[4], header[5]]) as usize;
+    let crc = u16::from_le_bytes([header[6], header[7]]);
+    if len > data.len() {
+        return None;
+    }
+
+    flash.read(offset + HEADER_SIZE as u32, &mut data[..len]).ok()?;
+    if crc16(&data[..len]) != crc {
+        log::warn!("Record stored at {:#x} failed its CRC check", offset);
+        return None;
+    }
+    Some(len)
+}
+
+/// Store a record at offset in the NVS partition behind a header, replacing the previous one
+fn write_record(flash: &mut FlashStorage<'_>, offset: u32, data: &[u8]) -> Result<(), ConfigError> {
+    // Flash is written in whole words
+    let size = (HEADER_SIZE + data.len()).next_multiple_of(4);
+    let offset = locate(flash, offset, size)?;
+    let mut buffer = vec![0u8; size];
+    buffer[HEADER_SIZE..HEADER_SIZE + data.len()].copy_from_slice(data);
+    buffer[..4].copy_from_slice(&CONFIG_MAGIC);
+    buffer[4..6].copy_from_slice(&(data.len() as u16).to_le_bytes());
+    buffer[6..8].copy_from_slice(&crc16(data).to_le_bytes());
+    flash.write(offset, &buffer).map_err(ConfigError::Flash)
+}
@@ -0,0 +1,74 @@
+use common::message::{ConfigPayload, Gamma, GAMMA_TABLE_SIZE};
+
+/// Exponent of the standard gamma curve
+const STANDARD_GAMMA: f32 = 2.8;
+
+/// Odd step between the dither phases of adjacent bytes, so neighbouring LEDs don't step up together
+const DITHER_SPREAD: usize = 97;
+
+/// Level sent to the strips for each channel level, in 8.8 fixed point
+pub type GammaTable = [u16; GAMMA_TABLE_SIZE];
+
+/// Build the lookup table for a gamma setting
+/// Tables of the wrong size fall back to the standard curve
+pub fn table(setting: &Gamma) -> GammaTable {
+    let mut table = [0; GAMMA_TABLE_SIZE];
+    match setting {
+        Gamma::Off => {
+            for (level, entry) in table.iter_mut().enumerate() {
+                *entry = (level as u16) << 8;
+            }
+        }
+        Gamma::Table(levels) if levels.len() == GAMMA_TABLE_SIZE => {
+            for (entry, &level) in table.iter_mut().zip(levels) {
+                *entry = (level as u16) << 8;
+            }
+        }
+        _ => {
+            for (level, entry) in table.iter_mut().enumerate() {
+                let fraction = level as f32 / (GAMMA_TABLE_SIZE - 1) as f32;
+                *entry = (libm::powf(fraction, STANDARD_GAMMA) * (255 << 8) as f32 + 0.5) as u16;
+            }
+        }
+    }
+    table
+}
+
+/// Converts channel levels to the levels sent to the strips, applying gamma correction and the brightness cap
+/// With dithering, levels between two 8-bit values alternate between them from frame to frame so they average out
+pub struct Corrector {
+    table: GammaTable,
+    dithering: bool,
+    frame: u8,
+}
+
+impl Corrector {
+    /// Create a corrector for the config's gamma and dithering settings
+    pub fn new(config: &ConfigPayload) -> Self {
+        Self {
+            table: table(&config.gamma),
+            dithering: config.dithering,
+            frame: 0,
+        }
+    }
+
+    /// Advance the dither pattern, called once per frame written
+    pub fn next_frame(&mut self) {
+        self.frame = self.frame.wrapping_add(1);
+    }
+
+    /// Level sent for the index-th byte of a strip
+    pub fn correct(&self, level: u8, cap: u8, index: usize) -> u8 {
+        let level = self.table[level as usize] as u32 * cap as u32 / 255;
+        let (whole, fraction) = ((level >> 8) as u8, level as u8);
+        let round_up = if self.dithering {
+            // Each byte steps through every threshold once every 256 frames, reversing the bits spreads
+            // the frames it's rounded up on evenly across them
+            let phase = (self.frame as usize + index * DITHER_SPREAD) as u8;
+            fraction > phase.reverse_bits()
+        } else {
+            fraction >= 128
+        };
+        whole.saturating_add(round_up as u8)
+    }
+}
@@ -0,0 +1,58 @@
+use common::message::Rgbw;
+use embassy_time::{Duration, Instant};
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
+
+/// Number of status indicators, shown on the first LEDs in this order when enabled
+pub const STATUS_INDICATORS: usize = 3;
+
+/// Time the error indicator stays lit after an error
+const ERROR_HOLD: Duration = Duration::from_secs(5);
+
+/// Frame rates at or above which the frame rate indicator shows green, or yellow below it
+const HEALTHY_FPS: u32 = 20;
+const DEGRADED_FPS: u32 = 10;
+
+/// Level the indicators are lit at, dim so they don't stand out from the rest of the tree
+const LEVEL: u8 = 48;
+
+const GREEN: Rgbw = Rgbw::new(0, LEVEL, 0, 0);
+const YELLOW: Rgbw = Rgbw::new(LEVEL, LEVEL, 0, 0);
+const RED: Rgbw = Rgbw::new(LEVEL, 0, 0, 0);
+const OFF: Rgbw = Rgbw::new(0, 0, 0, 0);
+
+/// Whether a message has been received from the host within the idle timeout
+static HOST_CONNECTED: AtomicBool = AtomicBool::new(false);
+
+/// Milliseconds since boot of the last error, 0 if there hasn't been one
+static LAST_ERROR_MS: AtomicU32 = AtomicU32::new(0);
+
+/// Record whether the host is connected
+pub fn set_host_connected(connected: bool) {
+    HOST_CONNECTED.store(connected, Ordering::Relaxed);
+}
+
+/// Light the error indicator
+pub fn record_error() {
+    LAST_ERROR_MS.store((Instant::now().as_millis() as u32).max(1), Ordering::Relaxed);
+}
+
+/// Draw the first count indicators over the start of a frame:
+/// host connection (green connected, red lost), recent errors (red for a few seconds after one)
+/// and frame rate (green, yellow or red by fps, off while nothing is rendered)
+pub fn overlay(leds: &mut [Rgbw], count: usize, fps: u32) {
+    let last_error = LAST_ERROR_MS.load(Ordering::Relaxed);
+    let since_error = Instant::now().as_millis().saturating_sub(last_error as u64);
+    let indicators = [
+        if HOST_CONNECTED.load(Ordering::Relaxed) { GREEN } else { RED },
+        if last_error != 0 && since_error < ERROR_HOLD.as_millis() { RED } else { OFF },
+        match fps {
+            0 => OFF,
+            fps if fps >= HEALTHY_FPS => GREEN,
+            fps if fps >= DEGRADED_FPS => YELLOW,
+            _ => RED,
+        },
+    ];
+    for (led, indicator) in leds.iter_mut().zip(indicators).take(count) {
+        *led = indicator;
+    }
+}
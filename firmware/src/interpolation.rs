@@ -0,0 +1,72 @@
+use alloc::vec::Vec;
+use common::message::{ConfigPayload, Rgbw};
+use embassy_time::{Duration, Instant};
+
+/// Fade from the frame on display to a newly received frame, spread over the time between received frames
+pub struct Interpolation {
+    from: Vec<Rgbw>,
+    start: Instant,
+    duration: Duration,
+}
+
+impl Interpolation {
+    /// Start fading from the frame on display
+    pub fn new(from: Vec<Rgbw>, duration: Duration) -> Self {
+        Self {
+            from,
+            start: Instant::now(),
+            duration,
+        }
+    }
+
+    /// Start crossfading from the frame on display to a new scene over the configured transition time
+    /// Returns None when transitions are off
+    pub fn transition(from: &[Rgbw], settings: &ConfigPayload) -> Option<Self> {
+        (settings.transition_ms > 0)
+            .then(|| Self::new(from.to_vec(), Duration::from_millis(settings.transition_ms as u64)))
+    }
+
+    /// Start a new fade from wherever this one has got to, reusing its frame
+    pub fn restart(&mut self, to: &[Rgbw], duration: Duration) {
+        let progress = self.progress();
+        for (from, to) in self.from.iter_mut().zip(to) {
+            *from = lerp(*from, *to, progress);
+        }
+        self.start = Instant::now();
+        self.duration = duration;
+    }
+
+    /// Write the frame between the starting frame and to at the current time into out
+    pub fn blend_into(&self, to: &[Rgbw], out: &mut [Rgbw]) {
+        let progress = self.progress();
+        for ((out, from), to) in out.iter_mut().zip(&self.from).zip(to) {
+            *out = lerp(*from, *to, progress);
+        }
+    }
+
+    /// Blend the starting frame into out, which holds the target frame
+    pub fn blend_over(&self, out: &mut [Rgbw]) {
+        let progress = self.progress();
+        for (out, from) in out.iter_mut().zip(&self.from) {
+            *out = lerp(*from, *out, progress);
+        }
+    }
+
+    /// Whether the fade has reached the target frame
+    pub fn is_finished(&self) -> bool {
+        self.start.elapsed() >= self.duration
+    }
+
+    /// Time through the fade, out of 256
+    fn progress(&self) -> i32 {
+        let elapsed = self.start.elapsed().as_micros();
+        let duration = self.duration.as_micros().max(1);
+        (elapsed * 256 / duration).min(256) as i32
+    }
+}
+
+/// Color progress / 256 of the way from a to b
+fn lerp(a: Rgbw, b: Rgbw, progress: i32) -> Rgbw {
+    let channel = |a: u8, b: u8| (a as i32 + (b as i32 - a as i32) * progress / 256) as u8;
+    Rgbw::new(channel(a.r, b.r), channel(a.g, b.g), channel(a.b, b.b), channel(a.w, b.w))
+}
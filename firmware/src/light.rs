@@ -0,0 +1,64 @@
+use common::message::ConfigPayload;
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::analog::adc::{Adc, AdcConfig, Attenuation};
+use esp_hal::peripherals::ADC1;
+use portable_atomic::{AtomicU16, Ordering};
+
+use crate::board::{LightSensorPin, MicrophonePin};
+use crate::sound::{self, Detector};
+
+/// Time between light sensor readings
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Highest reading of the 12-bit ADC, taken in full daylight
+pub const MAX_READING: u16 = 4095;
+
+/// Weight of each new reading in the smoothed level, out of 256, so a passing shadow doesn't flicker the tree
+const SMOOTHING: u32 = 32;
+
+/// Smoothed light sensor reading, from 0 in the dark to MAX_READING
+static AMBIENT_LIGHT: AtomicU16 = AtomicU16::new(MAX_READING);
+
+/// Smoothed ambient light level
+pub fn ambient_light() -> u16 {
+    AMBIENT_LIGHT.load(Ordering::Relaxed)
+}
+
+/// Brightness cap scaled by the ambient light when auto brightness is enabled,
+/// from min_brightness in the dark up to brightness_cap in daylight
+pub fn brightness_cap(settings: &ConfigPayload) -> u8 {
+    if !settings.auto_brightness {
+        return settings.brightness_cap;
+    }
+    let min = settings.min_brightness.min(settings.brightness_cap) as u32;
+    let range = settings.brightness_cap as u32 - min;
+    (min + range * ambient_light() as u32 / MAX_READING as u32) as u8
+}
+
+/// Light sensor task reading a photoresistor divider on the board's light sensor pin, brighter light reading higher
+/// The microphone shares the ADC, so it's sampled here too while sound reactivity is on
+#[embassy_executor::task]
+pub async fn light_task(adc: ADC1<'static>, pin: LightSensorPin, microphone: MicrophonePin) {
+    let mut config = AdcConfig::new();
+    let mut pin = config.enable_pin(pin, Attenuation::_11dB);
+    let mut microphone = config.enable_pin(microphone, Attenuation::_11dB);
+    let mut adc = Adc::new(adc, config).into_async();
+
+    let mut level = MAX_READING as u32 * 256;
+    let mut detector = Detector::new();
+    let mut next_reading = Instant::now();
+    loop {
+        if Instant::now() >= next_reading {
+            let reading = adc.read_oneshot(&mut pin).await.min(MAX_READING) as u32;
+            level = (level * (256 - SMOOTHING) + reading * 256 * SMOOTHING) / 256;
+            AMBIENT_LIGHT.store((level / 256) as u16, Ordering::Relaxed);
+            next_reading += SAMPLE_INTERVAL;
+        }
+        if sound::is_enabled() {
+            detector.add(adc.read_oneshot(&mut microphone).await);
+            Timer::after(sound::SAMPLE_INTERVAL).await;
+        } else {
+            Timer::at(next_reading).await;
+        }
+    }
+}
@@ -1,53 +1,212 @@
-// use common::message::{LogPayload, Message};
-// use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-// use embassy_sync::channel::Channel;
-// use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
-
-// /// Logger that sends log messages over serial UART using channels
-// pub struct SerialLogger;
-
-// impl SerialLogger {
-//     /// Create a new SerialLogger
-//     pub fn new() -> Self {
-//         Self
-//     }
-
-//     /// Initialize the logger as the global logger
-//     pub fn init(self, max_level: LevelFilter) -> Result<(), SetLoggerError> {
-//         log::set_boxed_logger(Box::new(self))?;
-//         log::set_max_level(max_level);
-//         Ok(())
-//     }
-
-//     /// Get a reference to the log channel for processing messages
-//     /// This should be called from the main async loop to process log messages
-//     pub fn channel() -> &'static Channel<CriticalSectionRawMutex, Message, 32> {
-//         &LOG_CHANNEL
-//     }
-// }
-
-// impl Log for SerialLogger {
-//     fn enabled(&self, _metadata: &Metadata) -> bool {
-//         // Let the log crate's max_level filter handle this
-//         true
-//     }
-
-//     fn log(&self, record: &Record) {
-//         if self.enabled(record.metadata()) {
-//             // Create log payload from the record
-//             let content = format!("{}", record.args());
-//             let payload = LogPayload::new(record.level(), content);
-//             let message = Message::Log(payload);
-
-//             // Try to send to channel (non-blocking, will drop if channel is full)
-//             // This prevents blocking the logger and avoids infinite loops
-//             let sender = LOG_CHANNEL.sender();
-//             let _ = sender.try_send(message);
-//         }
-//     }
-
-//     fn flush(&self) {
-//         // Channel-based logging handles this automatically
-//         // Messages are processed asynchronously in the main loop
-//     }
-// }
+use crate::messages::TX_CHANNEL;
+use alloc::format;
+use common::message::{LogPayload, Message, SourceLocation};
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, Instant};
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
+
+/// Log messages each level may send in a burst before being rate limited
+const RATE_LIMIT_BURST: u32 = 8;
+/// Log messages each level may send per second once its burst is used up
+const RATE_LIMIT_PER_SECOND: u32 = 4;
+
+/// Number of log messages dropped by rate limiting or a full TX channel
+static DROPPED_LOGS: AtomicU32 = AtomicU32::new(0);
+
+/// Whether log messages include their source location
+static VERBOSE_DIAGNOSTICS: AtomicBool = AtomicBool::new(false);
+
+/// Enable or disable source locations in forwarded log messages
+pub fn set_verbose_diagnostics(enabled: bool) {
+    VERBOSE_DIAGNOSTICS.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether forwarded log messages include their source location
+pub fn verbose_diagnostics() -> bool {
+    VERBOSE_DIAGNOSTICS.load(Ordering::Relaxed)
+}
+
+/// Get the number of log messages dropped since boot
+pub fn dropped_logs() -> u32 {
+    DROPPED_LOGS.load(Ordering::Relaxed)
+}
+
+/// Count a log message that couldn't be forwarded
+pub fn record_dropped_log() {
+    DROPPED_LOGS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Token bucket refilled at RATE_LIMIT_PER_SECOND up to RATE_LIMIT_BURST
+struct TokenBucket {
+    tokens: u32,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    const fn new() -> Self {
+        Self {
+            tokens: RATE_LIMIT_BURST,
+            last_refill: Instant::from_ticks(0),
+        }
+    }
+
+    fn try_take(&mut self, now: Instant) -> bool {
+        let refill = now.duration_since(self.last_refill).as_millis() * RATE_LIMIT_PER_SECOND as u64 / 1000;
+        if refill > 0 {
+            self.tokens = (self.tokens as u64 + refill).min(RATE_LIMIT_BURST as u64) as u32;
+            // Only advance by the time the refilled tokens account for, keeping the remainder
+            self.last_refill += Duration::from_millis(refill * 1000 / RATE_LIMIT_PER_SECOND as u64);
+        }
+
+        if self.tokens > 0 {
+            self.tokens -= 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Outcome of passing a log message through the LogLimiter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogVerdict {
+    /// Forward the message, after reporting that the previous message repeated `repeated` times if nonzero
+    Forward { repeated: u32 },
+    /// Drop the message, it repeats the previous one and is counted instead
+    Duplicate,
+    /// Drop the message, its level exceeded the rate limit
+    RateLimited,
+}
+
+/// Per-level rate limiter with duplicate message suppression
+///
+/// Keeps a fast error loop from saturating the TX channel and starving other messages.
+/// Repeats of the previous message are counted and summarized once a different message arrives.
+pub struct LogLimiter {
+    buckets: [TokenBucket; 5],
+    last: Option<(Level, u32)>,
+    repeated: u32,
+}
+
+impl LogLimiter {
+    /// Create a new LogLimiter with full buckets
+    pub const fn new() -> Self {
+        Self {
+            buckets: [const { TokenBucket::new() }; 5],
+            last: None,
+            repeated: 0,
+        }
+    }
+
+    /// Decide whether a log message should be forwarded
+    pub fn check(&mut self, level: Level, content: &str, now: Instant) -> LogVerdict {
+        let hash = content_hash(content);
+        if self.last == Some((level, hash)) {
+            self.repeated += 1;
+            return LogVerdict::Duplicate;
+        }
+
+        // Levels are numbered from 1 (Error) to 5 (Trace)
+        if !self.buckets[level as usize - 1].try_take(now) {
+            record_dropped_log();
+            return LogVerdict::RateLimited;
+        }
+
+        self.last = Some((level, hash));
+        LogVerdict::Forward {
+            repeated: core::mem::take(&mut self.repeated),
+        }
+    }
+}
+
+impl Default for LogLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// FNV-1a hash of a log message, used to detect repeats without storing the message
+fn content_hash(content: &str) -> u32 {
+    content
+        .bytes()
+        .fold(0x811c_9dc5, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193))
+}
+
+/// Rate limiter shared by every log call
+static LIMITER: Mutex<CriticalSectionRawMutex, RefCell<LogLimiter>> = Mutex::new(RefCell::new(LogLimiter::new()));
+
+/// The global logger
+static LOGGER: SerialLogger = SerialLogger;
+
+/// Logger that sends log messages to the host as Message::Log through the TX channel,
+/// so they share UART0 with the data protocol instead of colliding with its frames,
+/// and echoes them to the debug console on the USB-Serial-JTAG
+pub struct SerialLogger;
+
+impl SerialLogger {
+    /// Initialize the logger as the global logger
+    /// Must be called after the heap is initialized, since log messages are formatted into Strings,
+    /// and before any task is spawned, since the esp32c3 has no atomics for log to set the logger with safely
+    pub fn init(max_level: LevelFilter) -> Result<(), SetLoggerError> {
+        // Safety: called once at boot, before any task or interrupt handler that logs is running
+        unsafe {
+            log::set_logger_racy(&LOGGER)?;
+            log::set_max_level_racy(max_level);
+        }
+        Ok(())
+    }
+}
+
+impl Log for SerialLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        // Let the log crate's max_level filter handle this
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            // Create log payload from the record
+            let content = format!("{}", record.args());
+            let verdict = LIMITER.lock(|limiter| limiter.borrow_mut().check(record.level(), &content, Instant::now()));
+            let LogVerdict::Forward { repeated } = verdict else {
+                return;
+            };
+
+            // Try to send to channel (non-blocking, will drop if channel is full)
+            // This prevents blocking the logger and avoids infinite loops
+            // The summary is stamped like the record, so the host orders it just before it
+            let timestamp_ms = Instant::now().as_millis();
+            if repeated > 0 {
+                let summary = format!("last message repeated {} times", repeated);
+                esp_println::println!("INFO  - {}", summary);
+                let summary = LogPayload::new(Level::Info, summary)
+                    .at(timestamp_ms)
+                    .with_target(record.target().into());
+                if TX_CHANNEL.try_send(Message::Log(summary)).is_err() {
+                    record_dropped_log();
+                }
+            }
+            esp_println::println!("{:<5} - {}", record.level(), content);
+            let mut payload = LogPayload::new(record.level(), content)
+                .at(timestamp_ms)
+                .with_target(record.target().into());
+            if verbose_diagnostics() {
+                payload = payload.with_location(SourceLocation {
+                    file: record.file().unwrap_or_default().into(),
+                    line: record.line().unwrap_or_default(),
+                    module: record.module_path().unwrap_or_default().into(),
+                });
+            }
+            if TX_CHANNEL.try_send(Message::Log(payload)).is_err() {
+                record_dropped_log();
+            }
+        }
+    }
+
+    fn flush(&self) {
+        // Messages are sent asynchronously by the TX task
+    }
+}
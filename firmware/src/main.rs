@@ -7,29 +7,98 @@
 )]
 #![deny(clippy::large_stack_frames)]
 
+pub mod animation;
+#[cfg(feature = "apa102")]
+pub mod apa102;
+pub mod board;
+pub mod button;
+pub mod config;
+pub mod gamma;
+pub mod indicator;
+pub mod interpolation;
+pub mod light;
 pub mod logger;
 pub mod messages;
+#[cfg(feature = "wifi")]
+pub mod mirror;
+pub mod ota;
+pub mod panic;
+pub mod renderer;
+pub mod reset;
+#[cfg(feature = "wifi")]
+pub mod sacn;
+pub mod schedule;
+pub mod segments;
+pub mod sound;
+#[cfg(feature = "wifi")]
+pub mod sntp;
+pub mod strip;
+pub mod telemetry;
+pub mod watchdog;
+#[cfg(feature = "wifi")]
+pub mod wifi;
 
 use embassy_executor::Spawner;
+use embassy_futures::select::{Either, select};
+use embassy_time::{Duration, Instant, Timer};
 use esp_backtrace as _;
-use esp_hal::time::Rate;
 use esp_hal::uart;
-use esp_hal::rmt::Rmt;
 use esp_hal::clock::CpuClock;
+use esp_hal::rtc_cntl::Rtc;
+use esp_hal::rtc_cntl::sleep::Uart0WakeupSource;
 use esp_hal::timer::timg::TimerGroup;
-use esp_hal::uart::{AtCmdConfig, RxConfig, Uart};
-use esp_hal_smartled::{SmartLedsAdapterAsync, buffer_size_async};
-use smart_leds::{RGB8, SmartLedsWriteAsync, gamma};
-// use logger::SerialLogger;
-use common::message::Message;
+use esp_hal::uart::{AtCmdConfig, CtsConfig, HwFlowControl, RtsConfig, RxConfig, Uart};
+use esp_storage::FlashStorage;
+use logger::SerialLogger;
+use common::message::{
+    ButtonPress, DeviceInfoPayload, Fnv1a, HelloPayload, IdleBehavior,
+    LedsPayload, Message, NackReason, PalettePayload, PowerOffPayload, ScheduleAction, MAX_PALETTE_COLORS,
+    MAX_SCHEDULE_ENTRIES, MAX_SEGMENTS, PROTOCOL_VERSION, Rgb, Rgbw, SetLedsPayload, SetLedsRgbwPayload, StartEffectPayload,
+    StatusPayload, TimePayload, UpdateError, UpdateStatusPayload,
+};
 use alloc::vec::Vec;
 
-use crate::messages::{FIFO_FULL_THRESHOLD, PACKET_DELIMITER};
+use crate::animation::{Animation, next_effect};
+use crate::interpolation::Interpolation;
+use crate::sound::Reaction;
+use crate::segments::Segments;
+use crate::messages::{DEFAULT_BAUD_RATE, FIFO_FULL_THRESHOLD, MAX_FRAME_SIZE, PACKET_DELIMITER, RTS_THRESHOLD};
+use crate::renderer::SentFrame;
 
 extern crate alloc;
 
 
-const NUM_LEDS: usize = 513;
+/// Most LEDs the firmware can drive on each strip, the number in use is set by the config
+const MAX_LEDS: usize = 600;
+
+/// Number of strip outputs, one per RMT transmit channel, of which every supported chip has at least two
+/// The outputs are written concurrently, so splitting a long string between them halves the time a frame takes to latch
+const MAX_STRIPS: usize = 2;
+
+/// Index of the board's button in ButtonEvents
+const BOOT_BUTTON: u8 = 0;
+
+/// Time given to the TX task to send final messages before rebooting
+const REBOOT_DELAY: Duration = Duration::from_millis(500);
+
+/// Time given to the TX and render tasks to finish before entering light sleep
+const SLEEP_DELAY: Duration = Duration::from_millis(100);
+
+/// Rising edges on the UART RX line that wake the firmware from light sleep, the bytes carrying them are lost
+const UART_WAKEUP_EDGES: u16 = 3;
+
+/// Time between frames of firmware-resident animations
+const ANIMATION_FRAME_TIME: Duration = Duration::from_millis(33);
+
+/// Time between intermediate frames rendered while fading between received frames
+const INTERPOLATION_FRAME_TIME: Duration = Duration::from_millis(20);
+
+/// Longest gap between received frames that is faded over, later frames are displayed immediately
+const MAX_INTERPOLATION_TIME: Duration = Duration::from_millis(500);
+
+/// Time what's on display must stay unchanged before it's saved to be restored after a reboot,
+/// so a stream of frames doesn't wear out the flash
+const STATE_SAVE_DELAY: Duration = Duration::from_secs(30);
 
 // This creates a default app-descriptor required by the esp-idf bootloader.
 // For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
@@ -41,12 +110,21 @@ esp_bootloader_esp_idf::esp_app_desc!();
 )]
 #[esp_rtos::main]
 async fn main(spawner: Spawner) {
-    esp_println::logger::init_logger_from_env();
-
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
     let peripherals = esp_hal::init(config);
+    // The pins and peripherals wired differently on each board
+    let board = board::take!(peripherals);
 
     esp_alloc::heap_allocator!(#[esp_hal::ram(reclaimed)] size: 65536);
+    // The WiFi driver needs a heap of its own on top
+    #[cfg(feature = "wifi")]
+    esp_alloc::heap_allocator!(size: 72 * 1024);
+
+    // Mark the stack no task has used yet, for its high-water mark in Telemetry
+    telemetry::paint_stack();
+
+    // Log messages are queued for the host until the TX task starts
+    SerialLogger::init(log::LevelFilter::Info).unwrap();
 
     let timg0 = TimerGroup::new(peripherals.TIMG0);
     let sw_interrupt =
@@ -55,33 +133,57 @@ async fn main(spawner: Spawner) {
 
     log::info!("Embassy initialized!");
 
+    // Gather why the last reset happened for the host, then supervise the tasks from here on
+    let reset_report = reset::report();
+    spawner
+        .spawn(watchdog::watchdog_task(TimerGroup::new(peripherals.TIMG1).wdt))
+        .unwrap();
 
-    // Create RMT led driver
-    let rmt: Rmt<'_, esp_hal::Async> = Rmt::new(peripherals.RMT, Rate::from_mhz(80))
-        .expect("Failed to initialize RMT")
-        .into_async();
+    // Load the persisted settings
+    let mut flash = FlashStorage::new(peripherals.FLASH);
+    let mut settings = config::load(&mut flash).unwrap_or_else(|| {
+        log::info!("No stored config, using defaults");
+        config::default_config()
+    });
+    config::limit(&mut settings);
 
-    let rmt_channel = rmt.channel0;
-    let mut rmt_buffer = [esp_hal::rmt::PulseCode::default(); buffer_size_async(NUM_LEDS)];
+    // Reaching this point means the firmware works, so keep it if it was just updated
+    ota::confirm_image(&mut flash);
 
-    let mut led_driver = SmartLedsAdapterAsync::new(rmt_channel, peripherals.GPIO10, &mut rmt_buffer);
 
-    // Clear LEDs
-    let pixels: Vec<RGB8> = core::iter::repeat(RGB8::new(0, 0, 0)).take(NUM_LEDS).collect();
-    if let Err(e) = led_driver.write(pixels).await {
-        log::error!("Failed to write LEDs: {:?}", e);
-    }
-
-    log::info!("RMT led driver initialized");
+    // Start the render task driving the strips on the board's LED pins
+    let led_peripherals = renderer::LedPeripherals {
+        rmt: peripherals.RMT,
+        #[cfg(feature = "apa102")]
+        spi: peripherals.SPI2,
+        #[cfg(feature = "apa102")]
+        clock: board.led_clock,
+        pins: board.led_pins,
+    };
+    spawner
+        .spawn(renderer::render_task(led_peripherals, settings.clone()))
+        .unwrap();
 
     
-    // Create UART driver for UART0
-    let config = uart::Config::default()
+    // Create UART driver for the host UART, with RTS and CTS connected under hardware flow control
+    let mut config = uart::Config::default()
+        .with_baudrate(DEFAULT_BAUD_RATE)
         .with_rx(RxConfig::default().with_fifo_full_threshold(FIFO_FULL_THRESHOLD as u16));
+    if settings.uart_flow_control {
+        config = config.with_hw_flow_ctrl(HwFlowControl {
+            cts: CtsConfig::Enabled,
+            rts: RtsConfig::Enabled(RTS_THRESHOLD),
+        });
+    }
 
-    let mut uart0 = Uart::new(peripherals.UART0, config)
+    let mut uart0 = Uart::new(board.uart, config)
         .expect("Failed to initialize UART")
-        .into_async();
+        .with_rx(board.uart_rx)
+        .with_tx(board.uart_tx);
+    if settings.uart_flow_control {
+        uart0 = uart0.with_rts(board.uart_rts).with_cts(board.uart_cts);
+    }
+    let mut uart0 = uart0.into_async();
     uart0.set_at_cmd(AtCmdConfig::default().with_cmd_char(PACKET_DELIMITER));
 
     let (rx, tx) = uart0.split();
@@ -89,11 +191,34 @@ async fn main(spawner: Spawner) {
     log::info!("UART driver initialized");
 
     // Start embassy tasks to send and receive messages over UART
-    spawner.spawn(messages::tx_task(tx)).unwrap();
+    spawner.spawn(messages::tx_task(tx, config)).unwrap();
     spawner.spawn(messages::rx_task(rx)).unwrap();
-    
-    // Initialize logger
-    // SerialLogger::new().init(log::LevelFilter::Info).unwrap();
+
+    // Receive messages over WiFi as well once credentials are stored, and mirror frames between trees when enabled
+    #[cfg(feature = "wifi")]
+    {
+        let credentials = config::load_wifi(&mut flash).filter(|credentials| !credentials.ssid.is_empty());
+        if credentials.is_some() || settings.mirror != common::message::MirrorRole::Off {
+            wifi::start(&spawner, peripherals.WIFI, credentials, &settings);
+        }
+    }
+
+    // Follow the stored schedule once the time is synchronized
+    let mut current_schedule = config::load_schedule(&mut flash).unwrap_or_default();
+    spawner.spawn(schedule::schedule_task(current_schedule.clone())).unwrap();
+
+    // Measure the room's brightness for auto brightness, and the sound for sound-reactive effects
+    spawner
+        .spawn(light::light_task(peripherals.ADC1, board.light_sensor, board.microphone))
+        .unwrap();
+
+    // Report heap and stack usage to the host
+    spawner.spawn(telemetry::telemetry_task()).unwrap();
+
+    // Let the boot button change effects without the host
+    spawner
+        .spawn(button::button_task(board.button, BOOT_BUTTON))
+        .unwrap();
 
     log::info!("System initialized, entering main loop...");
     
@@ -101,34 +226,638 @@ async fn main(spawner: Spawner) {
     let message_receiver = messages::RX_CHANNEL.receiver();
     let message_sender = messages::TX_CHANNEL.sender();
 
+    // Last frame sent to the LEDs, kept for readback
+    let mut frame: Vec<Rgbw> = alloc::vec![Rgbw::new(0, 0, 0, 0); settings.num_leds()];
+    // Sequence number of the frame on display, FrameDeltas must be based on it
+    let mut frame_seq: Option<u32> = None;
+    // Colors referenced by indexed frames
+    let mut palette = PalettePayload::default();
+    // Animation started by StartEffect, runs until the next frame is received
+    let mut animation: Option<Animation> = None;
+    // Segments of the frame running their own effects or frozen, drawn over everything else
+    let mut segments = Segments::new();
+    // Fade to the last received frame when interpolation is enabled, and when that frame arrived
+    let mut interpolation: Option<Interpolation> = None;
+    let mut last_frame_at: Option<Instant> = None;
+    // Crossfade from what was on display into a new scene, drawn over the frame until it finishes
+    let mut transition: Option<Interpolation> = None;
+    // Scales animations by the microphone's level and beats while sound reactivity is on
+    let mut reaction = Reaction::new();
+    // When what's on display last changed, until it's saved to be restored after a reboot
+    let mut state_changed_at: Option<Instant> = None;
+
+    // Show what was on display before the reboot, until the host sends something else
+    if settings.restore_state {
+        match config::load_state(&mut flash) {
+            Some(Message::StartEffect(payload)) => {
+                log::info!("Restoring {:?} effect", payload.effect);
+                animation = Some(Animation::new(payload));
+            }
+            Some(Message::SetLedsRgbw(payload)) if payload.leds.len() == frame.len() => {
+                log::info!("Restoring the last frame");
+                renderer::render(payload.leds.len(), None, |leds| leds.copy_from_slice(&payload.leds));
+                frame = payload.leds;
+            }
+            _ => {}
+        }
+    }
+
+    // Firmware update in progress
+    let mut update: Option<ota::Update> = None;
+
+    // Host loss detection, the idle behavior is applied once per loss
+    let mut last_message = Instant::now();
+    let mut host_lost = false;
+    // Frames from sACN or a mirrored tree hold off the idle behavior, though the host is gone
+    let mut last_local_frame: Option<Instant> = None;
+
+    // Set by PowerOff until PowerOn, to whether to light sleep between messages
+    let mut power_off: Option<bool> = None;
+    let mut rtc = Rtc::new(peripherals.LPWR);
+    let uart_wakeup = Uart0WakeupSource::new(UART_WAKEUP_EDGES);
+
     // Main loop: continuously read messages from channel and process log messages
     loop {
-        // Try to receive a message from UART (non-blocking)
-        let message = message_receiver.receive().await;
+        // Sleep until the host sends something, once everything received has been handled
+        if power_off == Some(true) && messages::RX_CHANNEL.is_empty() && !messages::FRAME_MAILBOX.signaled() {
+            Timer::after(SLEEP_DELAY).await;
+            rtc.sleep_light(&[&uart_wakeup]);
+            // No task ran while asleep, so none could check in
+            watchdog::check_in_all();
+        }
+
+        // Save what's on display once it has settled
+        if state_changed_at.is_some_and(|changed_at| changed_at.elapsed() >= STATE_SAVE_DELAY) {
+            state_changed_at = None;
+            let state = match &animation {
+                Some(animation) => Message::StartEffect(animation.payload()),
+                None => Message::SetLedsRgbw(SetLedsRgbwPayload { seq: 0, leds: frame.clone() }),
+            };
+            if let Err(e) = config::save_state(&mut flash, &state) {
+                log::error!("Failed to store display state: {:?}", e);
+            }
+        }
+
+        let num_leds = settings.num_leds();
+        let host_timeout = Duration::from_secs(settings.idle_timeout_s as u64);
+
+        // Wait for a message from UART, for the next animation or interpolated frame while one is running,
+        // or for the host timeout until the host is lost
+        let wake = if animation.is_some() || (segments.is_animating() && power_off.is_none()) {
+            Some(Instant::now() + ANIMATION_FRAME_TIME)
+        } else if interpolation.is_some() || transition.is_some() {
+            Some(Instant::now() + INTERPOLATION_FRAME_TIME)
+        } else if !host_lost {
+            Some(last_message + host_timeout)
+        } else {
+            None
+        };
+        let wake = [wake, state_changed_at.map(|changed_at| changed_at + STATE_SAVE_DELAY)]
+            .into_iter()
+            .flatten()
+            .min();
+        let next_message = async {
+            match select(messages::FRAME_MAILBOX.wait(), message_receiver.receive()).await {
+                Either::First(message) | Either::Second(message) => message,
+            }
+        };
+        let received = match wake {
+            Some(wake) => match select(next_message, Timer::at(wake)).await {
+                Either::First(message) => Some(message),
+                Either::Second(()) => None,
+            },
+            None => Some(next_message.await),
+        };
+        let Some(message) = received else {
+            if !host_lost && last_message.elapsed() >= host_timeout {
+                host_lost = true;
+                indicator::set_host_connected(false);
+                log::warn!("No message from host for {} s", host_timeout.as_secs());
+                messages::reset_baud_rate();
+                let local_frames = last_local_frame.is_some_and(|at| at.elapsed() < host_timeout);
+                if animation.is_none() && power_off.is_none() && !local_frames {
+                    match settings.idle_behavior.clone() {
+                        IdleBehavior::Hold => {}
+                        IdleBehavior::Blackout => {
+                            interpolation = None;
+                            transition = Interpolation::transition(&frame, &settings);
+                            segments.clear();
+                            frame.fill(Rgbw::default());
+                            // A transition fades it out over the following frames instead
+                            if transition.is_none() {
+                                renderer::render(frame.len(), None, |leds| leds.copy_from_slice(&frame));
+                            }
+                            frame_seq = None;
+                        }
+                        IdleBehavior::Effect(payload) => {
+                            interpolation = None;
+                            transition = Interpolation::transition(&frame, &settings);
+                            animation = Some(Animation::new(payload));
+                        }
+                    }
+                }
+            }
+            if power_off.is_none() {
+                segments.render();
+            }
+            if let Some(current) = &interpolation {
+                renderer::render(frame.len(), None, |leds| {
+                    current.blend_into(&frame, leds);
+                    segments.apply(leds);
+                });
+                if current.is_finished() {
+                    interpolation = None;
+                }
+            } else if animation.is_some() || (segments.is_animating() && power_off.is_none()) || transition.is_some() {
+                if let Some(animation) = &mut animation {
+                    animation.render(&mut frame);
+                    // Frame deltas from the host can't be based on an animation frame
+                    frame_seq = None;
+                }
+                segments.apply(&mut frame);
+                renderer::render(frame.len(), None, |leds| {
+                    leds.copy_from_slice(&frame);
+                    if animation.is_some() && sound::is_enabled() {
+                        reaction.apply(leds);
+                    }
+                    if let Some(transition) = &transition {
+                        transition.blend_over(leds);
+                    }
+                });
+            }
+            if transition.as_ref().is_some_and(Interpolation::is_finished) {
+                transition = None;
+            }
+            continue;
+        };
+        // Button presses, schedule events and frames from local sources come from the device itself, so they don't
+        // mean the host is back
+        let local = matches!(message, Message::ButtonEvent(_) | Message::ScheduleEvent(_))
+            || message.frame_seq().is_some_and(messages::is_local_frame);
+        if message.frame_seq().is_some_and(messages::is_local_frame) {
+            last_local_frame = Some(Instant::now());
+        }
+        if !local {
+            last_message = Instant::now();
+            host_lost = false;
+            indicator::set_host_connected(true);
+        }
+        messages::update_flow_control();
+
+        // Frames are rejected while the LEDs are off
+        if power_off.is_some()
+            && let Some(seq) = message.frame_seq()
+        {
+            messages::nack(Some(seq), NackReason::PoweredOff);
+            continue;
+        }
+
+        // Frames from the host take over from any running animation, starting a new scene
+        let took_over = message.frame_seq().is_some() && animation.take().is_some();
+
+        // Timed frames are handled like the frame inside, and latched at their presentation time
+        let (message, present_at) = match message {
+            Message::TimedFrame(payload) => (*payload.frame, Some(Instant::from_micros(payload.present_at_us))),
+            message => (message, None),
+        };
+
+        let message = match message {
+            // The schedule powers the LEDs like the host would, without light sleep so the WiFi keeps running
+            Message::ScheduleEvent(action) => {
+                log::info!("Schedule turned the LEDs {:?}", action);
+                message_sender.try_send(Message::ScheduleEvent(action)).ok();
+                match action {
+                    ScheduleAction::Off => Message::PowerOff(PowerOffPayload { light_sleep: false }),
+                    ScheduleAction::On => {
+                        // With no host to send frames, the idle behavior is applied again as soon as the LEDs are on
+                        host_lost = false;
+                        Message::PowerOn
+                    }
+                }
+            }
+            // Every frame is displayed as RGBW, with the white channel off for RGB frames
+            Message::SetLeds(payload) => match payload.strip {
+                None => Message::SetLedsRgbw(payload.into()),
+                // A single strip's LEDs are patched onto the last frame
+                Some(strip) => {
+                    let mut leds = frame.clone();
+                    match settings.strip_range(strip as usize) {
+                        Some(range) if range.len() == payload.leds.len() => {
+                            for (led, &rgb) in leds[range].iter_mut().zip(&payload.leds) {
+                                *led = rgb.into();
+                            }
+                        }
+                        range => {
+                            log::warn!(
+                                "Received {} LEDs for strip {}, expected {:?}",
+                                payload.leds.len(),
+                                strip,
+                                range.map(|range| range.len())
+                            );
+                            messages::nack(Some(payload.seq), NackReason::WrongLength);
+                            continue;
+                        }
+                    }
+                    Message::SetLedsRgbw(SetLedsRgbwPayload { seq: payload.seq, leds })
+                }
+            },
+            // Run-length encoded frames are checked before expanding so a bad run can't exhaust the heap
+            Message::SetLedsRle(payload) => {
+                if payload.len() != num_leds {
+                    log::warn!("Received {} run-length encoded LEDs, expected {}", payload.len(), num_leds);
+                    messages::nack(Some(payload.seq), NackReason::WrongLength);
+                    continue;
+                }
+                Message::SetLedsRgbw(SetLedsPayload::from(payload).into())
+            }
+            Message::SetLedsIndexed(payload) => match payload.decode(&palette) {
+                Some(payload) => Message::SetLedsRgbw(payload.into()),
+                None => {
+                    log::warn!("Received indexed frame with an index outside the {} color palette", palette.colors.len());
+                    messages::nack(Some(payload.seq), NackReason::InvalidIndex);
+                    continue;
+                }
+            },
+            // Ranges, sparse updates and deltas are patched onto the last frame and displayed as a whole frame
+            Message::SetLedRange(payload) => {
+                let mut leds = frame.clone();
+                if !payload.apply(&mut leds) {
+                    log::warn!(
+                        "Received {} LEDs at {}, which doesn't fit in {} LEDs",
+                        payload.leds.len(),
+                        payload.start,
+                        num_leds
+                    );
+                    messages::nack(Some(payload.seq), NackReason::WrongLength);
+                    continue;
+                }
+                Message::SetLedsRgbw(SetLedsRgbwPayload { seq: payload.seq, leds })
+            }
+            Message::SetLedsSparse(payload) => {
+                let mut leds = frame.clone();
+                if !payload.apply(&mut leds) {
+                    log::warn!("Received sparse update with an index outside {} LEDs", num_leds);
+                    messages::nack(Some(payload.seq), NackReason::WrongLength);
+                    continue;
+                }
+                Message::SetLedsRgbw(SetLedsRgbwPayload { seq: payload.seq, leds })
+            }
+            Message::FrameDelta(payload) => {
+                if frame_seq != Some(payload.base_seq) {
+                    log::warn!(
+                        "Received delta against frame {}, displaying frame {:?}",
+                        payload.base_seq,
+                        frame_seq
+                    );
+                    messages::nack(Some(payload.seq), NackReason::BaseMismatch);
+                    continue;
+                }
+                let mut leds = frame.clone();
+                if !payload.apply(&mut leds) {
+                    log::warn!("Received delta with an index outside {} LEDs", num_leds);
+                    messages::nack(Some(payload.seq), NackReason::WrongLength);
+                    continue;
+                }
+                Message::SetLedsRgbw(SetLedsRgbwPayload { seq: payload.seq, leds })
+            }
+            message => message,
+        };
         match message {
+            Message::Hello(hello) => {
+                if hello.protocol_version != PROTOCOL_VERSION {
+                    log::warn!(
+                        "Host speaks protocol version {}, firmware speaks version {}",
+                        hello.protocol_version,
+                        PROTOCOL_VERSION
+                    );
+                }
+                let response = HelloPayload {
+                    protocol_version: PROTOCOL_VERSION,
+                    firmware_version: env!("CARGO_PKG_VERSION").into(),
+                };
+                message_sender.try_send(Message::Hello(response)).ok();
+            }
+            Message::GetDeviceInfo => {
+                let info = DeviceInfoPayload {
+                    num_leds: num_leds as u16,
+                    strips: settings.strips.iter().map(|strip| strip.num_leds).collect(),
+                    chip: board::CHIP.into(),
+                    fw_version: env!("CARGO_PKG_VERSION").into(),
+                    max_frame_size: MAX_FRAME_SIZE as u32,
+                };
+                message_sender.try_send(Message::DeviceInfo(info)).ok();
+            }
             Message::Heartbeat => {
-                // Respond with heartbeat
+                // Respond with heartbeat, followed by the firmware's health
                 message_sender.try_send(Message::Heartbeat).ok();
+                let status = StatusPayload {
+                    uptime_ms: Instant::now().as_millis(),
+                    free_heap: esp_alloc::HEAP.free() as u32,
+                    rx_queued: messages::RX_CHANNEL.len() as u8,
+                    rx_capacity: messages::RX_CHANNEL_SIZE as u8,
+                    frames_rendered: renderer::frames_rendered(),
+                    led_write_errors: renderer::led_write_errors(),
+                    dropped_logs: logger::dropped_logs(),
+                    ambient_light: settings.auto_brightness.then(light::ambient_light),
+                    effect: animation.as_ref().map(Animation::payload),
+                };
+                message_sender.try_send(Message::Status(status)).ok();
             }
-            Message::SetLeds(payload) => {
-                log::info!("Received SetLeds command with {} LEDs", payload.leds.len());
-                // Convert RGB values to RGB8 and write to LEDs
-                let pixels: Vec<RGB8> = gamma(payload.leds
-                    .iter()
-                    .map(|rgb| RGB8 {
-                        r: rgb.r,
-                        g: rgb.g,
-                        b: rgb.b,
-                    }))
-                    .collect();
-                
-                if pixels.len() == NUM_LEDS {
-                    if let Err(e) = led_driver.write(pixels).await {
-                        log::error!("Failed to write LEDs: {:?}", e);
+            Message::SetLedsRgbw(payload) => {
+                log::debug!("Received SetLeds command with {} LEDs", payload.leds.len());
+                if payload.leds.len() == num_leds {
+                    // Fade over the time since the previous frame, from whatever is on display
+                    let now = Instant::now();
+                    let interval = last_frame_at.replace(now).map(|last| now - last);
+                    // The first frame after a pause or an effect is a new scene, crossfaded into
+                    if took_over || interval.is_none_or(|interval| interval > MAX_INTERPOLATION_TIME) {
+                        transition = Interpolation::transition(&frame, &settings);
+                    }
+                    match interval {
+                        // Timed frames are already paced, and frames during a transition are crossfaded instead
+                        Some(interval)
+                            if settings.interpolation
+                                && present_at.is_none()
+                                && transition.is_none()
+                                && interval <= MAX_INTERPOLATION_TIME =>
+                        {
+                            match &mut interpolation {
+                                Some(current) => current.restart(&frame, interval),
+                                None => interpolation = Some(Interpolation::new(frame.clone(), interval)),
+                            }
+                            messages::ack(payload.seq);
+                        }
+                        _ => {
+                            interpolation = None;
+                            // Checksums cover the RGB frame, matching what the host sent for RGB frames
+                            let mut checksum = Fnv1a::new();
+                            for &rgbw in &payload.leds {
+                                let rgb = Rgb::from(rgbw);
+                                checksum.update(&[rgb.r, rgb.g, rgb.b]);
+                            }
+                            let sent = SentFrame {
+                                seq: payload.seq,
+                                checksum: checksum.finish(),
+                            };
+                            // Acknowledged and verified by the render task once written
+                            renderer::render_at(payload.leds.len(), Some(sent), present_at, |leds| {
+                                leds.copy_from_slice(&payload.leds);
+                                segments.apply(leds);
+                                if let Some(transition) = &transition {
+                                    transition.blend_over(leds);
+                                }
+                            });
+                        }
+                    }
+                    frame = payload.leds;
+                    segments.apply(&mut frame);
+                    frame_seq = Some(payload.seq);
+                    if settings.restore_state {
+                        state_changed_at = Some(Instant::now());
                     }
                 } else {
-                    log::warn!("Received {} LEDs, expected {}", pixels.len(), NUM_LEDS);
+                    log::warn!("Received {} LEDs, expected {}", payload.leds.len(), num_leds);
+                    messages::nack(Some(payload.seq), NackReason::WrongLength);
+                }
+            }
+            Message::StartEffect(_) if power_off.is_some() => {
+                log::warn!("Ignored StartEffect while the LEDs are powered off");
+            }
+            Message::StartEffect(payload) => {
+                log::info!("Starting {:?} effect at {}% speed", payload.effect, payload.speed);
+                interpolation = None;
+                transition = Interpolation::transition(&frame, &settings);
+                animation = Some(Animation::new(payload));
+                if settings.restore_state {
+                    state_changed_at = Some(Instant::now());
+                }
+            }
+            Message::ButtonEvent(event) => {
+                message_sender.try_send(Message::ButtonEvent(event)).ok();
+                // The host decides what presses do while it's connected
+                if host_lost && power_off.is_none() {
+                    interpolation = None;
+                    transition = Interpolation::transition(&frame, &settings);
+                    match event.press {
+                        ButtonPress::Short => {
+                            let effect = next_effect(animation.as_ref().map(Animation::effect));
+                            log::info!("Button switched to {:?} effect", effect);
+                            animation = Some(Animation::new(StartEffectPayload {
+                                effect,
+                                speed: 100,
+                                palette: Vec::new(),
+                            }));
+                        }
+                        ButtonPress::Long => {
+                            log::info!("Button turned the LEDs off");
+                            animation = None;
+                            segments.clear();
+                            frame.fill(Rgbw::default());
+                            // A transition fades it out over the following frames instead
+                            if transition.is_none() {
+                                renderer::render(frame.len(), None, |leds| leds.copy_from_slice(&frame));
+                            }
+                            frame_seq = None;
+                        }
+                    }
+                    if settings.restore_state {
+                        state_changed_at = Some(Instant::now());
+                    }
+                }
+            }
+            Message::GetConfig => {
+                message_sender.try_send(Message::Config(settings.clone())).ok();
+            }
+            Message::SetConfig(mut payload) => {
+                config::limit(&mut payload);
+                // The render task blanks the strips when they change
+                renderer::configure(payload.clone());
+                if payload.strips != settings.strips {
+                    frame = alloc::vec![Rgbw::default(); payload.num_leds()];
+                    frame_seq = None;
+                    interpolation = None;
+                    transition = None;
+                    // Segments may no longer fit the strips
+                    segments.clear();
+                    last_frame_at = None;
+                }
+                settings = payload;
+                if let Err(e) = config::save(&mut flash, &settings) {
+                    log::error!("Failed to store config: {:?}", e);
+                }
+                message_sender.try_send(Message::Config(settings.clone())).ok();
+            }
+            Message::BeginUpdate(payload) => {
+                log::info!("Starting firmware update of {} bytes", payload.size);
+                let error = match ota::Update::begin(&mut flash, payload) {
+                    Ok(started) => {
+                        update = Some(started);
+                        None
+                    }
+                    Err(e) => Some(e),
+                };
+                let status = UpdateStatusPayload { written: 0, error };
+                message_sender.send(Message::UpdateStatus(status)).await;
+            }
+            Message::FirmwareChunk(chunk) => {
+                let status = match &mut update {
+                    Some(current) => UpdateStatusPayload {
+                        error: current.write(&mut flash, &chunk).err(),
+                        written: current.written(),
+                    },
+                    None => UpdateStatusPayload {
+                        written: 0,
+                        error: Some(UpdateError::NotStarted),
+                    },
+                };
+                if let Some(e) = status.error {
+                    log::error!("Firmware update failed at {} bytes: {:?}", status.written, e);
+                    update = None;
                 }
+                // Sent with backpressure, the host waits for each status before sending the next chunk
+                message_sender.send(Message::UpdateStatus(status)).await;
+            }
+            Message::FinishUpdate => {
+                let (written, result) = match update.take() {
+                    Some(finished) => (finished.written(), finished.finish(&mut flash)),
+                    None => (0, Err(UpdateError::NotStarted)),
+                };
+                let status = UpdateStatusPayload {
+                    written,
+                    error: result.err(),
+                };
+                message_sender.send(Message::UpdateStatus(status)).await;
+                match result {
+                    Ok(()) => {
+                        log::info!("Firmware update verified, rebooting");
+                        Timer::after(REBOOT_DELAY).await;
+                        esp_hal::system::software_reset();
+                    }
+                    Err(e) => log::error!("Firmware update failed: {:?}", e),
+                }
+            }
+            Message::PowerOff(payload) => {
+                log::info!("Powering off");
+                interpolation = None;
+                transition = None;
+                animation = None;
+                frame.fill(Rgbw::default());
+                frame_seq = None;
+                renderer::set_power(false);
+                power_off = Some(payload.light_sleep);
+            }
+            Message::PowerOn => {
+                if power_off.take().is_some() {
+                    log::info!("Powering on");
+                    renderer::set_power(true);
+                    // Show the blank frame until the host sends one
+                    renderer::render(frame.len(), None, |leds| leds.copy_from_slice(&frame));
+                }
+            }
+            Message::SetWifiCredentials(credentials) => match config::save_wifi(&mut flash, &credentials) {
+                Ok(()) if credentials.ssid.is_empty() => log::info!("Cleared WiFi credentials"),
+                Ok(()) if cfg!(feature = "wifi") => {
+                    log::info!("Stored WiFi credentials for {}, joining it from the next boot", credentials.ssid)
+                }
+                Ok(()) => log::warn!("Stored WiFi credentials, but this firmware is built without WiFi support"),
+                Err(e) => log::error!("Failed to store WiFi credentials: {:?}", e),
+            },
+            Message::SetBaudRate(payload) => messages::request_baud_rate(payload.baud_rate),
+            Message::GetResetReport => {
+                message_sender.try_send(Message::ResetReport(reset_report.clone())).ok();
+            }
+            Message::GetSegments => {
+                message_sender.try_send(Message::Segments(segments.payload().clone())).ok();
+            }
+            Message::SetSegments(payload) => {
+                if payload.is_valid(num_leds) {
+                    log::info!("Running {} segments", payload.segments.len());
+                    segments.set(payload, &frame);
+                    segments.apply(&mut frame);
+                    if power_off.is_none() {
+                        renderer::render(frame.len(), None, |leds| leds.copy_from_slice(&frame));
+                    }
+                } else {
+                    log::warn!(
+                        "Received segments that overlap or don't fit in {} LEDs, or more than {}",
+                        num_leds,
+                        MAX_SEGMENTS
+                    );
+                }
+                message_sender.try_send(Message::Segments(segments.payload().clone())).ok();
+            }
+            Message::SetSoundReactive(payload) => {
+                log::info!(
+                    "Sound reactivity {} at sensitivity {}",
+                    if payload.enabled { "on" } else { "off" },
+                    payload.sensitivity
+                );
+                sound::configure(payload);
+                message_sender.try_send(Message::SoundReactive(sound::payload())).ok();
+            }
+            Message::GetTime => {
+                let time = TimePayload {
+                    uptime_us: Instant::now().as_micros(),
+                };
+                message_sender.try_send(Message::Time(time)).ok();
+            }
+            Message::GetSchedule => {
+                message_sender.try_send(Message::Schedule(current_schedule.clone())).ok();
+            }
+            Message::SetSchedule(payload) => {
+                if payload.entries.len() > MAX_SCHEDULE_ENTRIES {
+                    log::warn!(
+                        "Received schedule of {} entries, at most {} are allowed",
+                        payload.entries.len(),
+                        MAX_SCHEDULE_ENTRIES
+                    );
+                } else {
+                    if let Err(e) = config::save_schedule(&mut flash, &payload) {
+                        log::error!("Failed to store schedule: {:?}", e);
+                    }
+                    if schedule::now().is_none() {
+                        log::warn!("Stored schedule, but the time isn't synchronized, so it isn't followed yet");
+                    }
+                    schedule::update(payload.clone());
+                    current_schedule = payload;
+                }
+                message_sender.try_send(Message::Schedule(current_schedule.clone())).ok();
+            }
+            Message::Reboot => {
+                log::warn!("Rebooting at the host's request");
+                Timer::after(REBOOT_DELAY).await;
+                esp_hal::system::software_reset();
+            }
+            Message::FactoryReset => {
+                log::warn!("Factory reset at the host's request");
+                if let Err(e) = config::clear(&mut flash) {
+                    log::error!("Failed to clear config: {:?}", e);
+                }
+                Timer::after(REBOOT_DELAY).await;
+                esp_hal::system::software_reset();
+            }
+            Message::SetPalette(payload) => {
+                if payload.colors.len() > MAX_PALETTE_COLORS {
+                    log::warn!("Received palette of {} colors, at most {} are allowed", payload.colors.len(), MAX_PALETTE_COLORS);
+                } else {
+                    palette = payload;
+                }
+            }
+            Message::GetLeds(request) => {
+                let start = (request.offset as usize).min(frame.len());
+                let end = start.saturating_add(request.count as usize).min(frame.len());
+                let response = LedsPayload {
+                    offset: start as u16,
+                    leds: frame[start..end].iter().map(|&rgbw| Rgb::from(rgbw)).collect(),
+                };
+                message_sender.try_send(Message::Leds(response)).ok();
+            }
+            Message::SetVerboseDiagnostics(payload) => {
+                logger::set_verbose_diagnostics(payload.enabled);
+            }
+            Message::SetVerification(payload) => {
+                log::info!("Frame verification interval set to {}", payload.interval);
+                renderer::set_verification_interval(payload.interval);
             }
             msg => {
                 log::warn!("Received unexpected message: {:?}", msg);
@@ -1,34 +1,261 @@
-use common::message::Message;
+use common::chunking::{ChunkError, Reassembler};
+use common::framing::{self, FrameError};
+use common::message::{
+    AckPayload, BaudRatePayload, MAX_HOST_SEQ, Message, NackPayload, NackReason, Rgb, SetLedsPayload,
+};
+use core::cell::RefCell;
 use embassy_futures::yield_now;
+use embassy_sync::blocking_mutex::Mutex;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::Channel;
-use esp_hal::uart::{UartRx, UartTx};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, with_timeout};
+use esp_hal::uart::{self, UartRx, UartTx};
 use esp_hal::Async;
 use alloc::vec::Vec;
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::indicator;
+use crate::renderer;
+use crate::{MAX_LEDS, MAX_STRIPS};
+use crate::watchdog::{self, Task};
 
 /// Frame delimiter byte (0x00) - COBS ensures this never appears in encoded data
-pub const PACKET_DELIMITER: u8 = 0x00;
+pub const PACKET_DELIMITER: u8 = framing::FRAME_DELIMITER;
 pub const FIFO_FULL_THRESHOLD: usize = 120;
 
+/// Bytes in the 128 byte RX FIFO at which RTS tells the host to pause under hardware flow control,
+/// leaving room for the bytes the host's adapter sends before it notices
+pub const RTS_THRESHOLD: u8 = 124;
+
+/// Baud rate the UART starts at, and returns to when the host is lost
+pub const DEFAULT_BAUD_RATE: u32 = 115_200;
+
+/// Baud rates the host may switch the UART to
+pub const BAUD_RATES: core::ops::RangeInclusive<u32> = 9_600..=3_000_000;
+
+/// Time the host has to send a message at a new baud rate before the UART falls back to DEFAULT_BAUD_RATE
+const BAUD_RATE_CONFIRM_TIME: Duration = Duration::from_secs(1);
+
+/// Baud rate the UART is running at
+static BAUD_RATE: AtomicU32 = AtomicU32::new(DEFAULT_BAUD_RATE);
+
+/// Signaled by the RX task when a message arrives, confirming the baud rate works
+static MESSAGE_RECEIVED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Largest encoded frame accepted by the RX task, larger frames are rejected
+pub const MAX_FRAME_SIZE: usize = 4096;
+
+/// Largest message reassembled from chunks, an RGBW frame for every LED with room for its header
+const MAX_MESSAGE_SIZE: usize = MAX_STRIPS * MAX_LEDS * 4 + 64;
+
+/// Message being reassembled from the FrameChunks received so far
+static REASSEMBLER: Mutex<CriticalSectionRawMutex, RefCell<Reassembler>> =
+    Mutex::new(RefCell::new(Reassembler::new(MAX_MESSAGE_SIZE)));
+
 /// Channel sizes for messages
-const RX_CHANNEL_SIZE: usize = 16;
+pub const RX_CHANNEL_SIZE: usize = 16;
 const TX_CHANNEL_SIZE: usize = 16;
 
 /// Static channels for messages
 pub static RX_CHANNEL: Channel<CriticalSectionRawMutex, Message, RX_CHANNEL_SIZE> = Channel::new();
 pub static TX_CHANNEL: Channel<CriticalSectionRawMutex, Message, TX_CHANNEL_SIZE> = Channel::new();
 
+/// Queued messages at which the host is told to stop sending frames, before the channel fills and the UART FIFO overflows
+const BUSY_THRESHOLD: usize = RX_CHANNEL_SIZE * 3 / 4;
+/// Queued messages at which the host is told it may send frames again
+const READY_THRESHOLD: usize = RX_CHANNEL_SIZE / 4;
+
+/// First sequence number of frames from local sources like sACN and mirroring, above MAX_HOST_SEQ so they're never
+/// answered over the UART
+/// NEXT_LOCAL_SEQ is or'd with it rather than wrapped, so every local frame keeps the top bit set
+const LOCAL_FRAME_SEQ: u32 = MAX_HOST_SEQ + 1;
+
+/// Sequence number of the next frame from a local source
+static NEXT_LOCAL_SEQ: AtomicU32 = AtomicU32::new(LOCAL_FRAME_SEQ);
+
+/// Whether the host was last told Busy
+static BUSY: AtomicBool = AtomicBool::new(false);
+
+/// Whether a host frame arrived while the one before it was still waiting to be displayed, cleared once the host is
+/// told Busy
+static FRAME_OVERRUN: AtomicBool = AtomicBool::new(false);
+
+/// Latest frame received and not yet displayed, frames skip RX_CHANNEL so stale ones never queue up
+pub static FRAME_MAILBOX: Signal<CriticalSectionRawMutex, Message> = Signal::new();
+
+/// Whether a frame came from a local source rather than the host
+pub fn is_local_frame(seq: u32) -> bool {
+    seq & LOCAL_FRAME_SEQ != 0
+}
+
+/// Tell the host a frame was applied, unless it came from a local source
+pub fn ack(seq: u32) {
+    if !is_local_frame(seq) {
+        TX_CHANNEL.try_send(Message::Ack(AckPayload { seq })).ok();
+    }
+}
+
+/// Tell the host a frame was not applied, unless it came from a local source
+pub fn nack(seq: Option<u32>, reason: NackReason) {
+    // Superseded frames were dropped on purpose
+    if reason != NackReason::Superseded {
+        indicator::record_error();
+    }
+    if !seq.is_some_and(is_local_frame) {
+        TX_CHANNEL.try_send(Message::Nack(NackPayload { seq, reason })).ok();
+    }
+}
+
+/// Note that a frame arrived while the one before it was still waiting to be displayed, so frames are coming faster
+/// than they're written and the host should be told Busy
+/// Frames from local sources don't count, the host can't slow them down
+pub fn record_overrun(seq: u32) {
+    if !is_local_frame(seq) {
+        FRAME_OVERRUN.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Send Busy when RX_CHANNEL is nearly full or host frames overrun the display, and Ready once the channel has drained
+/// and no frame is waiting to be displayed
+/// Called whenever a message or frame is queued or taken, including by the render task
+pub fn update_flow_control() {
+    let queued = RX_CHANNEL.len();
+    let frames_pending = FRAME_MAILBOX.signaled() || renderer::frame_pending();
+    let busy = BUSY.load(Ordering::Relaxed);
+    let message = if !busy && (queued >= BUSY_THRESHOLD || FRAME_OVERRUN.load(Ordering::Relaxed)) {
+        Message::Busy
+    } else if busy && queued <= READY_THRESHOLD && !frames_pending {
+        Message::Ready
+    } else {
+        return;
+    };
+    // Retried on the next call if the TX channel is full
+    if TX_CHANNEL.try_send(message).is_ok() {
+        BUSY.store(!busy, Ordering::Relaxed);
+        FRAME_OVERRUN.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Hand a frame to the main loop, replacing a pending whole frame so only the newest is displayed
+/// Partial updates patch the frame before them, so they're rejected instead of replacing a pending frame
+fn deliver_frame(message: Message, seq: u32) {
+    if FRAME_MAILBOX.signaled() {
+        record_overrun(seq);
+    }
+    if !message.is_whole_frame() && FRAME_MAILBOX.signaled() {
+        nack(Some(seq), NackReason::ChannelFull);
+    } else {
+        if let Some(stale) = FRAME_MAILBOX.try_take()
+            && let Some(stale_seq) = stale.frame_seq()
+        {
+            nack(Some(stale_seq), NackReason::Superseded);
+        }
+        FRAME_MAILBOX.signal(message);
+    }
+    update_flow_control();
+}
+
+/// Decode a packet received from the host and hand it to the main loop, through RX_CHANNEL or FRAME_MAILBOX for frames
+/// Returns whether the packet held a valid message
+pub async fn receive_packet(packet: &mut [u8]) -> bool {
+    match framing::decode(packet) {
+        Ok(Message::FrameChunk(chunk)) => {
+            match REASSEMBLER.lock(|reassembler| reassembler.borrow_mut().push(chunk)) {
+                Ok(Some(message)) => dispatch(message).await,
+                Ok(None) => {}
+                Err(e) => {
+                    log::error!("Dropped chunked frame: {:?}", e);
+                    let reason = match e {
+                        ChunkError::Missing { .. } => NackReason::MissingChunk,
+                        ChunkError::TooLarge { .. } => NackReason::TooLarge,
+                        ChunkError::DecodeFailed { .. } => NackReason::DecodeFailed,
+                    };
+                    nack(Some(e.seq()), reason);
+                }
+            }
+            true
+        }
+        Ok(message) => {
+            dispatch(message).await;
+            true
+        }
+        Err(e @ FrameError::CrcMismatch { .. }) => {
+            log::error!("Failed to verify message: {}", e);
+            nack(None, NackReason::CrcMismatch);
+            false
+        }
+        Err(e) => {
+            log::error!("Failed to deserialize message: {}", e);
+            nack(None, NackReason::DecodeFailed);
+            false
+        }
+    }
+}
+
+/// Hand a decoded message to the main loop
+async fn dispatch(message: Message) {
+    match message.frame_seq() {
+        // It would be mistaken for a local frame and never answered, so it's refused outright
+        Some(seq) if is_local_frame(seq) => {
+            log::error!("Host frame {} is above the host sequence range", seq);
+            nack(None, NackReason::DecodeFailed);
+        }
+        Some(seq) => deliver_frame(message, seq),
+        None => {
+            RX_CHANNEL.send(message).await;
+            update_flow_control();
+        }
+    }
+}
+
+/// Baud rate the UART is running at
+pub fn baud_rate() -> u32 {
+    BAUD_RATE.load(Ordering::Relaxed)
+}
+
+/// Answer the host's request for a baud rate, switching to it once the answer is sent
+/// Rates outside BAUD_RATES are refused by answering with the current rate
+pub fn request_baud_rate(baud_rate: u32) {
+    let baud_rate = if BAUD_RATES.contains(&baud_rate) {
+        baud_rate
+    } else {
+        log::warn!("Refused baud rate {}, keeping {}", baud_rate, self::baud_rate());
+        self::baud_rate()
+    };
+    TX_CHANNEL.try_send(Message::BaudRate(BaudRatePayload { baud_rate })).ok();
+}
+
+/// Return the UART to DEFAULT_BAUD_RATE, so the next host can connect at it
+pub fn reset_baud_rate() {
+    if baud_rate() != DEFAULT_BAUD_RATE {
+        log::info!("Returning to {} baud", DEFAULT_BAUD_RATE);
+        request_baud_rate(DEFAULT_BAUD_RATE);
+    }
+}
+
+/// Hand a whole frame from a local source to the main loop, like a frame from the host
+pub fn deliver_local_frame(leds: Vec<Rgb>) {
+    let seq = NEXT_LOCAL_SEQ.fetch_add(1, Ordering::Relaxed) | LOCAL_FRAME_SEQ;
+    deliver_frame(Message::SetLeds(SetLedsPayload { seq, strip: None, leds }), seq);
+}
+
 /// UART TX task that continuously reads messages from TX_CHANNEL and sends them over UART1
+/// Sending a BaudRate switches the UART, whose config is otherwise left as given, to that rate
 #[embassy_executor::task]
-pub async fn tx_task(mut uart_tx: UartTx<'static, Async>) {
+pub async fn tx_task(mut uart_tx: UartTx<'static, Async>, config: uart::Config) {
     let receiver = TX_CHANNEL.receiver();
 
     loop {
-        // Wait for a message to send
-        let message = receiver.receive().await;
+        watchdog::check_in(Task::Tx);
+
+        // Wait for a message to send, waking to check in with the watchdog
+        let Ok(message) = with_timeout(watchdog::CHECK_IN_INTERVAL, receiver.receive()).await else {
+            continue;
+        };
 
-        // Serialize and COBS encode message (includes 0x00 delimiter at the end)
-        let encoded = match postcard::to_allocvec_cobs(&message) {
+        // Serialize, append CRC16 and COBS encode message (includes 0x00 delimiter at the end)
+        let encoded = match framing::encode(&message) {
             Ok(data) => data,
             Err(e) => {
                 log::error!("Failed to serialize message: {:?}", e);
@@ -55,39 +282,82 @@ pub async fn tx_task(mut uart_tx: UartTx<'static, Async>) {
 
         // Flush to ensure data is sent
         uart_tx.flush_async().await.ok();
+
+        if let Message::BaudRate(payload) = message
+            && payload.baud_rate != baud_rate()
+        {
+            switch_baud_rate(&mut uart_tx, config, payload.baud_rate).await;
+        }
+    }
+}
+
+/// Switch the UART to a baud rate, falling back to DEFAULT_BAUD_RATE unless a message arrives at it in time
+async fn switch_baud_rate(uart_tx: &mut UartTx<'static, Async>, config: uart::Config, baud_rate: u32) {
+    // The baud rate is shared by both directions, so configuring the TX half switches the RX half too
+    if let Err(e) = uart_tx.apply_config(&config.with_baudrate(baud_rate)) {
+        log::error!("Failed to switch to {} baud: {:?}", baud_rate, e);
+        return;
+    }
+    BAUD_RATE.store(baud_rate, Ordering::Relaxed);
+    if baud_rate == DEFAULT_BAUD_RATE {
+        return;
+    }
+
+    MESSAGE_RECEIVED.reset();
+    let deadline = Instant::now() + BAUD_RATE_CONFIRM_TIME;
+    while Instant::now() < deadline {
+        watchdog::check_in(Task::Tx);
+        if with_timeout(watchdog::CHECK_IN_INTERVAL, MESSAGE_RECEIVED.wait()).await.is_ok() {
+            log::info!("Switched to {} baud", baud_rate);
+            return;
+        }
     }
+    log::warn!("No message from the host at {} baud, falling back to {}", baud_rate, DEFAULT_BAUD_RATE);
+    uart_tx.apply_config(&config.with_baudrate(DEFAULT_BAUD_RATE)).ok();
+    BAUD_RATE.store(DEFAULT_BAUD_RATE, Ordering::Relaxed);
 }
 
-/// UART RX task that continuously reads from UART and pushes complete messages to RX_CHANNEL
+/// UART RX task that continuously reads from UART and pushes complete messages to RX_CHANNEL,
+/// or FRAME_MAILBOX for frames
 #[embassy_executor::task]
 pub async fn rx_task(mut uart_rx: UartRx<'static, Async>) {
     const MAX_BUFFER_SIZE: usize = 10 * FIFO_FULL_THRESHOLD + 16;
 
-    let sender = RX_CHANNEL.sender();
-
     let mut receive_buffer = Vec::with_capacity(MAX_BUFFER_SIZE);
     let mut read_buffer = [0u8; MAX_BUFFER_SIZE];
+    // Set when the current frame exceeded MAX_FRAME_SIZE, its remaining bytes are discarded
+    let mut oversized = false;
 
     // Continuously read from UART until a packet delimiter is found
     loop {
-        match uart_rx.read_async(&mut read_buffer).await {
+        watchdog::check_in(Task::Rx);
+
+        // Wake to check in with the watchdog while the host is quiet
+        let Ok(read) = with_timeout(watchdog::CHECK_IN_INTERVAL, uart_rx.read_async(&mut read_buffer)).await else {
+            continue;
+        };
+        match read {
             Ok(n) if n > 0 => {
                 // Append new data to receive buffer
                 receive_buffer.reserve(n);
                 for byte in &read_buffer[..n] {
-                    if *byte == PACKET_DELIMITER {
+                    if *byte == PACKET_DELIMITER && oversized {
+                        log::error!("Discarded frame larger than {} bytes", MAX_FRAME_SIZE);
+                        nack(None, NackReason::TooLarge);
+                        oversized = false;
+                    } else if *byte == PACKET_DELIMITER && receive_buffer.is_empty() {
+                        // Delimiters with nothing between them carry no message, the host sends them to wake the firmware
+                    } else if *byte == PACKET_DELIMITER {
                         // Then we've read a complete message (in receive_buffer), so decode and push to RX_CHANNEL
-                        match postcard::from_bytes_cobs::<Message>(&mut receive_buffer) {
-                            Ok(message) => {
-                                sender.send(message).await;
-                            }
-                            Err(e) => {
-                                log::error!("Failed to deserialize message: {:?}", e);
-                            }
+                        if receive_packet(&mut receive_buffer).await {
+                            MESSAGE_RECEIVED.signal(());
                         }
                         // Clear receive buffer and start reading again
                         receive_buffer.clear();
-                    } else {
+                    } else if receive_buffer.len() >= MAX_FRAME_SIZE {
+                        oversized = true;
+                        receive_buffer.clear();
+                    } else if !oversized {
                         // Otherwise, byte is part of the message, so add to receive buffer
                         receive_buffer.push(*byte);
                     }
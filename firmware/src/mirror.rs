@@ -0,0 +1,71 @@
+use alloc::vec::Vec;
+use common::message::{MirrorRole, Rgb, Rgbw};
+use common::mirror;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use esp_radio::esp_now::{BROADCAST_ADDRESS, EspNow};
+use portable_atomic::{AtomicBool, Ordering};
+
+use crate::messages;
+
+/// Whether this tree is the primary, broadcasting the frames it displays
+static PRIMARY: AtomicBool = AtomicBool::new(false);
+
+/// Latest frame displayed and not yet broadcast, only the newest is sent if the radio falls behind
+static OUTGOING: Signal<CriticalSectionRawMutex, Vec<Rgb>> = Signal::new();
+
+/// Queue a frame being displayed for broadcast when this tree is the primary
+pub fn publish(leds: &[Rgbw]) {
+    if PRIMARY.load(Ordering::Relaxed) {
+        OUTGOING.signal(leds.iter().map(|&led| Rgb::from(led)).collect());
+    }
+}
+
+/// Mirror task broadcasting the frames displayed by the primary, or displaying them on a secondary
+/// Secondaries pad or cut frames to their own num_leds
+#[embassy_executor::task]
+pub async fn mirror_task(mut esp_now: EspNow<'static>, role: MirrorRole, num_leds: usize) {
+    match role {
+        MirrorRole::Off => {}
+        MirrorRole::Primary => {
+            log::info!("Mirroring frames to secondary trees");
+            PRIMARY.store(true, Ordering::Relaxed);
+            let mut frame: u8 = 0;
+            loop {
+                let leds = OUTGOING.wait().await;
+                for packet in mirror::packets(frame, &leds) {
+                    if let Err(e) = esp_now.send_async(&BROADCAST_ADDRESS, &packet).await {
+                        log::warn!("Failed to broadcast mirrored frame: {:?}", e);
+                        break;
+                    }
+                }
+                frame = frame.wrapping_add(1);
+            }
+        }
+        MirrorRole::Secondary => {
+            log::info!("Displaying frames mirrored from the primary tree");
+            let mut leds = alloc::vec![Rgb::default(); num_leds];
+            // Frame being assembled and the packets of it received so far, one bit each
+            let mut assembling: Option<u8> = None;
+            let mut received = [0u64; 4];
+            loop {
+                let data = esp_now.receive_async().await;
+                let Some(packet) = mirror::parse(data.data()) else {
+                    continue;
+                };
+                // A packet of a newer frame means the rest of the previous one was lost
+                if assembling != Some(packet.frame) {
+                    assembling = Some(packet.frame);
+                    received = [0; 4];
+                }
+                packet.apply(&mut leds);
+                received[packet.index as usize / 64] |= 1 << (packet.index % 64);
+                let complete = (0..packet.count as usize).all(|index| received[index / 64] & (1 << (index % 64)) != 0);
+                if complete {
+                    assembling = None;
+                    messages::deliver_local_frame(leds.clone());
+                }
+            }
+        }
+    }
+}
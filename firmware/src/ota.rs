@@ -0,0 +1,102 @@
+use common::framing::Crc32;
+use common::message::{BeginUpdatePayload, FirmwareChunkPayload, UpdateError};
+use embedded_storage::{ReadStorage, Storage};
+use esp_bootloader_esp_idf::ota::OtaImageState;
+use esp_bootloader_esp_idf::ota_updater::OtaUpdater;
+use esp_bootloader_esp_idf::partitions::PARTITION_TABLE_MAX_LEN;
+use esp_storage::FlashStorage;
+
+/// Size of the blocks the written image is read back in for verification
+const VERIFY_BLOCK_SIZE: usize = 256;
+
+/// Firmware update in progress, written to the inactive OTA partition
+pub struct Update {
+    size: u32,
+    crc: u32,
+    written: u32,
+}
+
+impl Update {
+    /// Start an update, checking the image fits in the inactive OTA partition
+    /// Fails with UpdateError::Flash if the partition table has no OTA partitions, like espflash's default table
+    pub fn begin(flash: &mut FlashStorage<'_>, payload: BeginUpdatePayload) -> Result<Self, UpdateError> {
+        let mut buffer = [0u8; PARTITION_TABLE_MAX_LEN];
+        let mut ota = OtaUpdater::new(flash, &mut buffer).map_err(|_| UpdateError::Flash)?;
+        let (partition, _) = ota.next_partition().map_err(|_| UpdateError::Flash)?;
+        if payload.size as usize > partition.capacity() {
+            return Err(UpdateError::TooLarge);
+        }
+        Ok(Self {
+            size: payload.size,
+            crc: payload.crc,
+            written: 0,
+        })
+    }
+
+    /// Number of bytes of the image written so far
+    pub fn written(&self) -> u32 {
+        self.written
+    }
+
+    /// Write the next chunk of the image
+    pub fn write(&mut self, flash: &mut FlashStorage<'_>, chunk: &FirmwareChunkPayload) -> Result<(), UpdateError> {
+        if chunk.offset != self.written {
+            return Err(UpdateError::OutOfOrder);
+        }
+        if self.written as usize + chunk.data.len() > self.size as usize {
+            return Err(UpdateError::TooLarge);
+        }
+
+        let mut buffer = [0u8; PARTITION_TABLE_MAX_LEN];
+        let mut ota = OtaUpdater::new(flash, &mut buffer).map_err(|_| UpdateError::Flash)?;
+        let (mut partition, _) = ota.next_partition().map_err(|_| UpdateError::Flash)?;
+        partition
+            .write(chunk.offset, &chunk.data)
+            .map_err(|_| UpdateError::Flash)?;
+        self.written += chunk.data.len() as u32;
+        Ok(())
+    }
+
+    /// Verify the image read back from flash and make it boot on the next reset
+    pub fn finish(&self, flash: &mut FlashStorage<'_>) -> Result<(), UpdateError> {
+        if self.written != self.size {
+            return Err(UpdateError::Incomplete);
+        }
+
+        let mut buffer = [0u8; PARTITION_TABLE_MAX_LEN];
+        let mut ota = OtaUpdater::new(flash, &mut buffer).map_err(|_| UpdateError::Flash)?;
+        let (mut partition, _) = ota.next_partition().map_err(|_| UpdateError::Flash)?;
+        let mut crc = Crc32::new();
+        let mut block = [0u8; VERIFY_BLOCK_SIZE];
+        let mut offset = 0;
+        while offset < self.size {
+            let len = (self.size - offset).min(VERIFY_BLOCK_SIZE as u32) as usize;
+            partition
+                .read(offset, &mut block[..len])
+                .map_err(|_| UpdateError::Flash)?;
+            crc.update(&block[..len]);
+            offset += len as u32;
+        }
+        if crc.finish() != self.crc {
+            return Err(UpdateError::CrcMismatch);
+        }
+
+        ota.activate_next_partition().map_err(|_| UpdateError::Flash)?;
+        ota.set_current_ota_state(OtaImageState::New)
+            .map_err(|_| UpdateError::Flash)
+    }
+}
+
+/// Mark the running image as valid, so the bootloader doesn't roll back a freshly updated firmware
+pub fn confirm_image(flash: &mut FlashStorage<'_>) {
+    let mut buffer = [0u8; PARTITION_TABLE_MAX_LEN];
+    let Ok(mut ota) = OtaUpdater::new(flash, &mut buffer) else {
+        return;
+    };
+    if let Ok(OtaImageState::New | OtaImageState::PendingVerify) = ota.current_ota_state() {
+        log::info!("Booted updated firmware, marking it valid");
+        if let Err(e) = ota.set_current_ota_state(OtaImageState::Valid) {
+            log::error!("Failed to mark firmware valid: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use alloc::format;
+use alloc::string::String;
+use common::framing::{self, crc16};
+use common::message::{Message, PanicPayload, SourceLocation};
+use core::panic::PanicInfo;
+use esp_backtrace::Backtrace;
+use esp_hal::uart::{self, Uart};
+use portable_atomic::{AtomicBool, Ordering};
+
+use crate::board::HostUart;
+
+/// Set once a panic starts, so a panic while reporting one resets straight away
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Marks PANIC_RECORD as written by the panic handler, rather than left over from power on
+const PANIC_MAGIC: [u8; 4] = *b"PANC";
+
+/// Size of the header before the serialized panic: magic, length and CRC16
+const HEADER_SIZE: usize = 8;
+
+/// Size of the panic record, long messages and backtraces are cut short to fit
+const PANIC_RECORD_SIZE: usize = 512;
+
+/// Longest panic message kept when the whole panic doesn't fit in the record
+const SHORT_MESSAGE_LEN: usize = 128;
+
+/// Last panic, kept in RTC memory across the reset so it can be reported after the next boot
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut PANIC_RECORD: [u8; PANIC_RECORD_SIZE] = [0; PANIC_RECORD_SIZE];
+
+/// Take the panic that caused the last reset, called once at boot
+pub fn take_last_panic() -> Option<PanicPayload> {
+    // Safety: read before any task is spawned, the panic handler is the only other user
+    let record = unsafe { &mut *(&raw mut PANIC_RECORD) };
+    if record[..4] != PANIC_MAGIC {
+        return None;
+    }
+    record[..4].fill(0);
+    let len = u16::from_le_bytes([record[4], record[5]]) as usize;
+    let crc = u16::from_le_bytes([record[6], record[7]]);
+    let data = record.get(HEADER_SIZE..HEADER_SIZE + len)?;
+    if crc16(data) != crc {
+        return None;
+    }
+    postcard::from_bytes(data).ok()
+}
+
+/// Keep a panic in RTC memory, shortening it until it fits
+fn record_panic(mut payload: PanicPayload) {
+    // Safety: only the first panic gets here, and no task runs anymore
+    let record = unsafe { &mut *(&raw mut PANIC_RECORD) };
+    let len = loop {
+        match postcard::to_slice(&payload, &mut record[HEADER_SIZE..]) {
+            Ok(data) => break data.len(),
+            Err(_) if !payload.backtrace.is_empty() => {
+                payload.backtrace.pop();
+            }
+            Err(_) if payload.message.len() > SHORT_MESSAGE_LEN => {
+                let mut end = SHORT_MESSAGE_LEN;
+                while !payload.message.is_char_boundary(end) {
+                    end -= 1;
+                }
+                payload.message.truncate(end);
+            }
+            Err(_) => return,
+        }
+    };
+    let crc = crc16(&record[HEADER_SIZE..HEADER_SIZE + len]);
+    record[4..6].copy_from_slice(&(len as u16).to_le_bytes());
+    record[6..8].copy_from_slice(&crc.to_le_bytes());
+    record[..4].copy_from_slice(&PANIC_MAGIC);
+}
+
+/// Report the panic to the host as Message::Panic, keep it for the report after the reset, then reset
+///
+/// The async TX task can't run anymore, so UART0 is taken over and the frame is written blocking.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if !PANICKING.swap(true, Ordering::Relaxed) {
+        let payload = PanicPayload {
+            message: format!("{}", info.message()),
+            location: info.location().map(|location| SourceLocation {
+                file: location.file().into(),
+                line: location.line(),
+                module: String::new(),
+            }),
+            backtrace: Backtrace::capture()
+                .frames()
+                .iter()
+                .map(|frame| frame.program_counter() as u32)
+                .collect(),
+        };
+
+        record_panic(payload.clone());
+        if let Ok(encoded) = framing::encode(&Message::Panic(payload)) {
+            // Safety: the UART driver owned by the TX and RX tasks is never used again
+            let uart0 = unsafe { HostUart::steal() };
+            if let Ok(mut uart) = Uart::new(uart0, uart::Config::default()) {
+                // Terminate whatever frame the TX task was in the middle of, so the host resyncs
+                uart.write(&[framing::FRAME_DELIMITER]).ok();
+                let mut remaining = &encoded[..];
+                while let Ok(n @ 1..) = uart.write(remaining) {
+                    remaining = &remaining[n..];
+                }
+                uart.flush().ok();
+            }
+        }
+    }
+    esp_hal::system::software_reset()
+}
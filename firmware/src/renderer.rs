@@ -0,0 +1,498 @@
+use common::message::{
+    ColorOrder, ConfigPayload, Fnv1a, FrameChecksumPayload, Message, NackReason, Rgb, Rgbw, StripConfig,
+};
+use embassy_futures::join::join;
+use embassy_futures::select::{Either3, select3};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use esp_hal::gpio::AnyPin;
+use esp_hal::peripherals::RMT;
+use portable_atomic::{AtomicU32, Ordering};
+#[cfg(feature = "apa102")]
+use esp_hal::peripherals::SPI2;
+use esp_hal::rmt::{PulseCode, Rmt};
+#[cfg(feature = "apa102")]
+use esp_hal::spi::{self, master::Spi};
+use esp_hal::time::Rate;
+use static_cell::ConstStaticCell;
+
+use crate::gamma::Corrector;
+use crate::indicator;
+use crate::light;
+use crate::messages;
+#[cfg(feature = "wifi")]
+use crate::mirror;
+#[cfg(feature = "apa102")]
+use crate::apa102::{self, Apa102Driver};
+use crate::strip::{self, LedDriver, StripDriver};
+use crate::watchdog::{self, Task};
+use crate::{MAX_LEDS, MAX_STRIPS, config};
+
+/// Whether the strips are SK6812-RGBW, taking a white byte after the color bytes of each LED
+const RGBW_STRIP: bool = false;
+
+/// Number of bytes each LED takes on the wire
+const BYTES_PER_LED: usize = if RGBW_STRIP { 4 } else { 3 };
+
+#[cfg(feature = "apa102")]
+const _: () = assert!(!RGBW_STRIP, "APA102 strips have no white channel");
+
+/// Number of RMT pulse codes buffered for each strip, enough for its longest frame
+const RMT_BUFFER_SIZE: usize = strip::buffer_size(MAX_LEDS * BYTES_PER_LED);
+
+/// Longest an LED write may take before the RMT transfer is considered stalled
+/// A full strip normally latches in about 16 ms (24 bits * 1.25 us per LED)
+const LED_WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Times a frame is written before it's dropped, the drivers are recreated after each failed attempt
+const LED_WRITE_ATTEMPTS: u32 = 2;
+
+/// Time each color of the boot self-test takes to wipe along the strips
+const SELF_TEST_WIPE_TIME: Duration = Duration::from_millis(500);
+
+/// Frames each color of the boot self-test wipe is drawn in
+const SELF_TEST_STEPS: u32 = 25;
+
+/// Longest a timed frame is held for its presentation time, frames timed later are written early
+/// Kept within the watchdog's check-in interval
+const MAX_PRESENTATION_WAIT: Duration = watchdog::CHECK_IN_INTERVAL;
+
+/// Time over which the frame rate shown on the status LEDs is measured
+const FPS_WINDOW: Duration = Duration::from_secs(1);
+
+/// Most LEDs in a frame across all strips
+const MAX_FRAME_LEDS: usize = MAX_LEDS * MAX_STRIPS;
+
+/// Storage for a frame, allocated once so frames don't churn the heap
+type FrameBuffer = [Rgbw; MAX_FRAME_LEDS];
+
+/// The two frame buffers, one written to the LEDs while the next frame is filled into the other
+static BUFFERS: ConstStaticCell<[FrameBuffer; 2]> = ConstStaticCell::new([[Rgbw::new(0, 0, 0, 0); MAX_FRAME_LEDS]; 2]);
+
+/// Frame buffers not holding a frame
+static FREE_BUFFERS: Channel<CriticalSectionRawMutex, &'static mut FrameBuffer, 2> = Channel::new();
+
+/// Frame waiting to be written, replaced when a newer one arrives first
+static FRAME: Signal<CriticalSectionRawMutex, RenderJob> = Signal::new();
+
+/// Whether the LEDs should be driven, set by PowerOff and PowerOn
+static POWER: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+/// Settings to switch to before the next frame
+static SETTINGS: Signal<CriticalSectionRawMutex, ConfigPayload> = Signal::new();
+
+/// Frames written to the LEDs, reported in Status
+static FRAMES_RENDERED: AtomicU32 = AtomicU32::new(0);
+
+/// Failed or stalled LED writes, reported in Status
+static LED_WRITE_ERRORS: AtomicU32 = AtomicU32::new(0);
+
+/// Report the checksums of every Nth sent frame written to the LEDs, 0 when verification is off
+static VERIFICATION_INTERVAL: AtomicU32 = AtomicU32::new(0);
+
+/// Frame sent with a sequence number, by the host or a local source
+#[derive(Clone, Copy)]
+pub struct SentFrame {
+    pub seq: u32,
+    /// Checksum of the frame as decoded, before anything the firmware does to it
+    pub checksum: u32,
+}
+
+/// Frame for the render task to write
+struct RenderJob {
+    buffer: &'static mut FrameBuffer,
+    /// Number of LEDs in the frame
+    len: usize,
+    /// Frame the job displays, acknowledged and verified once it's written
+    sent: Option<SentFrame>,
+    /// Time to latch the frame at, None to write it right away
+    present_at: Option<Instant>,
+}
+
+/// Peripherals driving the strips, kept so the drivers can be recreated
+pub struct LedPeripherals {
+    pub rmt: RMT<'static>,
+    /// SPI bus clocking the first strip
+    #[cfg(feature = "apa102")]
+    pub spi: SPI2<'static>,
+    /// Clock pin of the first strip
+    #[cfg(feature = "apa102")]
+    pub clock: AnyPin<'static>,
+    /// Data pin of each strip output, in order
+    pub pins: [AnyPin<'static>; MAX_STRIPS],
+}
+
+/// Buffers each strip's frame is encoded into for its driver
+struct LedBuffers {
+    #[cfg(not(feature = "apa102"))]
+    first: [PulseCode; RMT_BUFFER_SIZE],
+    #[cfg(feature = "apa102")]
+    first: [u8; apa102::buffer_size(MAX_LEDS)],
+    second: [PulseCode; RMT_BUFFER_SIZE],
+}
+
+/// Create an LED driver for each strip output from reborrowed peripherals,
+/// an RMT channel for each, or an SPI bus for the first with the apa102 feature
+macro_rules! init_led_drivers {
+    ($peripherals:expr, $buffers:expr) => {{
+        let rmt: Rmt<'_, esp_hal::Async> =
+            Rmt::new($peripherals.rmt.reborrow(), Rate::from_mhz(strip::RMT_FREQUENCY_MHZ))
+                .expect("Failed to initialize RMT")
+                .into_async();
+        let [pin0, pin1] = &mut $peripherals.pins;
+        #[cfg(not(feature = "apa102"))]
+        let first = StripDriver::new(rmt.channel0, pin0.reborrow(), &mut $buffers.first)
+            .expect("Failed to configure RMT channel 0");
+        #[cfg(feature = "apa102")]
+        let first = {
+            let config = spi::master::Config::default()
+                .with_frequency(Rate::from_mhz(apa102::SPI_FREQUENCY_MHZ))
+                .with_mode(spi::Mode::_0);
+            let spi = Spi::new($peripherals.spi.reborrow(), config)
+                .expect("Failed to configure SPI")
+                .with_sck($peripherals.clock.reborrow())
+                .with_mosi(pin0.reborrow())
+                .into_async();
+            Apa102Driver::new(spi, &mut $buffers.first)
+        };
+        (
+            first,
+            StripDriver::new(rmt.channel1, pin1.reborrow(), &mut $buffers.second)
+                .expect("Failed to configure RMT channel 1"),
+        )
+    }};
+}
+
+/// Write a frame of Rgbw values to the LEDs with each strip's color order and chip timing,
+/// gamma corrected, dithered and limited to the brightness cap,
+/// each strip's part of the frame to its own output, all outputs at once,
+/// recreating the drivers and writing the frame again if a write fails or stalls
+/// Evaluates to the checksum of the bytes written to each strip output, or None if the frame wasn't written
+macro_rules! write_leds {
+    ($drivers:ident, $peripherals:expr, $buffers:expr, $leds:expr, $settings:expr, $corrector:expr) => {{
+        $corrector.next_frame();
+        let mut written = None;
+        for attempt in 1..=LED_WRITE_ATTEMPTS {
+            let (driver0, driver1) = &mut $drivers;
+            let (first, second) = join(
+                write_strip(driver0, 0, &$leds, &$settings, &$corrector),
+                write_strip(driver1, 1, &$leds, &$settings, &$corrector),
+            )
+            .await;
+            written = first.zip(second).map(|(first, second)| [first, second]);
+            if written.is_some() {
+                break;
+            }
+            // Tear down the wedged channels and start over with fresh drivers
+            LED_WRITE_ERRORS.fetch_add(1, Ordering::Relaxed);
+            log::warn!("Reinitializing RMT LED drivers after write attempt {}", attempt);
+            drop($drivers);
+            $drivers = init_led_drivers!($peripherals, $buffers);
+        }
+        written
+    }};
+}
+
+/// Queue a frame of len LEDs for the render task, filled in place by fill,
+/// replacing any frame it hasn't started writing
+/// Sent frames are acknowledged once written
+pub fn render(len: usize, sent: Option<SentFrame>, fill: impl FnOnce(&mut [Rgbw])) {
+    render_at(len, sent, None, fill);
+}
+
+/// Queue a frame like render, to be written at present_at
+pub fn render_at(len: usize, sent: Option<SentFrame>, present_at: Option<Instant>, fill: impl FnOnce(&mut [Rgbw])) {
+    if let Some(sent) = sent
+        && FRAME.signaled()
+    {
+        messages::record_overrun(sent.seq);
+    }
+    let buffer = match FREE_BUFFERS.try_receive() {
+        Ok(buffer) => buffer,
+        // The render task holds one buffer, so the other holds the frame it hasn't started
+        Err(_) => match take_pending() {
+            Some(buffer) => buffer,
+            None => {
+                log::warn!("No frame buffer free, dropping frame");
+                if let Some(sent) = sent {
+                    messages::nack(Some(sent.seq), NackReason::ChannelFull);
+                }
+                return;
+            }
+        },
+    };
+    let len = len.min(MAX_FRAME_LEDS);
+    fill(&mut buffer[..len]);
+    #[cfg(feature = "wifi")]
+    mirror::publish(&buffer[..len]);
+    if let Some(stale) = take_pending() {
+        FREE_BUFFERS.try_send(stale).ok();
+    }
+    FRAME.signal(RenderJob {
+        buffer,
+        len,
+        sent,
+        present_at,
+    });
+}
+
+/// Take back the buffer of the frame the render task hasn't started, which a newer frame replaces
+fn take_pending() -> Option<&'static mut FrameBuffer> {
+    let stale = FRAME.try_take()?;
+    if let Some(sent) = stale.sent {
+        messages::nack(Some(sent.seq), NackReason::Superseded);
+    }
+    Some(stale.buffer)
+}
+
+/// Whether a frame is waiting for the render task to start writing it
+pub fn frame_pending() -> bool {
+    FRAME.signaled()
+}
+
+/// Switch the render task to new settings before its next frame
+pub fn configure(settings: ConfigPayload) {
+    SETTINGS.signal(settings);
+}
+
+/// Blank the LEDs and release the RMT peripheral, or start driving them again
+pub fn set_power(on: bool) {
+    POWER.signal(on);
+}
+
+/// Report the checksums of every Nth sent frame once it's written to the LEDs, or stop with 0
+pub fn set_verification_interval(interval: u16) {
+    VERIFICATION_INTERVAL.store(interval as u32, Ordering::Relaxed);
+}
+
+/// Number of frames written to the LEDs since boot
+pub fn frames_rendered() -> u32 {
+    FRAMES_RENDERED.load(Ordering::Relaxed)
+}
+
+/// Number of LED writes that failed or stalled since boot, each followed by recreating the drivers
+pub fn led_write_errors() -> u32 {
+    LED_WRITE_ERRORS.load(Ordering::Relaxed)
+}
+
+/// Render task writing frames to the LEDs, so receiving and handling messages never waits on an RMT transfer
+/// Strips are wired to the pins in order
+#[embassy_executor::task]
+pub async fn render_task(mut peripherals: LedPeripherals, mut settings: ConfigPayload) {
+    // The peripherals are kept and reborrowed so the drivers can be recreated after a stall
+    let mut buffers = LedBuffers {
+        #[cfg(not(feature = "apa102"))]
+        first: [PulseCode::default(); RMT_BUFFER_SIZE],
+        #[cfg(feature = "apa102")]
+        first: [0; apa102::buffer_size(MAX_LEDS)],
+        second: [PulseCode::default(); RMT_BUFFER_SIZE],
+    };
+    let mut drivers = init_led_drivers!(peripherals, buffers);
+    let mut corrector = Corrector::new(&settings);
+
+    let [first, second] = BUFFERS.take();
+    FREE_BUFFERS.try_send(first).ok();
+    FREE_BUFFERS.try_send(second).ok();
+
+    // Wipe each color along the strips, so dead segments and swapped data lines or color orders stand out
+    if settings.self_test {
+        log::info!("Running LED self-test");
+        let mut leds = alloc::vec![Rgbw::default(); settings.num_leds()];
+        for color in [Rgbw::new(255, 0, 0, 0), Rgbw::new(0, 255, 0, 0), Rgbw::new(0, 0, 255, 0)] {
+            for step in 1..=SELF_TEST_STEPS {
+                watchdog::check_in(Task::Renderer);
+                let lit = leds.len() * step as usize / SELF_TEST_STEPS as usize;
+                leds[..lit].fill(color);
+                write_leds!(drivers, peripherals, buffers, leds, settings, corrector);
+                Timer::after(SELF_TEST_WIPE_TIME / SELF_TEST_STEPS).await;
+            }
+        }
+    }
+
+    // Clear LEDs, including any past the end of the configured strips
+    let all_leds = blank_settings(&settings);
+    let blank = alloc::vec![Rgbw::default(); all_leds.num_leds()];
+    write_leds!(drivers, peripherals, buffers, blank, all_leds, corrector);
+
+    log::info!("RMT led drivers initialized");
+
+    // Frame on display, kept so the status LEDs can be refreshed while no frames arrive
+    let mut shown: Option<(&'static mut FrameBuffer, usize)> = None;
+    // Frame rate over the last FPS_WINDOW, shown on the status LEDs
+    let mut fps = 0;
+    let mut fps_window_start = Instant::now();
+    let mut fps_window_frames: u32 = 0;
+    // Sent frames written, every VERIFICATION_INTERVALth of which has its checksums reported
+    let mut sent_frames_written: u32 = 0;
+
+    loop {
+        watchdog::check_in(Task::Renderer);
+
+        let window = fps_window_start.elapsed();
+        if window >= FPS_WINDOW {
+            fps = (fps_window_frames as u64 * 1000 / window.as_millis()) as u32;
+            fps_window_start = Instant::now();
+            fps_window_frames = 0;
+        }
+
+        // Settings are checked first so they apply to the frames sent after them
+        // Waits wake to check in with the watchdog, so a write that never returns resets the chip
+        let next = with_timeout(
+            watchdog::CHECK_IN_INTERVAL,
+            select3(SETTINGS.wait(), POWER.wait(), FRAME.wait()),
+        )
+        .await;
+        let job = match next {
+            // Refresh the status LEDs over the frame on display
+            Err(_) => {
+                let status_leds = settings.status_leds as usize;
+                if status_leds > 0
+                    && let Some((buffer, len)) = &mut shown
+                    && *len == settings.num_leds()
+                {
+                    let leds = &mut buffer[..*len];
+                    indicator::overlay(leds, status_leds, fps);
+                    write_leds!(drivers, peripherals, buffers, leds, settings, corrector);
+                }
+                continue;
+            }
+            Ok(Either3::First(new_settings)) => {
+                if new_settings.strips != settings.strips {
+                    // Turn off every LED so none are left lit past the end of a shorter strip
+                    let all_leds = blank_settings(&settings);
+                    let blank = alloc::vec![Rgbw::default(); all_leds.num_leds()];
+                    write_leds!(drivers, peripherals, buffers, blank, all_leds, corrector);
+                }
+                corrector = Corrector::new(&new_settings);
+                settings = new_settings;
+                continue;
+            }
+            Ok(Either3::Second(true)) => continue,
+            Ok(Either3::Second(false)) => {
+                log::info!("Powering off the LEDs");
+                let all_leds = blank_settings(&settings);
+                let blank = alloc::vec![Rgbw::default(); all_leds.num_leds()];
+                write_leds!(drivers, peripherals, buffers, blank, all_leds, corrector);
+                if let Some((previous, _)) = shown.take() {
+                    FREE_BUFFERS.try_send(previous).ok();
+                }
+                // Dropping the channels gates the RMT peripheral's clock
+                drop(drivers);
+                loop {
+                    watchdog::check_in(Task::Renderer);
+                    if let Ok(true) = with_timeout(watchdog::CHECK_IN_INTERVAL, POWER.wait()).await {
+                        break;
+                    }
+                }
+                log::info!("Powering on the LEDs");
+                drivers = init_led_drivers!(peripherals, buffers);
+                continue;
+            }
+            Ok(Either3::Third(job)) => job,
+        };
+        // Taking the frame may let the host send again
+        messages::update_flow_control();
+
+        // The previous frame is replaced, so its buffer can be filled while this one is written
+        if let Some((previous, _)) = shown.take() {
+            FREE_BUFFERS.try_send(previous).ok();
+        }
+        // Timed frames are latched on time by the timer, however jittery their arrival
+        if let Some(present_at) = job.present_at {
+            Timer::at(present_at.min(Instant::now() + MAX_PRESENTATION_WAIT)).await;
+        }
+        let leds = &mut job.buffer[..job.len];
+        indicator::overlay(leds, settings.status_leds as usize, fps);
+        // Frames queued before the strips changed no longer fit them
+        let written = if job.len == settings.num_leds() {
+            write_leds!(drivers, peripherals, buffers, leds, settings, corrector)
+        } else {
+            None
+        };
+        shown = Some((job.buffer, job.len));
+        if written.is_some() {
+            FRAMES_RENDERED.fetch_add(1, Ordering::Relaxed);
+            fps_window_frames += 1;
+        }
+        match (job.sent, written) {
+            (Some(sent), Some(output_checksums)) => {
+                messages::ack(sent.seq);
+                sent_frames_written = sent_frames_written.wrapping_add(1);
+                let interval = VERIFICATION_INTERVAL.load(Ordering::Relaxed);
+                // The host only knows the frames it sent
+                if interval > 0 && sent_frames_written % interval == 0 && !messages::is_local_frame(sent.seq) {
+                    let report = FrameChecksumPayload {
+                        seq: sent.seq,
+                        checksum: sent.checksum,
+                        output_checksums: output_checksums[..settings.strips.len()].to_vec(),
+                    };
+                    messages::TX_CHANNEL.try_send(Message::FrameChecksum(report)).ok();
+                }
+            }
+            (Some(sent), None) => messages::nack(Some(sent.seq), NackReason::WriteFailed),
+            (None, _) => {}
+        }
+    }
+}
+
+/// Write a strip's part of a frame to its driver, logging any failure
+/// Returns the checksum of the bytes handed to the driver, None if the write failed, and the checksum of no bytes for
+/// strips that aren't configured
+async fn write_strip(
+    driver: &mut impl LedDriver,
+    strip: usize,
+    leds: &[Rgbw],
+    settings: &ConfigPayload,
+    corrector: &Corrector,
+) -> Option<u32> {
+    let mut checksum = Fnv1a::new();
+    let (Some(range), Some(config)) = (settings.strip_range(strip), settings.strips.get(strip)) else {
+        return Some(checksum.finish());
+    };
+    // Convert RGBW values to the strip's wire format
+    let cap = light::brightness_cap(settings);
+    let bytes = encode_pixels(&leds[range], config.color_order)
+        .enumerate()
+        .map(move |(index, level)| corrector.correct(level, cap, index))
+        .inspect(|&byte| checksum.update(&[byte]));
+    match with_timeout(LED_WRITE_TIMEOUT, driver.write(bytes, config.chip)).await {
+        Ok(Ok(())) => Some(checksum.finish()),
+        Ok(Err(e)) => {
+            log::error!("Failed to write LEDs on strip {}: {:?}", strip, e);
+            None
+        }
+        Err(_) => {
+            log::error!("LED write on strip {} stalled for {} ms", strip, LED_WRITE_TIMEOUT.as_millis());
+            None
+        }
+    }
+}
+
+/// Settings covering every LED each output can drive, for turning them all off
+fn blank_settings(settings: &ConfigPayload) -> ConfigPayload {
+    ConfigPayload {
+        strips: (0..MAX_STRIPS)
+            .map(|strip| StripConfig {
+                num_leds: MAX_LEDS as u16,
+                ..settings.strips.get(strip).copied().unwrap_or(config::DEFAULT_STRIP)
+            })
+            .collect(),
+        ..settings.clone()
+    }
+}
+
+/// Pack a frame into the bytes the strip expects, color channels in order followed by white
+/// RGB strips mix the white channel into the other channels
+fn encode_pixels(leds: &[Rgbw], order: ColorOrder) -> impl Iterator<Item = u8> + '_ {
+    leds.iter().flat_map(move |&rgbw| {
+        let rgb = if RGBW_STRIP {
+            Rgb::new(rgbw.r, rgbw.g, rgbw.b)
+        } else {
+            Rgb::from(rgbw)
+        };
+        let [first, second, third] = order.arrange(rgb);
+        [first, second, third, rgbw.w].into_iter().take(BYTES_PER_LED)
+    })
+}
@@ -0,0 +1,46 @@
+use alloc::format;
+use common::message::{ResetReason, ResetReportPayload};
+use esp_hal::rtc_cntl::SocResetReason;
+
+use crate::{panic, watchdog};
+
+/// Gather why the firmware last reset, called once at boot before the watchdog task is spawned
+/// The panic and wedged tasks kept across the reset are taken, so they're only reported once
+pub fn report() -> ResetReportPayload {
+    let report = ResetReportPayload {
+        reason: reason(),
+        panic: panic::take_last_panic(),
+        wedged_tasks: watchdog::take_wedged()
+            .into_iter()
+            .map(|task| format!("{:?}", task))
+            .collect(),
+    };
+    for task in &report.wedged_tasks {
+        log::error!("Reset by the watchdog after the {} task stopped responding", task);
+    }
+    if let Some(panic) = &report.panic {
+        log::error!("Reset after panicking: {}", panic.message);
+    }
+    report
+}
+
+/// Cause of the last reset, as reported by the chip
+fn reason() -> ResetReason {
+    match esp_hal::system::reset_reason() {
+        Some(SocResetReason::ChipPowerOn) => ResetReason::PowerOn,
+        Some(SocResetReason::CoreSw | SocResetReason::Cpu0Sw) => ResetReason::Software,
+        Some(
+            SocResetReason::CoreMwdt0
+            | SocResetReason::CoreMwdt1
+            | SocResetReason::CoreRtcWdt
+            | SocResetReason::Cpu0Mwdt0
+            | SocResetReason::Cpu0Mwdt1
+            | SocResetReason::Cpu0RtcWdt
+            | SocResetReason::SysRtcWdt
+            | SocResetReason::SysSuperWdt,
+        ) => ResetReason::Watchdog,
+        Some(SocResetReason::SysBrownOut) => ResetReason::Brownout,
+        Some(SocResetReason::CoreDeepSleep) => ResetReason::DeepSleep,
+        _ => ResetReason::Other,
+    }
+}
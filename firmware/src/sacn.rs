@@ -0,0 +1,74 @@
+use alloc::vec::Vec;
+use common::e131::{self, E131_PORT, PIXELS_PER_UNIVERSE};
+use common::message::Rgb;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{Ipv4Address, Stack};
+
+use crate::messages;
+
+/// Largest E1.31 data packet, a full universe after the 126 byte header
+const MAX_PACKET_SIZE: usize = 638;
+
+/// Packets buffered while the main loop is busy
+const RX_PACKETS: usize = 8;
+
+/// sACN task receiving E1.31 universes from first_universe on, and displaying them as a frame of num_leds LEDs
+/// once every universe of the frame has arrived
+#[embassy_executor::task]
+pub async fn sacn_task(stack: Stack<'static>, first_universe: u16, num_leds: usize) {
+    let universes = num_leds.div_ceil(PIXELS_PER_UNIVERSE);
+    if universes == 0 || universes > u32::BITS as usize {
+        log::error!("sACN frames of {} universes aren't supported", universes);
+        return;
+    }
+    let universe_range = first_universe..first_universe.saturating_add(universes as u16);
+
+    stack.wait_config_up().await;
+    for universe in universe_range.clone() {
+        let [a, b, c, d] = e131::multicast_group(universe);
+        if let Err(e) = stack.join_multicast_group(Ipv4Address::new(a, b, c, d)) {
+            log::error!("Failed to join sACN universe {}: {:?}", universe, e);
+        }
+    }
+    log::info!("Receiving sACN universes {} to {}", universe_range.start, universe_range.end - 1);
+
+    let mut rx_meta = [PacketMetadata::EMPTY; RX_PACKETS];
+    let mut rx_buffer = [0u8; RX_PACKETS * MAX_PACKET_SIZE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0u8; 1];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    socket.bind(E131_PORT).expect("Failed to bind sACN socket");
+
+    let mut leds: Vec<Rgb> = alloc::vec![Rgb::default(); num_leds];
+    // Universes of the frame received so far, one bit each
+    let mut received: u32 = 0;
+    let complete = u32::MAX >> (u32::BITS as usize - universes);
+    let mut packet = [0u8; MAX_PACKET_SIZE];
+    loop {
+        let len = match socket.recv_from(&mut packet).await {
+            Ok((len, _)) => len,
+            Err(e) => {
+                log::warn!("Failed to receive sACN packet: {:?}", e);
+                continue;
+            }
+        };
+        let Some(data) = e131::parse(&packet[..len]) else {
+            continue;
+        };
+        if !universe_range.contains(&data.universe) || data.terminated {
+            continue;
+        }
+
+        let index = (data.universe - first_universe) as usize;
+        let pixels = leds[index * PIXELS_PER_UNIVERSE..].iter_mut().take(PIXELS_PER_UNIVERSE);
+        for (led, channels) in pixels.zip(data.data.chunks_exact(3)) {
+            *led = Rgb::new(channels[0], channels[1], channels[2]);
+        }
+        received |= 1 << index;
+
+        if received == complete {
+            received = 0;
+            messages::deliver_local_frame(leds.clone());
+        }
+    }
+}
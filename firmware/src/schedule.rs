@@ -0,0 +1,61 @@
+use common::message::{Message, ScheduleAction, SchedulePayload};
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use portable_atomic::{AtomicU32, Ordering};
+
+use crate::messages::RX_CHANNEL;
+
+/// Time between checks of the schedule
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Unix time at boot in seconds, 0 until the time is synchronized
+static BOOT_TIME: AtomicU32 = AtomicU32::new(0);
+
+/// Schedule replacing the one the schedule task follows
+static UPDATE: Signal<CriticalSectionRawMutex, SchedulePayload> = Signal::new();
+
+/// Set the current Unix time in seconds
+pub fn set_time(unix: u64) {
+    let boot_time = unix.saturating_sub(Instant::now().as_secs());
+    BOOT_TIME.store(boot_time as u32, Ordering::Relaxed);
+}
+
+/// Current Unix time in seconds, None until the time is synchronized
+pub fn now() -> Option<u64> {
+    match BOOT_TIME.load(Ordering::Relaxed) {
+        0 => None,
+        boot_time => Some(boot_time as u64 + Instant::now().as_secs()),
+    }
+}
+
+/// Follow a new schedule, applying whatever it says for the current time
+pub fn update(schedule: SchedulePayload) {
+    UPDATE.signal(schedule);
+}
+
+/// Schedule task powering the LEDs on and off as the schedule comes due, once the time is known,
+/// by handing ScheduleEvent messages to the main loop
+/// The state the schedule is in is applied at boot, so a reboot doesn't lose the day's routine
+#[embassy_executor::task]
+pub async fn schedule_task(mut schedule: SchedulePayload) {
+    // Last action handed to the main loop, each is only applied once so the host can override it until the next
+    let mut applied: Option<ScheduleAction> = None;
+    loop {
+        if let Some(now) = now()
+            && let Some(action) = schedule.action_at(now as i64)
+            && applied != Some(action)
+        {
+            match RX_CHANNEL.try_send(Message::ScheduleEvent(action)) {
+                Ok(()) => applied = Some(action),
+                Err(_) => log::warn!("Delayed scheduled {:?}, receive channel full", action),
+            }
+        }
+
+        if let Either::First(updated) = select(UPDATE.wait(), Timer::after(CHECK_INTERVAL)).await {
+            schedule = updated;
+            applied = None;
+        }
+    }
+}
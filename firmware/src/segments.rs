@@ -0,0 +1,87 @@
+use alloc::vec::Vec;
+use common::message::{Rgbw, SegmentMode, SegmentsPayload};
+use core::ops::Range;
+
+use crate::animation::Animation;
+
+/// Segment with the LEDs it's showing
+struct RunningSegment {
+    range: Range<usize>,
+    leds: Vec<Rgbw>,
+    /// Effect running on the segment, None while it's frozen
+    animation: Option<Animation>,
+}
+
+/// Segments of the frame running their own effects or frozen, kept over the frames from the host and
+/// whole-frame animations
+pub struct Segments {
+    payload: SegmentsPayload,
+    running: Vec<RunningSegment>,
+}
+
+impl Segments {
+    /// No segments, every LED shows the frame
+    pub fn new() -> Self {
+        Self {
+            payload: SegmentsPayload::default(),
+            running: Vec::new(),
+        }
+    }
+
+    /// Segments being run
+    pub fn payload(&self) -> &SegmentsPayload {
+        &self.payload
+    }
+
+    /// Replace the segments, frozen ones holding what frame shows in their range
+    /// The payload must be valid for frame's length
+    pub fn set(&mut self, payload: SegmentsPayload, frame: &[Rgbw]) {
+        self.running = payload
+            .segments
+            .iter()
+            .map(|segment| RunningSegment {
+                range: segment.range(),
+                leds: frame[segment.range()].to_vec(),
+                animation: match &segment.mode {
+                    SegmentMode::Effect(effect) => Some(Animation::new(effect.clone())),
+                    SegmentMode::Frozen => None,
+                },
+            })
+            .collect();
+        self.payload = payload;
+    }
+
+    /// Remove every segment
+    pub fn clear(&mut self) {
+        self.set(SegmentsPayload::default(), &[]);
+    }
+
+    /// Whether any segment is running an effect, so frames must be rendered for it
+    pub fn is_animating(&self) -> bool {
+        self.running.iter().any(|segment| segment.animation.is_some())
+    }
+
+    /// Render the next frame of every segment's effect
+    pub fn render(&mut self) {
+        for segment in &mut self.running {
+            if let Some(animation) = &mut segment.animation {
+                animation.render(&mut segment.leds);
+            }
+        }
+    }
+
+    /// Draw the segments over a frame
+    pub fn apply(&self, frame: &mut [Rgbw]) {
+        for segment in &self.running {
+            if let Some(leds) = frame.get_mut(segment.range.clone()) {
+                leds.copy_from_slice(&segment.leds);
+            }
+        }
+    }
+}
+
+impl Default for Segments {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,98 @@
+use common::sntp::{self, PACKET_SIZE, SNTP_PORT};
+use embassy_net::dns::{self, DnsQueryType};
+use embassy_net::udp::{PacketMetadata, RecvError, SendError, UdpSocket};
+use embassy_net::Stack;
+use embassy_time::{Duration, TimeoutError, Timer, with_timeout};
+
+use crate::schedule;
+
+/// Server the time is synchronized with
+const NTP_SERVER: &str = "pool.ntp.org";
+
+/// Time between synchronizations, the crystal drifts well under a second in this time
+const SYNC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Time before retrying a failed synchronization
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time the server is given to reply
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+enum SyncError {
+    Dns(dns::Error),
+    NoAddress,
+    Send(SendError),
+    Receive(RecvError),
+    Timeout,
+    InvalidReply,
+}
+
+impl core::fmt::Display for SyncError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            SyncError::Dns(e) => write!(f, "failed to look up the server ({:?})", e),
+            SyncError::NoAddress => write!(f, "the server has no IPv4 address"),
+            SyncError::Send(e) => write!(f, "failed to send the request ({:?})", e),
+            SyncError::Receive(e) => write!(f, "failed to receive the reply ({:?})", e),
+            SyncError::Timeout => write!(f, "the server didn't reply"),
+            SyncError::InvalidReply => write!(f, "the reply isn't a valid SNTP reply"),
+        }
+    }
+}
+
+impl From<TimeoutError> for SyncError {
+    fn from(_: TimeoutError) -> Self {
+        SyncError::Timeout
+    }
+}
+
+/// SNTP task keeping the time the schedule follows synchronized once the network is up
+#[embassy_executor::task]
+pub async fn sntp_task(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0u8; PACKET_SIZE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0u8; PACKET_SIZE];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    // Replies come back to an ephemeral port
+    socket.bind(0).expect("Failed to bind SNTP socket");
+
+    loop {
+        stack.wait_config_up().await;
+        let delay = match sync(stack, &socket).await {
+            Ok(unix) => {
+                let first = schedule::now().is_none();
+                schedule::set_time(unix);
+                if first {
+                    log::info!("Synchronized time with {}, Unix time {}", NTP_SERVER, unix);
+                }
+                SYNC_INTERVAL
+            }
+            Err(e) => {
+                log::warn!("Failed to synchronize time with {}: {}", NTP_SERVER, e);
+                RETRY_INTERVAL
+            }
+        };
+        Timer::after(delay).await;
+    }
+}
+
+/// Ask the server for the time, returning it in seconds since the Unix epoch
+async fn sync(stack: Stack<'static>, socket: &UdpSocket<'_>) -> Result<u64, SyncError> {
+    let addresses = stack
+        .dns_query(NTP_SERVER, DnsQueryType::A)
+        .await
+        .map_err(SyncError::Dns)?;
+    let address = *addresses.first().ok_or(SyncError::NoAddress)?;
+    socket
+        .send_to(&sntp::request(), (address, SNTP_PORT))
+        .await
+        .map_err(SyncError::Send)?;
+
+    let mut reply = [0u8; PACKET_SIZE];
+    let (len, _) = with_timeout(REPLY_TIMEOUT, socket.recv_from(&mut reply))
+        .await?
+        .map_err(SyncError::Receive)?;
+    sntp::parse(&reply[..len]).ok_or(SyncError::InvalidReply)
+}
@@ -0,0 +1,151 @@
+use common::message::{Rgbw, SoundReactivePayload};
+use embassy_time::{Duration, Instant};
+use portable_atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering};
+
+/// Time between microphone samples while sound reactivity is on, 2 kHz is plenty for the bass carrying the beat
+pub const SAMPLE_INTERVAL: Duration = Duration::from_micros(500);
+
+/// Samples averaged into each measurement of the sound level, 20 ms at the sample rate
+const WINDOW: u32 = 40;
+/// The microphone's DC offset follows the samples by 1 / 2^DC_SHIFT of the difference each sample
+const DC_SHIFT: u32 = 10;
+/// Weight of each window in the average level beats are measured against, out of 256, about a second's worth
+const AVERAGE_SMOOTHING: u32 = 5;
+/// Quietest level, in ADC counts from the DC offset, that isn't just the microphone's noise
+const NOISE_FLOOR: u32 = 12;
+/// Shortest time between beats, so one drum hit isn't counted twice
+const BEAT_HOLDOFF: Duration = Duration::from_millis(200);
+
+/// Brightness, out of 255, effects are scaled to in silence
+const QUIET_BRIGHTNESS: u16 = 64;
+/// Brightness, out of 255, a beat's flash loses each animation frame
+const FLASH_DECAY: u8 = 24;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SENSITIVITY: AtomicU8 = AtomicU8::new(128);
+/// Sound level relative to the loudest recent sound, out of 255
+static LEVEL: AtomicU8 = AtomicU8::new(0);
+/// Beats detected since boot
+static BEATS: AtomicU32 = AtomicU32::new(0);
+
+/// Turn sound reactivity on or off, the light task samples the microphone while it's on
+pub fn configure(payload: SoundReactivePayload) {
+    SENSITIVITY.store(payload.sensitivity, Ordering::Relaxed);
+    ENABLED.store(payload.enabled, Ordering::Relaxed);
+    if !payload.enabled {
+        LEVEL.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Current sound reactivity settings
+pub fn payload() -> SoundReactivePayload {
+    SoundReactivePayload {
+        enabled: is_enabled(),
+        sensitivity: SENSITIVITY.load(Ordering::Relaxed),
+    }
+}
+
+/// Whether effects follow the microphone
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Level and beat detector fed with microphone samples
+pub struct Detector {
+    /// DC offset of the microphone's output, scaled by 256
+    dc: i32,
+    /// Sum of the samples' distance from the DC offset in the current window
+    sum: u32,
+    count: u32,
+    /// Average window level, scaled by 256
+    average: u32,
+    /// Loudest recent window level, decaying so the level adapts to the room
+    peak: u32,
+    last_beat: Instant,
+}
+
+impl Detector {
+    pub fn new() -> Self {
+        Self {
+            // Microphone boards bias their output to the middle of the ADC's range
+            dc: 2048 * 256,
+            sum: 0,
+            count: 0,
+            average: 0,
+            peak: NOISE_FLOOR,
+            last_beat: Instant::now(),
+        }
+    }
+
+    /// Add a 12-bit reading of the microphone
+    pub fn add(&mut self, sample: u16) {
+        let sample = sample as i32 * 256;
+        self.dc += (sample - self.dc) >> DC_SHIFT;
+        self.sum += (sample - self.dc).unsigned_abs() / 256;
+        self.count += 1;
+        if self.count == WINDOW {
+            self.measure(self.sum / WINDOW);
+            self.sum = 0;
+            self.count = 0;
+        }
+    }
+
+    /// Update the level and look for a beat with the level of the last window
+    fn measure(&mut self, level: u32) {
+        self.peak = (self.peak - self.peak / 256).max(level).max(NOISE_FLOOR);
+        LEVEL.store((level * 255 / self.peak).min(255) as u8, Ordering::Relaxed);
+
+        // A beat is a window well above the average, from twice it at sensitivity 0 down to the average itself
+        let sensitivity = SENSITIVITY.load(Ordering::Relaxed) as u32;
+        let threshold = self.average / 256 * (512 - sensitivity) / 256;
+        if level > threshold && level > NOISE_FLOOR && self.last_beat.elapsed() >= BEAT_HOLDOFF {
+            BEATS.fetch_add(1, Ordering::Relaxed);
+            self.last_beat = Instant::now();
+        }
+        self.average = (self.average * (256 - AVERAGE_SMOOTHING) + level * 256 * AVERAGE_SMOOTHING) / 256;
+    }
+}
+
+impl Default for Detector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Effects driven by the detector, dimming with the sound level and flashing to full brightness on beats
+pub struct Reaction {
+    beats_seen: u32,
+    flash: u8,
+}
+
+impl Reaction {
+    pub fn new() -> Self {
+        Self {
+            beats_seen: BEATS.load(Ordering::Relaxed),
+            flash: 0,
+        }
+    }
+
+    /// Scale an animation frame by the sound, once per animation frame
+    pub fn apply(&mut self, leds: &mut [Rgbw]) {
+        let beats = BEATS.load(Ordering::Relaxed);
+        if beats != self.beats_seen {
+            self.beats_seen = beats;
+            self.flash = 255;
+        }
+        let level = LEVEL.load(Ordering::Relaxed) as u16;
+        let brightness = (QUIET_BRIGHTNESS + (255 - QUIET_BRIGHTNESS) * level / 255).max(self.flash as u16);
+        self.flash = self.flash.saturating_sub(FLASH_DECAY);
+
+        let scale = |channel: u8| (channel as u16 * brightness / 255) as u8;
+        for led in leds {
+            *led = Rgbw::new(scale(led.r), scale(led.g), scale(led.b), scale(led.w));
+        }
+    }
+}
+
+impl Default for Reaction {
+    fn default() -> Self {
+        Self::new()
+    }
+}
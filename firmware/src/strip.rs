@@ -0,0 +1,104 @@
+use common::message::LedChip;
+use esp_hal::Async;
+use esp_hal::gpio::Level;
+use esp_hal::gpio::interconnect::PeripheralOutput;
+use esp_hal::rmt::{self, Channel, PulseCode, Tx, TxChannelConfig, TxChannelCreator};
+
+/// Frequency the RMT peripheral is clocked at, pulse lengths are counted in its ticks
+pub const RMT_FREQUENCY_MHZ: u32 = 80;
+
+/// Number of pulse codes needed to send a number of bytes, one per bit followed by an end marker
+pub const fn buffer_size(bytes: usize) -> usize {
+    bytes * 8 + 1
+}
+
+/// Errors that can occur when writing to a strip
+#[derive(Debug)]
+pub enum StripError {
+    /// More bytes were written than the buffer holds
+    Overflow,
+    Rmt(rmt::Error),
+    #[cfg(feature = "apa102")]
+    Spi(esp_hal::spi::Error),
+}
+
+/// Output driving a strip with the bytes of a frame
+pub trait LedDriver {
+    /// Send bytes to the strip, with the chip's bit timing where it has one
+    async fn write(&mut self, bytes: impl Iterator<Item = u8>, chip: LedChip) -> Result<(), StripError>;
+}
+
+/// Length of the high and low halves of a 0 and a 1 bit, in nanoseconds
+struct BitTiming {
+    t0h: u32,
+    t0l: u32,
+    t1h: u32,
+    t1l: u32,
+}
+
+impl BitTiming {
+    /// Timing from the chip's datasheet
+    fn of(chip: LedChip) -> Self {
+        match chip {
+            LedChip::Ws2812b => Self { t0h: 400, t0l: 850, t1h: 800, t1l: 450 },
+            // The WS2811 in its 400 kHz mode, as wired on most strings
+            LedChip::Ws2811 => Self { t0h: 500, t0l: 2000, t1h: 1200, t1l: 1300 },
+            LedChip::Sk6812 => Self { t0h: 300, t0l: 900, t1h: 600, t1l: 600 },
+        }
+    }
+
+    /// Pulse codes sending a 0 and a 1 bit
+    fn pulses(&self) -> (PulseCode, PulseCode) {
+        let ticks = |ns: u32| (ns * RMT_FREQUENCY_MHZ / 1000) as u16;
+        (
+            PulseCode::new(Level::High, ticks(self.t0h), Level::Low, ticks(self.t0l)),
+            PulseCode::new(Level::High, ticks(self.t1h), Level::Low, ticks(self.t1l)),
+        )
+    }
+}
+
+/// LED strip driven from an RMT transmit channel, bytes are sent most significant bit first
+pub struct StripDriver<'d, const BUFFER_SIZE: usize> {
+    channel: Channel<'d, Async, Tx>,
+    buffer: &'d mut [PulseCode; BUFFER_SIZE],
+}
+
+impl<'d, const BUFFER_SIZE: usize> StripDriver<'d, BUFFER_SIZE> {
+    /// Configure an RMT channel to drive a strip on pin
+    pub fn new(
+        channel: impl TxChannelCreator<'d, Async>,
+        pin: impl PeripheralOutput<'d>,
+        buffer: &'d mut [PulseCode; BUFFER_SIZE],
+    ) -> Result<Self, StripError> {
+        let config = TxChannelConfig::default()
+            .with_clk_divider(1)
+            .with_idle_output_level(Level::Low)
+            .with_idle_output(true)
+            .with_carrier_modulation(false);
+        let channel = channel.configure_tx(pin, config).map_err(StripError::Rmt)?;
+        Ok(Self { channel, buffer })
+    }
+}
+
+impl<const BUFFER_SIZE: usize> LedDriver for StripDriver<'_, BUFFER_SIZE> {
+    /// Send bytes to the strip with the chip's bit timing
+    async fn write(&mut self, bytes: impl Iterator<Item = u8>, chip: LedChip) -> Result<(), StripError> {
+        let (zero, one) = BitTiming::of(chip).pulses();
+        let mut len = 0;
+        for byte in bytes {
+            // Leave room for the end marker
+            if len + 8 >= BUFFER_SIZE {
+                return Err(StripError::Overflow);
+            }
+            for bit in (0..8).rev() {
+                self.buffer[len] = if byte & (1 << bit) != 0 { one } else { zero };
+                len += 1;
+            }
+        }
+        self.buffer[len] = PulseCode::end_marker();
+        self.channel
+            .transmit(&self.buffer[..=len])
+            .await
+            .map_err(StripError::Rmt)
+    }
+}
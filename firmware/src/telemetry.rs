@@ -0,0 +1,94 @@
+use alloc::alloc::{Layout, alloc, dealloc};
+use common::message::{Message, TelemetryPayload};
+use embassy_time::{Duration, Timer};
+
+use crate::messages::TX_CHANNEL;
+
+/// Time between Telemetry reports
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Fills the unused part of the stack at boot, words still holding it have never been used
+const STACK_PAINT: u32 = 0xA5A5_A5A5;
+
+/// Stack left unpainted below the caller of paint_stack, for the painting itself
+const PAINT_MARGIN: usize = 1024;
+
+unsafe extern "C" {
+    /// Top of the stack every task runs on, set by the linker script
+    static _stack_start_cpu0: u32;
+    /// Bottom of the stack, where it would overflow
+    static _stack_end_cpu0: u32;
+}
+
+/// Highest and lowest address of the stack
+fn stack_bounds() -> (usize, usize) {
+    (&raw const _stack_start_cpu0 as usize, &raw const _stack_end_cpu0 as usize)
+}
+
+/// Fill the stack below the caller with STACK_PAINT, called once at boot so its high-water mark can be measured
+#[inline(never)]
+pub fn paint_stack() {
+    let here = 0u32;
+    let (_, bottom) = stack_bounds();
+    let end = (&raw const here as usize).saturating_sub(PAINT_MARGIN);
+    let mut word = bottom as *mut u32;
+    while (word as usize) < end {
+        // Safety: the words between the bottom of the stack and the margin below the caller are unused
+        unsafe {
+            word.write_volatile(STACK_PAINT);
+            word = word.add(1);
+        }
+    }
+}
+
+/// Most of the stack ever used, in bytes, found from the lowest word no longer holding STACK_PAINT
+fn stack_high_water() -> u32 {
+    let (top, bottom) = stack_bounds();
+    let mut word = bottom as *const u32;
+    // Safety: only words within the stack are read
+    while (word as usize) < top && unsafe { word.read_volatile() } == STACK_PAINT {
+        word = unsafe { word.add(1) };
+    }
+    (top - word as usize) as u32
+}
+
+/// Largest block that can be allocated in one piece, found by a binary search of trial allocations
+fn largest_free_block() -> u32 {
+    let (mut low, mut high) = (0, esp_alloc::HEAP.free());
+    while low < high {
+        let size = (low + high).div_ceil(2);
+        let Ok(layout) = Layout::from_size_align(size, 4) else {
+            break;
+        };
+        // Safety: the block is freed straight away, without being used
+        let block = unsafe { alloc(layout) };
+        if block.is_null() {
+            high = size - 1;
+        } else {
+            unsafe { dealloc(block, layout) };
+            low = size;
+        }
+    }
+    low as u32
+}
+
+/// Task sending Telemetry on the heap and stack, so fragmentation and stack growth show up before they crash
+/// Every embassy task runs on the one main stack, so its high-water mark covers them all
+#[embassy_executor::task]
+pub async fn telemetry_task() {
+    let (top, bottom) = stack_bounds();
+    let mut min_free_heap = u32::MAX;
+    loop {
+        Timer::after(TELEMETRY_INTERVAL).await;
+        let free_heap = esp_alloc::HEAP.free() as u32;
+        min_free_heap = min_free_heap.min(free_heap);
+        let telemetry = TelemetryPayload {
+            free_heap,
+            largest_free_block: largest_free_block(),
+            min_free_heap,
+            stack_size: (top - bottom) as u32,
+            stack_high_water: stack_high_water(),
+        };
+        TX_CHANNEL.try_send(Message::Telemetry(telemetry)).ok();
+    }
+}
@@ -0,0 +1,93 @@
+use alloc::vec::Vec;
+use embassy_time::{Duration, Timer};
+use esp_hal::peripherals::TIMG1;
+use esp_hal::timer::timg::{MwdtStage, MwdtStageAction, Wdt};
+use portable_atomic::{AtomicU8, Ordering};
+
+/// Longest a supervised task may wait before checking in, its waits time out after this long
+pub const CHECK_IN_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Time between checks that every supervised task has checked in
+const SUPERVISION_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Time without the hardware watchdog being fed before it resets the chip
+const WATCHDOG_TIMEOUT_S: u64 = 5;
+
+/// Marks WEDGED as written by the watchdog task, rather than left over from power on
+const WEDGED_MAGIC: u32 = 0x5744_0000;
+
+/// Tasks that stopped checking in before the last watchdog reset, kept in RTC memory across the reset
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut WEDGED: u32 = 0;
+
+/// Tasks that have checked in since the last supervision check, one bit per Task
+static CHECKED_IN: AtomicU8 = AtomicU8::new(0);
+
+/// Task supervised by the watchdog
+#[derive(Debug, Clone, Copy)]
+pub enum Task {
+    Rx,
+    Tx,
+    Renderer,
+}
+
+impl Task {
+    const ALL: [Task; 3] = [Task::Rx, Task::Tx, Task::Renderer];
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Record that a task is still running, called at least every CHECK_IN_INTERVAL
+pub fn check_in(task: Task) {
+    CHECKED_IN.fetch_or(task.bit(), Ordering::Relaxed);
+}
+
+/// Count every task as checked in, after a light sleep stopped them all
+pub fn check_in_all() {
+    for task in Task::ALL {
+        check_in(task);
+    }
+}
+
+/// Take the tasks that wedged if the last reset was the watchdog's, called once at boot
+pub fn take_wedged() -> Vec<Task> {
+    // Safety: read before any task is spawned, the watchdog task is the only writer
+    let wedged = unsafe { WEDGED };
+    unsafe { WEDGED = 0 };
+    if wedged & !0xff != WEDGED_MAGIC {
+        return Vec::new();
+    }
+    Task::ALL
+        .into_iter()
+        .filter(|task| wedged as u8 & task.bit() != 0)
+        .collect()
+}
+
+/// Watchdog task feeding the hardware watchdog while every supervised task keeps checking in
+/// Once one stops, the watchdog is left to reset the chip
+#[embassy_executor::task]
+pub async fn watchdog_task(mut wdt: Wdt<TIMG1<'static>>) {
+    wdt.set_timeout(MwdtStage::Stage0, esp_hal::time::Duration::from_secs(WATCHDOG_TIMEOUT_S));
+    wdt.set_stage_action(MwdtStage::Stage0, MwdtStageAction::ResetSystem);
+    wdt.enable();
+    wdt.feed();
+
+    let all = Task::ALL.iter().fold(0, |all, task| all | task.bit());
+    loop {
+        Timer::after(SUPERVISION_INTERVAL).await;
+        let checked_in = CHECKED_IN.swap(0, Ordering::Relaxed);
+        if checked_in == all {
+            wdt.feed();
+            continue;
+        }
+
+        let wedged = all & !checked_in;
+        // Safety: only written here, and read at boot before this task is spawned
+        unsafe { WEDGED = WEDGED_MAGIC | wedged as u32 };
+        log::error!("Task stopped responding, resetting in {} s", WATCHDOG_TIMEOUT_S);
+        // Leave the watchdog unfed until it resets the chip
+        core::future::pending::<()>().await;
+    }
+}
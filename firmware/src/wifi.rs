@@ -0,0 +1,154 @@
+use common::framing::UDP_PORT;
+use common::message::{ConfigPayload, MirrorRole, WifiCredentialsPayload};
+use embassy_executor::Spawner;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{Runner, Stack, StackResources};
+use embassy_time::{Duration, Timer};
+use esp_hal::peripherals::WIFI;
+use esp_hal::rng::Rng;
+use esp_radio::wifi::{ClientConfig, ModeConfig, WifiController, WifiDevice, WifiEvent};
+use static_cell::StaticCell;
+
+use crate::messages::{self, MAX_FRAME_SIZE};
+use crate::{mirror, sacn, sntp};
+
+/// Time before rejoining the network after failing to join or losing it
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Datagrams buffered while the main loop is busy
+const RX_DATAGRAMS: usize = 4;
+
+/// Sockets the network stack has room for: DHCP, DNS, messages, sACN and SNTP
+const MAX_SOCKETS: usize = 5;
+
+static RADIO: StaticCell<esp_radio::Controller<'static>> = StaticCell::new();
+static RESOURCES: StaticCell<StackResources<MAX_SOCKETS>> = StaticCell::new();
+
+/// Join the network in credentials and receive messages over UDP alongside the UART,
+/// and sACN when the settings enable it, keeping the time synchronized for the schedule
+/// Replies are still sent over the UART
+/// Frames are mirrored over ESP-NOW when the settings enable it, with or without a network
+pub fn start(
+    spawner: &Spawner,
+    wifi: WIFI<'static>,
+    credentials: Option<WifiCredentialsPayload>,
+    settings: &ConfigPayload,
+) {
+    let radio = RADIO.init(esp_radio::init().expect("Failed to initialize radio"));
+    let (controller, interfaces) =
+        esp_radio::wifi::new(radio, wifi, Default::default()).expect("Failed to initialize WiFi");
+
+    if settings.mirror != MirrorRole::Off {
+        let esp_now = interfaces.esp_now;
+        // Trees on a network use its channel, the others meet on the configured one
+        if credentials.is_none()
+            && let Err(e) = esp_now.set_channel(settings.mirror_channel)
+        {
+            log::error!("Failed to set ESP-NOW channel {}: {:?}", settings.mirror_channel, e);
+        }
+        spawner
+            .spawn(mirror::mirror_task(esp_now, settings.mirror, settings.num_leds()))
+            .unwrap();
+    }
+
+    let Some(credentials) = credentials else {
+        spawner.spawn(radio_task(controller)).unwrap();
+        return;
+    };
+
+    let rng = Rng::new();
+    let seed = (rng.random() as u64) << 32 | rng.random() as u64;
+    let (stack, runner) = embassy_net::new(
+        interfaces.sta,
+        embassy_net::Config::dhcpv4(Default::default()),
+        RESOURCES.init(StackResources::new()),
+        seed,
+    );
+
+    spawner.spawn(connection_task(controller, credentials)).unwrap();
+    spawner.spawn(net_task(runner)).unwrap();
+    spawner.spawn(udp_task(stack)).unwrap();
+    spawner.spawn(sntp::sntp_task(stack)).unwrap();
+    if settings.sacn_universe != 0 {
+        spawner
+            .spawn(sacn::sacn_task(stack, settings.sacn_universe, settings.num_leds()))
+            .unwrap();
+    }
+}
+
+/// Radio task keeping the radio started for ESP-NOW without joining a network
+#[embassy_executor::task]
+async fn radio_task(mut controller: WifiController<'static>) {
+    if let Err(e) = controller.set_config(&ModeConfig::Client(ClientConfig::default())) {
+        log::error!("Failed to configure WiFi: {:?}", e);
+    } else if let Err(e) = controller.start_async().await {
+        log::error!("Failed to start WiFi: {:?}", e);
+    }
+    // The radio stops when the controller is dropped
+    core::future::pending::<()>().await;
+}
+
+/// Connection task joining the network, and rejoining it whenever it's lost
+#[embassy_executor::task]
+async fn connection_task(mut controller: WifiController<'static>, credentials: WifiCredentialsPayload) {
+    let ssid = credentials.ssid.clone();
+    let config = ModeConfig::Client(
+        ClientConfig::default()
+            .with_ssid(credentials.ssid)
+            .with_password(credentials.password),
+    );
+    if let Err(e) = controller.set_config(&config) {
+        log::error!("Failed to configure WiFi: {:?}", e);
+        return;
+    }
+
+    loop {
+        if !matches!(controller.is_started(), Ok(true))
+            && let Err(e) = controller.start_async().await
+        {
+            log::error!("Failed to start WiFi: {:?}", e);
+        } else {
+            match controller.connect_async().await {
+                Ok(()) => {
+                    log::info!("Joined WiFi network {}", ssid);
+                    controller.wait_for_event(WifiEvent::StaDisconnected).await;
+                    log::warn!("Lost WiFi network {}", ssid);
+                }
+                Err(e) => log::warn!("Failed to join WiFi network {}: {:?}", ssid, e),
+            }
+        }
+        Timer::after(RECONNECT_DELAY).await;
+    }
+}
+
+/// Network task running the IP stack
+#[embassy_executor::task]
+async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) {
+    runner.run().await
+}
+
+/// UDP task handing each datagram to the main loop like a message received over the UART
+#[embassy_executor::task]
+async fn udp_task(stack: Stack<'static>) {
+    stack.wait_config_up().await;
+    if let Some(config) = stack.config_v4() {
+        log::info!("Listening for messages on {}:{}", config.address.address(), UDP_PORT);
+    }
+
+    let mut rx_meta = [PacketMetadata::EMPTY; RX_DATAGRAMS];
+    let mut rx_buffer = [0u8; RX_DATAGRAMS * MAX_FRAME_SIZE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0u8; 1];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    socket.bind(UDP_PORT).expect("Failed to bind UDP socket");
+
+    let mut packet = [0u8; MAX_FRAME_SIZE];
+    loop {
+        match socket.recv_from(&mut packet).await {
+            Ok((len, _)) => {
+                messages::receive_packet(&mut packet[..len]).await;
+            }
+            Err(e) => log::warn!("Failed to receive UDP datagram: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use common::message::Rgb16;
+use std::time::Duration;
+
+/// Default cap on full-swing flashes per second in reduced flash mode
+pub const DEFAULT_MAX_FLASHES_PER_SECOND: f32 = 2.0;
+
+/// Reduced flash filter for the output pipeline
+///
+/// Limits how fast each channel may change, so a full black to white to black flash
+/// can happen at most `max_flashes_per_second` times a second whatever the effect renders.
+/// Smaller swings may repeat faster, but their brightness delta is proportionally smaller.
+pub struct ReducedFlashFilter {
+    max_flashes_per_second: f32,
+    state: Vec<[f32; 3]>,
+}
+
+impl ReducedFlashFilter {
+    /// Create a new ReducedFlashFilter with the given flash frequency cap
+    pub fn new(max_flashes_per_second: f32) -> Self {
+        Self {
+            max_flashes_per_second,
+            state: Vec::new(),
+        }
+    }
+
+    /// Limit the change of each channel over dt, replacing the colors in leds with the limited output
+    pub fn apply(&mut self, leds: &mut [Rgb16], dt: Duration) {
+        // A full flash is a rise and a fall across the whole channel range
+        let max_delta = 2.0 * u16::MAX as f32 * self.max_flashes_per_second * dt.as_secs_f32();
+
+        // Start from black when the frame size changes, so the first frame can't flash either
+        if self.state.len() != leds.len() {
+            self.state = vec![[0.0; 3]; leds.len()];
+        }
+
+        for (state, rgb) in self.state.iter_mut().zip(leds.iter_mut()) {
+            for (channel, value) in state.iter_mut().zip([rgb.r, rgb.g, rgb.b]) {
+                *channel += (value as f32 - *channel).clamp(-max_delta, max_delta);
+            }
+            let [r, g, b] = state.map(|channel| channel.round() as u16);
+            *rgb = Rgb16::new(r, g, b);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: Rgb16 = Rgb16::new(u16::MAX, u16::MAX, u16::MAX);
+
+    #[test]
+    fn caps_the_change_per_tick() {
+        let mut filter = ReducedFlashFilter::new(2.0);
+        // The first frame starts from black, and nothing can change before any time has passed
+        let mut leds = [WHITE];
+        filter.apply(&mut leds, Duration::ZERO);
+        assert_eq!(leds, [Rgb16::new(0, 0, 0)]);
+
+        // Two flashes a second allow 4 full swings a second, 2/5 of the range in 100ms
+        let mut leds = [WHITE];
+        filter.apply(&mut leds, Duration::from_millis(100));
+        assert_eq!(leds, [Rgb16::new(26214, 26214, 26214)]);
+
+        // Falling is capped the same way, changes within the cap go straight through
+        let mut leds = [Rgb16::new(0, 30000, 26214)];
+        filter.apply(&mut leds, Duration::from_millis(50));
+        assert_eq!(leds, [Rgb16::new(13107, 30000, 26214)]);
+    }
+
+    #[test]
+    fn starts_from_black_when_resized() {
+        let mut filter = ReducedFlashFilter::new(2.0);
+        let mut leds = [WHITE];
+        filter.apply(&mut leds, Duration::from_secs(1));
+        assert_eq!(leds, [WHITE]);
+
+        let mut leds = [WHITE, WHITE];
+        filter.apply(&mut leds, Duration::from_millis(100));
+        assert_eq!(leds, [Rgb16::new(26214, 26214, 26214); 2]);
+    }
+}
@@ -0,0 +1,470 @@
+use christmas_tree_client::MessageHandler;
+use clap::ValueEnum;
+use common::message::Rgb;
+#[cfg(feature = "audio")]
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+#[cfg(feature = "audio")]
+use cpal::{FromSample, SampleFormat, SizedSample};
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+use crate::bridge::FrameStream;
+use crate::config::Config;
+use crate::log_file::RotatingLog;
+use crate::{DEFAULT_NUM_LEDS, HANDSHAKE_TIMEOUT};
+
+/// Samples in each FFT, about 23 ms of audio at 44.1 kHz
+pub const FFT_SIZE: usize = 1024;
+
+/// Frequency bands the spectrum is split into
+pub const NUM_BANDS: usize = 16;
+
+/// Lowest and highest frequencies shown, in Hz
+const MIN_FREQUENCY: f32 = 40.0;
+const MAX_FREQUENCY: f32 = 16_000.0;
+
+/// Frequencies below which sound counts towards the beat, in Hz
+const BASS_CUTOFF: f32 = 150.0;
+
+/// Fraction of the loudest recent band that remains after a second, so quiet music still fills the tree
+const PEAK_DECAY_PER_SECOND: f32 = 0.5;
+
+/// Loudness below which the analysis treats the input as silence rather than amplifying noise
+const MIN_PEAK: f32 = 0.5;
+
+/// Analyses the beat detector compares against, about a second of audio at FRAME_RATE
+const BEAT_HISTORY: usize = 60;
+
+/// How far above its recent average the bass has to jump to count as a beat
+const BEAT_THRESHOLD: f32 = 1.5;
+
+/// Shortest time between beats, so one loud kick isn't seen as several
+const MIN_BEAT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Frames rendered per second
+const FRAME_RATE: u32 = 60;
+
+/// Fraction of the tree a bar falls per second once the sound drops
+const FALL_PER_SECOND: f32 = 1.5;
+
+/// Time for a pulse to fade to half its brightness
+const PULSE_HALF_LIFE: Duration = Duration::from_millis(150);
+
+/// Hue turned on each beat, as a fraction of the color wheel
+const PULSE_HUE_STEP: f32 = 0.15;
+
+/// Music visualization shown on the tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AudioEffect {
+    /// A level meter rising up the tree from the bottom, green through to red
+    Bars,
+    /// The whole tree flashing a new color on every beat
+    Pulse,
+    /// The spectrum along the strand, bass at the bottom and treble at the top
+    Spectrum,
+}
+
+/// What was heard in one window of audio
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    /// Loudness of each frequency band from the lowest, from 0 to 1 relative to the loudest recent band
+    pub bands: Vec<f32>,
+    /// Overall loudness, from 0 to 1
+    pub level: f32,
+    /// Whether a beat started
+    pub beat: bool,
+}
+
+/// Splits audio into frequency bands and finds its beats
+pub struct Analyzer {
+    fft: Arc<dyn Fft<f32>>,
+    /// Hann window applied before the FFT
+    window: Vec<f32>,
+    /// FFT bins summed into each band
+    bands: Vec<Range<usize>>,
+    /// FFT bins summed into the beat's energy
+    bass: Range<usize>,
+    /// Loudest recent band, decaying so the bands adapt to the volume
+    peak: f32,
+    beats: BeatDetector,
+}
+
+impl Analyzer {
+    pub fn new(sample_rate: u32) -> Self {
+        let window = (0..FFT_SIZE)
+            .map(|index| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * index as f32 / FFT_SIZE as f32).cos())
+            .collect();
+        let bin = |frequency: f32| ((frequency * FFT_SIZE as f32 / sample_rate as f32) as usize).clamp(1, FFT_SIZE / 2);
+        let max_frequency = MAX_FREQUENCY.min(sample_rate as f32 / 2.0);
+        let mut start = bin(MIN_FREQUENCY);
+        let bands = (1..=NUM_BANDS)
+            .map(|band| {
+                let frequency = MIN_FREQUENCY * (max_frequency / MIN_FREQUENCY).powf(band as f32 / NUM_BANDS as f32);
+                // Low bands are narrower than a bin, so each takes at least one
+                let end = bin(frequency).max(start + 1).min(FFT_SIZE / 2);
+                let range = start.min(end - 1)..end;
+                start = end;
+                range
+            })
+            .collect();
+        Analyzer {
+            fft: FftPlanner::new().plan_fft_forward(FFT_SIZE),
+            window,
+            bands,
+            bass: 1..bin(BASS_CUTOFF).max(2),
+            peak: MIN_PEAK,
+            beats: BeatDetector::new(),
+        }
+    }
+
+    /// Analyze the latest FFT_SIZE samples, dt after the previous analysis
+    pub fn analyze(&mut self, samples: &[f32], dt: Duration) -> Analysis {
+        let mut buffer: Vec<Complex<f32>> = samples
+            .iter()
+            .zip(&self.window)
+            .map(|(&sample, &window)| Complex::new(sample * window, 0.0))
+            .collect();
+        buffer.resize(FFT_SIZE, Complex::default());
+        self.fft.process(&mut buffer);
+        let magnitudes: Vec<f32> = buffer[..FFT_SIZE / 2].iter().map(|bin| bin.norm()).collect();
+
+        let mut bands: Vec<f32> = self
+            .bands
+            .iter()
+            .map(|range| magnitudes[range.clone()].iter().sum::<f32>() / range.len() as f32)
+            .collect();
+        let loudest = bands.iter().copied().fold(0.0, f32::max);
+        self.peak = (self.peak * PEAK_DECAY_PER_SECOND.powf(dt.as_secs_f32())).max(loudest).max(MIN_PEAK);
+        for band in &mut bands {
+            *band = (*band / self.peak).min(1.0);
+        }
+
+        let energy = magnitudes[self.bass.clone()].iter().map(|bin| bin * bin).sum();
+        Analysis {
+            level: bands.iter().sum::<f32>() / bands.len() as f32,
+            beat: self.beats.push(energy, dt),
+            bands,
+        }
+    }
+}
+
+/// Finds beats as jumps in bass energy above its recent average
+pub struct BeatDetector {
+    history: VecDeque<f32>,
+    since_beat: Duration,
+}
+
+impl BeatDetector {
+    pub fn new() -> Self {
+        BeatDetector {
+            history: VecDeque::with_capacity(BEAT_HISTORY),
+            since_beat: MIN_BEAT_INTERVAL,
+        }
+    }
+
+    /// Add the bass energy of the latest analysis, dt after the previous one
+    /// Returns whether it starts a beat
+    pub fn push(&mut self, energy: f32, dt: Duration) -> bool {
+        self.since_beat += dt;
+        // Don't guess at beats until there's a full history to compare against
+        let full = self.history.len() == BEAT_HISTORY;
+        let average = self.history.iter().sum::<f32>() / self.history.len().max(1) as f32;
+        if full {
+            self.history.pop_front();
+        }
+        self.history.push_back(energy);
+        let beat = full && energy > average * BEAT_THRESHOLD && self.since_beat >= MIN_BEAT_INTERVAL;
+        if beat {
+            self.since_beat = Duration::ZERO;
+        }
+        beat
+    }
+}
+
+/// Renders an audio effect from each analysis, easing bars down and fading pulses between them
+pub struct Visualizer {
+    effect: AudioEffect,
+    /// Height of each bar, falling no faster than FALL_PER_SECOND
+    heights: Vec<f32>,
+    /// Brightness of the current pulse
+    pulse: f32,
+    /// Hue of the current pulse, as a fraction of the color wheel
+    hue: f32,
+}
+
+impl Visualizer {
+    pub fn new(effect: AudioEffect) -> Self {
+        Visualizer {
+            effect,
+            heights: vec![0.0; NUM_BANDS],
+            pulse: 0.0,
+            hue: 0.0,
+        }
+    }
+
+    /// Render the latest analysis onto the LEDs, dt after the previous frame
+    pub fn render(&mut self, analysis: &Analysis, dt: Duration, leds: &mut [Rgb]) {
+        let fall = FALL_PER_SECOND * dt.as_secs_f32();
+        match self.effect {
+            AudioEffect::Bars => {
+                self.heights[0] = analysis.level.max(self.heights[0] - fall);
+                let lit = (self.heights[0] * leds.len() as f32).round() as usize;
+                let len = leds.len().max(1) as f32;
+                for (index, led) in leds.iter_mut().enumerate() {
+                    // Green at the bottom of the tree through yellow to red at the top
+                    *led = if index < lit { hue(1.0 / 3.0 * (1.0 - index as f32 / len), 1.0) } else { Rgb::default() };
+                }
+            }
+            AudioEffect::Pulse => {
+                if analysis.beat {
+                    self.pulse = 1.0;
+                    self.hue = (self.hue + PULSE_HUE_STEP).fract();
+                } else {
+                    self.pulse *= 0.5f32.powf(dt.as_secs_f32() / PULSE_HALF_LIFE.as_secs_f32());
+                }
+                leds.fill(hue(self.hue, self.pulse));
+            }
+            AudioEffect::Spectrum => {
+                for (height, &band) in self.heights.iter_mut().zip(&analysis.bands) {
+                    *height = band.max(*height - fall);
+                }
+                let len = leds.len();
+                for (index, led) in leds.iter_mut().enumerate() {
+                    let band = index * NUM_BANDS / len;
+                    // Red for the bass through to violet for the treble
+                    *led = hue(band as f32 / NUM_BANDS as f32 * 0.8, self.heights[band]);
+                }
+            }
+        }
+    }
+}
+
+/// Fully saturated color of a hue, as a fraction of the color wheel from red, at a brightness from 0 to 1
+fn hue(hue: f32, brightness: f32) -> Rgb {
+    let channel = |offset: f32| {
+        let distance = ((hue + offset).fract() * 6.0 - 3.0).abs();
+        ((distance - 1.0).clamp(0.0, 1.0) * brightness.clamp(0.0, 1.0) * 255.0).round() as u8
+    };
+    Rgb::new(channel(0.0), channel(2.0 / 3.0), channel(1.0 / 3.0))
+}
+
+/// An audio input's stream, which captures until it's dropped, its sample rate and its mono samples
+#[cfg(feature = "audio")]
+type Capture = (cpal::Stream, u32, Receiver<Vec<f32>>);
+
+/// Start capturing from the named audio input, or the default one
+#[cfg(feature = "audio")]
+pub fn capture(device: Option<&str>) -> Result<Capture, Box<dyn Error>> {
+    let host = cpal::default_host();
+    let device = match device {
+        Some(name) => host
+            .input_devices()?
+            .find(|device| device.name().is_ok_and(|device_name| device_name == name))
+            .ok_or_else(|| format!("No audio input named {}", name))?,
+        None => host.default_input_device().ok_or("No default audio input")?,
+    };
+    let config = device.default_input_config()?;
+    let sample_rate = config.sample_rate().0;
+    let (samples, received) = mpsc::channel();
+    let stream = match config.sample_format() {
+        SampleFormat::F32 => build_stream::<f32>(&device, &config.config(), samples)?,
+        SampleFormat::I16 => build_stream::<i16>(&device, &config.config(), samples)?,
+        SampleFormat::U16 => build_stream::<u16>(&device, &config.config(), samples)?,
+        format => return Err(format!("Unsupported audio sample format {}", format).into()),
+    };
+    stream.play()?;
+    Ok((stream, sample_rate, received))
+}
+
+/// Build an input stream sending the mix of each callback's channels
+#[cfg(feature = "audio")]
+fn build_stream<S: SizedSample>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    samples: Sender<Vec<f32>>,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    f32: FromSample<S>,
+{
+    let channels = config.channels.max(1) as usize;
+    device.build_input_stream(
+        config,
+        move |data: &[S], _: &cpal::InputCallbackInfo| {
+            let mono = data
+                .chunks(channels)
+                .map(|frame| frame.iter().map(|&sample| sample.to_sample::<f32>()).sum::<f32>() / channels as f32)
+                .collect();
+            let _ = samples.send(mono);
+        },
+        |e| eprintln!("Audio capture error ({})", e),
+        None,
+    )
+}
+
+/// Visualize the audio input on the tree until the process is stopped
+#[cfg(feature = "audio")]
+pub fn run(
+    effect: AudioEffect,
+    device: Option<&str>,
+    message_handler: &MessageHandler,
+    log_file: &mut RotatingLog,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let num_leds = match message_handler.device_info(HANDSHAKE_TIMEOUT) {
+        Ok(info) => config.num_leds.unwrap_or(info.num_leds as usize),
+        Err(e) => {
+            let num_leds = config.num_leds.unwrap_or(DEFAULT_NUM_LEDS);
+            eprintln!("Warning: failed to get device info ({}), assuming {} LEDs", e, num_leds);
+            num_leds
+        }
+    };
+    let (_capture, sample_rate, samples) = capture(device)?;
+    let line = format!("Showing {:?} of the audio input at {} Hz on {} LEDs", effect, sample_rate, num_leds);
+    println!("{}", line);
+    log_file.write_line("server", &line)?;
+
+    let mut analyzer = Analyzer::new(sample_rate);
+    let mut visualizer = Visualizer::new(effect);
+    let mut stream = FrameStream::new(message_handler, config);
+    let mut window: VecDeque<f32> = VecDeque::from(vec![0.0; FFT_SIZE]);
+    let mut leds = vec![Rgb::default(); num_leds];
+    let frame_interval = Duration::from_secs(1) / FRAME_RATE;
+    let mut last_frame = Instant::now();
+    loop {
+        match samples.recv_timeout(frame_interval.saturating_sub(last_frame.elapsed())) {
+            Ok(chunk) => {
+                // Only the latest FFT_SIZE samples are analyzed
+                window.extend(chunk);
+                let excess = window.len().saturating_sub(FFT_SIZE);
+                window.drain(..excess);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Err("Audio capture stopped".into()),
+        }
+        if last_frame.elapsed() >= frame_interval {
+            let dt = last_frame.elapsed();
+            last_frame = Instant::now();
+            let analysis = analyzer.analyze(window.make_contiguous(), dt);
+            visualizer.render(&analysis, dt, &mut leds);
+            stream.send(leds.clone())?;
+        }
+        stream.service(log_file)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 44_100;
+    const DT: Duration = Duration::from_millis(16);
+
+    fn tone(frequency: f32, amplitude: f32) -> Vec<f32> {
+        (0..FFT_SIZE)
+            .map(|index| amplitude * (2.0 * std::f32::consts::PI * frequency * index as f32 / SAMPLE_RATE as f32).sin())
+            .collect()
+    }
+
+    #[test]
+    fn tones_land_in_their_band() {
+        let mut analyzer = Analyzer::new(SAMPLE_RATE);
+        let low = analyzer.analyze(&tone(60.0, 1.0), DT);
+        assert_eq!(low.bands.len(), NUM_BANDS);
+        assert_eq!(low.bands.iter().position(|&band| band == 1.0), Some(0));
+
+        let high = analyzer.analyze(&tone(8_000.0, 1.0), DT);
+        let loudest = high.bands.iter().copied().fold(0.0, f32::max);
+        assert!(high.bands.iter().position(|&band| band == loudest).unwrap() >= NUM_BANDS - 3);
+        assert!(high.bands[0] < 0.1);
+    }
+
+    #[test]
+    fn silence_is_not_amplified() {
+        let mut analyzer = Analyzer::new(SAMPLE_RATE);
+        let analysis = analyzer.analyze(&tone(440.0, 0.0001), DT);
+        assert!(analysis.level < 0.01);
+        assert!(!analysis.beat);
+    }
+
+    #[test]
+    fn beats_are_jumps_above_the_recent_average() {
+        let mut detector = BeatDetector::new();
+        for _ in 0..BEAT_HISTORY {
+            assert!(!detector.push(1.0, DT));
+        }
+        assert!(!detector.push(1.2, DT));
+        assert!(detector.push(3.0, DT));
+        // Too soon after the last beat
+        assert!(!detector.push(5.0, DT));
+        for _ in 0..20 {
+            detector.push(1.0, DT);
+        }
+        assert!(detector.push(3.0, DT));
+    }
+
+    #[test]
+    fn bars_rise_with_the_level_and_fall_slowly() {
+        let mut visualizer = Visualizer::new(AudioEffect::Bars);
+        let mut leds = vec![Rgb::default(); 10];
+        let loud = Analysis {
+            bands: vec![0.5; NUM_BANDS],
+            level: 0.5,
+            beat: false,
+        };
+        visualizer.render(&loud, DT, &mut leds);
+        assert_eq!(leds.iter().filter(|&&led| led != Rgb::default()).count(), 5);
+        assert_eq!(leds[0], Rgb::new(0, 255, 0));
+
+        let quiet = Analysis {
+            level: 0.0,
+            ..loud
+        };
+        visualizer.render(&quiet, Duration::from_millis(200), &mut leds);
+        assert_eq!(leds.iter().filter(|&&led| led != Rgb::default()).count(), 2);
+    }
+
+    #[test]
+    fn pulses_change_color_on_beats_and_fade() {
+        let mut visualizer = Visualizer::new(AudioEffect::Pulse);
+        let mut leds = vec![Rgb::default(); 3];
+        let mut analysis = Analysis {
+            bands: vec![0.0; NUM_BANDS],
+            level: 0.0,
+            beat: true,
+        };
+        visualizer.render(&analysis, DT, &mut leds);
+        let flash = leds[0];
+        assert_eq!(leds, vec![flash; 3]);
+        assert_eq!(flash.r.max(flash.g).max(flash.b), 255);
+
+        analysis.beat = false;
+        visualizer.render(&analysis, PULSE_HALF_LIFE, &mut leds);
+        assert_eq!(leds[0].r.max(leds[0].g).max(leds[0].b), 128);
+
+        analysis.beat = true;
+        visualizer.render(&analysis, DT, &mut leds);
+        assert_ne!(leds[0], flash);
+    }
+
+    #[test]
+    fn spectrum_puts_the_bass_at_the_bottom() {
+        let mut visualizer = Visualizer::new(AudioEffect::Spectrum);
+        let mut leds = vec![Rgb::default(); NUM_BANDS * 2];
+        let mut bands = vec![0.0; NUM_BANDS];
+        bands[0] = 1.0;
+        let analysis = Analysis {
+            bands,
+            level: 1.0 / NUM_BANDS as f32,
+            beat: false,
+        };
+        visualizer.render(&analysis, DT, &mut leds);
+        assert_eq!(&leds[..2], &[Rgb::new(255, 0, 0); 2]);
+        assert!(leds[2..].iter().all(|&led| led == Rgb::default()));
+    }
+}
@@ -0,0 +1,281 @@
+use christmas_tree_client::{MessageError, MessageHandler};
+use common::message::{Message, Rgb};
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::flow::{DEFAULT_ACK_TIMEOUT, DEFAULT_WINDOW_SIZE, SendWindow};
+use crate::log_file::RotatingLog;
+use crate::{DEFAULT_NUM_LEDS, HANDSHAKE_TIMEOUT};
+
+/// Time after which a ping counts as lost
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Time between checks for the tree's answers, short so it barely adds to the measurements
+const POLL_INTERVAL: Duration = Duration::from_micros(200);
+
+/// First frame rate tried
+const FIRST_RATE: u32 = 10;
+
+/// Factor the frame rate grows by from one step to the next
+const RATE_STEP: f32 = 1.25;
+
+/// Fraction of a frame rate that has to be reached for it to count as sustained
+const MIN_ACHIEVED: f32 = 0.95;
+
+/// Spread of the round trips of the pings that were answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Latency {
+    min: Duration,
+    median: Duration,
+    p99: Duration,
+    max: Duration,
+}
+
+impl Latency {
+    fn of(round_trips: &mut [Duration]) -> Option<Self> {
+        if round_trips.is_empty() {
+            return None;
+        }
+        round_trips.sort();
+        let percentile = |percent: usize| round_trips[(round_trips.len() - 1) * percent / 100];
+        Some(Latency {
+            min: percentile(0),
+            median: percentile(50),
+            p99: percentile(99),
+            max: percentile(100),
+        })
+    }
+}
+
+/// Frames sent at one frame rate, and what the tree made of them
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Step {
+    fps: u32,
+    sent: u64,
+    acked: u64,
+    /// Times the firmware reported it was falling behind
+    busy: u32,
+    /// Frames actually sent per second, lower than fps once the link can't carry them
+    achieved: f32,
+}
+
+impl Step {
+    /// Frames the firmware rejected, skipped for a newer one or never acknowledged
+    fn dropped(&self) -> u64 {
+        self.sent - self.acked
+    }
+
+    /// Whether the link carried the frame rate and the firmware displayed every frame
+    fn sustained(&self) -> bool {
+        self.dropped() == 0 && self.busy == 0 && self.achieved >= self.fps as f32 * MIN_ACHIEVED
+    }
+}
+
+/// Frame rates tried, growing by RATE_STEP up to max_fps
+fn rates(max_fps: u32) -> Vec<u32> {
+    let mut rates = Vec::new();
+    let mut rate = FIRST_RATE as f32;
+    while (rate.round() as u32) < max_fps {
+        rates.push(rate.round() as u32);
+        rate *= RATE_STEP;
+    }
+    rates.push(max_fps);
+    rates
+}
+
+/// Measure the round trip to the firmware with timestamped pings, then raise the rate full frames are sent at
+/// until the firmware drops them, printing a summary of both
+pub fn run(
+    pings: u32,
+    step_secs: u64,
+    max_fps: u32,
+    baud_rate: u32,
+    message_handler: &MessageHandler,
+    log_file: &mut RotatingLog,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let num_leds = match message_handler.device_info(HANDSHAKE_TIMEOUT) {
+        Ok(info) => config.num_leds.unwrap_or(info.num_leds as usize),
+        Err(e) => {
+            let num_leds = config.num_leds.unwrap_or(DEFAULT_NUM_LEDS);
+            eprintln!("Warning: failed to get device info ({}), assuming {} LEDs", e, num_leds);
+            num_leds
+        }
+    };
+
+    println!("Sending {} pings...", pings);
+    let mut round_trips = Vec::new();
+    for _ in 0..pings {
+        round_trips.extend(ping(message_handler)?);
+    }
+    let lost = pings as usize - round_trips.len();
+    let line = match Latency::of(&mut round_trips) {
+        Some(latency) => format!(
+            "Round trip over {} pings: min {:?}, median {:?}, 99th percentile {:?}, max {:?}, {} lost",
+            pings, latency.min, latency.median, latency.p99, latency.max, lost
+        ),
+        None => format!("None of the {} pings were answered", pings),
+    };
+    println!("{}", line);
+    log_file.write_line("server", &line)?;
+
+    let frame_bytes = num_leds * 3;
+    println!(
+        "Sending frames of {} LEDs ({} bytes of LED data) for {} s at each rate...",
+        num_leds, frame_bytes, step_secs
+    );
+    let mut best = None;
+    for fps in rates(max_fps) {
+        let step = hold(message_handler, num_leds, fps, Duration::from_secs(step_secs))?;
+        let line = format!(
+            "{:>4} fps: {} sent at {:.1} fps, {} acknowledged, {} dropped, busy {} times",
+            fps,
+            step.sent,
+            step.achieved,
+            step.acked,
+            step.dropped(),
+            step.busy
+        );
+        println!("{}", line);
+        log_file.write_line("server", &line)?;
+        if !step.sustained() {
+            break;
+        }
+        best = Some(step);
+    }
+    message_handler.send_frame(0, vec![Rgb::default(); num_leds])?;
+
+    let line = match best {
+        Some(step) => {
+            let bytes_per_second = step.achieved * frame_bytes as f32;
+            // Each byte takes 10 bits on the wire, with its start and stop bits
+            let capacity = baud_rate as f32 / 10.0;
+            format!(
+                "Highest sustained frame rate: {} fps, {:.1} kB/s of LED data, {:.0}% of what {} baud carries",
+                step.fps,
+                bytes_per_second / 1000.0,
+                bytes_per_second / capacity * 100.0,
+                baud_rate
+            )
+        }
+        None => format!("The tree couldn't keep up with {} fps, the lowest rate tried", FIRST_RATE.min(max_fps)),
+    };
+    println!("{}", line);
+    log_file.write_line("server", &line)?;
+    Ok(())
+}
+
+/// Ask for the firmware's clock, returning how long it took to answer unless it never did
+fn ping(message_handler: &MessageHandler) -> Result<Option<Duration>, MessageError> {
+    let sent = Instant::now();
+    message_handler.send(&Message::GetTime)?;
+    loop {
+        match message_handler.try_receive()? {
+            Some(Message::Time(_)) => return Ok(Some(sent.elapsed())),
+            Some(_) => {}
+            None if sent.elapsed() >= PING_TIMEOUT => return Ok(None),
+            None => std::thread::sleep(POLL_INTERVAL),
+        }
+    }
+}
+
+/// Send full frames at fps for duration, then wait for the firmware to answer the last of them
+fn hold(
+    message_handler: &MessageHandler,
+    num_leds: usize,
+    fps: u32,
+    duration: Duration,
+) -> Result<Step, MessageError> {
+    // Paced by the frame rate rather than the window, which only tracks the frames in flight
+    let mut window = SendWindow::new(DEFAULT_WINDOW_SIZE, DEFAULT_ACK_TIMEOUT);
+    let interval = Duration::from_secs(1) / fps;
+    let mut sent = 0;
+    let mut busy = 0;
+    let start = Instant::now();
+    let mut next = start;
+    while start.elapsed() < duration {
+        if Instant::now() >= next {
+            // Dim, so the strip's power supply isn't what's being measured
+            let level = (sent % 64) as u8;
+            message_handler.send_frame(window.send(), vec![Rgb::new(level, level, level); num_leds])?;
+            sent += 1;
+            // Frames the link was too slow for are skipped rather than sent in a burst
+            next = (next + interval).max(Instant::now());
+        }
+        busy += settle(message_handler, &mut window)?;
+        std::thread::sleep(next.saturating_duration_since(Instant::now()).min(POLL_INTERVAL));
+    }
+    let achieved = sent as f32 / start.elapsed().as_secs_f32();
+
+    let deadline = Instant::now() + DEFAULT_ACK_TIMEOUT;
+    while window.in_flight() > 0 && Instant::now() < deadline {
+        busy += settle(message_handler, &mut window)?;
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    Ok(Step {
+        fps,
+        sent,
+        acked: window.acked(),
+        busy,
+        achieved,
+    })
+}
+
+/// Handle the firmware's answers to frames, returning how many times it reported being busy
+fn settle(message_handler: &MessageHandler, window: &mut SendWindow) -> Result<u32, MessageError> {
+    let mut busy = 0;
+    while let Some(message) = message_handler.try_receive()? {
+        match message {
+            Message::Ack(ack) => {
+                window.ack(ack.seq);
+            }
+            Message::Nack(nack) => {
+                if let Some(seq) = nack.seq {
+                    window.nack(seq);
+                }
+            }
+            Message::Busy => busy += 1,
+            _ => {}
+        }
+    }
+    Ok(busy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_grow_up_to_the_max() {
+        assert_eq!(rates(60), vec![10, 13, 16, 20, 24, 31, 38, 48, 60]);
+        assert_eq!(rates(5), vec![5]);
+    }
+
+    #[test]
+    fn latency_percentiles() {
+        let mut round_trips: Vec<Duration> = (1..=100).rev().map(Duration::from_millis).collect();
+        let latency = Latency::of(&mut round_trips).unwrap();
+        assert_eq!(latency.min, Duration::from_millis(1));
+        assert_eq!(latency.median, Duration::from_millis(50));
+        assert_eq!(latency.p99, Duration::from_millis(99));
+        assert_eq!(latency.max, Duration::from_millis(100));
+        assert_eq!(Latency::of(&mut []), None);
+    }
+
+    #[test]
+    fn steps_are_sustained_without_drops_at_the_rate() {
+        let step = Step {
+            fps: 60,
+            sent: 180,
+            acked: 180,
+            busy: 0,
+            achieved: 59.5,
+        };
+        assert!(step.sustained());
+        assert!(!Step { acked: 179, ..step }.sustained());
+        assert!(!Step { busy: 1, ..step }.sustained());
+        // The link couldn't carry the frames fast enough
+        assert!(!Step { achieved: 50.0, ..step }.sustained());
+    }
+}
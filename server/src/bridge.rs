@@ -0,0 +1,526 @@
+use christmas_tree_client::MessageHandler;
+use clap::ValueEnum;
+use common::artnet::{self, ARTNET_PORT, PORTS_PER_REPLY, PollReply};
+use common::ddp::{self, DDP_PORT};
+use common::e131::{self, E131_PORT, PIXELS_PER_UNIVERSE};
+use common::opc::{self, BROADCAST_CHANNEL, OPC_PORT};
+use common::message::{Message, Rgb};
+use std::error::Error;
+use std::io::{ErrorKind, Read};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, UniverseMapping};
+use crate::flow::{DEFAULT_ACK_TIMEOUT, DEFAULT_WINDOW_SIZE, SendWindow};
+use crate::log_file::RotatingLog;
+use crate::logs::{self, LogReorderBuffer};
+use crate::pipeline::OutputPipeline;
+use crate::sinks::Sinks;
+use crate::{DEFAULT_NUM_LEDS, HANDSHAKE_TIMEOUT};
+
+/// How long a frame waits for its remaining universes before it's sent with what has arrived,
+/// for sources that skip universes that didn't change
+const FRAME_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Time the socket waits for a packet before the link is serviced
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Time between heartbeats keeping the link up
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Largest packet read from the network
+const MAX_PACKET_SIZE: usize = 1500;
+
+/// Network protocol bridged onto the serial link
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Protocol {
+    /// E1.31 (sACN) universes, received on their multicast groups and by unicast
+    Sacn,
+    /// Art-Net universes, mapped by port-address, answering ArtPoll so the tree appears in node lists
+    Artnet,
+    /// DDP pixel data, written to the LEDs from its byte offset and displayed on push
+    Ddp,
+    /// Open Pixel Control clients connecting over TCP, their broadcast channel and channel 1 lighting the LEDs
+    Opc,
+}
+
+/// Where bridged frames come from
+enum Source {
+    /// Packets of a UDP protocol
+    Udp(UdpSocket),
+    /// Frames from the connected OPC clients
+    Opc(Receiver<Vec<Rgb>>),
+}
+
+/// Where the channels of each universe land on the tree's LEDs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UniverseMap {
+    mappings: Vec<UniverseMapping>,
+}
+
+impl UniverseMap {
+    /// Map the configured universes onto num_leds LEDs, or without any, consecutive universes from
+    /// first_universe filled with PIXELS_PER_UNIVERSE LEDs each
+    pub fn new(mappings: &[UniverseMapping], first_universe: u16, num_leds: usize) -> Self {
+        let mappings = if mappings.is_empty() {
+            (0..num_leds.div_ceil(PIXELS_PER_UNIVERSE))
+                .map(|index| UniverseMapping {
+                    universe: first_universe.saturating_add(index as u16),
+                    start_led: index * PIXELS_PER_UNIVERSE,
+                    leds: PIXELS_PER_UNIVERSE.min(num_leds - index * PIXELS_PER_UNIVERSE),
+                    channel: 1,
+                })
+                .collect()
+        } else {
+            mappings
+                .iter()
+                .filter(|mapping| mapping.start_led < num_leds)
+                .map(|mapping| UniverseMapping {
+                    leds: mapping.leds.min(num_leds - mapping.start_led),
+                    ..*mapping
+                })
+                .collect()
+        };
+        UniverseMap { mappings }
+    }
+
+    /// Universes with LEDs mapped to them, in ascending order
+    pub fn universes(&self) -> Vec<u16> {
+        let mut universes: Vec<u16> = self.mappings.iter().map(|mapping| mapping.universe).collect();
+        universes.sort_unstable();
+        universes.dedup();
+        universes
+    }
+
+    /// Copy a universe's channel data onto the LEDs mapped to it
+    /// Returns whether any LEDs are mapped to the universe
+    pub fn apply(&self, universe: u16, data: &[u8], leds: &mut [Rgb]) -> bool {
+        let mut mapped = false;
+        for mapping in self.mappings.iter().filter(|mapping| mapping.universe == universe) {
+            mapped = true;
+            let channels = data.get(mapping.channel.saturating_sub(1) as usize..).unwrap_or_default();
+            let targets = leds.iter_mut().skip(mapping.start_led).take(mapping.leds);
+            for (led, rgb) in targets.zip(channels.chunks_exact(3)) {
+                *led = Rgb::new(rgb[0], rgb[1], rgb[2]);
+            }
+        }
+        mapped
+    }
+}
+
+/// Collects the universes of a frame until each has arrived or the frame times out
+pub struct FrameAssembler {
+    map: UniverseMap,
+    universes: Vec<u16>,
+    leds: Vec<Rgb>,
+    received: Vec<bool>,
+    /// When the first universe of the frame arrived
+    started: Option<Instant>,
+}
+
+impl FrameAssembler {
+    pub fn new(map: UniverseMap, num_leds: usize) -> Self {
+        let universes = map.universes();
+        FrameAssembler {
+            received: vec![false; universes.len()],
+            map,
+            universes,
+            leds: vec![Rgb::default(); num_leds],
+            started: None,
+        }
+    }
+
+    /// Add a universe's channel data, returning the frame once every mapped universe has arrived
+    pub fn push(&mut self, universe: u16, data: &[u8], now: Instant) -> Option<Vec<Rgb>> {
+        let index = self.universes.binary_search(&universe).ok()?;
+        self.map.apply(universe, data, &mut self.leds);
+        self.received[index] = true;
+        self.started.get_or_insert(now);
+        self.received.iter().all(|&received| received).then(|| self.finish())
+    }
+
+    /// The frame with what has arrived, once it has waited FRAME_TIMEOUT for its remaining universes
+    pub fn poll(&mut self, now: Instant) -> Option<Vec<Rgb>> {
+        let started = self.started?;
+        (now.duration_since(started) >= FRAME_TIMEOUT).then(|| self.finish())
+    }
+
+    /// Start the next frame, keeping the LEDs of universes that don't arrive in it
+    fn finish(&mut self) -> Vec<Rgb> {
+        self.received.fill(false);
+        self.started = None;
+        self.leds.clone()
+    }
+}
+
+/// Listen for a lighting protocol on the network and forward its frames to the tree until the process is stopped
+pub fn run(
+    protocol: Protocol,
+    message_handler: &MessageHandler,
+    log_file: &mut RotatingLog,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let num_leds = match message_handler.device_info(HANDSHAKE_TIMEOUT) {
+        Ok(info) => config.num_leds.unwrap_or(info.num_leds as usize),
+        Err(e) => {
+            let num_leds = config.num_leds.unwrap_or(DEFAULT_NUM_LEDS);
+            eprintln!("Warning: failed to get device info ({}), assuming {} LEDs", e, num_leds);
+            num_leds
+        }
+    };
+    let map = UniverseMap::new(&config.universes, config.first_universe, num_leds);
+    let universes = map.universes();
+    let mut assembler = FrameAssembler::new(map, num_leds);
+
+    let udp = |port: u16| -> std::io::Result<UdpSocket> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        Ok(socket)
+    };
+    let source = match protocol {
+        Protocol::Sacn => {
+            let socket = udp(E131_PORT)?;
+            for &universe in &universes {
+                let [a, b, c, d] = e131::multicast_group(universe);
+                if let Err(e) = socket.join_multicast_v4(&Ipv4Addr::new(a, b, c, d), &Ipv4Addr::UNSPECIFIED) {
+                    eprintln!("Warning: failed to join the multicast group of sACN universe {} ({})", universe, e);
+                }
+            }
+            Source::Udp(socket)
+        }
+        Protocol::Artnet => {
+            let socket = udp(ARTNET_PORT)?;
+            socket.set_broadcast(true)?;
+            Source::Udp(socket)
+        }
+        Protocol::Ddp => Source::Udp(udp(DDP_PORT)?),
+        Protocol::Opc => {
+            let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, OPC_PORT))?;
+            let (frames, received) = mpsc::channel();
+            std::thread::spawn(move || accept_opc(listener, frames, num_leds));
+            Source::Opc(received)
+        }
+    };
+    let line = match protocol {
+        Protocol::Ddp | Protocol::Opc => format!("Bridging {:?} onto {} LEDs", protocol, num_leds),
+        _ => format!("Bridging {:?} universes {:?} onto {} LEDs", protocol, universes, num_leds),
+    };
+    println!("{}", line);
+    log_file.write_line("server", &line)?;
+
+    let mut stream = FrameStream::new(message_handler, config);
+    let mut packet = [0u8; MAX_PACKET_SIZE];
+    // DDP writes pixels straight to the LEDs rather than through universes
+    let mut ddp_leds = vec![Rgb::default(); num_leds];
+    loop {
+        let frame = match &source {
+            Source::Udp(socket) => match socket.recv_from(&mut packet) {
+                Ok((len, sender)) => match protocol {
+                    Protocol::Sacn => e131::parse(&packet[..len])
+                        .filter(|data| !data.terminated)
+                        .and_then(|data| assembler.push(data.universe, data.data, Instant::now())),
+                    Protocol::Artnet => match artnet::parse(&packet[..len]) {
+                        Some(artnet::Packet::Dmx(dmx)) => assembler.push(dmx.port_address, dmx.data, Instant::now()),
+                        Some(artnet::Packet::Poll) => {
+                            if let Err(e) = answer_poll(socket, sender, &universes, num_leds) {
+                                eprintln!("Failed to answer ArtPoll from {} ({})", sender, e);
+                            }
+                            None
+                        }
+                        None => None,
+                    },
+                    Protocol::Ddp => ddp::parse(&packet[..len]).and_then(|data| {
+                        write_pixels(&mut ddp_leds, data.offset as usize, data.data);
+                        data.push.then(|| ddp_leds.clone())
+                    }),
+                    Protocol::Opc => None,
+                },
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => None,
+                Err(e) => return Err(e.into()),
+            },
+            // Only the latest of the frames that arrived since the last poll is worth sending
+            Source::Opc(frames) => match frames.recv_timeout(POLL_INTERVAL) {
+                Ok(leds) => Some(frames.try_iter().last().unwrap_or(leds)),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return Err("The OPC listener stopped".into()),
+            },
+        };
+        if let Some(leds) = frame.or_else(|| assembler.poll(Instant::now())) {
+            stream.send(leds)?;
+        }
+        stream.service(log_file)?;
+    }
+}
+
+/// Sends frames produced outside the tree as fast as the link takes them, keeping the link up meanwhile
+pub struct FrameStream<'a> {
+    message_handler: &'a MessageHandler,
+    flow_control: bool,
+    window: SendWindow,
+    firmware_logs: LogReorderBuffer,
+    last_heartbeat: Instant,
+    /// Frames dropped since the last heartbeat because the tree couldn't keep up
+    dropped: u64,
+    pipeline: OutputPipeline,
+    sinks: Sinks,
+    /// Lines about sinks failing or recovering, written out when the link is serviced
+    sink_events: Vec<String>,
+}
+
+impl<'a> FrameStream<'a> {
+    /// Stream frames to the tree through the config's output filters, copying them to the config's sinks
+    pub fn new(message_handler: &'a MessageHandler, config: &Config) -> Self {
+        FrameStream {
+            message_handler,
+            flow_control: config.flow_control,
+            window: SendWindow::new(DEFAULT_WINDOW_SIZE, DEFAULT_ACK_TIMEOUT),
+            firmware_logs: LogReorderBuffer::new(),
+            last_heartbeat: Instant::now(),
+            dropped: 0,
+            pipeline: OutputPipeline::from_config(0, config),
+            sinks: Sinks::open(&config.output_sinks()),
+            sink_events: Vec::new(),
+        }
+    }
+
+    /// Send a frame, or drop it if the tree reported Busy or flow control is on and the tree is still busy with earlier
+    /// frames
+    /// Sinks are sent every frame, whether or not the tree can keep up
+    pub fn send(&mut self, leds: Vec<Rgb>) -> Result<(), Box<dyn Error>> {
+        let leds = self.pipeline.apply(leds);
+        self.sink_events.extend(self.sinks.send(&leds));
+        if !self.window.is_paused() && (!self.flow_control || self.window.can_send()) {
+            self.message_handler.send_frame(self.window.send(), leds)?;
+        } else {
+            self.dropped += 1;
+        }
+        Ok(())
+    }
+
+    /// Handle the tree's messages, printing its logs, and send a heartbeat when one is due
+    pub fn service(&mut self, log_file: &mut RotatingLog) -> Result<(), Box<dyn Error>> {
+        while let Some(message) = self.message_handler.try_receive()? {
+            match message {
+                Message::Ack(ack) => {
+                    self.window.ack(ack.seq);
+                }
+                Message::Nack(nack) => {
+                    if let Some(seq) = nack.seq {
+                        self.window.nack(seq);
+                    }
+                }
+                Message::Busy => self.window.pause(),
+                Message::Ready => self.window.resume(),
+                Message::Log(payload) => self.firmware_logs.push(payload),
+                _ => {}
+            }
+        }
+        for payload in self.firmware_logs.drain_ready() {
+            let line = logs::render(&payload);
+            println!("{}", line);
+            log_file.write_line("firmware", &line)?;
+        }
+        for line in self.sink_events.drain(..) {
+            eprintln!("{}", line);
+            log_file.write_line("server", &line)?;
+        }
+
+        if self.last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL {
+            self.last_heartbeat = Instant::now();
+            self.message_handler.send(&Message::Heartbeat)?;
+            if self.dropped > 0 {
+                println!("Dropped {} frames the tree couldn't keep up with", self.dropped);
+                self.dropped = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write RGB bytes to the LEDs from a byte offset, dropping those past the last LED
+fn write_pixels(leds: &mut [Rgb], offset: usize, data: &[u8]) {
+    for (position, &value) in (offset..).zip(data) {
+        let Some(led) = leds.get_mut(position / 3) else {
+            break;
+        };
+        match position % 3 {
+            0 => led.r = value,
+            1 => led.g = value,
+            _ => led.b = value,
+        }
+    }
+}
+
+/// Accept OPC clients, forwarding the frames each of them sends
+fn accept_opc(listener: TcpListener, frames: Sender<Vec<Rgb>>, num_leds: usize) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let frames = frames.clone();
+                std::thread::spawn(move || serve_opc_client(stream, frames, num_leds));
+            }
+            Err(e) => eprintln!("Failed to accept an OPC client ({})", e),
+        }
+    }
+}
+
+/// Forward the frames an OPC client sends until it disconnects
+fn serve_opc_client(mut stream: TcpStream, frames: Sender<Vec<Rgb>>, num_leds: usize) {
+    let peer = stream.peer_addr().map(|peer| peer.to_string()).unwrap_or_default();
+    println!("OPC client {} connected", peer);
+    let mut leds = vec![Rgb::default(); num_leds];
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; MAX_PACKET_SIZE];
+    loop {
+        let len = match stream.read(&mut chunk) {
+            Ok(0) | Err(_) => break,
+            Ok(len) => len,
+        };
+        buffer.extend_from_slice(&chunk[..len]);
+        let mut consumed = 0;
+        while let Some((message, size)) = opc::parse(&buffer[consumed..]) {
+            consumed += size;
+            if let Some(pixels) = message.pixels()
+                && matches!(message.channel, BROADCAST_CHANNEL | 1)
+            {
+                write_pixels(&mut leds, 0, pixels);
+                if frames.send(leds.clone()).is_err() {
+                    return;
+                }
+            }
+        }
+        buffer.drain(..consumed);
+    }
+    println!("OPC client {} disconnected", peer);
+}
+
+/// Answer an ArtPoll with a reply for each group of universes sharing a net and sub-net
+fn answer_poll(socket: &UdpSocket, controller: SocketAddr, universes: &[u16], num_leds: usize) -> std::io::Result<()> {
+    let ip = match local_ip(controller)? {
+        IpAddr::V4(ip) => ip.octets(),
+        IpAddr::V6(_) => return Ok(()),
+    };
+    let long_name = format!("christmas-tree-rs bridge driving {} LEDs", num_leds);
+    for (index, ports) in poll_reply_ports(universes).iter().enumerate() {
+        let reply = PollReply {
+            ip,
+            short_name: "Christmas tree",
+            long_name: &long_name,
+            port_addresses: ports,
+            bind_index: index as u8 + 1,
+        };
+        socket.send_to(&reply.encode(), (controller.ip(), ARTNET_PORT))?;
+    }
+    Ok(())
+}
+
+/// Port-addresses described by each ArtPollReply, at most PORTS_PER_REPLY sharing a net and sub-net in each
+fn poll_reply_ports(universes: &[u16]) -> Vec<Vec<u16>> {
+    let mut replies: Vec<Vec<u16>> = Vec::new();
+    for &universe in universes {
+        match replies.last_mut() {
+            Some(ports) if ports.len() < PORTS_PER_REPLY && ports[0] >> 4 == universe >> 4 => ports.push(universe),
+            _ => replies.push(vec![universe]),
+        }
+    }
+    replies
+}
+
+/// Address of the interface packets to a controller leave from
+fn local_ip(controller: SocketAddr) -> std::io::Result<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(controller)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(universe: u16, start_led: usize, leds: usize, channel: u16) -> UniverseMapping {
+        UniverseMapping {
+            universe,
+            start_led,
+            leds,
+            channel,
+        }
+    }
+
+    #[test]
+    fn default_map_fills_consecutive_universes() {
+        let map = UniverseMap::new(&[], 1, 400);
+        assert_eq!(map.universes(), vec![1, 2, 3]);
+        assert_eq!(map.mappings[2], mapping(3, 340, 60, 1));
+    }
+
+    #[test]
+    fn configured_map_places_channels_on_leds() {
+        let map = UniverseMap::new(&[mapping(7, 2, 2, 4)], 1, 10);
+        let mut leds = vec![Rgb::default(); 10];
+        assert!(map.apply(7, &[0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9], &mut leds));
+        assert_eq!(&leds[1..5], &[Rgb::default(), Rgb::new(1, 2, 3), Rgb::new(4, 5, 6), Rgb::default()]);
+        assert!(!map.apply(8, &[255; 12], &mut leds));
+    }
+
+    #[test]
+    fn mappings_are_clipped_to_the_tree() {
+        let map = UniverseMap::new(&[mapping(1, 8, 170, 1), mapping(2, 20, 170, 1)], 1, 10);
+        assert_eq!(map.universes(), vec![1]);
+        assert_eq!(map.mappings[0].leds, 2);
+    }
+
+    #[test]
+    fn pixels_are_written_from_byte_offsets() {
+        let mut leds = vec![Rgb::default(); 3];
+        write_pixels(&mut leds, 4, &[1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(leds, vec![Rgb::default(), Rgb::new(0, 1, 2), Rgb::new(3, 4, 5)]);
+    }
+
+    #[test]
+    fn opc_clients_send_frames() {
+        use std::io::Write;
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (frames, received) = mpsc::channel();
+        std::thread::spawn(move || accept_opc(listener, frames, 2));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(&[2, 0, 0, 3, 9, 9, 9]).unwrap();
+        client.write_all(&[0, 0, 0, 3, 1, 2, 3, 1, 0]).unwrap();
+        client.write_all(&[0, 3, 4, 5, 6]).unwrap();
+        let timeout = Duration::from_secs(1);
+        assert_eq!(received.recv_timeout(timeout).unwrap(), vec![Rgb::new(1, 2, 3), Rgb::default()]);
+        assert_eq!(received.recv_timeout(timeout).unwrap(), vec![Rgb::new(4, 5, 6), Rgb::default()]);
+    }
+
+    #[test]
+    fn poll_replies_group_ports_by_sub_net() {
+        let replies = poll_reply_ports(&[0, 1, 2, 3, 4, 15, 16, 0x0110]);
+        assert_eq!(replies, vec![vec![0, 1, 2, 3], vec![4, 15], vec![16], vec![0x0110]]);
+    }
+
+    #[test]
+    fn assembler_waits_for_every_universe() {
+        let start = Instant::now();
+        let mut assembler = FrameAssembler::new(UniverseMap::new(&[], 1, 200), 200);
+        assert_eq!(assembler.push(1, &[10; 510], start), None);
+        assert_eq!(assembler.push(9, &[10; 510], start), None);
+        let frame = assembler.push(2, &[20; 90], start).unwrap();
+        assert_eq!(frame[0], Rgb::new(10, 10, 10));
+        assert_eq!(frame[199], Rgb::new(20, 20, 20));
+    }
+
+    #[test]
+    fn assembler_sends_partial_frames_after_a_timeout() {
+        let start = Instant::now();
+        let mut assembler = FrameAssembler::new(UniverseMap::new(&[], 1, 200), 200);
+        assert_eq!(assembler.poll(start + FRAME_TIMEOUT), None);
+        assembler.push(2, &[20; 90], start);
+        assert_eq!(assembler.poll(start + FRAME_TIMEOUT / 2), None);
+        let frame = assembler.poll(start + FRAME_TIMEOUT).unwrap();
+        assert_eq!(frame[0], Rgb::default());
+        assert_eq!(frame[199], Rgb::new(20, 20, 20));
+    }
+}
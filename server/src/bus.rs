@@ -0,0 +1,207 @@
+use christmas_tree_client::{MessageError, MessageHandler, Transport};
+use common::message::{Message, Rgb, StartEffectPayload};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+use crate::pipeline::OutputPipeline;
+
+/// How much a source matters, the tree showing the highest priority source that's active
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Shown only when nothing else is
+    Idle,
+    /// Colors, effects and presets set for the tree to show from then on
+    Scheduled,
+    /// Streams and sequences
+    Live,
+    /// Brief overlays such as a doorbell flash
+    Notification,
+}
+
+/// What a source shows on the tree
+#[derive(Debug, Clone, PartialEq)]
+pub enum Scene {
+    Frame(Vec<Rgb>),
+    /// A built-in effect the firmware animates
+    Effect(StartEffectPayload),
+}
+
+impl Scene {
+    /// Show the scene on the tree, a frame being numbered with seq and sent through the pipeline
+    pub fn show<T: Transport>(
+        self,
+        tree: &MessageHandler<T>,
+        seq: u32,
+        pipeline: &mut OutputPipeline,
+    ) -> Result<(), MessageError> {
+        match self {
+            Scene::Frame(leds) => tree.send_frame(seq, pipeline.apply(leds)),
+            Scene::Effect(payload) => tree.send(&Message::StartEffect(payload)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Source {
+    name: String,
+    priority: Priority,
+    /// What the source last published
+    scene: Scene,
+    /// When the source is released if it doesn't publish again
+    expires: Option<Instant>,
+}
+
+/// Decides which of the sources publishing scenes the tree shows
+///
+/// The highest priority source wins, the one that published last among sources of the same priority. When it's
+/// released or expires the tree falls back to the next source's last scene.
+#[derive(Debug, Default)]
+pub struct FrameBus {
+    /// Sources from the least to the most recent to publish
+    sources: Vec<Source>,
+    /// Name of the source the tree shows
+    shown: Option<String>,
+}
+
+impl FrameBus {
+    /// Publish a scene from a source, replacing the source's last scene and priority
+    ///
+    /// A source with a hold is released if it doesn't publish again within it.
+    /// Returns the scene the tree should now show, None if it should keep showing what it does.
+    pub fn publish(
+        &mut self,
+        name: &str,
+        priority: Priority,
+        scene: Scene,
+        hold: Option<Duration>,
+        now: Instant,
+    ) -> Option<Scene> {
+        self.sources.retain(|source| source.name != name);
+        self.sources.push(Source {
+            name: name.to_string(),
+            priority,
+            scene,
+            expires: hold.map(|hold| now + hold),
+        });
+        self.expire(now);
+        self.settle(Some(name))
+    }
+
+    /// Stop showing a source, returning the scene of the source the tree falls back to if it was shown
+    pub fn release(&mut self, name: &str) -> Option<Scene> {
+        self.sources.retain(|source| source.name != name);
+        self.settle(None)
+    }
+
+    /// Release the sources whose hold has run out, returning the scene the tree falls back to if one was shown
+    pub fn release_expired(&mut self, now: Instant) -> Option<Scene> {
+        self.expire(now);
+        self.settle(None)
+    }
+
+    /// Name and priority of each source, the one the tree shows first
+    pub fn sources(&self) -> Vec<(&str, Priority)> {
+        let mut sources: Vec<(&str, Priority)> = self
+            .sources
+            .iter()
+            .rev()
+            .map(|source| (source.name.as_str(), source.priority))
+            .collect();
+        // Stable, so sources of the same priority stay most recent first
+        sources.sort_by_key(|source| std::cmp::Reverse(source.1));
+        sources
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.sources
+            .retain(|source| source.expires.is_none_or(|expires| expires > now));
+    }
+
+    /// Pick the source to show, returning its scene if the tree should be sent it
+    fn settle(&mut self, published: Option<&str>) -> Option<Scene> {
+        // max_by_key picks the last of equal sources, the most recent to publish
+        let Some(winner) = self.sources.iter().max_by_key(|source| source.priority) else {
+            self.shown = None;
+            return None;
+        };
+        if self.shown.as_deref() == Some(winner.name.as_str()) && published != Some(winner.name.as_str()) {
+            return None;
+        }
+        self.shown = Some(winner.name.clone());
+        Some(winner.scene.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::message::Effect;
+
+    fn frame(value: u8) -> Scene {
+        Scene::Frame(vec![Rgb::new(value, value, value)])
+    }
+
+    #[test]
+    fn higher_priorities_override_and_fall_back() {
+        let now = Instant::now();
+        let mut bus = FrameBus::default();
+        let ambient = Scene::Effect(StartEffectPayload {
+            effect: Effect::Twinkle,
+            speed: 100,
+            palette: Vec::new(),
+        });
+        assert_eq!(
+            bus.publish("ambient", Priority::Scheduled, ambient.clone(), None, now),
+            Some(ambient.clone())
+        );
+        assert_eq!(
+            bus.publish("doorbell", Priority::Notification, frame(255), None, now),
+            Some(frame(255))
+        );
+        // Lower priorities are kept for later without reaching the tree
+        assert_eq!(bus.publish("stream", Priority::Live, frame(1), None, now), None);
+        assert_eq!(bus.publish("stream", Priority::Live, frame(2), None, now), None);
+
+        assert_eq!(bus.release("doorbell"), Some(frame(2)));
+        assert_eq!(bus.release("stream"), Some(ambient));
+        assert_eq!(bus.release("ambient"), None);
+        assert!(bus.sources().is_empty());
+    }
+
+    #[test]
+    fn the_latest_of_equal_priorities_wins() {
+        let now = Instant::now();
+        let mut bus = FrameBus::default();
+        bus.publish("color", Priority::Scheduled, frame(1), None, now);
+        assert_eq!(
+            bus.publish("frame", Priority::Scheduled, frame(2), None, now),
+            Some(frame(2))
+        );
+        assert_eq!(
+            bus.publish("color", Priority::Scheduled, frame(3), None, now),
+            Some(frame(3))
+        );
+        assert_eq!(
+            bus.sources(),
+            vec![("color", Priority::Scheduled), ("frame", Priority::Scheduled)]
+        );
+        // Releasing a source that isn't shown leaves the tree alone
+        assert_eq!(bus.release("frame"), None);
+    }
+
+    #[test]
+    fn held_sources_expire() {
+        let now = Instant::now();
+        let mut bus = FrameBus::default();
+        bus.publish("idle", Priority::Idle, frame(0), None, now);
+        let hold = Some(Duration::from_secs(5));
+        assert_eq!(
+            bus.publish("timer", Priority::Notification, frame(9), hold, now),
+            Some(frame(9))
+        );
+        assert_eq!(bus.release_expired(now + Duration::from_secs(4)), None);
+        assert_eq!(bus.release_expired(now + Duration::from_secs(5)), Some(frame(0)));
+        assert_eq!(bus.sources(), vec![("idle", Priority::Idle)]);
+    }
+}
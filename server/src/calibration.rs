@@ -0,0 +1,301 @@
+use christmas_tree_client::{MessageError, MessageHandler};
+use common::message::{Message, Rgb, next_host_seq};
+#[cfg(feature = "camera")]
+use nokhwa::Camera;
+#[cfg(feature = "camera")]
+use nokhwa::pixel_format::RgbFormat;
+#[cfg(feature = "camera")]
+use nokhwa::utils::{CameraIndex, RequestedFormat, RequestedFormatType};
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::log_file::RotatingLog;
+use crate::mapping::{Point3, TreeMap};
+use crate::pipeline::OutputPipeline;
+use crate::{DEFAULT_NUM_LEDS, HANDSHAKE_TIMEOUT};
+
+/// Color each LED is lit in while it's photographed
+const LIT: Rgb = Rgb::new(255, 255, 255);
+
+/// Time for the camera's exposure to settle once the firmware has displayed a frame
+const SETTLE_TIME: Duration = Duration::from_millis(100);
+
+/// Frames the camera may have buffered from before the LEDs changed, discarded before each photo
+const STALE_FRAMES: usize = 2;
+
+/// Smallest rise in brightness over the dark tree that counts as an LED being seen
+const MIN_CONTRAST: u8 = 40;
+
+/// Distance in pixels from the brightest point of a lit LED that its glow is averaged over
+const SPOT_RADIUS: usize = 12;
+
+/// Grayscale photo of the tree
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub luma: Vec<u8>,
+}
+
+impl Image {
+    /// Convert a photo with 3 bytes of RGB per pixel, row by row
+    pub fn from_rgb(width: usize, height: usize, rgb: &[u8]) -> Image {
+        let luma = rgb
+            .chunks_exact(3)
+            .map(|pixel| ((pixel[0] as u32 * 299 + pixel[1] as u32 * 587 + pixel[2] as u32 * 114) / 1000) as u8)
+            .collect();
+        Image { width, height, luma }
+    }
+}
+
+/// Where an LED lit on its own appears in a photo, compared to one of the dark tree, in pixels from the top left
+/// corner. None when the LED can't be seen, such as when it's behind the tree.
+pub fn locate(dark: &Image, lit: &Image) -> Option<(f32, f32)> {
+    let rise = |index: usize| lit.luma[index].saturating_sub(dark.luma.get(index).copied().unwrap_or(0));
+    let (peak, brightest) = (0..lit.luma.len()).map(|index| (rise(index), index)).max()?;
+    if peak < MIN_CONTRAST {
+        return None;
+    }
+    // The middle of the LED's glow, weighted by how bright it is, is steadier than its brightest pixel
+    let (peak_x, peak_y) = (brightest % lit.width, brightest / lit.width);
+    let (mut x, mut y, mut total) = (0.0, 0.0, 0.0);
+    for row in peak_y.saturating_sub(SPOT_RADIUS)..(peak_y + SPOT_RADIUS + 1).min(lit.height) {
+        for column in peak_x.saturating_sub(SPOT_RADIUS)..(peak_x + SPOT_RADIUS + 1).min(lit.width) {
+            let weight = rise(row * lit.width + column) as f32;
+            x += column as f32 * weight;
+            y += row as f32 * weight;
+            total += weight;
+        }
+    }
+    Some((x / total, y / total))
+}
+
+/// Fill in the LEDs that weren't seen between the nearest ones seen along the strip, None if none were seen
+pub fn fill_missing(points: &[Option<(f32, f32)>]) -> Option<Vec<(f32, f32)>> {
+    let seen: Vec<usize> = (0..points.len()).filter(|&index| points[index].is_some()).collect();
+    let position = |index: usize| points[index].unwrap_or_default();
+    let first = *seen.first()?;
+    let filled = (0..points.len())
+        .map(|index| {
+            if let Some(point) = points[index] {
+                return point;
+            }
+            let next = seen.partition_point(|&seen| seen < index);
+            match (next.checked_sub(1).map(|previous| seen[previous]), seen.get(next)) {
+                (Some(before), Some(&after)) => {
+                    let t = (index - before) as f32 / (after - before) as f32;
+                    let ((x0, y0), (x1, y1)) = (position(before), position(after));
+                    (x0 + (x1 - x0) * t, y0 + (y1 - y0) * t)
+                }
+                (Some(before), None) => position(before),
+                (None, _) => position(first),
+            }
+        })
+        .collect();
+    Some(filled)
+}
+
+/// Build a map from where each LED appears in a photo of the tree's front, and optionally in one of its side
+/// taken after turning it a quarter turn anticlockwise seen from above, without moving the camera
+///
+/// Positions are scaled so the tree is 2 wide at its widest, and sit on the ground below the lowest LED.
+pub fn build_map(front: &[(f32, f32)], side: Option<&[(f32, f32)]>) -> TreeMap {
+    let middle = |points: &[(f32, f32)]| {
+        let (min, max) = points.iter().fold((f32::MAX, f32::MIN), |(min, max), &(x, _)| (min.min(x), max.max(x)));
+        (min + max) / 2.0
+    };
+    let front_middle = middle(front);
+    let side_middle = side.map(middle).unwrap_or_default();
+    let points: Vec<Point3> = front
+        .iter()
+        .enumerate()
+        .map(|(index, &(x, height))| match side.and_then(|side| side.get(index)) {
+            // Turning the tree anticlockwise brings its far side to the camera's left
+            Some(&(side_x, side_height)) => Point3::new(
+                x - front_middle,
+                side_middle - side_x,
+                -(height + side_height) / 2.0,
+            ),
+            None => Point3::new(x - front_middle, 0.0, -height),
+        })
+        .collect();
+    let radius = points.iter().map(|point| point.x.abs().max(point.y.abs())).fold(f32::EPSILON, f32::max);
+    let bottom = points.iter().map(|point| point.z).fold(f32::MAX, f32::min);
+    TreeMap::new(
+        points
+            .iter()
+            .map(|point| Point3::new(point.x / radius, point.y / radius, (point.z - bottom) / radius))
+            .collect(),
+    )
+}
+
+/// Map the tree by photographing each LED lit on its own, writing the map as a GIFT CSV
+#[cfg(feature = "camera")]
+pub fn run(
+    camera: u32,
+    output: &Path,
+    flat: bool,
+    message_handler: &MessageHandler,
+    log_file: &mut RotatingLog,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let num_leds = match message_handler.device_info(HANDSHAKE_TIMEOUT) {
+        Ok(info) => config.num_leds.unwrap_or(info.num_leds as usize),
+        Err(e) => {
+            let num_leds = config.num_leds.unwrap_or(DEFAULT_NUM_LEDS);
+            eprintln!("Warning: failed to get device info ({}), assuming {} LEDs", e, num_leds);
+            num_leds
+        }
+    };
+    let format = RequestedFormat::new::<RgbFormat>(RequestedFormatType::AbsoluteHighestResolution);
+    let mut camera = Camera::new(CameraIndex::Index(camera), format)?;
+    camera.open_stream()?;
+    println!("Calibrating {} LEDs, keep the room dark and the camera and tree still", num_leds);
+
+    let mut tree = Tree {
+        message_handler,
+        num_leds,
+        seq: 0,
+        pipeline: OutputPipeline::from_config(num_leds, config),
+    };
+    let front = tree.photograph(&mut camera)?;
+    let side = if flat {
+        None
+    } else {
+        print!("Turn the tree a quarter turn anticlockwise, seen from above, then press enter");
+        std::io::stdout().flush()?;
+        std::io::stdin().read_line(&mut String::new())?;
+        Some(tree.photograph(&mut camera)?)
+    };
+    tree.show(None)?;
+    camera.stop_stream()?;
+
+    let seen = |points: &[Option<(f32, f32)>]| points.iter().filter(|point| point.is_some()).count();
+    let mut line = format!("Saw {}/{} LEDs from the front", seen(&front), num_leds);
+    if let Some(side) = &side {
+        line += &format!(" and {}/{} from the side", seen(side), num_leds);
+    }
+    println!("{}", line);
+    log_file.write_line("server", &line)?;
+
+    let front = fill_missing(&front).ok_or("No LEDs were seen from the front, is the camera pointed at the tree?")?;
+    let side = match side {
+        Some(side) => Some(fill_missing(&side).ok_or("No LEDs were seen from the side")?),
+        None => None,
+    };
+    build_map(&front, side.as_deref()).save(output)?;
+    let line = format!("Wrote the tree map to {}", output.display());
+    println!("{}", line);
+    log_file.write_line("server", &line)?;
+    Ok(())
+}
+
+/// The tree's LEDs, lit one at a time
+#[cfg(feature = "camera")]
+struct Tree<'a> {
+    message_handler: &'a MessageHandler,
+    num_leds: usize,
+    seq: u32,
+    pipeline: OutputPipeline,
+}
+
+#[cfg(feature = "camera")]
+impl Tree<'_> {
+    /// Photograph each LED lit on its own, giving where it appears in pixels
+    fn photograph(&mut self, camera: &mut Camera) -> Result<Vec<Option<(f32, f32)>>, Box<dyn Error>> {
+        self.show(None)?;
+        let dark = capture(camera)?;
+        let mut points = Vec::with_capacity(self.num_leds);
+        for index in 0..self.num_leds {
+            self.show(Some(index))?;
+            points.push(locate(&dark, &capture(camera)?));
+            print!("\rPhotographed LED {}/{}", index + 1, self.num_leds);
+            std::io::stdout().flush()?;
+        }
+        println!();
+        Ok(points)
+    }
+
+    /// Light a single LED, or none, waiting until the firmware has displayed it
+    fn show(&mut self, lit: Option<usize>) -> Result<(), MessageError> {
+        let mut leds = vec![Rgb::default(); self.num_leds];
+        if let Some(index) = lit {
+            leds[index] = LIT;
+        }
+        self.seq = next_host_seq(self.seq);
+        self.message_handler.send_frame(self.seq, self.pipeline.apply(leds))?;
+        loop {
+            match self.message_handler.receive(HANDSHAKE_TIMEOUT)? {
+                Message::Ack(ack) if ack.seq == self.seq => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Take a photo once the camera has caught up with the LEDs
+#[cfg(feature = "camera")]
+fn capture(camera: &mut Camera) -> Result<Image, Box<dyn Error>> {
+    std::thread::sleep(SETTLE_TIME);
+    for _ in 0..STALE_FRAMES {
+        camera.frame()?;
+    }
+    let photo = camera.frame()?.decode_image::<RgbFormat>()?;
+    Ok(Image::from_rgb(photo.width() as usize, photo.height() as usize, photo.as_raw()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(width: usize, height: usize, spots: &[(usize, usize, u8)]) -> Image {
+        let mut luma = vec![10; width * height];
+        for &(x, y, level) in spots {
+            luma[y * width + x] = level;
+        }
+        Image { width, height, luma }
+    }
+
+    #[test]
+    fn locates_the_lit_led() {
+        // A lamp in the corner is lit in both photos, the LED glows across two pixels
+        let dark = image(40, 30, &[(0, 0, 255)]);
+        let lit = image(40, 30, &[(0, 0, 255), (20, 10, 210), (21, 10, 210)]);
+        assert_eq!(locate(&dark, &lit), Some((20.5, 10.0)));
+        // A faint change is noise, not an LED
+        assert_eq!(locate(&dark, &image(40, 30, &[(5, 5, 30)])), None);
+    }
+
+    #[test]
+    fn fills_unseen_leds_along_the_strip() {
+        let points = [None, Some((0.0, 0.0)), None, None, Some((3.0, 6.0)), None];
+        assert_eq!(
+            fill_missing(&points),
+            Some(vec![(0.0, 0.0), (0.0, 0.0), (1.0, 2.0), (2.0, 4.0), (3.0, 6.0), (3.0, 6.0)])
+        );
+        assert_eq!(fill_missing(&[None, None]), None);
+    }
+
+    #[test]
+    fn builds_a_map_from_two_views() {
+        // LEDs around the bottom of the tree on its right, left, back and front, and one at the top 200 pixels higher
+        let front = [(300.0, 400.0), (100.0, 400.0), (200.0, 400.0), (200.0, 400.0), (200.0, 200.0)];
+        // Turning the tree anticlockwise brings its back to the left and its right to the middle
+        let side = [(200.0, 400.0), (200.0, 400.0), (100.0, 400.0), (300.0, 400.0), (200.0, 200.0)];
+        let map = build_map(&front, Some(&side));
+        assert_eq!(
+            map.points(),
+            &[
+                Point3::new(1.0, 0.0, 0.0),
+                Point3::new(-1.0, 0.0, 0.0),
+                Point3::new(0.0, 1.0, 0.0),
+                Point3::new(0.0, -1.0, 0.0),
+                Point3::new(0.0, 0.0, 2.0),
+            ]
+        );
+        let flat = build_map(&front, None);
+        assert_eq!(flat.points()[4], Point3::new(0.0, 0.0, 2.0));
+    }
+}
@@ -0,0 +1,408 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use common::message::{Effect, MirrorRole, Rgb};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use crate::audio::AudioEffect;
+use crate::bridge::Protocol;
+use crate::config::DEFAULT_CONFIG_FILE;
+use crate::media::Projection;
+use crate::notify::MAX_FLASHES;
+
+/// Drive the christmas tree over its serial link
+#[derive(Debug, Parser)]
+#[command(name = "tree", version)]
+pub struct Cli {
+    /// Config file, reloaded while streaming when it changes
+    #[arg(long, default_value = DEFAULT_CONFIG_FILE)]
+    pub config: PathBuf,
+    /// Serial port the tree is connected to, overriding the config file's
+    #[arg(long)]
+    pub port: Option<String>,
+    /// Record the frames streamed to the tree to a file for `tree play`, replacing it if it exists
+    #[arg(long, global = true)]
+    pub record: Option<PathBuf>,
+    /// What to do, streaming frames to the tree when omitted
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Stream frames to the tree, displaying its logs and link health
+    Run {
+        /// Effect to render, one of `tree effects`, the tree keeps its current display when omitted
+        effect: Option<String>,
+        /// Effect parameter as id=value, parameters not given take their defaults
+        #[arg(long = "param", value_parser = parse_param)]
+        params: Vec<(String, String)>,
+    },
+    /// Light every LED in one color, given as RRGGBB hex, R,G,B or a name
+    SetColor {
+        #[arg(value_parser = parse_color)]
+        color: Rgb,
+    },
+    /// Start one of the firmware's built-in effects
+    Effect {
+        effect: EffectName,
+        /// Speed as a multiple of the effect's normal speed
+        #[arg(long, default_value_t = 1.0)]
+        speed: f32,
+    },
+    /// Cap the tree's brightness, in percent
+    Brightness {
+        #[arg(value_parser = clap::value_parser!(u8).range(0..=100))]
+        percent: u8,
+    },
+    /// Display the tree's logs as they arrive
+    Logs {
+        #[command(subcommand)]
+        command: Option<LogsCommand>,
+    },
+    /// Show the tree's device info, settings and last reset
+    Info,
+    /// Drive the tree from lighting software over the network, mapping sACN and Art-Net universes onto its LEDs
+    /// as the config file sets
+    Bridge { protocol: Protocol },
+    /// Visualize music from an audio input, such as a microphone near the speakers
+    Audio {
+        effect: AudioEffect,
+        /// Audio input to capture, the system's default input when omitted
+        #[arg(long)]
+        device: Option<String>,
+    },
+    /// Run an effect script, <name>.rhai from the config's script_dir, reloading it whenever it changes
+    Script { name: String },
+    /// Replay a recording made with --record or a record sink, with the timing it was recorded with
+    Play {
+        file: PathBuf,
+        /// Start again from the beginning each time it ends
+        #[arg(long = "loop")]
+        repeat: bool,
+        /// Playback speed as a multiple of the recorded speed
+        #[arg(long, default_value_t = 1.0)]
+        speed: f32,
+    },
+    /// Show a GIF or video on the tree, sampling each frame where the config's map_file puts the LEDs
+    ///
+    /// Videos are decoded by ffmpeg, which has to be on the PATH.
+    Media {
+        file: PathBuf,
+        /// How the frames are laid over the tree
+        #[arg(long, value_enum, default_value_t = Projection::Front)]
+        projection: Projection,
+        /// Start again from the beginning each time it ends
+        #[arg(long = "loop")]
+        repeat: bool,
+    },
+    /// Show host effects for the weather at the config's latitude and longitude, like snowfall while it snows
+    ///
+    /// The weather is read from Open-Meteo, and the effects picked by the config's weather rules.
+    Weather,
+    /// Show the tree's logs, frame rate and link health in a terminal dashboard, with keys to pick an effect and
+    /// set the brightness
+    Tui,
+    /// Measure the link's round trip with timestamped pings, and the highest full-frame rate the tree keeps up with
+    /// before it drops frames
+    Bench {
+        /// Pings sent to measure the round trip
+        #[arg(long, default_value_t = 100)]
+        pings: u32,
+        /// Seconds each frame rate is held for
+        #[arg(long, default_value_t = 3)]
+        step_secs: u64,
+        /// Highest frame rate tried
+        #[arg(long, default_value_t = 240, value_parser = clap::value_parser!(u32).range(1..))]
+        max_fps: u32,
+    },
+    /// Map where each LED is by photographing it lit on its own with a webcam, from the tree's front and then its side
+    Calibrate {
+        /// Index of the camera to photograph the tree with
+        #[arg(long, default_value_t = 0)]
+        camera: u32,
+        /// File the map is written to, the config's map_file or tree.csv when omitted
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// Only photograph the front, for a map with no depth
+        #[arg(long)]
+        flat: bool,
+    },
+    /// Save and recall named looks of the tree, kept in the config's presets_file
+    Preset {
+        #[command(subcommand)]
+        command: PresetCommand,
+    },
+    /// Simulate a tree in a window, drawing its LEDs where the config's map_file puts them, for developing effects
+    /// without the tree. Other commands drive it through the pseudo-terminal it prints, given as their --port.
+    Simulate {
+        /// LEDs on the simulated tree, the config's num_leds or the map's LEDs when omitted
+        #[arg(long)]
+        leds: Option<u16>,
+    },
+    /// Serve an HTTP API, and a page controlling the tree from a browser
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "0.0.0.0:8080")]
+        listen: SocketAddr,
+    },
+    /// Flash the tree in a color to get attention, leaving it lit in the color afterwards unless told to resume
+    Notify {
+        #[arg(long, value_parser = parse_color)]
+        color: Rgb,
+        /// Number of flashes
+        #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..=i64::from(MAX_FLASHES)))]
+        times: u32,
+        /// Show what the tree was showing before once the flashes end
+        #[arg(long)]
+        then_resume: bool,
+    },
+    /// Turn the LEDs off
+    Off {
+        /// Let the tree light sleep until the next message
+        #[arg(long)]
+        sleep: bool,
+    },
+    /// Turn the LEDs back on
+    On,
+    /// List the host's effects, optionally only those with a tag, without connecting
+    Effects { tag: Option<String> },
+    /// Push a firmware image over the link, the tree reboots into it once verified
+    ///
+    /// The image is made with `espflash save-image`, and the tree must have been flashed with firmware/partitions.csv
+    Update { image: PathBuf },
+    /// Restart the firmware
+    Reboot,
+    /// Clear the tree's config and reboot it with defaults
+    FactoryReset,
+    /// Store the WiFi network the tree joins from its next boot
+    Wifi { ssid: String, password: String },
+    /// Set the LEDs, color order and chip of each strip output, as <leds>[:<order>[:<chip>]]
+    ///
+    /// Splitting one string between both outputs, e.g. `tree strips 257 256`, lets a frame latch in half the time
+    Strips {
+        #[arg(required = true)]
+        strips: Vec<String>,
+    },
+    /// Set the firmware's gamma correction to off, standard or an exponent
+    ///
+    /// Turn it off when effects already correct their colors, so they aren't corrected twice
+    Gamma { gamma: String },
+    /// Set whether the firmware dithers dim levels across frames
+    Dither { state: Toggle },
+    /// Set whether the firmware fades between received frames
+    Interpolate { state: Toggle },
+    /// Set whether the firmware wipes colors along the strips at boot
+    Selftest { state: Toggle },
+    /// Set how many LEDs at the start of the tree show the firmware's status
+    StatusLeds { count: u8 },
+    /// Dim the tree in a dark room down to a minimum level out of 255, or turn it off
+    AutoBrightness { min_level: String },
+    /// Set the first E1.31 universe the firmware displays over WiFi, or turn it off
+    Sacn { universe: String },
+    /// Set whether frames are mirrored to or from other trees over ESP-NOW
+    Mirror {
+        role: MirrorRoleName,
+        /// WiFi channel used while the tree isn't joined to a network
+        channel: Option<u8>,
+    },
+    /// Set whether the UART uses RTS/CTS flow control from the tree's next boot
+    UartFlowControl { state: Toggle },
+    /// Set whether the tree shows what was on display again after a reboot
+    RestoreState { state: Toggle },
+    /// Turn the tree's microphone-driven effects on or off
+    Sound {
+        state: Toggle,
+        /// How readily a rise in the sound level counts as a beat, 0-255
+        #[arg(default_value_t = 128)]
+        sensitivity: u8,
+    },
+    /// Set how long the tree crossfades into new scenes in milliseconds, 0 to cut straight to them
+    Transition { ms: u16 },
+    /// Show the schedule, or replace it with a location, UTC offset and <on|off>@<HH:MM|dawn|dusk>[+-minutes] entries
+    Schedule {
+        #[arg(allow_hyphen_values = true)]
+        schedule: Vec<String>,
+    },
+    /// Show the segments running their own effects, or replace them with <start>+<len>:<frozen|effect[@speed]>
+    /// until the tree reboots
+    Segments { segments: Vec<String> },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PresetCommand {
+    /// Save a firmware effect with the tree's current brightness and segments
+    Save {
+        name: String,
+        effect: EffectName,
+        /// Speed as a multiple of the effect's normal speed
+        #[arg(long, default_value_t = 1.0)]
+        speed: f32,
+        /// Brightness in percent, the tree's current brightness when omitted
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+        brightness: Option<u8>,
+    },
+    /// Show a saved preset on the tree
+    Load { name: String },
+    /// List the saved presets without connecting
+    List,
+    /// Delete a saved preset without connecting
+    Delete { name: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum LogsCommand {
+    /// Print the end of the log file without connecting
+    Tail {
+        #[arg(default_value_t = 20)]
+        lines: usize,
+        /// Keep printing lines as they're written
+        #[arg(short, long)]
+        follow: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Toggle {
+    On,
+    Off,
+}
+
+impl Toggle {
+    pub fn is_on(self) -> bool {
+        self == Toggle::On
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EffectName {
+    Rainbow,
+    Chase,
+    Twinkle,
+    Breathe,
+}
+
+impl From<EffectName> for Effect {
+    fn from(name: EffectName) -> Self {
+        match name {
+            EffectName::Rainbow => Effect::Rainbow,
+            EffectName::Chase => Effect::Chase,
+            EffectName::Twinkle => Effect::Twinkle,
+            EffectName::Breathe => Effect::Breathe,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MirrorRoleName {
+    Off,
+    Primary,
+    Secondary,
+}
+
+impl From<MirrorRoleName> for MirrorRole {
+    fn from(name: MirrorRoleName) -> Self {
+        match name {
+            MirrorRoleName::Off => MirrorRole::Off,
+            MirrorRoleName::Primary => MirrorRole::Primary,
+            MirrorRoleName::Secondary => MirrorRole::Secondary,
+        }
+    }
+}
+
+/// Colors that can be given by name
+const NAMED_COLORS: [(&str, Rgb); 10] = [
+    ("red", Rgb::new(255, 0, 0)),
+    ("orange", Rgb::new(255, 96, 0)),
+    ("yellow", Rgb::new(255, 192, 0)),
+    ("green", Rgb::new(0, 255, 0)),
+    ("cyan", Rgb::new(0, 255, 255)),
+    ("blue", Rgb::new(0, 0, 255)),
+    ("purple", Rgb::new(128, 0, 255)),
+    ("magenta", Rgb::new(255, 0, 255)),
+    ("pink", Rgb::new(255, 64, 128)),
+    ("white", Rgb::new(255, 255, 255)),
+];
+
+/// Parse a color from `RRGGBB` hex, with or without a leading `#`, `R,G,B` or a name such as `red`
+pub fn parse_color(arg: &str) -> Result<Rgb, String> {
+    let error = || format!("{} is not a color, use RRGGBB, R,G,B or a color name", arg);
+    if let Some((_, rgb)) = NAMED_COLORS.iter().find(|(name, _)| name.eq_ignore_ascii_case(arg.trim())) {
+        return Ok(*rgb);
+    }
+    if let Some((r, rest)) = arg.split_once(',') {
+        let (g, b) = rest.split_once(',').ok_or_else(error)?;
+        let channel = |channel: &str| channel.trim().parse::<u8>().map_err(|_| error());
+        return Ok(Rgb::new(channel(r)?, channel(g)?, channel(b)?));
+    }
+    let hex = arg.strip_prefix('#').unwrap_or(arg);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(error());
+    }
+    let channel = |range| u8::from_str_radix(&hex[range], 16).map_err(|_| error());
+    Ok(Rgb::new(channel(0..2)?, channel(2..4)?, channel(4..6)?))
+}
+
+/// Parse an effect parameter given as `id=value`
+pub fn parse_param(arg: &str) -> Result<(String, String), String> {
+    let (id, value) = arg.split_once('=').ok_or_else(|| format!("{} is not id=value", arg))?;
+    Ok((id.trim().to_string(), value.trim().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn cli_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn parses_colors() {
+        assert_eq!(parse_color("#ff8000"), Ok(Rgb::new(255, 128, 0)));
+        assert_eq!(parse_color("00ff10"), Ok(Rgb::new(0, 255, 16)));
+        assert_eq!(parse_color("255, 0,7"), Ok(Rgb::new(255, 0, 7)));
+        assert_eq!(parse_color("Red"), Ok(Rgb::new(255, 0, 0)));
+        assert!(parse_color("reddish").is_err());
+        assert!(parse_color("256,0,0").is_err());
+    }
+
+    #[test]
+    fn parses_notifications() {
+        let cli = Cli::try_parse_from(["tree", "notify", "--color", "red", "--times", "3", "--then-resume"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Notify {
+                times: 3,
+                then_resume: true,
+                ..
+            })
+        ));
+        assert!(Cli::try_parse_from(["tree", "notify", "--color", "red", "--times", "0"]).is_err());
+    }
+
+    #[test]
+    fn parses_effect_speed() {
+        let cli = Cli::try_parse_from(["tree", "effect", "rainbow", "--speed", "2"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Effect {
+                effect: EffectName::Rainbow,
+                speed
+            }) if speed == 2.0
+        ));
+    }
+
+    #[test]
+    fn parses_effect_params() {
+        let args = ["tree", "run", "ramp", "--param", "channel=blue", "--param", "speed=4"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        let Some(Command::Run { effect, params }) = cli.command else {
+            panic!("expected run, got {:?}", cli.command);
+        };
+        assert_eq!(effect.as_deref(), Some("ramp"));
+        assert_eq!(params, vec![("channel".into(), "blue".into()), ("speed".into(), "4".into())]);
+        assert!(parse_param("speed").is_err());
+    }
+}
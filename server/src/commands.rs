@@ -0,0 +1,476 @@
+use christmas_tree_client::MessageHandler;
+use common::message::{
+    ColorOrder, Effect, Gamma, LedChip, Message, MirrorRole, PanicPayload, PowerOffPayload, ResetReason,
+    ResetReportPayload, ScheduleEntry, SchedulePayload, Segment, SegmentsPayload, SoundReactivePayload,
+    StartEffectPayload, StripConfig, WifiCredentialsPayload, GAMMA_TABLE_SIZE, MAX_SCHEDULE_ENTRIES, MAX_SEGMENTS,
+};
+use std::error::Error;
+
+use crate::cli::{Command, PresetCommand};
+use crate::config::Config;
+use crate::log_file::RotatingLog;
+use crate::notify::{self, Notification};
+use crate::pipeline::OutputPipeline;
+use crate::presets::{Preset, PresetStore};
+use crate::{HANDSHAKE_TIMEOUT, UPDATE_CHUNK_SIZE, UPDATE_TIMEOUT};
+
+/// Run a command that sends the tree a request and exits
+/// Commands that keep running, like Run and Logs, are handled by main
+pub fn execute(
+    command: Command,
+    message_handler: &MessageHandler,
+    log_file: &mut RotatingLog,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    match command {
+        Command::SetColor { color } => {
+            let info = message_handler.device_info(HANDSHAKE_TIMEOUT)?;
+            let mut pipeline = OutputPipeline::from_config(info.num_leds as usize, config);
+            message_handler.send_frame(0, pipeline.apply(vec![color; info.num_leds as usize]))?;
+            println!("Lit {} LEDs in {:?}", info.num_leds, color);
+            log_file.write_line("server", &format!("Set color to {:?}", color))?;
+        }
+        Command::Notify {
+            color,
+            times,
+            then_resume,
+        } => {
+            let notification = Notification {
+                color,
+                times,
+                then_resume,
+            };
+            println!("Flashing {:?} {} times", color, times);
+            log_file.write_line("server", &format!("Flashed {:?} {} times", color, times))?;
+            notify::run(notification, message_handler, config)?;
+        }
+        Command::Effect { effect, speed } => {
+            let payload = start_effect(effect.into(), speed);
+            println!("Starting {:?} at {}% speed", payload.effect, payload.speed);
+            log_file.write_line("server", &format!("Started {:?} at {}% speed", payload.effect, payload.speed))?;
+            message_handler.send(&Message::StartEffect(payload))?;
+        }
+        Command::Brightness { percent } => {
+            let mut config = message_handler.config(HANDSHAKE_TIMEOUT)?;
+            config.brightness_cap = brightness_cap(percent);
+            let config = message_handler.set_config(config, HANDSHAKE_TIMEOUT)?;
+            println!("Brightness is now capped at {}/255", config.brightness_cap);
+            log_file.write_line("server", &format!("Set brightness to {}%", percent))?;
+        }
+        Command::Info => {
+            let info = message_handler.device_info(HANDSHAKE_TIMEOUT)?;
+            println!(
+                "Device: {} with {} LEDs on strips of {:?}, firmware {}, max frame size {} bytes",
+                info.chip, info.num_leds, info.strips, info.fw_version, info.max_frame_size
+            );
+            let config = message_handler.config(HANDSHAKE_TIMEOUT)?;
+            println!("{:#?}", config);
+            let report = message_handler.reset_report(HANDSHAKE_TIMEOUT)?;
+            println!("Last reset by {:?}", report.reason);
+            for event in reset_events(&report) {
+                println!("{}", event);
+            }
+        }
+        Command::Off { sleep } => {
+            message_handler.send(&Message::PowerOff(PowerOffPayload { light_sleep: sleep }))?;
+            let power = if sleep { "off, light sleeping" } else { "off" };
+            println!("Powering the tree {}", power);
+            log_file.write_line("server", &format!("Set power to {}", power))?;
+        }
+        Command::On => {
+            message_handler.send(&Message::PowerOn)?;
+            println!("Powering the tree on");
+            log_file.write_line("server", "Set power to on")?;
+        }
+        Command::Update { image: path } => {
+            let image = std::fs::read(&path)?;
+            println!("Uploading {} ({} bytes)...", path.display(), image.len());
+            message_handler.update_firmware(&image, UPDATE_CHUNK_SIZE, UPDATE_TIMEOUT, |written| {
+                print!("\r{} / {} bytes", written, image.len());
+                let _ = std::io::Write::flush(&mut std::io::stdout());
+            })?;
+            println!("\nFirmware verified, the tree is rebooting into it");
+            log_file.write_line("server", &format!("Updated firmware to {}", path.display()))?;
+        }
+        Command::Reboot => {
+            message_handler.send(&Message::Reboot)?;
+            println!("Rebooting the tree");
+            log_file.write_line("server", "Rebooted firmware")?;
+        }
+        Command::FactoryReset => {
+            message_handler.send(&Message::FactoryReset)?;
+            println!("Cleared the tree's config, rebooting it with defaults");
+            log_file.write_line("server", "Factory reset firmware")?;
+        }
+        Command::Wifi { ssid, password } => {
+            message_handler.send(&Message::SetWifiCredentials(WifiCredentialsPayload {
+                ssid: ssid.clone(),
+                password,
+            }))?;
+            println!("Sent WiFi credentials for {}, the tree joins it from its next boot", ssid);
+            log_file.write_line("server", &format!("Set WiFi network to {}", ssid))?;
+        }
+        Command::Strips { strips } => {
+            let mut config = message_handler.config(HANDSHAKE_TIMEOUT)?;
+            let strips = strips
+                .iter()
+                .enumerate()
+                .map(|(index, arg)| parse_strip(arg, config.strips.get(index)))
+                .collect::<Option<Vec<_>>>()
+                .ok_or("Strips are <leds>[:<rgb|rbg|grb|gbr|brg|bgr>[:<ws2812b|ws2811|sk6812>]]")?;
+            config.strips = strips;
+            let config = message_handler.set_config(config, HANDSHAKE_TIMEOUT)?;
+            for (index, strip) in config.strips.iter().enumerate() {
+                println!(
+                    "Strip {}: {} LEDs, {:?} order, {:?}",
+                    index, strip.num_leds, strip.color_order, strip.chip
+                );
+            }
+            log_file.write_line("server", &format!("Set strips to {:?}", config.strips))?;
+        }
+        Command::Gamma { gamma: arg } => {
+            let gamma = match arg.as_str() {
+                "off" => Gamma::Off,
+                "standard" => Gamma::Standard,
+                exponent => Gamma::Table(gamma_table(
+                    exponent.parse().map_err(|_| "Gamma is off, standard or an exponent")?,
+                )),
+            };
+            let mut config = message_handler.config(HANDSHAKE_TIMEOUT)?;
+            config.gamma = gamma;
+            let config = message_handler.set_config(config, HANDSHAKE_TIMEOUT)?;
+            let description = match &config.gamma {
+                Gamma::Table(_) => format!("a gamma of {}", arg),
+                gamma => format!("{:?} gamma", gamma),
+            };
+            println!("The tree now uses {}", description);
+            log_file.write_line("server", &format!("Set gamma to {}", arg))?;
+        }
+        Command::Dither { state } => {
+            let mut config = message_handler.config(HANDSHAKE_TIMEOUT)?;
+            config.dithering = state.is_on();
+            let config = message_handler.set_config(config, HANDSHAKE_TIMEOUT)?;
+            println!("Dithering is now {}", on_off(config.dithering));
+            log_file.write_line("server", &format!("Set dithering to {}", on_off(config.dithering)))?;
+        }
+        Command::Interpolate { state } => {
+            let mut config = message_handler.config(HANDSHAKE_TIMEOUT)?;
+            config.interpolation = state.is_on();
+            let config = message_handler.set_config(config, HANDSHAKE_TIMEOUT)?;
+            println!("Interpolation is now {}", on_off(config.interpolation));
+            log_file.write_line("server", &format!("Set interpolation to {}", on_off(config.interpolation)))?;
+        }
+        Command::Selftest { state } => {
+            let mut config = message_handler.config(HANDSHAKE_TIMEOUT)?;
+            config.self_test = state.is_on();
+            let config = message_handler.set_config(config, HANDSHAKE_TIMEOUT)?;
+            println!("Boot self-test is now {}", on_off(config.self_test));
+            log_file.write_line("server", &format!("Set boot self-test to {}", on_off(config.self_test)))?;
+        }
+        Command::StatusLeds { count } => {
+            let mut config = message_handler.config(HANDSHAKE_TIMEOUT)?;
+            config.status_leds = count;
+            let config = message_handler.set_config(config, HANDSHAKE_TIMEOUT)?;
+            println!("{} LEDs now show the firmware's status", config.status_leds);
+            log_file.write_line("server", &format!("Set status LEDs to {}", count))?;
+        }
+        Command::AutoBrightness { min_level } => {
+            let min_brightness = match min_level.as_str() {
+                "off" => None,
+                level => Some(level.parse::<u8>().map_err(|_| "Auto brightness is off or a min level 0-255")?),
+            };
+            let mut config = message_handler.config(HANDSHAKE_TIMEOUT)?;
+            config.auto_brightness = min_brightness.is_some();
+            if let Some(level) = min_brightness {
+                config.min_brightness = level;
+            }
+            let config = message_handler.set_config(config, HANDSHAKE_TIMEOUT)?;
+            if config.auto_brightness {
+                println!("Auto brightness is now on, down to {} in the dark", config.min_brightness);
+            } else {
+                println!("Auto brightness is now off");
+            }
+            log_file.write_line("server", &format!("Set auto brightness to {}", min_level))?;
+        }
+        Command::Sacn { universe } => {
+            let sacn_universe = match universe.as_str() {
+                "off" => 0,
+                universe => match universe.parse::<u16>() {
+                    Ok(universe) if universe > 0 => universe,
+                    _ => return Err("The sACN universe is off or a universe from 1".into()),
+                },
+            };
+            let mut config = message_handler.config(HANDSHAKE_TIMEOUT)?;
+            config.sacn_universe = sacn_universe;
+            let config = message_handler.set_config(config, HANDSHAKE_TIMEOUT)?;
+            match config.sacn_universe {
+                0 => println!("sACN is now off"),
+                universe => println!("sACN starts at universe {} from the tree's next boot", universe),
+            }
+            log_file.write_line("server", &format!("Set sACN universe to {}", universe))?;
+        }
+        Command::Mirror { role, channel } => {
+            let mut config = message_handler.config(HANDSHAKE_TIMEOUT)?;
+            config.mirror = role.into();
+            if let Some(channel) = channel {
+                config.mirror_channel = channel;
+            }
+            let config = message_handler.set_config(config, HANDSHAKE_TIMEOUT)?;
+            match config.mirror {
+                MirrorRole::Off => println!("Mirroring is now off"),
+                role => println!(
+                    "Mirroring as {:?} on channel {} from the tree's next boot, trees on WiFi use their network's channel",
+                    role, config.mirror_channel
+                ),
+            }
+            log_file.write_line("server", &format!("Set mirror role to {:?}", config.mirror))?;
+        }
+        Command::UartFlowControl { state } => {
+            let mut config = message_handler.config(HANDSHAKE_TIMEOUT)?;
+            config.uart_flow_control = state.is_on();
+            message_handler.set_config(config, HANDSHAKE_TIMEOUT)?;
+            println!(
+                "UART flow control is {} from the tree's next boot, set hardware_flow_control in the config file to match",
+                on_off(state.is_on())
+            );
+            log_file.write_line("server", &format!("Set UART flow control to {}", on_off(state.is_on())))?;
+        }
+        Command::RestoreState { state } => {
+            let mut config = message_handler.config(HANDSHAKE_TIMEOUT)?;
+            config.restore_state = state.is_on();
+            let config = message_handler.set_config(config, HANDSHAKE_TIMEOUT)?;
+            println!("Restoring the display after a reboot is now {}", on_off(config.restore_state));
+            log_file.write_line("server", &format!("Set display restore to {}", on_off(config.restore_state)))?;
+        }
+        Command::Sound { state, sensitivity } => {
+            let payload = SoundReactivePayload {
+                enabled: state.is_on(),
+                sensitivity,
+            };
+            let payload = message_handler.set_sound_reactive(payload, HANDSHAKE_TIMEOUT)?;
+            println!("Sound reactivity is {} at sensitivity {}", on_off(payload.enabled), payload.sensitivity);
+            log_file.write_line("server", &format!("Set sound reactivity to {}", on_off(payload.enabled)))?;
+        }
+        Command::Transition { ms } => {
+            let mut config = message_handler.config(HANDSHAKE_TIMEOUT)?;
+            config.transition_ms = ms;
+            let config = message_handler.set_config(config, HANDSHAKE_TIMEOUT)?;
+            println!("Transitions now take {} ms", config.transition_ms);
+            log_file.write_line("server", &format!("Set transition time to {} ms", config.transition_ms))?;
+        }
+        Command::Schedule { schedule: args } => {
+            const USAGE: &str =
+                "The schedule is <latitude> <longitude> <utc-offset-minutes> <on|off>@<HH:MM|dawn|dusk>[+-minutes]...";
+            let schedule = if args.is_empty() {
+                message_handler.schedule(HANDSHAKE_TIMEOUT)?
+            } else {
+                let degrees = |arg: Option<&String>| {
+                    arg.and_then(|arg| arg.parse::<f32>().ok())
+                        .map(|degrees| (degrees * 100.0).round() as i16)
+                        .ok_or(USAGE)
+                };
+                let schedule = SchedulePayload {
+                    latitude: degrees(args.first())?,
+                    longitude: degrees(args.get(1))?,
+                    utc_offset_min: args.get(2).and_then(|arg| arg.parse().ok()).ok_or(USAGE)?,
+                    entries: args[3.min(args.len())..]
+                        .iter()
+                        .map(|entry| entry.parse::<ScheduleEntry>().map_err(|_| USAGE))
+                        .collect::<Result<_, _>>()?,
+                };
+                let stored = message_handler.set_schedule(schedule.clone(), HANDSHAKE_TIMEOUT)?;
+                if stored != schedule {
+                    return Err(format!(
+                        "The tree rejected the schedule, it holds at most {} entries",
+                        MAX_SCHEDULE_ENTRIES
+                    )
+                    .into());
+                }
+                log_file.write_line("server", &format!("Set schedule to {:?}", stored))?;
+                stored
+            };
+            println!(
+                "Schedule at {:.2}, {:.2}, UTC{:+} minutes, followed once the tree has synchronized its time over WiFi:",
+                schedule.latitude as f32 / 100.0,
+                schedule.longitude as f32 / 100.0,
+                schedule.utc_offset_min
+            );
+            for entry in &schedule.entries {
+                println!("  {:?} at {:?}", entry.action, entry.time);
+            }
+        }
+        Command::Segments { segments: args } => {
+            const USAGE: &str = "Segments are <start>+<len>:<frozen|rainbow|chase|twinkle|breathe>[@speed]";
+            let segments = if args.is_empty() {
+                message_handler.segments(HANDSHAKE_TIMEOUT)?
+            } else {
+                let segments = SegmentsPayload {
+                    segments: args
+                        .iter()
+                        .map(|segment| segment.parse::<Segment>().map_err(|_| USAGE))
+                        .collect::<Result<_, _>>()?,
+                };
+                let running = message_handler.set_segments(segments.clone(), HANDSHAKE_TIMEOUT)?;
+                if running != segments {
+                    return Err(format!(
+                        "The tree rejected the segments, they must fit in its LEDs without overlapping and number at most {}",
+                        MAX_SEGMENTS
+                    )
+                    .into());
+                }
+                log_file.write_line("server", &format!("Set segments to {:?}", running))?;
+                running
+            };
+            if segments.segments.is_empty() {
+                println!("No segments, frames light every LED");
+            }
+            for segment in &segments.segments {
+                println!("  LEDs {:?}: {:?}", segment.range(), segment.mode);
+            }
+        }
+        Command::Preset { command } => {
+            let store = PresetStore::new(&config.presets_file);
+            match command {
+                PresetCommand::Save {
+                    name,
+                    effect,
+                    speed,
+                    brightness,
+                } => {
+                    let brightness = match brightness {
+                        Some(percent) => percent,
+                        None => brightness_percent(message_handler.config(HANDSHAKE_TIMEOUT)?.brightness_cap),
+                    };
+                    let preset = Preset {
+                        effect: start_effect(effect.into(), speed),
+                        brightness,
+                        segments: message_handler.segments(HANDSHAKE_TIMEOUT)?.segments,
+                    };
+                    println!("Saved preset {}: {}", name, preset);
+                    log_file.write_line("server", &format!("Saved preset {}: {}", name, preset))?;
+                    store.save(&name, preset)?;
+                }
+                PresetCommand::Load { name } => {
+                    let preset = store.get(&name)?;
+                    preset.apply(message_handler)?;
+                    println!("Loaded preset {}: {}", name, preset);
+                    log_file.write_line("server", &format!("Loaded preset {}", name))?;
+                }
+                PresetCommand::List | PresetCommand::Delete { .. } => {
+                    unreachable!("listing and deleting presets are handled by main")
+                }
+            }
+        }
+        Command::Run { .. }
+        | Command::Logs { .. }
+        | Command::Effects { .. }
+        | Command::Serve { .. }
+        | Command::Bridge { .. }
+        | Command::Audio { .. }
+        | Command::Script { .. }
+        | Command::Calibrate { .. }
+        | Command::Simulate { .. }
+        | Command::Play { .. }
+        | Command::Media { .. }
+        | Command::Weather
+        | Command::Tui
+        | Command::Bench { .. } => {
+            unreachable!(
+                "run, logs, effects, serve, bridge, audio, script, calibrate, simulate, play, media, weather, tui and \
+                 bench are handled by main"
+            )
+        }
+    }
+    Ok(())
+}
+
+/// Start an effect at a multiple of its normal speed with its own colors
+pub fn start_effect(effect: Effect, speed: f32) -> StartEffectPayload {
+    StartEffectPayload {
+        effect,
+        speed: (speed * 100.0).round().clamp(1.0, u16::MAX as f32) as u16,
+        palette: Vec::new(),
+    }
+}
+
+/// Brightness cap out of 255 for a brightness in percent
+pub fn brightness_cap(percent: u8) -> u8 {
+    (percent.min(100) as u16 * 255 / 100) as u8
+}
+
+/// Brightness in percent for a brightness cap out of 255
+pub fn brightness_percent(cap: u8) -> u8 {
+    ((cap as u16 * 100 + 127) / 255) as u8
+}
+
+/// Events worth reporting from the firmware's last reset: an unexpected reset, a panic or wedged tasks
+pub fn reset_events(report: &ResetReportPayload) -> Vec<String> {
+    let mut events = Vec::new();
+    if !matches!(report.reason, ResetReason::PowerOn | ResetReason::Software) || report.panic.is_some() {
+        events.push(format!("Firmware last reset by {:?}", report.reason));
+    }
+    events.extend(report.panic.as_ref().map(describe_panic));
+    events.extend(
+        report
+            .wedged_tasks
+            .iter()
+            .map(|task| format!("Firmware watchdog reset after the {} task stopped responding", task)),
+    );
+    events
+}
+
+/// Describe a firmware panic with its location and backtrace
+pub fn describe_panic(panic: &PanicPayload) -> String {
+    let location = panic
+        .location
+        .as_ref()
+        .map(|location| format!(" at {}:{}", location.file, location.line))
+        .unwrap_or_default();
+    let backtrace: Vec<String> = panic.backtrace.iter().map(|pc| format!("{:#010x}", pc)).collect();
+    format!("Firmware panicked{}: {} (backtrace: {})", location, panic.message, backtrace.join(" "))
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled { "on" } else { "off" }
+}
+
+/// Parse a strip from `<leds>[:<order>[:<chip>]]`, keeping the previous order and chip of the strip when omitted
+fn parse_strip(arg: &str, previous: Option<&StripConfig>) -> Option<StripConfig> {
+    let mut parts = arg.split(':');
+    let num_leds = parts.next()?.parse().ok()?;
+    let color_order = match parts.next() {
+        None => previous.map_or(ColorOrder::Grb, |strip| strip.color_order),
+        Some("rgb") => ColorOrder::Rgb,
+        Some("rbg") => ColorOrder::Rbg,
+        Some("grb") => ColorOrder::Grb,
+        Some("gbr") => ColorOrder::Gbr,
+        Some("brg") => ColorOrder::Brg,
+        Some("bgr") => ColorOrder::Bgr,
+        Some(_) => return None,
+    };
+    let chip = match parts.next() {
+        None => previous.map_or(LedChip::Ws2812b, |strip| strip.chip),
+        Some("ws2812b") => LedChip::Ws2812b,
+        Some("ws2811") => LedChip::Ws2811,
+        Some("sk6812") => LedChip::Sk6812,
+        Some(_) => return None,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(StripConfig {
+        num_leds,
+        color_order,
+        chip,
+    })
+}
+
+/// Gamma table raising each level, as a fraction of full brightness, to the power of exponent
+fn gamma_table(exponent: f32) -> Vec<u8> {
+    (0..GAMMA_TABLE_SIZE)
+        .map(|level| {
+            let fraction = level as f32 / (GAMMA_TABLE_SIZE - 1) as f32;
+            (fraction.powf(exponent) * 255.0).round() as u8
+        })
+        .collect()
+}
@@ -0,0 +1,735 @@
+use common::e131::PIXELS_PER_UNIVERSE;
+use common::message::Effect;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Config file read when `--config` isn't given, the defaults are used if it doesn't exist
+pub const DEFAULT_CONFIG_FILE: &str = "tree.toml";
+
+/// Minutes in a day
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// Settings of the host and the tree, loaded from a TOML file
+///
+/// Every setting is optional. The port, baud rate, flow control, LED count and metrics address apply from the next
+/// start, the rest are re-applied whenever the file changes.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Serial port the tree is connected to
+    pub port: String,
+    /// Baud rate negotiated with the firmware after connecting at the default rate,
+    /// the link stays at the default if either end can't keep up
+    pub baud_rate: u32,
+    /// Use RTS/CTS flow control on the serial port, which must match the firmware's uart_flow_control setting
+    /// (`tree uart-flow-control <on|off>`) and needs RTS and CTS wired to GPIO3 and GPIO2
+    pub hardware_flow_control: bool,
+    /// Number of LEDs frames are sized to, the firmware's DeviceInfo is used when omitted
+    pub num_leds: Option<usize>,
+    /// Effect the tree runs once connected
+    pub default_effect: Option<DefaultEffect>,
+    /// Offset of local time from UTC in minutes, for the brightness schedule and rules
+    pub utc_offset_min: i16,
+    /// Where the tree is in degrees north, for rules at sunrise and sunset
+    pub latitude: Option<f64>,
+    /// Where the tree is in degrees east, for rules at sunrise and sunset
+    pub longitude: Option<f64>,
+    /// Brightness the tree is capped at from each time of day
+    pub brightness: Vec<BrightnessEntry>,
+    /// Throttle frame output to what the firmware acknowledges
+    /// Output pauses while the firmware reports Busy either way
+    pub flow_control: bool,
+    /// Ask the firmware to report the checksums of every Nth frame it writes to the LEDs (0 disables)
+    pub verify_interval: u16,
+    /// Delay in milliseconds after which frames are presented on the firmware's clock, smoothing out jitter in the
+    /// link (omitted displays frames as they arrive), it should cover the link's latency but stay under the frame
+    /// interval
+    pub presentation_delay_ms: Option<u64>,
+    /// Time constant of the output smoothing filter in milliseconds (omitted disables smoothing)
+    pub smoothing_ms: Option<u64>,
+    /// Ask the firmware to include source locations in its log messages
+    pub verbose_diagnostics: bool,
+    /// TOML file of per-LED white balance and brightness corrections applied to the frames `tree run` renders,
+    /// reloaded whenever it changes
+    pub correction_file: Option<PathBuf>,
+    /// Limit flashes and brightness jumps from every effect for photosensitive viewers
+    pub reduced_flash: bool,
+    /// First universe `tree bridge` maps onto the LEDs when no universes are configured,
+    /// consecutive universes following it fill PIXELS_PER_UNIVERSE LEDs each
+    pub first_universe: u16,
+    /// Where `tree bridge` places the channels of each universe on the LEDs, Art-Net universes being port-addresses
+    pub universes: Vec<UniverseMapping>,
+    /// GIFT CSV of where each LED is on the tree, for effects animating through its volume,
+    /// the LEDs are assumed to be wound in a spiral from the bottom of the tree when omitted
+    pub map_file: Option<PathBuf>,
+    /// Directory `tree script` loads effect scripts from, reloading each when it changes
+    pub script_dir: PathBuf,
+    /// JSON file `tree preset` and the HTTP API keep named presets in
+    pub presets_file: PathBuf,
+    /// Directory the HTTP API plays FSEQ sequences from
+    pub sequence_dir: PathBuf,
+    /// What `tree run` does at times of day, like turning the tree on or loading a preset
+    pub rules: Vec<Rule>,
+    /// Where frames are copied to besides the tree, reopened whenever they change
+    pub sinks: Vec<SinkConfig>,
+    /// Where `tree weather` reads the weather, and the effects it shows for it
+    pub weather: WeatherConfig,
+    /// Address `tree run` serves Prometheus metrics on at /metrics, like "0.0.0.0:9184", not served when omitted
+    pub metrics_listen: Option<SocketAddr>,
+    /// Recording frames are copied to from `--record`, which isn't read from the file
+    #[serde(skip)]
+    pub record_file: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            port: "/dev/ttyACM0".to_string(),
+            baud_rate: 921_600,
+            hardware_flow_control: false,
+            num_leds: None,
+            default_effect: None,
+            utc_offset_min: 0,
+            latitude: None,
+            longitude: None,
+            brightness: Vec::new(),
+            flow_control: true,
+            verify_interval: 10,
+            presentation_delay_ms: None,
+            smoothing_ms: None,
+            verbose_diagnostics: false,
+            correction_file: None,
+            reduced_flash: false,
+            first_universe: 1,
+            universes: Vec::new(),
+            map_file: None,
+            script_dir: PathBuf::from("scripts"),
+            presets_file: PathBuf::from("presets.json"),
+            sequence_dir: PathBuf::from("sequences"),
+            rules: Vec::new(),
+            sinks: Vec::new(),
+            weather: WeatherConfig::default(),
+            metrics_listen: None,
+            record_file: None,
+        }
+    }
+}
+
+impl Config {
+    /// Read and parse a config file
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+        toml::from_str(&contents).map_err(ConfigError::Parse)
+    }
+
+    /// Read a config file, using the defaults if it doesn't exist
+    pub fn load_or_default(path: &Path) -> Result<Config, ConfigError> {
+        match Config::load(path) {
+            Err(ConfigError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            result => result,
+        }
+    }
+
+    pub fn presentation_delay(&self) -> Option<Duration> {
+        self.presentation_delay_ms.map(Duration::from_millis)
+    }
+
+    pub fn smoothing_time_constant(&self) -> Option<Duration> {
+        self.smoothing_ms.map(Duration::from_millis)
+    }
+
+    /// Whether the settings that only apply from the next start differ
+    pub fn needs_restart(&self, other: &Config) -> bool {
+        self.port != other.port
+            || self.baud_rate != other.baud_rate
+            || self.hardware_flow_control != other.hardware_flow_control
+            || self.num_leds != other.num_leds
+            || self.metrics_listen != other.metrics_listen
+    }
+
+    /// Brightness in percent the schedule sets at a minute of the local day,
+    /// carrying the day's last entry over midnight, or None without a schedule
+    pub fn brightness_at(&self, minute_of_day: u16) -> Option<u8> {
+        let latest = |entry: &&BrightnessEntry| entry.at.0;
+        self.brightness
+            .iter()
+            .filter(|entry| entry.at.0 <= minute_of_day)
+            .max_by_key(latest)
+            .or_else(|| self.brightness.iter().max_by_key(latest))
+            .map(|entry| entry.percent)
+    }
+
+    /// Brightness in percent the schedule sets now
+    pub fn brightness_now(&self) -> Option<u8> {
+        self.brightness_at(self.local_minute().rem_euclid(MINUTES_PER_DAY as i64) as u16)
+    }
+
+    /// The sinks frames are copied to, with the recording from `--record`
+    pub fn output_sinks(&self) -> Vec<SinkConfig> {
+        let record = self.record_file.clone().map(|path| SinkConfig::Record { path });
+        self.sinks.iter().cloned().chain(record).collect()
+    }
+
+    /// Latitude and longitude of the tree, if both are set
+    pub fn location(&self) -> Option<(f64, f64)> {
+        self.latitude.zip(self.longitude)
+    }
+
+    /// Local time now in minutes since the Unix epoch
+    pub fn local_minute(&self) -> i64 {
+        let minutes = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 60;
+        minutes as i64 + self.utc_offset_min as i64
+    }
+}
+
+/// Effect the tree runs once connected
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DefaultEffect {
+    pub effect: Effect,
+    /// Speed as a multiple of the effect's normal speed
+    #[serde(default = "DefaultEffect::default_speed")]
+    pub speed: f32,
+}
+
+impl DefaultEffect {
+    fn default_speed() -> f32 {
+        1.0
+    }
+}
+
+/// LEDs lit by the RGB channels of a universe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UniverseMapping {
+    pub universe: u16,
+    /// First LED lit by the universe
+    pub start_led: usize,
+    /// Number of LEDs lit by the universe
+    #[serde(default = "UniverseMapping::default_leds")]
+    pub leds: usize,
+    /// DMX channel of the first LED's red, from 1
+    #[serde(default = "UniverseMapping::default_channel")]
+    pub channel: u16,
+}
+
+impl UniverseMapping {
+    fn default_leds() -> usize {
+        PIXELS_PER_UNIVERSE
+    }
+
+    fn default_channel() -> u16 {
+        1
+    }
+}
+
+/// Somewhere frames are copied to besides the tree, a `[[sinks]]` table with its `type`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SinkConfig {
+    /// Another tree, or the pseudo-terminal of `tree simulate`
+    Serial { port: String },
+    /// A DDP receiver such as a WLED device, as `host` or `host:port`
+    Ddp { address: String },
+    /// A recording of the frames, replaced each time they start
+    Record { path: PathBuf },
+}
+
+impl fmt::Display for SinkConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SinkConfig::Serial { port } => write!(f, "serial port {}", port),
+            SinkConfig::Ddp { address } => write!(f, "DDP receiver {}", address),
+            SinkConfig::Record { path } => write!(f, "recording {}", path.display()),
+        }
+    }
+}
+
+/// Where `tree weather` reads the weather at the config's latitude and longitude, a `[weather]` table
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WeatherConfig {
+    /// Open-Meteo's forecast API, or a server answering the same queries
+    pub url: String,
+    /// Minutes between readings
+    pub poll_interval_min: u64,
+    /// Host effects for the weather, the first rule it matches being shown, replacing the built-in rules when given
+    pub rules: Vec<WeatherRule>,
+}
+
+impl Default for WeatherConfig {
+    fn default() -> Self {
+        let fixed = |value: &str| ParamBinding::Fixed(toml::Value::String(value.to_string()));
+        WeatherConfig {
+            url: "https://api.open-meteo.com/v1/forecast".to_string(),
+            poll_interval_min: 15,
+            rules: vec![
+                // Snowfall as heavy as the snow
+                WeatherRule {
+                    when: Some(Sky::Snow),
+                    below_c: None,
+                    above_c: None,
+                    effect: "snowfall".to_string(),
+                    params: BTreeMap::from([(
+                        "density".to_string(),
+                        ParamBinding::Reading {
+                            reading: Reading::SnowfallCm,
+                            from: [0.0, 2.0],
+                            to: [0.2, 1.0],
+                        },
+                    )]),
+                },
+                // A blue shimmer when it's freezing, glinting faster the windier it is
+                WeatherRule {
+                    when: None,
+                    below_c: Some(0.0),
+                    above_c: None,
+                    effect: "shimmer".to_string(),
+                    params: BTreeMap::from([
+                        ("color".to_string(), fixed("4080ff")),
+                        (
+                            "speed".to_string(),
+                            ParamBinding::Reading {
+                                reading: Reading::WindKmh,
+                                from: [0.0, 40.0],
+                                to: [0.5, 3.0],
+                            },
+                        ),
+                    ]),
+                },
+                // A warm shimmer otherwise
+                WeatherRule {
+                    when: None,
+                    below_c: None,
+                    above_c: None,
+                    effect: "shimmer".to_string(),
+                    params: BTreeMap::from([
+                        ("color".to_string(), fixed("ffa040")),
+                        ("depth".to_string(), fixed("0.3")),
+                    ]),
+                },
+            ],
+        }
+    }
+}
+
+/// Host effect shown in some weather, a `[[weather.rules]]` table
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WeatherRule {
+    /// What the sky is doing, any weather matching when omitted
+    pub when: Option<Sky>,
+    /// Matches only below this temperature in °C
+    pub below_c: Option<f32>,
+    /// Matches only at or above this temperature in °C
+    pub above_c: Option<f32>,
+    /// One of `tree effects`
+    pub effect: String,
+    /// Parameters of the effect, those not given taking their defaults
+    #[serde(default)]
+    pub params: BTreeMap<String, ParamBinding>,
+}
+
+/// What the sky is doing, from the weather code of a reading
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Sky {
+    Clear,
+    Cloudy,
+    Fog,
+    Rain,
+    Snow,
+    Storm,
+}
+
+/// Value of an effect parameter in a weather rule
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum ParamBinding {
+    /// A reading scaled from one range onto another, clamped to it,
+    /// like `{ reading = "wind_kmh", from = [0, 40], to = [0.5, 3] }`
+    Reading {
+        reading: Reading,
+        from: [f32; 2],
+        to: [f32; 2],
+    },
+    /// A value as `tree run --param` takes it, like `"ff0000"` or `2.5`
+    Fixed(toml::Value),
+}
+
+/// Reading of the weather an effect parameter can follow
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reading {
+    TemperatureC,
+    WindKmh,
+    PrecipitationMm,
+    SnowfallCm,
+    /// Percentage of the sky covered
+    CloudCover,
+}
+
+/// Brightness the tree is capped at from a time of day until the next entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BrightnessEntry {
+    pub at: TimeOfDay,
+    /// Brightness in percent, 0-100
+    #[serde(deserialize_with = "deserialize_percent")]
+    pub percent: u8,
+}
+
+/// Action taken at a time of day, on the given days of the week or every day if none are given
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub at: RuleTime,
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    /// `"on"`, `"off"` or `{ preset = "<name>" }`
+    pub action: RuleAction,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    On,
+    Off,
+    /// Load a saved preset
+    Preset(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Weekday {
+    /// Day of the week of a day counted from the Unix epoch, which was a Thursday
+    pub fn of_day(day: i64) -> Weekday {
+        const WEEK: [Weekday; 7] = [
+            Weekday::Thu,
+            Weekday::Fri,
+            Weekday::Sat,
+            Weekday::Sun,
+            Weekday::Mon,
+            Weekday::Tue,
+            Weekday::Wed,
+        ];
+        WEEK[day.rem_euclid(7) as usize]
+    }
+}
+
+/// When a rule fires each day, written as `HH:MM`, or as `sunrise` or `sunset` with an optional offset in minutes
+/// like `sunset+60` or `sunrise-30`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum RuleTime {
+    At(TimeOfDay),
+    Sunrise(i16),
+    Sunset(i16),
+}
+
+impl RuleTime {
+    /// Whether the time depends on the sun, and so on the tree's location
+    pub fn is_solar(&self) -> bool {
+        !matches!(self, RuleTime::At(_))
+    }
+}
+
+impl TryFrom<String> for RuleTime {
+    type Error = String;
+
+    fn try_from(time: String) -> Result<Self, Self::Error> {
+        let offset = |offset: &str| match offset {
+            "" => Ok(0),
+            offset => offset
+                .parse()
+                .map_err(|_| format!("{} is not sunrise or sunset with an offset like +60 or -30", time)),
+        };
+        if let Some(after) = time.strip_prefix("sunrise") {
+            return Ok(RuleTime::Sunrise(offset(after)?));
+        }
+        if let Some(after) = time.strip_prefix("sunset") {
+            return Ok(RuleTime::Sunset(offset(after)?));
+        }
+        TimeOfDay::try_from(time).map(RuleTime::At)
+    }
+}
+
+impl fmt::Display for RuleTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleTime::At(time) => write!(f, "{}", time),
+            RuleTime::Sunrise(0) => write!(f, "sunrise"),
+            RuleTime::Sunset(0) => write!(f, "sunset"),
+            RuleTime::Sunrise(after) => write!(f, "sunrise{:+}", after),
+            RuleTime::Sunset(after) => write!(f, "sunset{:+}", after),
+        }
+    }
+}
+
+/// Local time of day in minutes since midnight, written as `HH:MM`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeOfDay(pub u16);
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(time: String) -> Result<Self, Self::Error> {
+        let error = || format!("{} is not a time of day, use HH:MM", time);
+        let (hours, minutes) = time.split_once(':').ok_or_else(error)?;
+        let hours: u16 = hours.parse().map_err(|_| error())?;
+        let minutes: u16 = minutes.parse().map_err(|_| error())?;
+        if hours >= 24 || minutes >= 60 {
+            return Err(error());
+        }
+        Ok(TimeOfDay(hours * 60 + minutes))
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
+}
+
+fn deserialize_percent<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    let percent = u8::deserialize(deserializer)?;
+    if percent > 100 {
+        return Err(serde::de::Error::custom(format!("{} is not a percentage, use 0-100", percent)));
+    }
+    Ok(percent)
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "Failed to read config file: {}", e),
+            ConfigError::Parse(e) => write!(f, "Invalid config file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Notices a config file changing by polling its modification time
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl ConfigWatcher {
+    /// Watch a config file from its current contents
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let modified = modified(&path);
+        ConfigWatcher { path, modified }
+    }
+
+    /// The config file's contents if it has been written, created or removed since the last poll
+    /// A removed file gives the defaults
+    pub fn poll(&mut self) -> Option<Result<Config, ConfigError>> {
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+        Some(Config::load_or_default(&self.path))
+    }
+}
+
+/// When a file was last modified, or None if it doesn't exist
+pub fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+
+    #[test]
+    fn parses_a_config_file() {
+        let config: Config = toml::from_str(
+            r#"
+            port = "/dev/ttyUSB0"
+            num_leds = 300
+            utc_offset_min = 60
+            default_effect = { effect = "twinkle", speed = 2.0 }
+
+            [[brightness]]
+            at = "07:30"
+            percent = 100
+
+            [[brightness]]
+            at = "22:00"
+            percent = 20
+
+            [[universes]]
+            universe = 5
+            start_led = 170
+
+            [[sinks]]
+            type = "ddp"
+            address = "192.168.1.40"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.port, "/dev/ttyUSB0");
+        assert_eq!(config.num_leds, Some(300));
+        assert_eq!(config.baud_rate, Config::default().baud_rate);
+        assert_eq!(
+            config.default_effect,
+            Some(DefaultEffect {
+                effect: Effect::Twinkle,
+                speed: 2.0
+            })
+        );
+        assert_eq!(config.brightness[0].at, TimeOfDay(7 * 60 + 30));
+        assert_eq!(
+            config.universes,
+            vec![UniverseMapping {
+                universe: 5,
+                start_led: 170,
+                leds: PIXELS_PER_UNIVERSE,
+                channel: 1
+            }]
+        );
+        assert_eq!(
+            config.sinks,
+            vec![SinkConfig::Ddp {
+                address: "192.168.1.40".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn parses_rules() {
+        let config: Config = toml::from_str(
+            r#"
+            [[rules]]
+            at = "16:30"
+            action = "on"
+
+            [[rules]]
+            at = "22:00"
+            days = ["sat", "sun"]
+            action = { preset = "calm" }
+
+            [[rules]]
+            at = "sunset-30"
+            action = "on"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.rules[0].action, RuleAction::On);
+        assert_eq!(
+            config.rules[1],
+            Rule {
+                at: RuleTime::At(TimeOfDay(22 * 60)),
+                days: vec![Weekday::Sat, Weekday::Sun],
+                action: RuleAction::Preset("calm".to_string()),
+            }
+        );
+        assert_eq!(config.rules[2].at, RuleTime::Sunset(-30));
+        assert_eq!(config.rules[2].at.to_string(), "sunset-30");
+        assert!(toml::from_str::<Config>("[[rules]]\nat = \"12:00\"\naction = \"dim\"").is_err());
+        assert!(toml::from_str::<Config>("[[rules]]\nat = \"sunset+1h\"\naction = \"on\"").is_err());
+        // 2024-12-24 was a Tuesday
+        assert_eq!(Weekday::of_day(20_081), Weekday::Tue);
+    }
+
+    #[test]
+    fn parses_weather_rules() {
+        let config: Config = toml::from_str(
+            r#"
+            [weather]
+            poll_interval_min = 30
+
+            [[weather.rules]]
+            when = "rain"
+            above_c = 5.0
+            effect = "sweep"
+            params = { color = "0000ff", period = { reading = "precipitation_mm", from = [0, 10], to = [4, 0.5] } }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.weather.url, WeatherConfig::default().url);
+        assert_eq!(config.weather.poll_interval_min, 30);
+        let rule = &config.weather.rules[0];
+        assert_eq!((rule.when, rule.below_c, rule.above_c), (Some(Sky::Rain), None, Some(5.0)));
+        assert_eq!(rule.params["color"], ParamBinding::Fixed(toml::Value::String("0000ff".to_string())));
+        assert_eq!(
+            rule.params["period"],
+            ParamBinding::Reading {
+                reading: Reading::PrecipitationMm,
+                from: [0.0, 10.0],
+                to: [4.0, 0.5],
+            }
+        );
+        assert!(toml::from_str::<Config>("[[weather.rules]]\nwhen = \"hail\"\neffect = \"solid\"").is_err());
+    }
+
+    #[test]
+    fn empty_file_uses_defaults() {
+        assert_eq!(toml::from_str::<Config>("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        assert!(toml::from_str::<Config>("prot = \"/dev/ttyUSB0\"").is_err());
+        assert!(toml::from_str::<Config>("[[brightness]]\nat = \"25:00\"\npercent = 50").is_err());
+        assert!(toml::from_str::<Config>("[[brightness]]\nat = \"12:00\"\npercent = 150").is_err());
+        assert!(toml::from_str::<Config>("[[sinks]]\ntype = \"wled\"\naddress = \"tree.local\"").is_err());
+    }
+
+    #[test]
+    fn brightness_schedule_wraps_around_midnight() {
+        let config: Config = toml::from_str(
+            "[[brightness]]\nat = \"22:00\"\npercent = 20\n[[brightness]]\nat = \"07:00\"\npercent = 100",
+        )
+        .unwrap();
+        assert_eq!(config.brightness_at(6 * 60), Some(20));
+        assert_eq!(config.brightness_at(7 * 60), Some(100));
+        assert_eq!(config.brightness_at(21 * 60 + 59), Some(100));
+        assert_eq!(config.brightness_at(23 * 60), Some(20));
+        assert_eq!(Config::default().brightness_at(12 * 60), None);
+    }
+
+    #[test]
+    fn watcher_reloads_changed_files() {
+        let path = std::env::temp_dir().join(format!("tree-config-{}.toml", std::process::id()));
+        std::fs::write(&path, "num_leds = 50").unwrap();
+        let mut watcher = ConfigWatcher::new(&path);
+        assert!(watcher.poll().is_none());
+
+        let mut file = File::create(&path).unwrap();
+        file.write_all(b"num_leds = 60").unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(1)).unwrap();
+        assert_eq!(watcher.poll().unwrap().unwrap().num_leds, Some(60));
+        assert!(watcher.poll().is_none());
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(watcher.poll().unwrap().unwrap(), Config::default());
+    }
+}
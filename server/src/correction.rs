@@ -0,0 +1,168 @@
+use common::message::Rgb16;
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+
+/// Correction of a run of LEDs, such as a length of strip from another batch
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Section {
+    /// First LED of the section
+    pub start: usize,
+    /// Number of LEDs in the section, up to the last LED when omitted
+    pub len: Option<usize>,
+    /// Factors red, green and blue are scaled by
+    #[serde(default = "Section::neutral")]
+    pub white_balance: [f32; 3],
+    /// Factor the whole color is scaled by
+    #[serde(default = "Section::full")]
+    pub brightness: f32,
+}
+
+impl Section {
+    fn neutral() -> [f32; 3] {
+        [1.0; 3]
+    }
+
+    fn full() -> f32 {
+        1.0
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CorrectionFile {
+    #[serde(default)]
+    section: Vec<Section>,
+}
+
+#[derive(Debug)]
+pub enum CorrectionError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    /// A factor is negative or not a number
+    Factor(f32),
+}
+
+impl fmt::Display for CorrectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CorrectionError::Io(e) => write!(f, "Failed to read the color correction file: {}", e),
+            CorrectionError::Parse(e) => write!(f, "Invalid color correction file: {}", e),
+            CorrectionError::Factor(factor) => {
+                write!(f, "Invalid color correction file: {} is not a factor of 0 or more", factor)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CorrectionError {}
+
+/// Per-LED white balance and brightness correction for the output pipeline, evening out LEDs that don't match
+///
+/// Read from a TOML file of `[[section]]` tables, a section later in the file replacing the correction of the
+/// LEDs it shares with earlier ones. LEDs outside every section are left as they are.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ColorCorrection {
+    sections: Vec<Section>,
+    /// Factor each channel of each LED is scaled by, built for the length of the last frame
+    gains: Vec<[f32; 3]>,
+}
+
+impl ColorCorrection {
+    pub fn load(path: &Path) -> Result<ColorCorrection, CorrectionError> {
+        let contents = std::fs::read_to_string(path).map_err(CorrectionError::Io)?;
+        ColorCorrection::parse(&contents)
+    }
+
+    pub fn parse(toml: &str) -> Result<ColorCorrection, CorrectionError> {
+        let file: CorrectionFile = toml::from_str(toml).map_err(CorrectionError::Parse)?;
+        for section in &file.section {
+            for &factor in section.white_balance.iter().chain([&section.brightness]) {
+                if !factor.is_finite() || factor < 0.0 {
+                    return Err(CorrectionError::Factor(factor));
+                }
+            }
+        }
+        Ok(ColorCorrection {
+            sections: file.section,
+            gains: Vec::new(),
+        })
+    }
+
+    /// Correct a frame's colors, channels scaled past full brightness staying at full
+    pub fn apply(&mut self, leds: &mut [Rgb16]) {
+        if self.gains.len() != leds.len() {
+            self.gains = self.build_gains(leds.len());
+        }
+        for (rgb, gain) in leds.iter_mut().zip(&self.gains) {
+            let scale = |channel: u16, gain: f32| (channel as f32 * gain).round().min(u16::MAX as f32) as u16;
+            *rgb = Rgb16::new(scale(rgb.r, gain[0]), scale(rgb.g, gain[1]), scale(rgb.b, gain[2]));
+        }
+    }
+
+    fn build_gains(&self, len: usize) -> Vec<[f32; 3]> {
+        let mut gains = vec![[1.0; 3]; len];
+        for section in &self.sections {
+            let end = section.len.map_or(len, |count| section.start.saturating_add(count)).min(len);
+            if section.start < end {
+                gains[section.start..end].fill(section.white_balance.map(|factor| factor * section.brightness));
+            }
+        }
+        gains
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrects_each_section() {
+        let mut correction = ColorCorrection::parse(
+            r#"
+            # The second batch is bluer and brighter
+            [[section]]
+            start = 2
+            white_balance = [1.0, 1.0, 0.5]
+            brightness = 0.5
+
+            # A single LED that's too dim
+            [[section]]
+            start = 3
+            len = 1
+            brightness = 2.0
+            "#,
+        )
+        .unwrap();
+        let mut leds = vec![Rgb16::new(40_000, 40_000, 40_000); 5];
+        correction.apply(&mut leds);
+        assert_eq!(
+            leds,
+            vec![
+                Rgb16::new(40_000, 40_000, 40_000),
+                Rgb16::new(40_000, 40_000, 40_000),
+                Rgb16::new(20_000, 20_000, 10_000),
+                Rgb16::new(u16::MAX, u16::MAX, u16::MAX),
+                Rgb16::new(20_000, 20_000, 10_000),
+            ]
+        );
+
+        // Sections past the end of a shorter frame are left out
+        let mut leds = vec![Rgb16::new(1000, 1000, 1000); 2];
+        correction.apply(&mut leds);
+        assert_eq!(leds, vec![Rgb16::new(1000, 1000, 1000); 2]);
+    }
+
+    #[test]
+    fn rejects_invalid_factors() {
+        assert!(matches!(
+            ColorCorrection::parse("[[section]]\nstart = 0\nbrightness = -1.0"),
+            Err(CorrectionError::Factor(_))
+        ));
+        assert!(matches!(
+            ColorCorrection::parse("[[section]]\nstart = 0\ngain = 1.0"),
+            Err(CorrectionError::Parse(_))
+        ));
+    }
+}
@@ -0,0 +1,332 @@
+use common::message::Rgb;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use crate::cli::parse_color;
+use crate::frame::Frame;
+use crate::mapping::TreeMap;
+
+mod ramp;
+mod shimmer;
+mod snowfall;
+mod solid;
+mod sweep;
+mod text;
+
+pub use ramp::Ramp;
+pub use shimmer::Shimmer;
+pub use snowfall::Snowfall;
+pub use solid::Solid;
+pub use sweep::Sweep;
+pub use text::ScrollingText;
+
+/// Kind and range of an effect parameter
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ParamKind {
+    /// Floating point value within an inclusive range
+    Float { min: f32, max: f32, default: f32 },
+    /// Integer value within an inclusive range
+    Int { min: i64, max: i64, default: i64 },
+    /// On/off switch
+    Bool { default: bool },
+    /// RGB color
+    Color { default: [u8; 3] },
+    /// One of a fixed set of options
+    Choice {
+        options: &'static [&'static str],
+        default: &'static str,
+    },
+    /// Free-form text
+    Text { default: &'static str },
+}
+
+impl ParamKind {
+    /// Value the parameter takes when none is given
+    pub fn default_value(&self) -> ParamValue {
+        match self {
+            ParamKind::Float { default, .. } => ParamValue::Float(*default),
+            ParamKind::Int { default, .. } => ParamValue::Int(*default),
+            ParamKind::Bool { default } => ParamValue::Bool(*default),
+            ParamKind::Color { default: [r, g, b] } => ParamValue::Color(Rgb::new(*r, *g, *b)),
+            ParamKind::Choice { default, .. } | ParamKind::Text { default } => ParamValue::Text(default.to_string()),
+        }
+    }
+
+    /// Parse a value given as text, checking it's within the parameter's range
+    pub fn parse(&self, value: &str) -> Result<ParamValue, String> {
+        match self {
+            ParamKind::Float { min, max, .. } => {
+                let parsed: f32 = value.parse().map_err(|_| format!("{} is not a number", value))?;
+                if !(*min..=*max).contains(&parsed) {
+                    return Err(format!("{} is outside {}-{}", value, min, max));
+                }
+                Ok(ParamValue::Float(parsed))
+            }
+            ParamKind::Int { min, max, .. } => {
+                let parsed: i64 = value.parse().map_err(|_| format!("{} is not an integer", value))?;
+                if !(*min..=*max).contains(&parsed) {
+                    return Err(format!("{} is outside {}-{}", value, min, max));
+                }
+                Ok(ParamValue::Int(parsed))
+            }
+            ParamKind::Bool { .. } => match value {
+                "on" | "true" => Ok(ParamValue::Bool(true)),
+                "off" | "false" => Ok(ParamValue::Bool(false)),
+                _ => Err(format!("{} is not on or off", value)),
+            },
+            ParamKind::Color { .. } => parse_color(value).map(ParamValue::Color),
+            ParamKind::Choice { options, .. } if options.contains(&value) => Ok(ParamValue::Text(value.to_string())),
+            ParamKind::Choice { options, .. } => Err(format!("{} is not one of {}", value, options.join(", "))),
+            ParamKind::Text { .. } => Ok(ParamValue::Text(value.to_string())),
+        }
+    }
+}
+
+/// Value of an effect parameter, choices and text both being Text
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamValue {
+    Float(f32),
+    Int(i64),
+    Bool(bool),
+    Color(Rgb),
+    Text(String),
+}
+
+/// Value of every parameter of an effect
+///
+/// Getters fall back to a zero value for ids the effect doesn't have or of another kind,
+/// which can't happen for params built from the effect's own schema.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Params {
+    values: BTreeMap<&'static str, ParamValue>,
+}
+
+impl Params {
+    pub fn float(&self, id: &str) -> f32 {
+        match self.values.get(id) {
+            Some(ParamValue::Float(value)) => *value,
+            _ => 0.0,
+        }
+    }
+
+    pub fn int(&self, id: &str) -> i64 {
+        match self.values.get(id) {
+            Some(ParamValue::Int(value)) => *value,
+            _ => 0,
+        }
+    }
+
+    pub fn bool(&self, id: &str) -> bool {
+        matches!(self.values.get(id), Some(ParamValue::Bool(true)))
+    }
+
+    pub fn color(&self, id: &str) -> Rgb {
+        match self.values.get(id) {
+            Some(ParamValue::Color(value)) => *value,
+            _ => Rgb::default(),
+        }
+    }
+
+    pub fn text(&self, id: &str) -> &str {
+        match self.values.get(id) {
+            Some(ParamValue::Text(value)) => value,
+            _ => "",
+        }
+    }
+}
+
+/// Description of a single effect parameter
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ParamSpec {
+    pub id: &'static str,
+    pub name: &'static str,
+    pub description: &'static str,
+    pub kind: ParamKind,
+}
+
+/// Metadata describing an effect, so UIs can present it without knowing it in advance
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EffectInfo {
+    /// Unique identifier used to select the effect
+    pub id: &'static str,
+    /// Human readable name
+    pub name: &'static str,
+    pub description: &'static str,
+    pub params: Vec<ParamSpec>,
+    pub tags: Vec<&'static str>,
+}
+
+impl EffectInfo {
+    /// Find a parameter spec by id
+    pub fn param(&self, id: &str) -> Option<&ParamSpec> {
+        self.params.iter().find(|param| param.id == id)
+    }
+
+    /// Whether the effect has the given tag
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.contains(&tag)
+    }
+
+    /// Parse parameter values given as (id, value) text, the parameters not given taking their defaults
+    pub fn params(&self, values: &[(String, String)]) -> Result<Params, String> {
+        let mut params = Params::default();
+        for param in &self.params {
+            params.values.insert(param.id, param.kind.default_value());
+        }
+        for (id, value) in values {
+            let param = self.param(id).ok_or_else(|| format!("{} has no parameter {}", self.id, id))?;
+            let value = param.kind.parse(value).map_err(|e| format!("Invalid {}: {}", id, e))?;
+            params.values.insert(param.id, value);
+        }
+        Ok(params)
+    }
+}
+
+impl fmt::Display for EffectInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} ({}): {}", self.name, self.id, self.description)?;
+        if !self.tags.is_empty() {
+            writeln!(f, "  tags: {}", self.tags.join(", "))?;
+        }
+        for param in &self.params {
+            writeln!(f, "  {} - {} {:?}", param.id, param.description, param.kind)?;
+        }
+        Ok(())
+    }
+}
+
+/// An effect rendered on the host, in a module of its own registered in `EffectRegistry::with_builtin`
+pub trait Effect: Send {
+    /// Metadata and parameter schema of the effect
+    fn info() -> EffectInfo
+    where
+        Self: Sized;
+
+    /// Start the effect with a value for every parameter in its schema, and the position of each LED on the tree
+    fn init(&mut self, params: &Params, map: &TreeMap);
+
+    /// Advance the effect by dt and render it into the frame
+    fn tick(&mut self, dt: Duration, frame: &mut Frame);
+}
+
+struct Registered {
+    info: EffectInfo,
+    create: fn() -> Box<dyn Effect>,
+}
+
+/// Registry of every effect available to the server
+pub struct EffectRegistry {
+    effects: Vec<Registered>,
+}
+
+impl EffectRegistry {
+    /// Create an empty EffectRegistry
+    pub fn new() -> Self {
+        Self {
+            effects: Vec::new(),
+        }
+    }
+
+    /// Create an EffectRegistry containing the built-in effects
+    pub fn with_builtin() -> Self {
+        let mut registry = Self::new();
+        registry.register::<Solid>().expect("built-in effect ids are unique");
+        registry.register::<Ramp>().expect("built-in effect ids are unique");
+        registry.register::<Sweep>().expect("built-in effect ids are unique");
+        registry.register::<ScrollingText>().expect("built-in effect ids are unique");
+        registry.register::<Shimmer>().expect("built-in effect ids are unique");
+        registry.register::<Snowfall>().expect("built-in effect ids are unique");
+        registry
+    }
+
+    /// Register an effect
+    /// Returns Err with the effect's info if one with the same id is already registered
+    pub fn register<E: Effect + Default + 'static>(&mut self) -> Result<(), EffectInfo> {
+        let info = E::info();
+        if self.get(info.id).is_some() {
+            return Err(info);
+        }
+        self.effects.push(Registered {
+            info,
+            create: create::<E>,
+        });
+        Ok(())
+    }
+
+    /// Find an effect by id
+    pub fn get(&self, id: &str) -> Option<&EffectInfo> {
+        self.list().find(|effect| effect.id == id)
+    }
+
+    /// List every registered effect in registration order
+    pub fn list(&self) -> impl Iterator<Item = &EffectInfo> {
+        self.effects.iter().map(|effect| &effect.info)
+    }
+
+    /// List the effects with the given tag
+    pub fn with_tag<'a>(&'a self, tag: &'a str) -> impl Iterator<Item = &'a EffectInfo> {
+        self.list().filter(move |effect| effect.has_tag(tag))
+    }
+
+    /// Parse an effect's parameter values given as (id, value) text, the parameters not given taking their defaults
+    pub fn params(&self, id: &str, values: &[(String, String)]) -> Result<Params, String> {
+        self.registered(id)?.info.params(values)
+    }
+
+    /// Create an effect, initialized with a value for every parameter and the tree's map
+    pub fn create(&self, id: &str, params: &Params, map: &TreeMap) -> Result<Box<dyn Effect>, String> {
+        let mut effect = (self.registered(id)?.create)();
+        effect.init(params, map);
+        Ok(effect)
+    }
+
+    fn registered(&self, id: &str) -> Result<&Registered, String> {
+        self.effects
+            .iter()
+            .find(|effect| effect.info.id == id)
+            .ok_or_else(|| format!("No effect {}, see `tree effects`", id))
+    }
+}
+
+fn create<E: Effect + Default + 'static>() -> Box<dyn Effect> {
+    Box::new(E::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(values: &[(&str, &str)]) -> Vec<(String, String)> {
+        values.iter().map(|(id, value)| (id.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn params_take_defaults_and_check_ranges() {
+        let info = Ramp::info();
+        let params = info.params(&values(&[("speed", "5")])).unwrap();
+        assert_eq!((params.text("channel"), params.int("speed")), ("red", 5));
+
+        assert!(info.params(&values(&[("speed", "0")])).is_err());
+        assert!(info.params(&values(&[("channel", "purple")])).is_err());
+        assert!(info.params(&values(&[("colour", "ff0000")])).is_err());
+    }
+
+    #[test]
+    fn registered_effects_render_frames() {
+        let mut registry = EffectRegistry::with_builtin();
+        let ids: Vec<&str> = registry.list().map(|effect| effect.id).collect();
+        assert_eq!(ids, vec!["solid", "ramp", "sweep", "text", "shimmer", "snowfall"]);
+        assert!(registry.register::<Solid>().is_err());
+        assert!(registry.params("sparkle", &[]).is_err());
+
+        let map = TreeMap::spiral(3);
+        let params = registry.params("solid", &values(&[("color", "00ff00")])).unwrap();
+        let mut effect = registry.create("solid", &params, &map).unwrap();
+        let mut frame = Frame::new(3);
+        effect.tick(Duration::from_millis(10), &mut frame);
+        assert_eq!(frame.leds(), &[Rgb::new(0, 255, 0); 3]);
+    }
+}
@@ -0,0 +1,83 @@
+use common::message::Rgb;
+use std::time::Duration;
+
+use super::{Effect, EffectInfo, ParamKind, ParamSpec, Params};
+use crate::frame::Frame;
+use crate::mapping::TreeMap;
+
+/// One channel ramping up over the whole tree, wrapping back to off
+#[derive(Debug, Default)]
+pub struct Ramp {
+    /// Index of the ramped channel in r, g, b
+    channel: usize,
+    speed: u8,
+    level: u8,
+}
+
+impl Effect for Ramp {
+    fn info() -> EffectInfo {
+        EffectInfo {
+            id: "ramp",
+            name: "Ramp",
+            description: "Ramp a single channel from off to full brightness and wrap around",
+            params: vec![
+                ParamSpec {
+                    id: "channel",
+                    name: "Channel",
+                    description: "Color channel to ramp",
+                    kind: ParamKind::Choice {
+                        options: &["red", "green", "blue"],
+                        default: "red",
+                    },
+                },
+                ParamSpec {
+                    id: "speed",
+                    name: "Speed",
+                    description: "Brightness steps per tick",
+                    kind: ParamKind::Int {
+                        min: 1,
+                        max: 255,
+                        default: 1,
+                    },
+                },
+            ],
+            tags: vec!["animated", "builtin"],
+        }
+    }
+
+    fn init(&mut self, params: &Params, _map: &TreeMap) {
+        self.channel = match params.text("channel") {
+            "green" => 1,
+            "blue" => 2,
+            _ => 0,
+        };
+        self.speed = params.int("speed").clamp(1, 255) as u8;
+        self.level = 0;
+    }
+
+    fn tick(&mut self, _dt: Duration, frame: &mut Frame) {
+        self.level = self.level.wrapping_add(self.speed);
+        let mut channels = [0; 3];
+        channels[self.channel] = self.level;
+        frame.fill(0..frame.len(), Rgb::new(channels[0], channels[1], channels[2]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ramps_the_chosen_channel_and_wraps() {
+        let values = [("channel".to_string(), "blue".to_string()), ("speed".to_string(), "100".to_string())];
+        let mut ramp = Ramp::default();
+        ramp.init(&Ramp::info().params(&values).unwrap(), &TreeMap::default());
+        let mut frame = Frame::new(2);
+        let mut levels = Vec::new();
+        for _ in 0..3 {
+            ramp.tick(Duration::from_millis(10), &mut frame);
+            levels.push(frame.leds()[1]);
+        }
+        assert_eq!(levels, vec![Rgb::new(0, 0, 100), Rgb::new(0, 0, 200), Rgb::new(0, 0, 44)]);
+    }
+}
@@ -0,0 +1,102 @@
+use common::message::Rgb;
+use std::f32::consts::TAU;
+use std::time::Duration;
+
+use super::{Effect, EffectInfo, ParamKind, ParamSpec, Params};
+use crate::frame::Frame;
+use crate::mapping::TreeMap;
+
+/// Fraction of the golden ratio, which spreads the LEDs' phases evenly without neighbours matching
+const GOLDEN: f32 = 0.618_034;
+
+/// A color glinting as each LED dims and brightens on its own
+#[derive(Debug, Default)]
+pub struct Shimmer {
+    color: Rgb,
+    /// Glints of each LED per second
+    speed: f32,
+    /// How far the LEDs dim, as a fraction of the color
+    depth: f32,
+    /// Seconds the effect has run
+    elapsed: f32,
+}
+
+impl Effect for Shimmer {
+    fn info() -> EffectInfo {
+        EffectInfo {
+            id: "shimmer",
+            name: "Shimmer",
+            description: "Shimmer the tree in a color, each LED dimming and brightening out of step with the next",
+            params: vec![
+                ParamSpec {
+                    id: "color",
+                    name: "Color",
+                    description: "Color at its brightest",
+                    kind: ParamKind::Color { default: [64, 128, 255] },
+                },
+                ParamSpec {
+                    id: "speed",
+                    name: "Speed",
+                    description: "Glints of each LED per second",
+                    kind: ParamKind::Float {
+                        min: 0.1,
+                        max: 10.0,
+                        default: 1.0,
+                    },
+                },
+                ParamSpec {
+                    id: "depth",
+                    name: "Depth",
+                    description: "How far the LEDs dim, as a fraction of the color",
+                    kind: ParamKind::Float {
+                        min: 0.0,
+                        max: 1.0,
+                        default: 0.6,
+                    },
+                },
+            ],
+            tags: vec!["animated", "builtin"],
+        }
+    }
+
+    fn init(&mut self, params: &Params, _map: &TreeMap) {
+        self.color = params.color("color");
+        self.speed = params.float("speed");
+        self.depth = params.float("depth");
+        self.elapsed = 0.0;
+    }
+
+    fn tick(&mut self, dt: Duration, frame: &mut Frame) {
+        self.elapsed += dt.as_secs_f32();
+        let cycles = self.elapsed * self.speed;
+        for index in 0..frame.len() {
+            let phase = (index as f32 * GOLDEN).fract();
+            let wave = 0.5 + 0.5 * ((cycles + phase) * TAU).sin();
+            let level = 1.0 - self.depth * wave;
+            let scale = |channel: u8| (channel as f32 * level).round() as u8;
+            frame.set(index, Rgb::new(scale(self.color.r), scale(self.color.g), scale(self.color.b)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glints_within_the_depth() {
+        let values = [("color".to_string(), "0000c8".to_string()), ("depth".to_string(), "0.5".to_string())];
+        let mut shimmer = Shimmer::default();
+        shimmer.init(&Shimmer::info().params(&values).unwrap(), &TreeMap::spiral(20));
+        let mut frame = Frame::new(20);
+        let mut seen = Vec::new();
+        for _ in 0..10 {
+            shimmer.tick(Duration::from_millis(100), &mut frame);
+            assert!(frame.leds().iter().all(|led| (100..=200).contains(&led.b) && led.r == 0 && led.g == 0));
+            seen.push(frame.leds()[0].b);
+        }
+        // Neighbours are out of step, and each LED changes over time
+        assert_ne!(frame.leds()[0], frame.leds()[1]);
+        assert!(seen.iter().any(|&blue| blue != seen[0]));
+    }
+}
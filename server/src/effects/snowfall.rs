@@ -0,0 +1,182 @@
+use common::message::Rgb;
+use std::time::Duration;
+
+use super::{Effect, EffectInfo, ParamKind, ParamSpec, Params};
+use crate::frame::Frame;
+use crate::mapping::TreeMap;
+
+/// Distance from a flake at which LEDs stop glowing, as a fraction of the tree's height
+const RADIUS: f32 = 0.08;
+
+/// LEDs to each flake at the greatest density
+const LEDS_PER_FLAKE: f32 = 5.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Flake {
+    /// Position in tree heights, from the bottom of the tree
+    x: f32,
+    y: f32,
+    z: f32,
+    /// Tree heights fallen per second
+    fall: f32,
+}
+
+/// Snowflakes drifting down through the tree
+#[derive(Debug, Default)]
+pub struct Snowfall {
+    color: Rgb,
+    /// Tree heights a flake falls per second, on average
+    speed: f32,
+    /// Position of each LED in tree heights, from the bottom of the tree
+    positions: Vec<[f32; 3]>,
+    flakes: Vec<Flake>,
+    /// Numbers drawn so far, each flake landing somewhere new
+    drawn: u32,
+}
+
+impl Snowfall {
+    /// A number from 0 to 1 that looks random, the next each time
+    fn draw(&mut self) -> f32 {
+        let mut x = self.drawn;
+        self.drawn = self.drawn.wrapping_add(1);
+        x ^= x >> 16;
+        x = x.wrapping_mul(0x7feb_352d);
+        x ^= x >> 15;
+        x = x.wrapping_mul(0x846c_a68b);
+        x ^= x >> 16;
+        x as f32 / u32::MAX as f32
+    }
+
+    /// A flake at a height above a randomly picked LED
+    fn spawn(&mut self, z: f32) -> Flake {
+        let index = ((self.draw() * self.positions.len() as f32) as usize).min(self.positions.len() - 1);
+        let [x, y, _] = self.positions[index];
+        let fall = self.speed * (0.75 + 0.5 * self.draw());
+        Flake { x, y, z, fall }
+    }
+}
+
+impl Effect for Snowfall {
+    fn info() -> EffectInfo {
+        EffectInfo {
+            id: "snowfall",
+            name: "Snowfall",
+            description: "Drift snowflakes down through the tree, following the LEDs' mapped positions",
+            params: vec![
+                ParamSpec {
+                    id: "color",
+                    name: "Color",
+                    description: "Color of the flakes",
+                    kind: ParamKind::Color {
+                        default: [255, 255, 255],
+                    },
+                },
+                ParamSpec {
+                    id: "density",
+                    name: "Density",
+                    description: "How heavily it snows, from a few flakes to one for every five LEDs",
+                    kind: ParamKind::Float {
+                        min: 0.0,
+                        max: 1.0,
+                        default: 0.5,
+                    },
+                },
+                ParamSpec {
+                    id: "speed",
+                    name: "Speed",
+                    description: "Tree heights a flake falls per second",
+                    kind: ParamKind::Float {
+                        min: 0.05,
+                        max: 2.0,
+                        default: 0.3,
+                    },
+                },
+            ],
+            tags: vec!["animated", "3d", "builtin"],
+        }
+    }
+
+    fn init(&mut self, params: &Params, map: &TreeMap) {
+        self.color = params.color("color");
+        self.speed = params.float("speed");
+        let (bottom, top) = map.bounds().map(|(min, max)| (min.z, max.z)).unwrap_or((0.0, 1.0));
+        let height = (top - bottom).max(f32::EPSILON);
+        self.positions = map
+            .points()
+            .iter()
+            .map(|point| [point.x / height, point.y / height, (point.z - bottom) / height])
+            .collect();
+        self.flakes.clear();
+        if self.positions.is_empty() {
+            return;
+        }
+        let count = ((self.positions.len() as f32 * params.float("density") / LEDS_PER_FLAKE).round() as usize).max(1);
+        // Flakes start all through the tree rather than all arriving at once
+        for _ in 0..count {
+            let z = self.draw() * (1.0 + RADIUS);
+            let flake = self.spawn(z);
+            self.flakes.push(flake);
+        }
+    }
+
+    fn tick(&mut self, dt: Duration, frame: &mut Frame) {
+        let mut flakes = std::mem::take(&mut self.flakes);
+        for flake in &mut flakes {
+            flake.z -= flake.fall * dt.as_secs_f32();
+            // A flake that has fallen out of the bottom of the tree starts again above the top
+            if flake.z < -RADIUS {
+                *flake = self.spawn(1.0 + RADIUS);
+            }
+        }
+        self.flakes = flakes;
+        // LEDs missing from the map stay dark
+        for (index, [x, y, z]) in self.positions.iter().enumerate().take(frame.len()) {
+            let level = self
+                .flakes
+                .iter()
+                .map(|flake| {
+                    let distance = ((flake.x - x).powi(2) + (flake.y - y).powi(2) + (flake.z - z).powi(2)).sqrt();
+                    (1.0 - distance / RADIUS).max(0.0)
+                })
+                .fold(0.0, f32::max);
+            let scale = |channel: u8| (channel as f32 * level).round() as u8;
+            frame.set(index, Rgb::new(scale(self.color.r), scale(self.color.g), scale(self.color.b)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapping::Point3;
+
+    fn brightest(frame: &Frame) -> usize {
+        (0..frame.len()).max_by_key(|&index| frame.leds()[index].r).unwrap()
+    }
+
+    #[test]
+    fn flakes_fall_and_start_again_above_the_tree() {
+        // A column of LEDs a tenth of the tree apart
+        let map = TreeMap::new((0..=10).map(|z| Point3::new(0.0, 0.0, z as f32 / 10.0)).collect());
+        let mut snow = Snowfall::default();
+        snow.init(&Snowfall::info().params(&[]).unwrap(), &map);
+        assert_eq!(snow.flakes.len(), 1);
+        snow.flakes = vec![Flake {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+            fall: 0.5,
+        }];
+        let mut frame = Frame::new(11);
+
+        snow.tick(Duration::ZERO, &mut frame);
+        assert_eq!(brightest(&frame), 10);
+        assert_eq!(frame.leds()[10], Rgb::new(255, 255, 255));
+        assert_eq!(frame.leds()[8], Rgb::default());
+        snow.tick(Duration::from_secs(1), &mut frame);
+        assert_eq!(brightest(&frame), 5);
+        snow.tick(Duration::from_secs(2), &mut frame);
+        assert!(snow.flakes[0].z > 1.0);
+        assert!(frame.leds().iter().all(|&led| led == Rgb::default()));
+    }
+}
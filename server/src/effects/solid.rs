@@ -0,0 +1,42 @@
+use common::message::Rgb;
+use std::time::Duration;
+
+use super::{Effect, EffectInfo, ParamKind, ParamSpec, Params};
+use crate::frame::Frame;
+use crate::mapping::TreeMap;
+
+/// A single color over the whole tree
+#[derive(Debug, Default)]
+pub struct Solid {
+    color: Rgb,
+}
+
+impl Effect for Solid {
+    fn info() -> EffectInfo {
+        EffectInfo {
+            id: "solid",
+            name: "Solid",
+            description: "Fill the whole tree with a single color",
+            params: vec![ParamSpec {
+                id: "color",
+                name: "Color",
+                description: "Color to fill with",
+                kind: ParamKind::Color {
+                    default: [255, 255, 255],
+                },
+            }],
+            tags: vec!["static", "builtin"],
+        }
+    }
+
+    fn init(&mut self, params: &Params, _map: &TreeMap) {
+        self.color = params.color("color");
+    }
+
+    fn tick(&mut self, _dt: Duration, frame: &mut Frame) {
+        // Only LEDs that changed are marked dirty, so nothing is resent once the tree is filled
+        for index in 0..frame.len() {
+            frame.set(index, self.color);
+        }
+    }
+}
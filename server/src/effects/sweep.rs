@@ -0,0 +1,134 @@
+use common::message::Rgb;
+use std::time::Duration;
+
+use super::{Effect, EffectInfo, ParamKind, ParamSpec, Params};
+use crate::frame::Frame;
+use crate::mapping::TreeMap;
+
+/// A glowing horizontal plane rising up through the tree, wrapping back to the bottom, or falling down through it
+#[derive(Debug, Default)]
+pub struct Sweep {
+    color: Rgb,
+    /// Seconds for the plane to rise through the whole tree
+    period: f32,
+    /// Half the thickness of the plane, as a fraction of the tree's height
+    half_width: f32,
+    /// Height of each LED along the plane's path, from 0 where it enters the tree to 1 where it leaves
+    heights: Vec<f32>,
+    /// How far through its period the plane is, from 0 to 1
+    phase: f32,
+}
+
+impl Effect for Sweep {
+    fn info() -> EffectInfo {
+        EffectInfo {
+            id: "sweep",
+            name: "Sweep",
+            description: "Sweep a plane of light up through the tree, following the LEDs' mapped positions",
+            params: vec![
+                ParamSpec {
+                    id: "color",
+                    name: "Color",
+                    description: "Color of the plane",
+                    kind: ParamKind::Color { default: [0, 255, 64] },
+                },
+                ParamSpec {
+                    id: "period",
+                    name: "Period",
+                    description: "Seconds for the plane to rise through the tree",
+                    kind: ParamKind::Float {
+                        min: 0.1,
+                        max: 60.0,
+                        default: 3.0,
+                    },
+                },
+                ParamSpec {
+                    id: "thickness",
+                    name: "Thickness",
+                    description: "Thickness of the plane as a fraction of the tree's height",
+                    kind: ParamKind::Float {
+                        min: 0.01,
+                        max: 1.0,
+                        default: 0.2,
+                    },
+                },
+                ParamSpec {
+                    id: "downward",
+                    name: "Downward",
+                    description: "Sweep the plane down through the tree instead of up",
+                    kind: ParamKind::Bool { default: false },
+                },
+            ],
+            tags: vec!["animated", "3d", "builtin"],
+        }
+    }
+
+    fn init(&mut self, params: &Params, map: &TreeMap) {
+        self.color = params.color("color");
+        self.period = params.float("period").max(0.1);
+        self.half_width = params.float("thickness").max(0.01) / 2.0;
+        let (bottom, top) = map.bounds().map(|(min, max)| (min.z, max.z)).unwrap_or((0.0, 1.0));
+        let height = (top - bottom).max(f32::EPSILON);
+        let downward = params.bool("downward");
+        self.heights = map
+            .points()
+            .iter()
+            .map(|point| (point.z - bottom) / height)
+            .map(|height| if downward { 1.0 - height } else { height })
+            .collect();
+        self.phase = 0.0;
+    }
+
+    fn tick(&mut self, dt: Duration, frame: &mut Frame) {
+        self.phase = (self.phase + dt.as_secs_f32() / self.period).fract();
+        // The plane starts below the tree and ends above it, so it enters and leaves smoothly
+        let plane = self.phase * (1.0 + 4.0 * self.half_width) - 2.0 * self.half_width;
+        // LEDs missing from the map stay dark
+        for (index, height) in self.heights.iter().enumerate().take(frame.len()) {
+            let level = (1.0 - (height - plane).abs() / self.half_width).max(0.0);
+            let scale = |channel: u8| (channel as f32 * level).round() as u8;
+            frame.set(index, Rgb::new(scale(self.color.r), scale(self.color.g), scale(self.color.b)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lights_the_leds_the_plane_passes() {
+        // LEDs at the top, bottom and middle of the tree, the fourth LED missing from the map
+        let map = TreeMap::parse("0,0,2\n0,0,0\n0,0,1").unwrap();
+        let values = [("period".to_string(), "1".to_string()), ("thickness".to_string(), "0.5".to_string())];
+        let mut sweep = Sweep::default();
+        sweep.init(&Sweep::info().params(&values).unwrap(), &map);
+        let mut frame = Frame::new(4);
+
+        // Halfway through, the plane is at the middle of the tree
+        sweep.tick(Duration::from_millis(500), &mut frame);
+        let green = |led: &Rgb| led.g;
+        assert_eq!(frame.leds().iter().map(green).collect::<Vec<_>>(), vec![0, 0, 255, 0]);
+        // Later it's leaving through the top
+        sweep.tick(Duration::from_millis(300), &mut frame);
+        assert_eq!(frame.leds().iter().map(green).collect::<Vec<_>>(), vec![153, 0, 0, 0]);
+    }
+
+    #[test]
+    fn sweeps_down_when_asked() {
+        let map = TreeMap::parse("0,0,2\n0,0,0\n0,0,1").unwrap();
+        let values = [
+            ("period".to_string(), "1".to_string()),
+            ("thickness".to_string(), "0.5".to_string()),
+            ("downward".to_string(), "on".to_string()),
+        ];
+        let mut sweep = Sweep::default();
+        sweep.init(&Sweep::info().params(&values).unwrap(), &map);
+        let mut frame = Frame::new(3);
+
+        // The plane leaves through the bottom instead of the top
+        sweep.tick(Duration::from_millis(800), &mut frame);
+        let green = |led: &Rgb| led.g;
+        assert_eq!(frame.leds().iter().map(green).collect::<Vec<_>>(), vec![0, 153, 0]);
+    }
+}
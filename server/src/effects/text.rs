@@ -0,0 +1,207 @@
+use common::message::Rgb;
+use std::f32::consts::TAU;
+use std::time::Duration;
+
+use super::{Effect, EffectInfo, ParamKind, ParamSpec, Params};
+use crate::frame::Frame;
+use crate::mapping::TreeMap;
+
+/// Rows of the font, the text filling the tree's height
+const ROWS: usize = 7;
+
+/// Columns of each glyph, followed by a blank column before the next
+const GLYPH_WIDTH: usize = 5;
+const ADVANCE: usize = GLYPH_WIDTH + 1;
+
+/// Columns of text around the tree, four characters' worth
+const COLUMNS_AROUND: f32 = 24.0;
+
+/// Blank characters between the end of the message and its start coming round again
+const GAP: usize = 2;
+
+/// 5x7 glyphs as columns from the left, the lowest bit of each the top row
+const FONT: [(char, [u8; GLYPH_WIDTH]); 49] = [
+    (' ', [0x00, 0x00, 0x00, 0x00, 0x00]),
+    ('!', [0x00, 0x00, 0x5f, 0x00, 0x00]),
+    ('#', [0x14, 0x7f, 0x14, 0x7f, 0x14]),
+    ('&', [0x36, 0x49, 0x55, 0x22, 0x50]),
+    ('\'', [0x00, 0x00, 0x07, 0x00, 0x00]),
+    ('*', [0x2a, 0x1c, 0x7f, 0x1c, 0x2a]),
+    ('+', [0x08, 0x08, 0x3e, 0x08, 0x08]),
+    (',', [0x00, 0x50, 0x30, 0x00, 0x00]),
+    ('-', [0x08, 0x08, 0x08, 0x08, 0x08]),
+    ('.', [0x00, 0x60, 0x60, 0x00, 0x00]),
+    (':', [0x00, 0x36, 0x36, 0x00, 0x00]),
+    ('?', [0x02, 0x01, 0x51, 0x09, 0x06]),
+    ('♥', [0x0c, 0x1e, 0x3c, 0x1e, 0x0c]),
+    ('0', [0x3e, 0x51, 0x49, 0x45, 0x3e]),
+    ('1', [0x00, 0x42, 0x7f, 0x40, 0x00]),
+    ('2', [0x42, 0x61, 0x51, 0x49, 0x46]),
+    ('3', [0x21, 0x41, 0x45, 0x4b, 0x31]),
+    ('4', [0x18, 0x14, 0x12, 0x7f, 0x10]),
+    ('5', [0x27, 0x45, 0x45, 0x45, 0x39]),
+    ('6', [0x3c, 0x4a, 0x49, 0x49, 0x30]),
+    ('7', [0x01, 0x71, 0x09, 0x05, 0x03]),
+    ('8', [0x36, 0x49, 0x49, 0x49, 0x36]),
+    ('9', [0x06, 0x49, 0x49, 0x29, 0x1e]),
+    ('A', [0x7e, 0x11, 0x11, 0x11, 0x7e]),
+    ('B', [0x7f, 0x49, 0x49, 0x49, 0x36]),
+    ('C', [0x3e, 0x41, 0x41, 0x41, 0x22]),
+    ('D', [0x7f, 0x41, 0x41, 0x22, 0x1c]),
+    ('E', [0x7f, 0x49, 0x49, 0x49, 0x41]),
+    ('F', [0x7f, 0x09, 0x09, 0x09, 0x01]),
+    ('G', [0x3e, 0x41, 0x49, 0x49, 0x7a]),
+    ('H', [0x7f, 0x08, 0x08, 0x08, 0x7f]),
+    ('I', [0x00, 0x41, 0x7f, 0x41, 0x00]),
+    ('J', [0x20, 0x40, 0x41, 0x3f, 0x01]),
+    ('K', [0x7f, 0x08, 0x14, 0x22, 0x41]),
+    ('L', [0x7f, 0x40, 0x40, 0x40, 0x40]),
+    ('M', [0x7f, 0x02, 0x0c, 0x02, 0x7f]),
+    ('N', [0x7f, 0x04, 0x08, 0x10, 0x7f]),
+    ('O', [0x3e, 0x41, 0x41, 0x41, 0x3e]),
+    ('P', [0x7f, 0x09, 0x09, 0x09, 0x06]),
+    ('Q', [0x3e, 0x41, 0x51, 0x21, 0x5e]),
+    ('R', [0x7f, 0x09, 0x19, 0x29, 0x46]),
+    ('S', [0x46, 0x49, 0x49, 0x49, 0x31]),
+    ('T', [0x01, 0x01, 0x7f, 0x01, 0x01]),
+    ('U', [0x3f, 0x40, 0x40, 0x40, 0x3f]),
+    ('V', [0x1f, 0x20, 0x40, 0x20, 0x1f]),
+    ('W', [0x3f, 0x40, 0x38, 0x40, 0x3f]),
+    ('X', [0x63, 0x14, 0x08, 0x14, 0x63]),
+    ('Y', [0x07, 0x08, 0x70, 0x08, 0x07]),
+    ('Z', [0x61, 0x51, 0x49, 0x45, 0x43]),
+];
+
+/// Glyph of a character, letters in upper case and characters without one as `?`
+fn glyph(c: char) -> [u8; GLYPH_WIDTH] {
+    let find = |c: char| FONT.iter().find(|(glyph, _)| *glyph == c).map(|(_, columns)| *columns);
+    find(c.to_ascii_uppercase()).or_else(|| find('?')).unwrap_or_default()
+}
+
+/// Columns of a message set in the font, looping back to its start after a gap
+fn set_text(message: &str) -> Vec<u8> {
+    message
+        .chars()
+        .chain(std::iter::repeat_n(' ', GAP))
+        .flat_map(|c| glyph(c).into_iter().chain([0]))
+        .collect()
+}
+
+/// A message scrolling around the tree in lights
+#[derive(Debug, Default)]
+pub struct ScrollingText {
+    color: Rgb,
+    /// Characters scrolled past per second
+    speed: f32,
+    columns: Vec<u8>,
+    /// Where each LED is around the tree, in columns from the back, and which row of the text it's on
+    positions: Vec<(f32, usize)>,
+    /// Columns the text has scrolled
+    offset: f32,
+}
+
+impl Effect for ScrollingText {
+    fn info() -> EffectInfo {
+        EffectInfo {
+            id: "text",
+            name: "Scrolling text",
+            description: "Scroll a message around the tree, following the LEDs' mapped positions",
+            params: vec![
+                ParamSpec {
+                    id: "message",
+                    name: "Message",
+                    description: "Text to scroll, in capitals, digits and a little punctuation",
+                    kind: ParamKind::Text { default: "MERRY XMAS" },
+                },
+                ParamSpec {
+                    id: "speed",
+                    name: "Speed",
+                    description: "Characters scrolled past per second",
+                    kind: ParamKind::Float {
+                        min: 0.1,
+                        max: 20.0,
+                        default: 2.0,
+                    },
+                },
+                ParamSpec {
+                    id: "color",
+                    name: "Color",
+                    description: "Color of the text",
+                    kind: ParamKind::Color { default: [255, 0, 0] },
+                },
+            ],
+            tags: vec!["animated", "3d", "builtin"],
+        }
+    }
+
+    fn init(&mut self, params: &Params, map: &TreeMap) {
+        self.color = params.color("color");
+        self.speed = params.float("speed");
+        self.columns = set_text(params.text("message"));
+        let (bottom, top) = map.bounds().map(|(min, max)| (min.z, max.z)).unwrap_or((0.0, 1.0));
+        let height = (top - bottom).max(f32::EPSILON);
+        self.positions = map
+            .points()
+            .iter()
+            .map(|point| {
+                // Columns run left to right across the front of the tree, which faces -y
+                let around = (0.5 + point.x.atan2(-point.y) / TAU) * COLUMNS_AROUND;
+                let row = ((top - point.z) / height * ROWS as f32) as usize;
+                (around, row.min(ROWS - 1))
+            })
+            .collect();
+        self.offset = 0.0;
+    }
+
+    fn tick(&mut self, dt: Duration, frame: &mut Frame) {
+        let len = self.columns.len() as f32;
+        self.offset = (self.offset + dt.as_secs_f32() * self.speed * ADVANCE as f32) % len;
+        // LEDs missing from the map stay dark
+        for (index, &(around, row)) in self.positions.iter().enumerate().take(frame.len()) {
+            let column = ((around + self.offset) % len) as usize;
+            let lit = self.columns.get(column).is_some_and(|bits| bits & (1 << row) != 0);
+            frame.set(index, if lit { self.color } else { Rgb::default() });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapping::Point3;
+
+    /// A point around the middle of the tree in front of a column
+    fn at_column(column: usize) -> Point3 {
+        let angle = ((column as f32 + 0.5) / COLUMNS_AROUND - 0.5) * TAU;
+        Point3::new(angle.sin(), -angle.cos(), 0.5)
+    }
+
+    #[test]
+    fn sets_messages_in_the_font() {
+        let columns = set_text("Hi");
+        assert_eq!(columns.len(), (2 + GAP) * ADVANCE);
+        assert_eq!(columns[..ADVANCE], [0x7f, 0x08, 0x08, 0x08, 0x7f, 0]);
+        assert_eq!(columns[ADVANCE..2 * ADVANCE], [0x00, 0x41, 0x7f, 0x41, 0x00, 0]);
+        assert_eq!(glyph('~'), glyph('?'));
+    }
+
+    #[test]
+    fn scrolls_text_across_the_front() {
+        // LEDs along the middle row in front of the first six columns, and two giving the tree its height
+        let mut points: Vec<Point3> = (0..6).map(at_column).collect();
+        points.extend([Point3::new(0.0, 1.0, 0.0), Point3::new(0.0, 1.0, 1.0)]);
+        let map = TreeMap::new(points);
+        let values = [("message".to_string(), "I".to_string()), ("speed".to_string(), "1".to_string())];
+        let mut text = ScrollingText::default();
+        text.init(&ScrollingText::info().params(&values).unwrap(), &map);
+        let mut frame = Frame::new(8);
+        let red = |frame: &Frame| frame.leds()[..6].iter().map(|led| led.r > 0).collect::<Vec<_>>();
+
+        // Only the I's stem crosses the middle row
+        text.tick(Duration::ZERO, &mut frame);
+        assert_eq!(red(&frame), [false, false, true, false, false, false]);
+        // One column later it has moved left
+        text.tick(Duration::from_secs(1) / ADVANCE as u32, &mut frame);
+        assert_eq!(red(&frame), [false, true, false, false, false, false]);
+    }
+}
@@ -0,0 +1,204 @@
+use common::message::next_host_seq;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Default number of frames allowed in flight
+pub const DEFAULT_WINDOW_SIZE: usize = 4;
+
+/// Default time after which an unacknowledged frame is assumed lost
+pub const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Longest sending stays paused by a Busy, in case the firmware's Ready was lost
+pub const MAX_PAUSE: Duration = Duration::from_secs(1);
+
+/// Outcome of a frame sent through the SendWindow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameOutcome {
+    Acked,
+    Nacked,
+    /// Neither acknowledged nor rejected before a later frame was acked or the timeout passed
+    Lost,
+}
+
+/// Sliding window limiting how many frames may be in flight to the firmware
+///
+/// Each frame is assigned a sequence number that the firmware echoes back in an Ack or Nack,
+/// so transmission throttles to what the firmware can consume. The firmware applies frames
+/// in order, so an Ack also settles every earlier frame still in flight as lost.
+pub struct SendWindow {
+    size: usize,
+    timeout: Duration,
+    next_seq: u32,
+    in_flight: VecDeque<(u32, Instant)>,
+    /// When the firmware reported Busy, cleared by Ready
+    paused_at: Option<Instant>,
+    acked: u64,
+    nacked: u64,
+    lost: u64,
+}
+
+impl SendWindow {
+    /// Create a new SendWindow allowing size frames in flight
+    pub fn new(size: usize, timeout: Duration) -> Self {
+        Self {
+            size,
+            timeout,
+            next_seq: 0,
+            in_flight: VecDeque::with_capacity(size),
+            paused_at: None,
+            acked: 0,
+            nacked: 0,
+            lost: 0,
+        }
+    }
+
+    /// Whether another frame may be sent now
+    pub fn can_send(&mut self) -> bool {
+        self.expire();
+        !self.is_paused() && self.in_flight.len() < self.size
+    }
+
+    /// Stop sending frames, after the firmware reported Busy
+    pub fn pause(&mut self) {
+        self.paused_at.get_or_insert_with(Instant::now);
+    }
+
+    /// Resume sending frames, after the firmware reported Ready
+    pub fn resume(&mut self) {
+        self.paused_at = None;
+    }
+
+    /// Whether sending is paused by the firmware
+    pub fn is_paused(&mut self) -> bool {
+        if self.paused_at.is_some_and(|paused_at| paused_at.elapsed() >= MAX_PAUSE) {
+            self.paused_at = None;
+        }
+        self.paused_at.is_some()
+    }
+
+    /// Allocate the sequence number for a frame about to be sent and count it as in flight
+    pub fn send(&mut self) -> u32 {
+        let seq = self.next_seq;
+        self.next_seq = next_host_seq(self.next_seq);
+        self.in_flight.push_back((seq, Instant::now()));
+        seq
+    }
+
+    /// Handle an Ack for seq
+    /// Returns the outcome of every frame it settled, oldest first
+    pub fn ack(&mut self, seq: u32) -> Vec<FrameOutcome> {
+        let Some(position) = self.position(seq) else {
+            return Vec::new();
+        };
+        let mut outcomes: Vec<FrameOutcome> = self.in_flight.drain(..=position).map(|_| FrameOutcome::Lost).collect();
+        self.lost += position as u64;
+        self.acked += 1;
+        if let Some(outcome) = outcomes.last_mut() {
+            *outcome = FrameOutcome::Acked;
+        }
+        outcomes
+    }
+
+    /// Handle a Nack for seq, freeing its slot
+    pub fn nack(&mut self, seq: u32) -> Option<FrameOutcome> {
+        let position = self.position(seq)?;
+        self.in_flight.remove(position);
+        self.nacked += 1;
+        Some(FrameOutcome::Nacked)
+    }
+
+    /// Number of frames currently in flight
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Number of frames acknowledged by the firmware
+    pub fn acked(&self) -> u64 {
+        self.acked
+    }
+
+    /// Number of frames rejected by the firmware
+    pub fn nacked(&self) -> u64 {
+        self.nacked
+    }
+
+    /// Number of frames assumed lost
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    fn position(&self, seq: u32) -> Option<usize> {
+        self.in_flight.iter().position(|(in_flight, _)| *in_flight == seq)
+    }
+
+    fn expire(&mut self) {
+        while let Some((_, sent_at)) = self.in_flight.front() {
+            if sent_at.elapsed() < self.timeout {
+                break;
+            }
+            self.in_flight.pop_front();
+            self.lost += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use christmas_tree_client::Simulator;
+    use common::message::{MAX_HOST_SEQ, Message, Rgb};
+
+    #[test]
+    fn ack_settles_earlier_frames() {
+        let mut window = SendWindow::new(3, Duration::from_secs(60));
+        let first = window.send();
+        let second = window.send();
+        let third = window.send();
+        assert!(!window.can_send());
+
+        assert_eq!(window.nack(first), Some(FrameOutcome::Nacked));
+        assert_eq!(window.ack(third), vec![FrameOutcome::Lost, FrameOutcome::Acked]);
+        assert_eq!(window.ack(second), vec![]);
+        assert_eq!((window.acked(), window.nacked(), window.lost()), (1, 1, 1));
+        assert!(window.can_send());
+    }
+
+    #[test]
+    fn simulator_acks_every_frame() {
+        let (simulator, host) = Simulator::start(10);
+        let mut window = SendWindow::new(DEFAULT_WINDOW_SIZE, DEFAULT_ACK_TIMEOUT);
+        for _ in 0..20 {
+            while !window.can_send() {
+                if let Message::Ack(ack) = host.receive(Duration::from_secs(1)).unwrap() {
+                    assert_eq!(window.ack(ack.seq), vec![FrameOutcome::Acked]);
+                }
+            }
+            host.send_frame(window.send(), vec![Rgb::new(255, 0, 0); 10]).unwrap();
+        }
+        while window.in_flight() > 0 {
+            if let Message::Ack(ack) = host.receive(Duration::from_secs(1)).unwrap() {
+                window.ack(ack.seq);
+            }
+        }
+        assert_eq!((window.acked(), window.lost()), (20, 0));
+        assert_eq!(simulator.state().frames, 20);
+    }
+
+    #[test]
+    fn busy_pauses_sending() {
+        let mut window = SendWindow::new(3, Duration::from_secs(60));
+        window.pause();
+        assert!(!window.can_send());
+        window.resume();
+        assert!(window.can_send());
+    }
+
+    #[test]
+    fn sequence_wraps_below_local_frames() {
+        let mut window = SendWindow::new(3, Duration::from_secs(60));
+        window.next_seq = MAX_HOST_SEQ;
+        assert_eq!(window.send(), MAX_HOST_SEQ);
+        assert_eq!(window.send(), 0);
+        assert_eq!(window.ack(0), vec![FrameOutcome::Lost, FrameOutcome::Acked]);
+    }
+}
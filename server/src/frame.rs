@@ -0,0 +1,247 @@
+use common::message::{
+    frame_checksum, FrameDeltaPayload, MAX_HOST_SEQ, Message, Rgb, SetLedRangePayload, SetLedsPayload,
+    SetLedsRlePayload,
+};
+use std::ops::Range;
+
+/// Approximate encoded size of one LED in a delta: a varint index and 3 color bytes
+const SPARSE_LED_SIZE: usize = 5;
+
+/// Approximate encoded size of one run in a run-length encoded frame: a varint length and 3 color bytes
+const RLE_RUN_SIZE: usize = 5;
+
+/// Frame of LED colors that tracks which regions were modified since it was last transmitted
+///
+/// Dirty regions are kept sorted and merged, so the transmit stage can skip untouched
+/// spans without comparing the whole frame against the previous one.
+pub struct Frame {
+    leds: Vec<Rgb>,
+    dirty: Vec<Range<usize>>,
+}
+
+impl Frame {
+    /// Create a new black frame of the given length, marked entirely dirty
+    pub fn new(len: usize) -> Self {
+        let mut frame = Self {
+            leds: vec![Rgb::new(0, 0, 0); len],
+            dirty: Vec::new(),
+        };
+        frame.mark_dirty(0..len);
+        frame
+    }
+
+    /// Number of LEDs in the frame
+    pub fn len(&self) -> usize {
+        self.leds.len()
+    }
+
+    /// Get the current LED colors
+    pub fn leds(&self) -> &[Rgb] {
+        &self.leds
+    }
+
+    /// Set a single LED, marking it dirty if its color changed
+    pub fn set(&mut self, index: usize, color: Rgb) {
+        if let Some(led) = self.leds.get_mut(index)
+            && *led != color
+        {
+            *led = color;
+            self.mark_dirty(index..index + 1);
+        }
+    }
+
+    /// Set every LED in a range to the same color and mark the range dirty
+    pub fn fill(&mut self, range: Range<usize>, color: Rgb) {
+        let range = self.clamp(range);
+        self.leds[range.clone()].fill(color);
+        self.mark_dirty(range);
+    }
+
+    /// Mark a range of LEDs as modified
+    pub fn mark_dirty(&mut self, range: Range<usize>) {
+        let mut merged = self.clamp(range);
+        if merged.is_empty() {
+            return;
+        }
+
+        // Absorb every region that overlaps or touches the new one
+        self.dirty.retain(|region| {
+            if region.start <= merged.end && merged.start <= region.end {
+                merged.start = merged.start.min(region.start);
+                merged.end = merged.end.max(region.end);
+                false
+            } else {
+                true
+            }
+        });
+
+        let position = self.dirty.partition_point(|region| region.start < merged.start);
+        self.dirty.insert(position, merged);
+    }
+
+    /// Take the modified regions, marking the frame clean
+    pub fn take_dirty(&mut self) -> Vec<Range<usize>> {
+        std::mem::take(&mut self.dirty)
+    }
+
+    /// Build the smallest update covering the frame's modifications, marking it clean
+    /// Scattered changes are sent as a delta against the previous update, clustered ones as a
+    /// single range spanning every dirty region, and the whole frame is sent when neither is shorter,
+    /// run-length encoded if that makes it smaller
+    /// Returns None if nothing changed since the last call
+    pub fn take_update(&mut self) -> Option<FrameUpdate> {
+        let dirty = self.take_dirty();
+        let span = dirty.first()?.start..dirty.last()?.end;
+        let changed: usize = dirty.iter().map(Range::len).sum();
+        let message = if changed * SPARSE_LED_SIZE < span.len() * 3 {
+            Message::FrameDelta(FrameDeltaPayload {
+                seq: 0,
+                base_seq: 0,
+                leds: dirty
+                    .into_iter()
+                    .flatten()
+                    .map(|index| (index as u16, self.leds[index]))
+                    .collect(),
+            })
+        } else if span.len() < self.leds.len() {
+            Message::SetLedRange(SetLedRangePayload {
+                seq: 0,
+                start: span.start as u16,
+                leds: self.leds[span].to_vec(),
+            })
+        } else {
+            let rle = SetLedsRlePayload::encode(0, &self.leds);
+            if rle.runs.len() * RLE_RUN_SIZE < self.leds.len() * 3 {
+                Message::SetLedsRle(rle)
+            } else {
+                Message::SetLeds(SetLedsPayload {
+                    seq: 0,
+                    strip: None,
+                    leds: self.leds.clone(),
+                })
+            }
+        };
+        Some(FrameUpdate {
+            message,
+            checksum: frame_checksum(&self.leds),
+        })
+    }
+
+    fn clamp(&self, range: Range<usize>) -> Range<usize> {
+        let end = range.end.min(self.leds.len());
+        range.start.min(end)..end
+    }
+}
+
+/// Message updating the firmware's frame, along with the checksum of the whole frame once it's applied
+pub struct FrameUpdate {
+    message: Message,
+    checksum: u32,
+}
+
+impl FrameUpdate {
+    /// Checksum of the whole frame the firmware displays after applying the update
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    /// Get the message to transmit with the given sequence number
+    /// Updates must be numbered consecutively, deltas are based on the update numbered just before them
+    pub fn into_message(mut self, seq: u32) -> Message {
+        match &mut self.message {
+            Message::SetLeds(payload) => payload.seq = seq,
+            Message::SetLedRange(payload) => payload.seq = seq,
+            Message::SetLedsRle(payload) => payload.seq = seq,
+            Message::FrameDelta(payload) => {
+                payload.seq = seq;
+                payload.base_seq = seq.wrapping_sub(1) & MAX_HOST_SEQ;
+            }
+            _ => {}
+        }
+        self.message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dirty_regions_merge() {
+        let mut frame: Frame = Frame::new(100);
+        frame.take_dirty();
+
+        frame.mark_dirty(10..20);
+        frame.mark_dirty(40..50);
+        frame.mark_dirty(0..5);
+        assert_eq!(frame.dirty, [0..5, 10..20, 40..50]);
+
+        // Touching and overlapping regions collapse into one
+        frame.mark_dirty(20..40);
+        assert_eq!(frame.dirty, [0..5, 10..50]);
+
+        // Out of range regions are clamped
+        frame.mark_dirty(95..200);
+        assert_eq!(frame.dirty, [0..5, 10..50, 95..100]);
+    }
+
+    #[test]
+    fn unchanged_set_stays_clean() {
+        let mut frame: Frame = Frame::new(10);
+        assert!(frame.take_update().is_some());
+
+        frame.set(3, Rgb::new(0, 0, 0));
+        assert!(frame.take_update().is_none());
+
+        frame.set(3, Rgb::new(255, 0, 0));
+        assert_eq!(frame.dirty, [Range { start: 3, end: 4 }]);
+        match frame.take_update().unwrap().into_message(1) {
+            Message::SetLedRange(payload) => assert_eq!((payload.start, payload.leds), (3, vec![Rgb::new(255, 0, 0)])),
+            message => panic!("Expected a range update, got {:?}", message),
+        }
+        assert!(frame.dirty.is_empty());
+    }
+
+    #[test]
+    fn update_covers_dirty_span() {
+        let mut frame: Frame = Frame::new(10);
+        assert!(matches!(frame.take_update().unwrap().into_message(1), Message::SetLedsRle(_)));
+
+        frame.fill(2..6, Rgb::new(1, 0, 0));
+        let update = frame.take_update().unwrap();
+        assert_eq!(update.checksum(), frame_checksum(frame.leds()));
+        match update.into_message(4) {
+            Message::SetLedRange(payload) => {
+                assert_eq!((payload.seq, payload.start), (4, 2));
+                assert_eq!(payload.leds, frame.leds()[2..6]);
+            }
+            message => panic!("Expected a range update, got {:?}", message),
+        }
+        assert!(frame.take_update().is_none());
+    }
+
+    #[test]
+    fn scattered_changes_are_deltas() {
+        let mut frame: Frame = Frame::new(100);
+        frame.take_dirty();
+
+        frame.set(2, Rgb::new(1, 0, 0));
+        frame.set(90, Rgb::new(2, 0, 0));
+        match frame.take_update().unwrap().into_message(6) {
+            Message::FrameDelta(payload) => {
+                assert_eq!((payload.seq, payload.base_seq), (6, 5));
+                assert_eq!(payload.leds, [(2, Rgb::new(1, 0, 0)), (90, Rgb::new(2, 0, 0))]);
+            }
+            message => panic!("Expected a delta, got {:?}", message),
+        }
+    }
+
+    #[test]
+    fn varied_frames_are_sent_raw() {
+        let mut frame: Frame = Frame::new(10);
+        for index in 0..10 {
+            frame.set(index, Rgb::new(index as u8, 0, 0));
+        }
+        assert!(matches!(frame.take_update().unwrap().into_message(1), Message::SetLeds(_)));
+    }
+}
@@ -0,0 +1,366 @@
+use common::message::Rgb;
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+/// File extension of FSEQ sequences
+pub const FSEQ_EXTENSION: &str = "fseq";
+
+/// Length of the fixed part of a version 2 header, which the compression block index follows
+const V2_HEADER_SIZE: usize = 32;
+
+/// Length of the header fields shared by every version
+const COMMON_HEADER_SIZE: usize = 20;
+
+#[derive(Debug)]
+pub enum FseqError {
+    Io(std::io::Error),
+    /// The file isn't a sequence this can play
+    Invalid(String),
+}
+
+impl fmt::Display for FseqError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FseqError::Io(e) => write!(f, "Failed to read the sequence: {}", e),
+            FseqError::Invalid(message) => write!(f, "Invalid sequence: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for FseqError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    None,
+    Zstd,
+    Zlib,
+}
+
+/// Frames compressed together, the first of them and where their data is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Block {
+    first_frame: u32,
+    offset: usize,
+    len: usize,
+}
+
+/// A sequence in the FSEQ format xLights and Falcon Player save, version 1 or 2, compressed or not
+///
+/// Channels light the LEDs 3 at a time from channel 0 in RGB order, as xLights lays out a model starting at channel 1.
+/// Compressed blocks are only decompressed once playback reaches them.
+pub struct Sequence {
+    step: Duration,
+    frame_count: u32,
+    /// Channels in each frame's data
+    channel_count: usize,
+    /// Where each frame's data lands, as the first channel and number of channels of each range, in order
+    ranges: Vec<(usize, usize)>,
+    compression: Compression,
+    blocks: Vec<Block>,
+    /// Channel data, from the channel data offset to the end of the file
+    data: Vec<u8>,
+    /// The last block decompressed and its data
+    cache: Option<(usize, Vec<u8>)>,
+}
+
+impl Sequence {
+    pub fn load(path: &Path) -> Result<Sequence, FseqError> {
+        Sequence::parse(std::fs::read(path).map_err(FseqError::Io)?)
+    }
+
+    pub fn parse(mut file: Vec<u8>) -> Result<Sequence, FseqError> {
+        let invalid = |message: &str| FseqError::Invalid(message.to_string());
+        if file.len() < COMMON_HEADER_SIZE || !(file.starts_with(b"PSEQ") || file.starts_with(b"FSEQ")) {
+            return Err(invalid("the file isn't an FSEQ sequence"));
+        }
+        let u16_at = |offset: usize| u16::from_le_bytes([file[offset], file[offset + 1]]) as usize;
+        let u32_at =
+            |offset: usize| u32::from_le_bytes([file[offset], file[offset + 1], file[offset + 2], file[offset + 3]]);
+        let data_offset = u16_at(4);
+        let major_version = file[7];
+        let channel_count = u32_at(10) as usize;
+        let frame_count = u32_at(14);
+        let step = Duration::from_millis(file[18].max(1) as u64);
+        if data_offset > file.len() {
+            return Err(invalid("the channel data starts past the end of the file"));
+        }
+
+        let mut sequence = Sequence {
+            step,
+            frame_count,
+            channel_count,
+            ranges: vec![(0, channel_count)],
+            compression: Compression::None,
+            blocks: Vec::new(),
+            data: Vec::new(),
+            cache: None,
+        };
+        match major_version {
+            1 => {}
+            2 => {
+                if data_offset < V2_HEADER_SIZE {
+                    return Err(invalid("the header is cut short"));
+                }
+                sequence.compression = match file[20] & 0x0f {
+                    0 => Compression::None,
+                    1 => Compression::Zstd,
+                    2 => Compression::Zlib,
+                    other => {
+                        return Err(FseqError::Invalid(format!(
+                            "compression type {} isn't supported",
+                            other
+                        )));
+                    }
+                };
+                // Later versions keep the block count's high bits in the compression byte
+                let block_count = file[21] as usize | ((file[20] as usize & 0xf0) << 4);
+                let range_count = file[22] as usize;
+                let ranges_offset = V2_HEADER_SIZE + block_count * 8;
+                if ranges_offset + range_count * 6 > data_offset {
+                    return Err(invalid("the block index and sparse ranges run into the channel data"));
+                }
+                if sequence.compression != Compression::None {
+                    let mut offset = 0;
+                    for entry in file[V2_HEADER_SIZE..ranges_offset].chunks_exact(8) {
+                        let first_frame = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+                        let len = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]) as usize;
+                        // Unused entries are left empty
+                        if len > 0 {
+                            sequence.blocks.push(Block {
+                                first_frame,
+                                offset,
+                                len,
+                            });
+                        }
+                        offset += len;
+                    }
+                    if sequence.blocks.is_empty() && frame_count > 0 {
+                        return Err(invalid("a compressed sequence has no blocks"));
+                    }
+                }
+                let u24_at = |offset: usize| u32::from_le_bytes([file[offset], file[offset + 1], file[offset + 2], 0]);
+                let ranges: Vec<(usize, usize)> = (0..range_count)
+                    .map(|index| ranges_offset + index * 6)
+                    .map(|offset| (u24_at(offset) as usize, u24_at(offset + 3) as usize))
+                    .collect();
+                if !ranges.is_empty() {
+                    sequence.ranges = ranges;
+                }
+            }
+            version => return Err(FseqError::Invalid(format!("version {} isn't supported", version))),
+        }
+        sequence.data = file.split_off(data_offset);
+        Ok(sequence)
+    }
+
+    /// Time each frame is shown for
+    pub fn step(&self) -> Duration {
+        self.step
+    }
+
+    pub fn frame_count(&self) -> u32 {
+        self.frame_count
+    }
+
+    /// The LEDs a frame lights, LEDs past the sequence's channels staying off
+    pub fn leds(&mut self, index: u32, num_leds: usize) -> Result<Vec<Rgb>, FseqError> {
+        self.decompress(index)?;
+        let frame = self.frame(index)?;
+        let mut channels = vec![0u8; num_leds * 3];
+        let mut position = 0;
+        for &(start, count) in &self.ranges {
+            let data = &frame[position.min(frame.len())..(position + count).min(frame.len())];
+            if let Some(destination) = channels.get_mut(start..) {
+                let len = data.len().min(destination.len());
+                destination[..len].copy_from_slice(&data[..len]);
+            }
+            position += count;
+        }
+        Ok(channels
+            .chunks_exact(3)
+            .map(|rgb| Rgb::new(rgb[0], rgb[1], rgb[2]))
+            .collect())
+    }
+
+    /// Decompress the block holding a frame, unless it's the last block decompressed
+    fn decompress(&mut self, index: u32) -> Result<(), FseqError> {
+        if self.compression == Compression::None || index >= self.frame_count {
+            return Ok(());
+        }
+        let block = self.block(index);
+        if self.cache.as_ref().is_some_and(|(cached, _)| *cached == block) {
+            return Ok(());
+        }
+        let Block { offset, len, .. } = self.blocks[block];
+        let compressed = self
+            .data
+            .get(offset..offset + len)
+            .ok_or_else(|| FseqError::Invalid(format!("frame {} is cut short", index)))?;
+        let decompressed = match self.compression {
+            Compression::Zstd => zstd::stream::decode_all(compressed).map_err(FseqError::Io)?,
+            _ => {
+                let mut decompressed = Vec::new();
+                let mut decoder = flate2::read::ZlibDecoder::new(compressed);
+                decoder.read_to_end(&mut decompressed).map_err(FseqError::Io)?;
+                decompressed
+            }
+        };
+        self.cache = Some((block, decompressed));
+        Ok(())
+    }
+
+    /// A frame's channel data, which has to have been decompressed if it's compressed
+    fn frame(&self, index: u32) -> Result<&[u8], FseqError> {
+        if index >= self.frame_count {
+            return Err(FseqError::Invalid(format!("there is no frame {}", index)));
+        }
+        let missing = || FseqError::Invalid(format!("frame {} is cut short", index));
+        // Data is found by the frame's position in its block, which is the whole file when it isn't compressed
+        let (data, position) = match &self.cache {
+            None if self.compression == Compression::None => (&self.data, index),
+            Some((block, decompressed)) if *block == self.block(index) => {
+                let first_frame = self.blocks[*block].first_frame;
+                (decompressed, index.checked_sub(first_frame).ok_or_else(missing)?)
+            }
+            _ => return Err(missing()),
+        };
+        let start = position as usize * self.channel_count;
+        data.get(start..start + self.channel_count).ok_or_else(missing)
+    }
+
+    /// Index of the compressed block holding a frame
+    fn block(&self, index: u32) -> usize {
+        self.blocks
+            .partition_point(|block| block.first_frame <= index)
+            .saturating_sub(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Channel data of 3 frames of 2 LEDs
+    const FRAMES: [[u8; 6]; 3] = [[1, 2, 3, 4, 5, 6], [7, 8, 9, 10, 11, 12], [13, 14, 15, 16, 17, 18]];
+
+    fn header(version: u8, data_offset: usize, channels: u32) -> Vec<u8> {
+        let mut header = b"PSEQ".to_vec();
+        header.extend_from_slice(&(data_offset as u16).to_le_bytes());
+        header.extend_from_slice(&[0, version]);
+        header.extend_from_slice(&(if version == 1 { 28u16 } else { 32 }).to_le_bytes());
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&(FRAMES.len() as u32).to_le_bytes());
+        header.extend_from_slice(&[50, 0]);
+        header
+    }
+
+    /// A version 2 sequence, its frames compressed in blocks of the frames from each given frame
+    fn v2(compression: u8, blocks: &[u32]) -> Vec<u8> {
+        let compress = |data: &[u8]| match compression {
+            1 => zstd::stream::encode_all(data, 0).unwrap(),
+            _ => {
+                let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+        };
+        let mut compressed = Vec::new();
+        let mut index = Vec::new();
+        for (number, &first) in blocks.iter().enumerate() {
+            let end = blocks.get(number + 1).copied().unwrap_or(FRAMES.len() as u32);
+            let block = compress(&FRAMES[first as usize..end as usize].concat());
+            index.extend_from_slice(&first.to_le_bytes());
+            index.extend_from_slice(&(block.len() as u32).to_le_bytes());
+            compressed.extend(block);
+        }
+        // An unused entry at the end of the index
+        index.extend_from_slice(&[0; 8]);
+        let mut file = header(2, V2_HEADER_SIZE + index.len(), 6);
+        file.extend_from_slice(&[compression, blocks.len() as u8 + 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        file.extend(index);
+        file.extend(compressed);
+        file
+    }
+
+    fn all_leds(sequence: &mut Sequence, num_leds: usize) -> Vec<Vec<Rgb>> {
+        (0..sequence.frame_count())
+            .map(|index| sequence.leds(index, num_leds).unwrap())
+            .collect()
+    }
+
+    fn expected() -> Vec<Vec<Rgb>> {
+        FRAMES
+            .iter()
+            .map(|frame| {
+                vec![
+                    Rgb::new(frame[0], frame[1], frame[2]),
+                    Rgb::new(frame[3], frame[4], frame[5]),
+                ]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn plays_uncompressed_sequences() {
+        let mut v1 = header(1, 28, 6);
+        v1.extend_from_slice(&[0; 8]);
+        v1.extend(FRAMES.concat());
+        let mut sequence = Sequence::parse(v1).unwrap();
+        assert_eq!(sequence.step(), Duration::from_millis(50));
+        assert_eq!(all_leds(&mut sequence, 2), expected());
+
+        let mut v2 = header(2, 32, 6);
+        v2.extend_from_slice(&[0; 12]);
+        v2.extend(FRAMES.concat());
+        assert_eq!(all_leds(&mut Sequence::parse(v2).unwrap(), 2), expected());
+    }
+
+    #[test]
+    fn plays_compressed_sequences() {
+        for compression in [1, 2] {
+            let mut sequence = Sequence::parse(v2(compression, &[0, 1])).unwrap();
+            assert_eq!(all_leds(&mut sequence, 2), expected());
+            // Going back to an earlier block decompresses it again
+            assert_eq!(sequence.leds(0, 2).unwrap(), expected()[0]);
+        }
+    }
+
+    #[test]
+    fn sparse_ranges_place_channels() {
+        // The frames' 6 channels are the green and blue of LED 1 and the 4 channels from LED 2's green
+        let mut file = header(2, 44, 6);
+        file.extend_from_slice(&[0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        file.extend_from_slice(&[4, 0, 0, 2, 0, 0, 7, 0, 0, 4, 0, 0]);
+        file.extend(FRAMES.concat());
+        let mut sequence = Sequence::parse(file).unwrap();
+        assert_eq!(
+            sequence.leds(0, 4).unwrap(),
+            vec![Rgb::default(), Rgb::new(0, 1, 2), Rgb::new(0, 3, 4), Rgb::new(5, 6, 0)]
+        );
+        // LEDs the tree doesn't have are left out
+        assert_eq!(sequence.leds(0, 2).unwrap(), vec![Rgb::default(), Rgb::new(0, 1, 2)]);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(matches!(
+            Sequence::parse(b"GIF89a".to_vec()),
+            Err(FseqError::Invalid(_))
+        ));
+        let mut file = v2(3, &[0]);
+        assert!(matches!(Sequence::parse(file.clone()), Err(FseqError::Invalid(_))));
+        file[7] = 3;
+        assert!(matches!(Sequence::parse(file), Err(FseqError::Invalid(_))));
+
+        // Frames cut off the end of the file
+        let mut short = header(1, 28, 6);
+        short.extend_from_slice(&[0; 8]);
+        short.extend_from_slice(&FRAMES[0]);
+        let mut sequence = Sequence::parse(short).unwrap();
+        assert!(sequence.leds(0, 2).is_ok());
+        assert!(matches!(sequence.leds(1, 2), Err(FseqError::Invalid(_))));
+    }
+}
@@ -0,0 +1,56 @@
+use common::message::{Rgb, Rgb16};
+
+/// Quantizes 16-bit colors to 8 bits with temporal error diffusion
+///
+/// The rounding error of each channel is carried into the next frame, so a value between
+/// two 8-bit steps alternates between them with the right average instead of banding.
+pub struct Quantizer {
+    error: Vec<[i32; 3]>,
+}
+
+impl Quantizer {
+    /// Create a new Quantizer with no accumulated error
+    pub fn new() -> Self {
+        Self { error: Vec::new() }
+    }
+
+    /// Quantize a frame of 16-bit colors to 8 bits
+    pub fn quantize(&mut self, leds: &[Rgb16]) -> Vec<Rgb> {
+        if self.error.len() != leds.len() {
+            self.error = vec![[0; 3]; leds.len()];
+        }
+
+        leds.iter()
+            .zip(self.error.iter_mut())
+            .map(|(rgb, error)| {
+                let mut quantized = [0u8; 3];
+                for ((channel, error), output) in [rgb.r, rgb.g, rgb.b].into_iter().zip(error.iter_mut()).zip(&mut quantized) {
+                    // Each 8-bit step is 257 16-bit steps (0xff * 257 == 0xffff)
+                    let value = channel as i32 + *error;
+                    let step = ((value + 128) / 257).clamp(0, 255);
+                    *error = value - step * 257;
+                    *output = step as u8;
+                }
+                Rgb::new(quantized[0], quantized[1], quantized[2])
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dithering_averages_to_the_input() {
+        let mut quantizer = Quantizer::new();
+        // A quarter of the way between 8-bit values 1 and 2
+        let input = [Rgb16::new(257 + 64, 0, 0xffff)];
+
+        let frames: Vec<Rgb> = (0..256).map(|_| quantizer.quantize(&input)[0]).collect();
+        let red_sum: u32 = frames.iter().map(|rgb| rgb.r as u32).sum();
+        assert_eq!(red_sum, 256 + 64);
+        assert!(frames.iter().all(|rgb| rgb.r == 1 || rgb.r == 2));
+        assert!(frames.iter().all(|rgb| rgb.g == 0 && rgb.b == 255));
+    }
+}
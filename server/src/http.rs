@@ -0,0 +1,719 @@
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{get, post, put};
+use axum::{Json, Router};
+use christmas_tree_client::{MessageError, MessageHandler, Transport};
+use common::message::{DeviceInfoPayload, Effect, MAX_HOST_SEQ, Message, PowerOffPayload, Rgb};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::bus::{FrameBus, Priority, Scene};
+use crate::cli::parse_color;
+use crate::commands;
+use crate::config::Config;
+use crate::effects::{EffectInfo, EffectRegistry};
+use crate::log_file::RotatingLog;
+use crate::logs;
+use crate::mapping::TreeMap;
+use crate::metrics::Metrics;
+use crate::pipeline::OutputPipeline;
+use crate::presets::{Preset, PresetError, PresetStore};
+use crate::HANDSHAKE_TIMEOUT;
+
+mod notify;
+mod render;
+mod sequence;
+mod stream;
+mod wled;
+
+use sequence::Playback;
+use wled::WledState;
+
+/// How often the link is kept alive with a heartbeat and the firmware's messages are read
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Page served at the root for controlling the tree from a browser
+const INDEX_HTML: &str = include_str!("http/index.html");
+
+/// Source on the frame bus of the colors, effects and presets set through the API
+const AMBIENT: &str = "ambient";
+
+/// Source on the frame bus of frames pushed one at a time
+const PUSHED_FRAMES: &str = "pushed frames";
+
+/// What the API's handlers share
+pub struct ApiState<T: Transport> {
+    /// Link to the tree, locked for a whole request so replies reach the request waiting for them
+    tree: Mutex<MessageHandler<T>>,
+    /// The firmware's DeviceInfo, read once connected
+    info: DeviceInfoPayload,
+    log_file: Mutex<RotatingLog>,
+    /// Sequence number of the next pushed frame
+    seq: AtomicU32,
+    /// What WLED apps have set
+    wled: Mutex<WledState>,
+    effects: EffectRegistry,
+    presets: PresetStore,
+    /// Directory sequences are played from
+    sequence_dir: PathBuf,
+    /// The sequence playing, if any
+    playback: Mutex<Option<Playback>>,
+    /// Decides which source the tree shows, always locked after the tree
+    bus: Mutex<FrameBus>,
+    /// Filters frames on their way to the tree, locked after the bus
+    pipeline: Mutex<OutputPipeline>,
+    /// Number of the latest notification, earlier ones having stopped flashing
+    notifications: AtomicU32,
+    /// Position of each LED, for rendering the host's effects
+    map: TreeMap,
+    /// Number of the latest effect rendered as the ambient scene, earlier ones having stopped
+    renderings: AtomicU32,
+    metrics: Metrics,
+}
+
+impl<T: Transport> ApiState<T> {
+    pub fn new(
+        tree: MessageHandler<T>,
+        info: DeviceInfoPayload,
+        log_file: RotatingLog,
+        presets: PresetStore,
+        sequence_dir: PathBuf,
+        map: TreeMap,
+        pipeline: OutputPipeline,
+    ) -> Self {
+        ApiState {
+            tree: Mutex::new(tree),
+            info,
+            log_file: Mutex::new(log_file),
+            seq: AtomicU32::new(0),
+            wled: Mutex::new(WledState::default()),
+            effects: EffectRegistry::with_builtin(),
+            presets,
+            sequence_dir,
+            playback: Mutex::new(None),
+            bus: Mutex::new(FrameBus::default()),
+            pipeline: Mutex::new(pipeline),
+            notifications: AtomicU32::new(0),
+            map,
+            renderings: AtomicU32::new(0),
+            metrics: Metrics::default(),
+        }
+    }
+
+    /// Run f with exclusive use of the link, off the async runtime since it may wait for the firmware
+    async fn with_tree<R: Send + 'static>(
+        self: &Arc<Self>,
+        f: impl FnOnce(&MessageHandler<T>) -> Result<R, MessageError> + Send + 'static,
+    ) -> Result<R, ApiError>
+    where
+        T: 'static,
+    {
+        let state = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let tree = state.tree.lock().map_err(|_| ApiError::Unavailable)?;
+            f(&tree).map_err(ApiError::Tree)
+        })
+        .await
+        .map_err(|_| ApiError::Unavailable)?
+    }
+
+    /// Change the frame bus, sending the tree the scene it should show if that changed
+    fn update_bus(&self, f: impl FnOnce(&mut FrameBus) -> Option<Scene>) -> Result<(), ApiError> {
+        let tree = self.tree.lock().map_err(|_| ApiError::Unavailable)?;
+        let mut bus = self.bus.lock().map_err(|_| ApiError::Unavailable)?;
+        let Some(scene) = f(&mut bus) else {
+            return Ok(());
+        };
+        let mut pipeline = self.pipeline.lock().map_err(|_| ApiError::Unavailable)?;
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed) & MAX_HOST_SEQ;
+        let is_frame = matches!(scene, Scene::Frame(_));
+        let written = Instant::now();
+        scene.show(&tree, seq, &mut pipeline).map_err(ApiError::Tree)?;
+        if is_frame {
+            self.metrics.frame_sent(written.elapsed());
+        }
+        Ok(())
+    }
+
+    /// Publish a scene on the frame bus, off the async runtime, the tree showing it if its source wins
+    async fn publish(
+        self: &Arc<Self>,
+        source: impl Into<String>,
+        priority: Priority,
+        scene: Scene,
+        hold: Option<Duration>,
+    ) -> Result<(), ApiError>
+    where
+        T: 'static,
+    {
+        let (state, source) = (self.clone(), source.into());
+        tokio::task::spawn_blocking(move || {
+            state.update_bus(|bus| bus.publish(&source, priority, scene, hold, Instant::now()))
+        })
+        .await
+        .map_err(|_| ApiError::Unavailable)?
+    }
+
+    /// Release a source from the frame bus, the tree falling back to the next source if it was shown
+    async fn release(self: &Arc<Self>, source: impl Into<String>) -> Result<(), ApiError>
+    where
+        T: 'static,
+    {
+        let (state, source) = (self.clone(), source.into());
+        tokio::task::spawn_blocking(move || state.update_bus(|bus| bus.release(&source)))
+            .await
+            .map_err(|_| ApiError::Unavailable)?
+    }
+
+    /// Set the scene the tree shows when nothing overrides it, stopping any effect being rendered as it
+    async fn set_ambient(self: &Arc<Self>, scene: Scene) -> Result<(), ApiError>
+    where
+        T: 'static,
+    {
+        self.renderings.fetch_add(1, Ordering::Relaxed);
+        self.publish(AMBIENT, Priority::Scheduled, scene, None).await
+    }
+
+    /// Record an API request in the log file
+    fn log(&self, line: &str) {
+        if let Ok(mut log_file) = self.log_file.lock() {
+            log_file.write_line("http", line).ok();
+        }
+    }
+}
+
+/// Serve the HTTP API on addr until the process is stopped
+/// Effects are rendered on the config's map, or with the LEDs wound in a spiral when the tree hasn't been mapped.
+pub fn serve(
+    addr: SocketAddr,
+    tree: MessageHandler,
+    log_file: RotatingLog,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let info = tree.device_info(HANDSHAKE_TIMEOUT)?;
+    let map = match &config.map_file {
+        Some(path) => TreeMap::load(path)?,
+        None => TreeMap::spiral(info.num_leds as usize),
+    };
+    if map.len() != info.num_leds as usize {
+        eprintln!("Warning: the tree map has {} LEDs but the tree has {}", map.len(), info.num_leds);
+    }
+    let presets = PresetStore::new(&config.presets_file);
+    let pipeline = OutputPipeline::from_config(info.num_leds as usize, config);
+    let state = ApiState::new(tree, info, log_file, presets, config.sequence_dir.clone(), map, pipeline);
+    let state = Arc::new(state);
+
+    let heartbeat_state = state.clone();
+    std::thread::spawn(move || keep_alive(&heartbeat_state));
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        println!("Serving the HTTP API on http://{}", listener.local_addr()?);
+        axum::serve(listener, router(state)).await
+    })?;
+    Ok(())
+}
+
+/// Routes of the HTTP API
+pub fn router<T: Transport + 'static>(state: Arc<ApiState<T>>) -> Router {
+    Router::new()
+        .route("/", get(|| async { Html(INDEX_HTML) }))
+        .route("/api/info", get(info::<T>))
+        .route("/metrics", get(metrics::<T>))
+        .route("/api/power", put(power::<T>))
+        .route("/api/brightness", put(brightness::<T>))
+        .route("/api/color", put(color::<T>))
+        .route("/api/effect", put(effect::<T>))
+        .route("/api/effects", get(effects::<T>))
+        .route("/api/effects/{id}", put(render::render::<T>))
+        .route("/api/presets", get(presets::<T>))
+        .route("/api/presets/{name}", put(save_preset::<T>).delete(delete_preset::<T>))
+        .route("/api/presets/{name}/load", post(load_preset::<T>))
+        .route("/api/frame", post(frame::<T>).delete(release_frame::<T>))
+        .route("/api/sources", get(sources::<T>))
+        .route("/api/notify", post(notify::notify::<T>).delete(notify::clear::<T>))
+        .route("/api/frames", get(stream::frames::<T>))
+        .route("/api/sequences", get(sequence::list::<T>))
+        .route(
+            "/api/sequence",
+            get(sequence::status::<T>).put(sequence::play::<T>).delete(sequence::stop::<T>),
+        )
+        // Enough of WLED's JSON API for WLED apps and integrations to control the tree
+        .route("/json", get(wled::get_all::<T>))
+        .route("/json/state", get(wled::get_state::<T>).post(wled::post_state::<T>))
+        .route("/json/info", get(wled::get_info::<T>))
+        .route("/json/eff", get(wled::get_effects))
+        .route("/json/pal", get(wled::get_palettes))
+        .with_state(state)
+}
+
+/// Send heartbeats so the firmware keeps the link up, display its logs, and release sources whose hold ran out
+fn keep_alive<T: Transport>(state: &ApiState<T>) {
+    loop {
+        if let Err(e) = state.update_bus(|bus| bus.release_expired(Instant::now())) {
+            eprintln!("Failed to fall back from an expired source: {:?}", e);
+        }
+        if let Ok(tree) = state.tree.lock() {
+            tree.send(&Message::Heartbeat).ok();
+            state.metrics.heartbeat_sent();
+            while let Ok(Some(message)) = tree.try_receive() {
+                match message {
+                    Message::Log(payload) => {
+                        let line = logs::render(&payload);
+                        println!("{}", line);
+                        if let Ok(mut log_file) = state.log_file.lock() {
+                            log_file.write_line("firmware", &line).ok();
+                        }
+                    }
+                    Message::Heartbeat => state.metrics.heartbeat_received(),
+                    Message::Telemetry(telemetry) => state.metrics.telemetry(&telemetry),
+                    _ => {}
+                }
+            }
+        }
+        std::thread::sleep(HEARTBEAT_INTERVAL);
+    }
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// The request doesn't describe something the tree can do
+    BadRequest(String),
+    /// The tree didn't answer or the link failed
+    Tree(MessageError),
+    /// The link is unusable after a handler panicked while holding it
+    Unavailable,
+    /// Nothing has the requested name
+    NotFound(String),
+    /// The host failed to do what was asked
+    Internal(String),
+}
+
+impl From<PresetError> for ApiError {
+    fn from(e: PresetError) -> Self {
+        match e {
+            PresetError::NotFound(_) => ApiError::NotFound(e.to_string()),
+            e => ApiError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match self {
+            ApiError::BadRequest(message) => (StatusCode::BAD_REQUEST, message).into_response(),
+            ApiError::Tree(e) => (StatusCode::BAD_GATEWAY, e.to_string()).into_response(),
+            ApiError::Unavailable => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+            ApiError::NotFound(message) => (StatusCode::NOT_FOUND, message).into_response(),
+            ApiError::Internal(message) => (StatusCode::INTERNAL_SERVER_ERROR, message).into_response(),
+        }
+    }
+}
+
+async fn info<T: Transport + 'static>(State(state): State<Arc<ApiState<T>>>) -> Json<DeviceInfoPayload> {
+    Json(state.info.clone())
+}
+
+/// Measurements of the link and the firmware for Prometheus
+async fn metrics<T: Transport + 'static>(State(state): State<Arc<ApiState<T>>>) -> String {
+    state.metrics.render()
+}
+
+/// The effects the host can render, with their parameter schemas
+async fn effects<T: Transport + 'static>(State(state): State<Arc<ApiState<T>>>) -> Json<Vec<EffectInfo>> {
+    Json(state.effects.list().cloned().collect())
+}
+
+async fn presets<T: Transport + 'static>(
+    State(state): State<Arc<ApiState<T>>>,
+) -> Result<Json<BTreeMap<String, Preset>>, ApiError> {
+    Ok(Json(state.presets.list()?))
+}
+
+async fn save_preset<T: Transport + 'static>(
+    State(state): State<Arc<ApiState<T>>>,
+    Path(name): Path<String>,
+    Json(preset): Json<Preset>,
+) -> Result<StatusCode, ApiError> {
+    if preset.brightness > 100 {
+        return Err(ApiError::BadRequest(format!("{} is not a percentage, use 0-100", preset.brightness)));
+    }
+    let line = format!("Saved preset {}: {}", name, preset);
+    state.presets.save(&name, preset)?;
+    state.log(&line);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_preset<T: Transport + 'static>(
+    State(state): State<Arc<ApiState<T>>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    state.presets.delete(&name)?;
+    state.log(&format!("Deleted preset {}", name));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn load_preset<T: Transport + 'static>(
+    State(state): State<Arc<ApiState<T>>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let preset = state.presets.get(&name)?;
+    let scene = Scene::Effect(preset.effect.clone());
+    state.with_tree(move |tree| preset.apply_settings(tree)).await?;
+    state.set_ambient(scene).await?;
+    state.log(&format!("Loaded preset {}", name));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct PowerRequest {
+    on: bool,
+    /// Let the tree light sleep while off
+    #[serde(default)]
+    sleep: bool,
+}
+
+async fn power<T: Transport + 'static>(
+    State(state): State<Arc<ApiState<T>>>,
+    Json(request): Json<PowerRequest>,
+) -> Result<StatusCode, ApiError> {
+    let message = if request.on {
+        Message::PowerOn
+    } else {
+        Message::PowerOff(PowerOffPayload {
+            light_sleep: request.sleep,
+        })
+    };
+    state.with_tree(move |tree| tree.send(&message)).await?;
+    state.log(&format!("Set power to {}", if request.on { "on" } else { "off" }));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct BrightnessRequest {
+    percent: u8,
+}
+
+async fn brightness<T: Transport + 'static>(
+    State(state): State<Arc<ApiState<T>>>,
+    Json(request): Json<BrightnessRequest>,
+) -> Result<StatusCode, ApiError> {
+    if request.percent > 100 {
+        return Err(ApiError::BadRequest(format!("{} is not a percentage, use 0-100", request.percent)));
+    }
+    state
+        .with_tree(move |tree| {
+            let mut config = tree.config(HANDSHAKE_TIMEOUT)?;
+            config.brightness_cap = commands::brightness_cap(request.percent);
+            tree.set_config(config, HANDSHAKE_TIMEOUT)
+        })
+        .await?;
+    state.log(&format!("Set brightness to {}%", request.percent));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct ColorRequest {
+    /// Color as RRGGBB hex, R,G,B or a name
+    color: String,
+}
+
+async fn color<T: Transport + 'static>(
+    State(state): State<Arc<ApiState<T>>>,
+    Json(request): Json<ColorRequest>,
+) -> Result<StatusCode, ApiError> {
+    let color = parse_color(&request.color).map_err(ApiError::BadRequest)?;
+    let leds = vec![color; state.info.num_leds as usize];
+    state.set_ambient(Scene::Frame(leds)).await?;
+    state.log(&format!("Set color to {:?}", color));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct EffectRequest {
+    effect: Effect,
+    /// Speed as a multiple of the effect's normal speed
+    #[serde(default = "EffectRequest::default_speed")]
+    speed: f32,
+}
+
+impl EffectRequest {
+    fn default_speed() -> f32 {
+        1.0
+    }
+}
+
+async fn effect<T: Transport + 'static>(
+    State(state): State<Arc<ApiState<T>>>,
+    Json(request): Json<EffectRequest>,
+) -> Result<StatusCode, ApiError> {
+    let payload = commands::start_effect(request.effect, request.speed);
+    let line = format!("Started {:?} at {}% speed", payload.effect, payload.speed);
+    state.set_ambient(Scene::Effect(payload)).await?;
+    state.log(&line);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct FrameQuery {
+    /// Priority on the frame bus, scheduled like colors and effects by default
+    #[serde(default = "FrameQuery::default_priority")]
+    priority: Priority,
+    /// Milliseconds after which the frame is released if no other is pushed
+    hold_ms: Option<u64>,
+}
+
+impl FrameQuery {
+    fn default_priority() -> Priority {
+        Priority::Scheduled
+    }
+}
+
+/// Push a frame of raw RGB bytes, three per LED
+async fn frame<T: Transport + 'static>(
+    State(state): State<Arc<ApiState<T>>>,
+    Query(query): Query<FrameQuery>,
+    body: Bytes,
+) -> Result<StatusCode, ApiError> {
+    let leds = parse_frame(&body, state.info.num_leds as usize)?;
+    let hold = query.hold_ms.map(Duration::from_millis);
+    state.publish(PUSHED_FRAMES, query.priority, Scene::Frame(leds), hold).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Release the pushed frames, the tree falling back to what they overrode
+async fn release_frame<T: Transport + 'static>(State(state): State<Arc<ApiState<T>>>) -> Result<StatusCode, ApiError> {
+    state.release(PUSHED_FRAMES).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Serialize)]
+struct SourceStatus {
+    name: String,
+    priority: Priority,
+}
+
+/// The sources on the frame bus, the one the tree shows first
+async fn sources<T: Transport + 'static>(
+    State(state): State<Arc<ApiState<T>>>,
+) -> Result<Json<Vec<SourceStatus>>, ApiError> {
+    let bus = state.bus.lock().map_err(|_| ApiError::Unavailable)?;
+    let sources = bus.sources().into_iter().map(|(name, priority)| SourceStatus {
+        name: name.to_string(),
+        priority,
+    });
+    Ok(Json(sources.collect()))
+}
+
+/// LEDs of a frame of raw RGB bytes, which must light every LED of the tree
+pub fn parse_frame(bytes: &[u8], num_leds: usize) -> Result<Vec<Rgb>, ApiError> {
+    if bytes.len() != num_leds * 3 {
+        return Err(ApiError::BadRequest(format!(
+            "A frame is {} bytes, three for each of the tree's {} LEDs, not {}",
+            num_leds * 3,
+            num_leds,
+            bytes.len()
+        )));
+    }
+    Ok(bytes.chunks_exact(3).map(|rgb| Rgb::new(rgb[0], rgb[1], rgb[2])).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use christmas_tree_client::{Loopback, Simulator};
+    use tower::ServiceExt;
+
+    pub(super) fn api(num_leds: u16) -> (Simulator, Router) {
+        let (simulator, host) = Simulator::start(num_leds);
+        let info = host.device_info(HANDSHAKE_TIMEOUT).unwrap();
+        let path = std::env::temp_dir().join(format!("tree-http-{}-{}.log", std::process::id(), num_leds));
+        let presets = PresetStore::new(path.with_extension("json"));
+        let sequence_dir = path.with_extension("sequences");
+        let log_file = RotatingLog::open(path, Default::default()).unwrap();
+        let map = TreeMap::spiral(num_leds as usize);
+        let pipeline = OutputPipeline::new(num_leds as usize);
+        let state = ApiState::new(host, info, log_file, presets, sequence_dir, map, pipeline);
+        let state: Arc<ApiState<Loopback>> = Arc::new(state);
+        (simulator, router(state))
+    }
+
+    pub(super) fn json_request(method: &str, uri: &str, body: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    /// Wait for the simulator to display a frame
+    pub(super) fn wait_for_frame(simulator: &Simulator) {
+        for _ in 0..100 {
+            if simulator.state().frames > 0 {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the simulator never displayed a frame");
+    }
+
+    /// Wait for the simulator to show a frame lighting every LED in a color
+    pub(super) fn wait_for_color(simulator: &Simulator, color: Rgb) {
+        for _ in 0..100 {
+            if simulator.state().leds.iter().all(|&rgb| rgb == color) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the simulator never showed {:?}", color);
+    }
+
+    #[tokio::test]
+    async fn info_and_the_control_page_are_served() {
+        let (_simulator, api) = api(16);
+        let response = api.clone().oneshot(json_request("GET", "/api/info", "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(info["num_leds"], 16);
+
+        let response = api.clone().oneshot(json_request("GET", "/", "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = api.oneshot(json_request("GET", "/api/lights", "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn solid_color_lights_every_led() {
+        let (simulator, api) = api(5);
+        let response = api.oneshot(json_request("PUT", "/api/color", r##"{"color": "#ff8000"}"##)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        wait_for_frame(&simulator);
+        assert_eq!(simulator.state().leds, vec![Rgb::new(255, 128, 0); 5]);
+    }
+
+    #[tokio::test]
+    async fn raw_frames_are_pushed() {
+        let (simulator, api) = api(2);
+        let request = Request::post("/api/frame").body(Body::from(vec![1, 2, 3, 4, 5, 6])).unwrap();
+        assert_eq!(api.clone().oneshot(request).await.unwrap().status(), StatusCode::NO_CONTENT);
+        wait_for_frame(&simulator);
+        assert_eq!(simulator.state().leds, vec![Rgb::new(1, 2, 3), Rgb::new(4, 5, 6)]);
+
+        let request = Request::post("/api/frame").body(Body::from(vec![1, 2, 3])).unwrap();
+        assert_eq!(api.oneshot(request).await.unwrap().status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn pushed_frames_override_the_ambient_color_until_released() {
+        let (simulator, api) = api(10);
+        let response = api.clone().oneshot(json_request("PUT", "/api/color", r#"{"color": "ff0000"}"#)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NO_CONTENT);
+        wait_for_color(&simulator, Rgb::new(255, 0, 0));
+
+        let request = Request::post("/api/frame?priority=notification").body(Body::from([0, 0, 255].repeat(10)));
+        assert_eq!(api.clone().oneshot(request.unwrap()).await.unwrap().status(), StatusCode::NO_CONTENT);
+        wait_for_color(&simulator, Rgb::new(0, 0, 255));
+        // Setting the color while overridden only changes what the tree falls back to
+        let response = api.clone().oneshot(json_request("PUT", "/api/color", r#"{"color": "00ff00"}"#)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NO_CONTENT);
+
+        let response = api.clone().oneshot(json_request("GET", "/api/sources", "")).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let sources: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let expected = serde_json::json!([
+            {"name": "pushed frames", "priority": "notification"},
+            {"name": "ambient", "priority": "scheduled"},
+        ]);
+        assert_eq!(sources, expected);
+
+        let response = api.oneshot(json_request("DELETE", "/api/frame", "")).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NO_CONTENT);
+        wait_for_color(&simulator, Rgb::new(0, 255, 0));
+    }
+
+    #[tokio::test]
+    async fn effects_and_power_reach_the_tree() {
+        let (simulator, api) = api(3);
+        let request = json_request("PUT", "/api/effect", r#"{"effect": "chase", "speed": 2}"#);
+        let response = api.clone().oneshot(request).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NO_CONTENT);
+        let response = api.oneshot(json_request("PUT", "/api/power", r#"{"on": false}"#)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NO_CONTENT);
+
+        std::thread::sleep(Duration::from_millis(100));
+        let unhandled = simulator.state().unhandled;
+        assert_eq!(unhandled[0], Message::StartEffect(commands::start_effect(Effect::Chase, 2.0)));
+        assert_eq!(unhandled[1], Message::PowerOff(PowerOffPayload { light_sleep: false }));
+    }
+
+    #[tokio::test]
+    async fn metrics_count_the_frames_sent() {
+        let (_simulator, api) = api(13);
+        let response = api.clone().oneshot(json_request("PUT", "/api/color", r#"{"color": "red"}"#)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NO_CONTENT);
+        let response = api.oneshot(json_request("GET", "/metrics", "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(text.lines().any(|line| line == "tree_frames_sent_total 1"), "{}", text);
+        assert!(text.lines().any(|line| line == "tree_serial_write_seconds_count 1"), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn effects_are_listed_with_their_params() {
+        let (_simulator, api) = api(7);
+        let response = api.oneshot(json_request("GET", "/api/effects", "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let effects: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(effects[1]["id"], "ramp");
+        let speed = serde_json::json!({"type": "int", "min": 1, "max": 255, "default": 1});
+        assert_eq!(effects[1]["params"][1]["kind"], speed);
+    }
+
+    #[tokio::test]
+    async fn presets_are_saved_and_deleted() {
+        let (_simulator, api) = api(8);
+        let preset = r#"{"effect": {"effect": "twinkle", "speed": 50, "palette": []}, "brightness": 60}"#;
+        let response = api.clone().oneshot(json_request("PUT", "/api/presets/christmas%20eve", preset)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NO_CONTENT);
+
+        let response = api.clone().oneshot(json_request("GET", "/api/presets", "")).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let presets: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(presets["christmas eve"]["brightness"], 60);
+
+        let response = api.clone().oneshot(json_request("DELETE", "/api/presets/christmas%20eve", "")).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NO_CONTENT);
+        let response = api.oneshot(json_request("POST", "/api/presets/christmas%20eve/load", "")).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn invalid_requests_are_rejected() {
+        let (_simulator, api) = api(4);
+        let response = api.clone().oneshot(json_request("PUT", "/api/color", r#"{"color": "reddish"}"#)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::BAD_REQUEST);
+        let response = api.clone().oneshot(json_request("PUT", "/api/brightness", r#"{"percent": 120}"#)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::BAD_REQUEST);
+        let preset = r#"{"effect": {"effect": "twinkle", "speed": 50, "palette": []}, "brightness": 120}"#;
+        let response = api.clone().oneshot(json_request("PUT", "/api/presets/bright", preset)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::BAD_REQUEST);
+        let response = api.clone().oneshot(json_request("PUT", "/api/effect", r#"{"effect": "sparkle"}"#)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let response = api.oneshot(json_request("PUT", "/api/color", "ff0000")).await;
+        assert_eq!(response.unwrap().status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,139 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use christmas_tree_client::Transport;
+use serde::Deserialize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+
+use super::{ApiError, ApiState};
+use crate::bus::{Priority, Scene};
+use crate::cli::parse_color;
+use crate::notify::{Notification, MAX_FLASHES};
+
+/// Source of notifications on the frame bus
+const NOTIFICATION: &str = "notification";
+
+#[derive(Debug, Deserialize)]
+pub struct NotifyRequest {
+    /// Color as RRGGBB hex, R,G,B or a name
+    color: String,
+    /// Number of flashes
+    #[serde(default = "NotifyRequest::default_times")]
+    times: u32,
+    /// Show what the tree showed before once the flashes end, rather than staying lit in the color
+    #[serde(default)]
+    then_resume: bool,
+}
+
+impl NotifyRequest {
+    fn default_times() -> u32 {
+        3
+    }
+}
+
+/// Flash the tree over whatever it's showing, in place of any notification already flashing
+/// Unless it resumes, the tree stays lit in the color until the notification is cleared.
+pub async fn notify<T: Transport + 'static>(
+    State(state): State<Arc<ApiState<T>>>,
+    Json(request): Json<NotifyRequest>,
+) -> Result<StatusCode, ApiError> {
+    let color = parse_color(&request.color).map_err(ApiError::BadRequest)?;
+    if !(1..=MAX_FLASHES).contains(&request.times) {
+        let message = format!("{} is not a number of flashes, use 1-{}", request.times, MAX_FLASHES);
+        return Err(ApiError::BadRequest(message));
+    }
+    let notification = Notification {
+        color,
+        times: request.times,
+        then_resume: request.then_resume,
+    };
+    // Taking a new number stops the notification flashing
+    let number = state.notifications.fetch_add(1, Ordering::Relaxed) + 1;
+    state.log(&format!("Flashing {:?} {} times", color, request.times));
+    let flash_state = state.clone();
+    std::thread::spawn(move || flash(&flash_state, notification, number));
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Stop the notification, the tree falling back to what it overrode
+pub async fn clear<T: Transport + 'static>(State(state): State<Arc<ApiState<T>>>) -> Result<StatusCode, ApiError> {
+    state.notifications.fetch_add(1, Ordering::Relaxed);
+    state.release(NOTIFICATION).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Show a notification's flashes on time until they end or another notification replaces them
+fn flash<T: Transport>(state: &ApiState<T>, notification: Notification, number: u32) {
+    // Checked with the bus locked, so a replaced notification can't publish once it's been released
+    let current = || state.notifications.load(Ordering::Relaxed) == number;
+    for (leds, duration) in notification.steps(state.info.num_leds as usize) {
+        if !current() {
+            return;
+        }
+        let published = state.update_bus(|bus| {
+            if !current() {
+                return None;
+            }
+            bus.publish(NOTIFICATION, Priority::Notification, Scene::Frame(leds), None, Instant::now())
+        });
+        if let Err(e) = published {
+            eprintln!("Failed to flash a notification: {:?}", e);
+            return;
+        }
+        std::thread::sleep(duration);
+    }
+    if notification.then_resume {
+        let resumed = state.update_bus(|bus| if current() { bus.release(NOTIFICATION) } else { None });
+        if let Err(e) = resumed {
+            eprintln!("Failed to resume after a notification: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::tests::{api, json_request, wait_for_color};
+    use common::message::Rgb;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn notifications_flash_then_resume() {
+        let (simulator, api) = api(11);
+        let response = api.clone().oneshot(json_request("PUT", "/api/color", r#"{"color": "green"}"#)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NO_CONTENT);
+        wait_for_color(&simulator, Rgb::new(0, 255, 0));
+
+        let body = r#"{"color": "red", "times": 1, "then_resume": true}"#;
+        let response = api.clone().oneshot(json_request("POST", "/api/notify", body)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::ACCEPTED);
+        wait_for_color(&simulator, Rgb::new(255, 0, 0));
+        wait_for_color(&simulator, Rgb::new(0, 255, 0));
+
+        let response = api.oneshot(json_request("POST", "/api/notify", r#"{"color": "red", "times": 0}"#)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn clearing_a_notification_falls_back() {
+        let (simulator, api) = api(14);
+        let response = api.clone().oneshot(json_request("PUT", "/api/color", r#"{"color": "green"}"#)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NO_CONTENT);
+        wait_for_color(&simulator, Rgb::new(0, 255, 0));
+
+        // Without resuming, the tree stays lit in the color after the last flash
+        let body = r#"{"color": "red", "times": 1}"#;
+        let response = api.clone().oneshot(json_request("POST", "/api/notify", body)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::ACCEPTED);
+        wait_for_color(&simulator, Rgb::new(255, 0, 0));
+
+        let response = api.clone().oneshot(json_request("DELETE", "/api/notify", "")).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NO_CONTENT);
+        wait_for_color(&simulator, Rgb::new(0, 255, 0));
+
+        let response = api.oneshot(json_request("POST", "/api/notify", r#"{"color": "reddish"}"#)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::BAD_REQUEST);
+    }
+}
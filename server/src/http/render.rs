@@ -0,0 +1,115 @@
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use christmas_tree_client::Transport;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::{ApiError, ApiState, AMBIENT};
+use crate::bus::{Priority, Scene};
+use crate::effects::Effect;
+use crate::frame::Frame;
+
+/// Time between frames of an effect rendered for the API
+const FRAME_INTERVAL: Duration = Duration::from_millis(33);
+
+/// Render one of the host's effects as the tree's ambient scene, in place of its color, effect or preset
+/// The body sets the effect's parameters by id, those not given taking their defaults.
+pub async fn render<T: Transport + 'static>(
+    State(state): State<Arc<ApiState<T>>>,
+    Path(id): Path<String>,
+    Json(params): Json<BTreeMap<String, Value>>,
+) -> Result<StatusCode, ApiError> {
+    if state.effects.get(&id).is_none() {
+        return Err(ApiError::NotFound(format!("There's no effect called {}, see /api/effects", id)));
+    }
+    // Parameters are parsed as on the command line, so numbers and switches can be sent as JSON or as strings
+    let values: Vec<(String, String)> = params
+        .into_iter()
+        .map(|(param, value)| match value {
+            Value::String(value) => (param, value),
+            value => (param, value.to_string()),
+        })
+        .collect();
+    let params = state.effects.params(&id, &values).map_err(ApiError::BadRequest)?;
+    let effect = state.effects.create(&id, &params, &state.map).map_err(ApiError::BadRequest)?;
+    // Taking a new number stops the effect being rendered
+    let number = state.renderings.fetch_add(1, Ordering::Relaxed) + 1;
+    state.log(&format!("Rendering effect {}", id));
+    let render_state = state.clone();
+    std::thread::spawn(move || run(&render_state, effect, number));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Publish an effect's frames on time until something else is set as the ambient scene
+fn run<T: Transport>(state: &ApiState<T>, mut effect: Box<dyn Effect>, number: u32) {
+    // Checked with the bus locked, so a replaced effect can't publish once the ambient scene has changed
+    let current = || state.renderings.load(Ordering::Relaxed) == number;
+    let mut frame: Frame = Frame::new(state.info.num_leds as usize);
+    let mut last_frame = Instant::now();
+    while current() {
+        let now = Instant::now();
+        effect.tick(now - last_frame, &mut frame);
+        last_frame = now;
+        let leds = frame.leds().to_vec();
+        let published = state.update_bus(|bus| {
+            if !current() {
+                return None;
+            }
+            bus.publish(AMBIENT, Priority::Scheduled, Scene::Frame(leds), None, Instant::now())
+        });
+        if let Err(e) = published {
+            eprintln!("Failed to send an effect frame: {:?}", e);
+            state.log(&format!("Failed to send an effect frame: {:?}", e));
+            return;
+        }
+        std::thread::sleep((last_frame + FRAME_INTERVAL).saturating_duration_since(Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::tests::{api, json_request, wait_for_color};
+    use common::message::Rgb;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn host_effects_are_rendered_until_the_color_is_set() {
+        let (simulator, api) = api(12);
+        let response = api.clone().oneshot(json_request("PUT", "/api/effects/solid", r#"{"color": "0000ff"}"#)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NO_CONTENT);
+        wait_for_color(&simulator, Rgb::new(0, 0, 255));
+
+        let response = api.clone().oneshot(json_request("PUT", "/api/color", r#"{"color": "green"}"#)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NO_CONTENT);
+        wait_for_color(&simulator, Rgb::new(0, 255, 0));
+        // The stopped effect doesn't paint over the color
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(simulator.state().leds, vec![Rgb::new(0, 255, 0); 12]);
+
+        let body = r#"{"message": "MERRY XMAS", "speed": 30}"#;
+        let response = api.clone().oneshot(json_request("PUT", "/api/effects/text", body)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::BAD_REQUEST);
+        let response = api.oneshot(json_request("PUT", "/api/effects/sparkle", "{}")).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn params_are_given_as_json_values_or_strings() {
+        let (_simulator, api) = api(15);
+        let body = r#"{"color": "red", "period": 2, "downward": true}"#;
+        let response = api.clone().oneshot(json_request("PUT", "/api/effects/sweep", body)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NO_CONTENT);
+        let response = api.clone().oneshot(json_request("PUT", "/api/effects/sweep", r#"{"period": "2.5"}"#)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NO_CONTENT);
+
+        let response = api.clone().oneshot(json_request("PUT", "/api/effects/sweep", r#"{"period": "fast"}"#)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::BAD_REQUEST);
+        let response = api.oneshot(json_request("PUT", "/api/effects/sweep", r#"{"period": 600}"#)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::BAD_REQUEST);
+    }
+}
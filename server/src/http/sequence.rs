@@ -0,0 +1,238 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use christmas_tree_client::Transport;
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use super::{ApiError, ApiState};
+use crate::bus::{Priority, Scene};
+use crate::fseq::{FseqError, Sequence, FSEQ_EXTENSION};
+
+/// Source of sequence frames on the frame bus
+const SEQUENCE: &str = "sequence";
+
+/// A sequence playing on the tree, stopped once it's dropped
+pub struct Playback {
+    file: String,
+    repeat: bool,
+    frame_count: u32,
+    /// Frame the playing thread last sent
+    frame: Arc<AtomicU32>,
+    /// Set to stop the playing thread
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for Playback {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlaybackStatus {
+    file: String,
+    #[serde(rename = "loop")]
+    repeat: bool,
+    frame: u32,
+    frames: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PlayRequest {
+    /// Name of a sequence in the sequence directory
+    file: String,
+    /// Start again from the beginning each time it ends
+    #[serde(default, rename = "loop")]
+    repeat: bool,
+}
+
+/// The FSEQ sequences that can be played, by file name
+pub async fn list<T: Transport + 'static>(
+    State(state): State<Arc<ApiState<T>>>,
+) -> Result<Json<Vec<String>>, ApiError> {
+    let entries = match std::fs::read_dir(&state.sequence_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Json(Vec::new())),
+        Err(e) => return Err(ApiError::Internal(format!("Failed to list the sequences: {}", e))),
+    };
+    let mut files: Vec<String> = entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| Path::new(name).extension().is_some_and(|extension| extension == FSEQ_EXTENSION))
+        .collect();
+    files.sort();
+    Ok(Json(files))
+}
+
+/// What's playing, null when nothing is
+pub async fn status<T: Transport + 'static>(State(state): State<Arc<ApiState<T>>>) -> Json<Option<PlaybackStatus>> {
+    let playback = state.playback.lock().ok();
+    Json(playback.as_ref().and_then(|playback| playback.as_ref()).map(|playback| PlaybackStatus {
+        file: playback.file.clone(),
+        repeat: playback.repeat,
+        frame: playback.frame.load(Ordering::Relaxed),
+        frames: playback.frame_count,
+    }))
+}
+
+/// Play a sequence from the sequence directory, in place of any that's playing
+/// Sequences override colors and effects until they end or are stopped.
+pub async fn play<T: Transport + 'static>(
+    State(state): State<Arc<ApiState<T>>>,
+    Json(request): Json<PlayRequest>,
+) -> Result<StatusCode, ApiError> {
+    // Only files directly in the sequence directory can be played
+    let mut components = Path::new(&request.file).components();
+    if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+        return Err(ApiError::BadRequest(format!("{} is not the name of a sequence", request.file)));
+    }
+    let path = state.sequence_dir.join(&request.file);
+    let sequence = tokio::task::spawn_blocking(move || Sequence::load(&path))
+        .await
+        .map_err(|_| ApiError::Unavailable)?
+        .map_err(|e| match e {
+            FseqError::Io(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                ApiError::NotFound(format!("No sequence {}", request.file))
+            }
+            FseqError::Io(e) => ApiError::Internal(e.to_string()),
+            e => ApiError::BadRequest(e.to_string()),
+        })?;
+    if sequence.frame_count() == 0 {
+        return Err(ApiError::BadRequest(format!("{} has no frames", request.file)));
+    }
+
+    let playback = Playback {
+        file: request.file.clone(),
+        repeat: request.repeat,
+        frame_count: sequence.frame_count(),
+        frame: Arc::new(AtomicU32::new(0)),
+        stop: Arc::new(AtomicBool::new(false)),
+    };
+    let (frame, stop) = (playback.frame.clone(), playback.stop.clone());
+    // Replacing the playback stops the one playing
+    *state.playback.lock().map_err(|_| ApiError::Unavailable)? = Some(playback);
+    state.log(&format!("Playing sequence {}{}", request.file, if request.repeat { " on a loop" } else { "" }));
+    let player_state = state.clone();
+    std::thread::spawn(move || run(&player_state, sequence, request.repeat, &frame, &stop));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Stop the sequence that's playing, the tree falling back to what it overrode
+pub async fn stop<T: Transport + 'static>(State(state): State<Arc<ApiState<T>>>) -> Result<StatusCode, ApiError> {
+    let stopped = state.playback.lock().map_err(|_| ApiError::Unavailable)?.take();
+    if let Some(playback) = stopped {
+        state.release(SEQUENCE).await?;
+        state.log(&format!("Stopped sequence {}", playback.file));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Send a sequence's frames on time until it ends or is stopped
+fn run<T: Transport>(state: &ApiState<T>, mut sequence: Sequence, repeat: bool, frame: &AtomicU32, stop: &AtomicBool) {
+    let num_leds = state.info.num_leds as usize;
+    let mut started = Instant::now();
+    let mut index = 0;
+    while !stop.load(Ordering::Relaxed) {
+        if index == sequence.frame_count() {
+            if !repeat {
+                break;
+            }
+            index = 0;
+            started = Instant::now();
+        }
+        let leds = match sequence.leds(index, num_leds) {
+            Ok(leds) => leds,
+            Err(e) => {
+                eprintln!("{}", e);
+                state.log(&e.to_string());
+                break;
+            }
+        };
+        std::thread::sleep((started + sequence.step() * index).saturating_duration_since(Instant::now()));
+        if stop.load(Ordering::Relaxed) {
+            return;
+        }
+        // Checked with the bus locked, so a stopped sequence can't publish once it's been released
+        let published = state.update_bus(|bus| {
+            if stop.load(Ordering::Relaxed) {
+                return None;
+            }
+            bus.publish(SEQUENCE, Priority::Live, Scene::Frame(leds), None, Instant::now())
+        });
+        match published {
+            Ok(()) => {}
+            Err(ApiError::Tree(e)) => {
+                eprintln!("Failed to send a sequence frame: {}", e);
+                state.log(&format!("Failed to send a sequence frame: {}", e));
+                break;
+            }
+            Err(_) => return,
+        }
+        frame.store(index, Ordering::Relaxed);
+        index += 1;
+    }
+    // Nothing is playing once the sequence ends, unless another has taken its place
+    if let Ok(mut playback) = state.playback.lock()
+        && playback.as_ref().is_some_and(|playback| std::ptr::eq(Arc::as_ptr(&playback.stop), stop))
+    {
+        *playback = None;
+        state.update_bus(|bus| bus.release(SEQUENCE)).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::tests::{api, json_request, wait_for_frame};
+    use common::message::Rgb;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn sequences_play_from_the_sequence_dir() {
+        let (simulator, api) = api(9);
+        let dir = std::env::temp_dir().join(format!("tree-http-{}-9.sequences", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // A version 1 sequence of one 50 ms frame lighting 2 LEDs
+        let mut fseq = b"PSEQ\x1c\x00\x00\x01\x1c\x00".to_vec();
+        fseq.extend_from_slice(&6u32.to_le_bytes());
+        fseq.extend_from_slice(&1u32.to_le_bytes());
+        fseq.extend_from_slice(&[50, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        fseq.extend_from_slice(&[1, 2, 3, 4, 5, 6]);
+        std::fs::write(dir.join("show.fseq"), fseq).unwrap();
+
+        let response = api.clone().oneshot(json_request("GET", "/api/sequences", "")).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(serde_json::from_slice::<Vec<String>>(&body).unwrap(), vec!["show.fseq"]);
+
+        let response = api.clone().oneshot(json_request("PUT", "/api/sequence", r#"{"file": "show.fseq"}"#)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NO_CONTENT);
+        wait_for_frame(&simulator);
+        assert_eq!(simulator.state().leds[..3], [Rgb::new(1, 2, 3), Rgb::new(4, 5, 6), Rgb::default()]);
+
+        let response = api.clone().oneshot(json_request("PUT", "/api/sequence", r#"{"file": "../show.fseq"}"#)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::BAD_REQUEST);
+        let response = api.clone().oneshot(json_request("PUT", "/api/sequence", r#"{"file": "none.fseq"}"#)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NOT_FOUND);
+        let response = api.oneshot(json_request("DELETE", "/api/sequence", "")).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NO_CONTENT);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn status_is_null_when_nothing_plays() {
+        let (_simulator, api) = api(17);
+        let response = api.clone().oneshot(json_request("GET", "/api/sequence", "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap(), serde_json::Value::Null);
+
+        // Without a sequence directory there's nothing to list
+        let response = api.oneshot(json_request("GET", "/api/sequences", "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(serde_json::from_slice::<Vec<String>>(&body).unwrap(), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,151 @@
+use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::response::Response;
+use christmas_tree_client::Transport;
+use common::message::Rgb;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::{parse_frame, ApiError, ApiState};
+use crate::bus::{Priority, Scene};
+
+/// Most frames per second forwarded from a stream, frames arriving faster replace the one waiting to be sent
+const MAX_STREAM_FPS: f32 = 60.0;
+
+/// Number of the next stream, naming its source on the frame bus
+static NEXT_STREAM: AtomicU32 = AtomicU32::new(1);
+
+/// Spaces frames at least an interval apart
+#[derive(Debug)]
+pub struct FrameLimiter {
+    interval: Duration,
+    last_sent: Option<Instant>,
+}
+
+impl FrameLimiter {
+    pub fn new(max_fps: f32) -> Self {
+        FrameLimiter {
+            interval: Duration::from_secs_f32(1.0 / max_fps),
+            last_sent: None,
+        }
+    }
+
+    /// When the next frame may be sent, now if it can be sent immediately
+    pub fn next_slot(&self, now: Instant) -> Instant {
+        self.last_sent.map_or(now, |last_sent| (last_sent + self.interval).max(now))
+    }
+
+    /// Record a frame being sent
+    pub fn record(&mut self, now: Instant) {
+        self.last_sent = Some(now);
+    }
+}
+
+/// Upgrade to a WebSocket streaming frames to the tree
+///
+/// Each binary message is a frame of raw RGB bytes, three per LED, and each text message a JSON array of
+/// `[r, g, b]` triples. Frames must light every LED. A rejected frame is answered with a text message explaining why.
+/// Streams override colors and effects until they're closed.
+pub async fn frames<T: Transport + 'static>(
+    State(state): State<Arc<ApiState<T>>>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    upgrade.on_upgrade(move |socket| stream_frames(socket, state))
+}
+
+async fn stream_frames<T: Transport + 'static>(mut socket: WebSocket, state: Arc<ApiState<T>>) {
+    let num_leds = state.info.num_leds as usize;
+    let mut limiter = FrameLimiter::new(MAX_STREAM_FPS);
+    let mut pending: Option<Vec<Rgb>> = None;
+    let mut received = 0u64;
+    let mut sent = 0u64;
+    let source = format!("stream {}", NEXT_STREAM.fetch_add(1, Ordering::Relaxed));
+    state.log(&format!("Frame stream opened as {}", source));
+
+    loop {
+        let slot = tokio::time::Instant::from_std(limiter.next_slot(Instant::now()));
+        tokio::select! {
+            message = socket.recv() => {
+                let Some(Ok(message)) = message else {
+                    break;
+                };
+                match parse_message(message, num_leds) {
+                    Ok(Some(leds)) => {
+                        received += 1;
+                        pending = Some(leds);
+                    }
+                    Ok(None) => {}
+                    Err(ApiError::BadRequest(reason)) => {
+                        if socket.send(WsMessage::Text(reason.into())).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => {}
+                }
+            }
+            _ = tokio::time::sleep_until(slot), if pending.is_some() => {
+                let Some(leds) = pending.take() else {
+                    continue;
+                };
+                limiter.record(Instant::now());
+                let scene = Scene::Frame(leds);
+                if let Err(ApiError::Tree(e)) = state.publish(&*source, Priority::Live, scene, None).await {
+                    eprintln!("Failed to forward a streamed frame: {}", e);
+                    break;
+                }
+                sent += 1;
+            }
+        }
+    }
+    if let Err(e) = state.release(&*source).await {
+        eprintln!("Failed to fall back from a closed stream: {:?}", e);
+    }
+    state.log(&format!("Frame stream closed after {} frames, {} forwarded", received, sent));
+}
+
+/// The frame in a WebSocket message, None for control messages
+fn parse_message(message: WsMessage, num_leds: usize) -> Result<Option<Vec<Rgb>>, ApiError> {
+    match message {
+        WsMessage::Binary(bytes) => parse_frame(&bytes, num_leds).map(Some),
+        WsMessage::Text(text) => {
+            let leds: Vec<[u8; 3]> = serde_json::from_str(text.as_str())
+                .map_err(|e| ApiError::BadRequest(format!("A text frame is a JSON array of [r, g, b]: {}", e)))?;
+            let bytes: Vec<u8> = leds.into_iter().flatten().collect();
+            parse_frame(&bytes, num_leds).map(Some)
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limiter_spaces_frames() {
+        let mut limiter = FrameLimiter::new(50.0);
+        let start = Instant::now();
+        assert_eq!(limiter.next_slot(start), start);
+        limiter.record(start);
+        assert_eq!(limiter.next_slot(start), start + Duration::from_millis(20));
+        let late = start + Duration::from_millis(35);
+        assert_eq!(limiter.next_slot(late), late);
+    }
+
+    #[test]
+    fn parses_binary_and_json_frames() {
+        let binary = WsMessage::Binary(vec![1, 2, 3, 4, 5, 6].into());
+        assert_eq!(parse_message(binary, 2).unwrap(), Some(vec![Rgb::new(1, 2, 3), Rgb::new(4, 5, 6)]));
+        let text = WsMessage::Text("[[255, 0, 0], [0, 0, 255]]".into());
+        assert_eq!(parse_message(text, 2).unwrap(), Some(vec![Rgb::new(255, 0, 0), Rgb::new(0, 0, 255)]));
+        assert_eq!(parse_message(WsMessage::Ping(Vec::new().into()), 2).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_frames_of_the_wrong_size() {
+        assert!(parse_message(WsMessage::Binary(vec![1, 2, 3].into()), 2).is_err());
+        assert!(parse_message(WsMessage::Text("[[1, 2, 3]]".into()), 2).is_err());
+        assert!(parse_message(WsMessage::Text("[[1, 2]]".into()), 1).is_err());
+    }
+}
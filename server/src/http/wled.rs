@@ -0,0 +1,306 @@
+use axum::extract::State;
+use axum::Json;
+use christmas_tree_client::{MessageError, MessageHandler, Transport};
+use common::message::{Effect, Message, PowerOffPayload, Rgb};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use super::{ApiError, ApiState};
+use crate::bus::Scene;
+use crate::cli::parse_color;
+use crate::commands;
+use crate::HANDSHAKE_TIMEOUT;
+
+/// WLED version reported to apps, one whose JSON API they all understand
+const WLED_VERSION: &str = "0.14.0";
+
+/// WLED's UDP sync port, reported in the info though the tree doesn't sync
+const WLED_UDP_PORT: u16 = 21324;
+
+/// Effects in WLED's numbering, Solid being WLED's effect 0
+const EFFECTS: [(&str, Option<Effect>); 5] = [
+    ("Solid", None),
+    ("Rainbow", Some(Effect::Rainbow)),
+    ("Chase", Some(Effect::Chase)),
+    ("Twinkle", Some(Effect::Twinkle)),
+    ("Breathe", Some(Effect::Breathe)),
+];
+
+/// What WLED apps have set, reported back to them in WLED's state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WledState {
+    pub on: bool,
+    /// Brightness out of 255
+    pub bri: u8,
+    /// Primary color of the only segment
+    pub color: Rgb,
+    /// Effect in WLED's numbering
+    pub fx: u8,
+    /// Effect speed out of 255, 128 being the effect's normal speed
+    pub sx: u8,
+}
+
+impl Default for WledState {
+    fn default() -> Self {
+        WledState {
+            on: true,
+            bri: 255,
+            color: Rgb::new(255, 160, 0),
+            fx: 0,
+            sx: 128,
+        }
+    }
+}
+
+/// Changes to WLED's state, every field optional
+#[derive(Debug, Default, Deserialize)]
+pub struct StateUpdate {
+    /// true, false or "t" to toggle
+    on: Option<Value>,
+    bri: Option<u8>,
+    /// A segment object or an array of them, of which the first is applied
+    seg: Option<Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct SegmentUpdate {
+    /// Colors as [r, g, b(, w)] arrays or RRGGBB hex strings, of which the first is applied
+    col: Option<Vec<Value>>,
+    fx: Option<u8>,
+    sx: Option<u8>,
+}
+
+/// Apply a WLED state update to the previous state
+fn update(previous: WledState, update: StateUpdate) -> Result<WledState, ApiError> {
+    let mut state = previous;
+    match update.on {
+        Some(Value::Bool(on)) => state.on = on,
+        Some(Value::String(toggle)) if toggle == "t" => state.on = !state.on,
+        Some(on) => return Err(ApiError::BadRequest(format!("on is true, false or \"t\", not {}", on))),
+        None => {}
+    }
+    if let Some(bri) = update.bri {
+        state.bri = bri;
+    }
+    let segment = match update.seg {
+        Some(Value::Array(mut segments)) if !segments.is_empty() => Some(segments.swap_remove(0)),
+        Some(Value::Array(_)) | None => None,
+        Some(segment) => Some(segment),
+    };
+    if let Some(segment) = segment {
+        let segment: SegmentUpdate =
+            serde_json::from_value(segment).map_err(|e| ApiError::BadRequest(format!("Invalid segment: {}", e)))?;
+        if let Some(color) = segment.col.as_ref().and_then(|colors| colors.first()) {
+            state.color = parse_wled_color(color)?;
+        }
+        if let Some(fx) = segment.fx {
+            if fx as usize >= EFFECTS.len() {
+                return Err(ApiError::BadRequest(format!("The tree has no effect {}", fx)));
+            }
+            state.fx = fx;
+        }
+        if let Some(sx) = segment.sx {
+            state.sx = sx;
+        }
+    }
+    Ok(state)
+}
+
+/// Parse a WLED color, an [r, g, b] or [r, g, b, w] array or an RRGGBB hex string
+fn parse_wled_color(color: &Value) -> Result<Rgb, ApiError> {
+    let invalid = || ApiError::BadRequest(format!("{} is not a color", color));
+    match color {
+        Value::String(hex) => parse_color(hex).map_err(ApiError::BadRequest),
+        Value::Array(channels) if channels.len() >= 3 => {
+            let channel = |index: usize| {
+                channels[index].as_u64().and_then(|value| u8::try_from(value).ok()).ok_or_else(invalid)
+            };
+            Ok(Rgb::new(channel(0)?, channel(1)?, channel(2)?))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// WLED's state object for a tree of num_leds LEDs
+fn state_json(state: &WledState, num_leds: u16) -> Value {
+    let Rgb { r, g, b } = state.color;
+    json!({
+        "on": state.on,
+        "bri": state.bri,
+        "transition": 7,
+        "ps": -1,
+        "pl": -1,
+        "mainseg": 0,
+        "seg": [{
+            "id": 0,
+            "start": 0,
+            "stop": num_leds,
+            "len": num_leds,
+            "on": true,
+            "bri": 255,
+            "col": [[r, g, b], [0, 0, 0], [0, 0, 0]],
+            "fx": state.fx,
+            "sx": state.sx,
+            "ix": 128,
+            "pal": 0,
+            "sel": true,
+        }],
+    })
+}
+
+/// WLED's info object
+fn info_json<T: Transport>(state: &ApiState<T>) -> Value {
+    json!({
+        "ver": WLED_VERSION,
+        "name": "Christmas tree",
+        "brand": "WLED",
+        "product": "christmas-tree-rs",
+        "arch": state.info.chip,
+        "leds": {
+            "count": state.info.num_leds,
+            "rgbw": false,
+            "wv": 0,
+            "cct": 0,
+            "seglc": [1],
+            "maxseg": 1,
+        },
+        "udpport": WLED_UDP_PORT,
+        "live": false,
+        "fxcount": EFFECTS.len(),
+        "palcount": 1,
+    })
+}
+
+fn effect_names() -> Vec<&'static str> {
+    EFFECTS.iter().map(|(name, _)| *name).collect()
+}
+
+/// Show a WLED state on the tree, sending only what changed from the previous state
+/// Returns the color or effect to publish on the frame bus if that changed.
+fn apply<T: Transport>(
+    tree: &MessageHandler<T>,
+    previous: WledState,
+    state: WledState,
+    num_leds: usize,
+) -> Result<Option<Scene>, MessageError> {
+    if state.bri != previous.bri {
+        let mut config = tree.config(HANDSHAKE_TIMEOUT)?;
+        config.brightness_cap = state.bri;
+        tree.set_config(config, HANDSHAKE_TIMEOUT)?;
+    }
+    if !state.on {
+        if previous.on {
+            tree.send(&Message::PowerOff(PowerOffPayload { light_sleep: false }))?;
+        }
+        return Ok(None);
+    }
+    if !previous.on {
+        tree.send(&Message::PowerOn)?;
+    }
+    if previous.on && (state.color, state.fx, state.sx) == (previous.color, previous.fx, previous.sx) {
+        return Ok(None);
+    }
+    Ok(Some(match EFFECTS[state.fx as usize].1 {
+        None => Scene::Frame(vec![state.color; num_leds]),
+        Some(effect) => {
+            let speed = state.sx.max(1) as f32 / 128.0;
+            Scene::Effect(commands::start_effect(effect, speed))
+        }
+    }))
+}
+
+pub async fn get_state<T: Transport + 'static>(State(api): State<Arc<ApiState<T>>>) -> Result<Json<Value>, ApiError> {
+    let state = *api.wled.lock().map_err(|_| ApiError::Unavailable)?;
+    Ok(Json(state_json(&state, api.info.num_leds)))
+}
+
+pub async fn post_state<T: Transport + 'static>(
+    State(api): State<Arc<ApiState<T>>>,
+    Json(body): Json<StateUpdate>,
+) -> Result<Json<Value>, ApiError> {
+    let previous = *api.wled.lock().map_err(|_| ApiError::Unavailable)?;
+    let state = update(previous, body)?;
+    let num_leds = api.info.num_leds as usize;
+    if let Some(scene) = api.with_tree(move |tree| apply(tree, previous, state, num_leds)).await? {
+        api.set_ambient(scene).await?;
+    }
+    *api.wled.lock().map_err(|_| ApiError::Unavailable)? = state;
+    api.log(&format!("WLED app set {:?}", state));
+    Ok(Json(state_json(&state, api.info.num_leds)))
+}
+
+pub async fn get_info<T: Transport + 'static>(State(api): State<Arc<ApiState<T>>>) -> Json<Value> {
+    Json(info_json(&api))
+}
+
+pub async fn get_all<T: Transport + 'static>(State(api): State<Arc<ApiState<T>>>) -> Result<Json<Value>, ApiError> {
+    let state = *api.wled.lock().map_err(|_| ApiError::Unavailable)?;
+    Ok(Json(json!({
+        "state": state_json(&state, api.info.num_leds),
+        "info": info_json(&api),
+        "effects": effect_names(),
+        "palettes": ["Default"],
+    })))
+}
+
+pub async fn get_effects() -> Json<Value> {
+    Json(json!(effect_names()))
+}
+
+pub async fn get_palettes() -> Json<Value> {
+    Json(json!(["Default"]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::tests::{api, json_request};
+    use axum::http::StatusCode;
+    use tower::ServiceExt;
+
+    #[test]
+    fn updates_apply_to_the_previous_state() {
+        let body = json!({"on": "t", "bri": 40, "seg": [{"col": [[0, 255, 0, 0]], "fx": 3, "sx": 200}]});
+        let state = update(WledState::default(), serde_json::from_value(body).unwrap()).unwrap();
+        assert_eq!(
+            state,
+            WledState {
+                on: false,
+                bri: 40,
+                color: Rgb::new(0, 255, 0),
+                fx: 3,
+                sx: 200,
+            }
+        );
+        let body = json!({"seg": {"col": ["0000ff"]}});
+        let state = update(state, serde_json::from_value(body).unwrap()).unwrap();
+        assert_eq!(state.color, Rgb::new(0, 0, 255));
+    }
+
+    #[test]
+    fn rejects_unknown_effects_and_colors() {
+        let effect = json!({"seg": [{"fx": 99}]});
+        assert!(update(WledState::default(), serde_json::from_value(effect).unwrap()).is_err());
+        let color = json!({"seg": [{"col": [[300, 0, 0]]}]});
+        assert!(update(WledState::default(), serde_json::from_value(color).unwrap()).is_err());
+    }
+
+    #[tokio::test]
+    async fn wled_apps_drive_the_tree() {
+        let (simulator, api) = api(6);
+        let body = r#"{"seg": [{"col": [[0, 0, 255]], "fx": 0}]}"#;
+        let response = api.clone().oneshot(json_request("POST", "/json/state", body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(simulator.state().leds, vec![Rgb::new(0, 0, 255); 6]);
+
+        let response = api.clone().oneshot(json_request("POST", "/json/state", r#"{"on": false}"#)).await;
+        assert_eq!(response.unwrap().status(), StatusCode::OK);
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(simulator.state().unhandled, vec![Message::PowerOff(PowerOffPayload { light_sleep: false })]);
+
+        let response = api.oneshot(json_request("GET", "/json/info", "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
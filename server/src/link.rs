@@ -0,0 +1,279 @@
+use crate::flow::FrameOutcome;
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How far back events count towards the link health score
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Health score below which the link is reported as degraded
+pub const DEGRADED_SCORE: u8 = 80;
+
+/// Event observed on the serial link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkEvent {
+    HeartbeatSent,
+    HeartbeatReceived,
+    /// Firmware acknowledged a frame
+    FrameAcked,
+    /// Firmware rejected a frame
+    FrameNacked,
+    /// Frame was neither acknowledged nor rejected
+    FrameLost,
+    /// Firmware reported a frame checksum matching a sent frame
+    FrameVerified,
+    /// Firmware reported a frame checksum matching no sent frame
+    FrameCorrupted,
+    /// Reading or decoding from the link failed
+    ReceiveError,
+    /// Frame failed its CRC check, on either end of the link
+    CrcFailure,
+    /// Receiver discarded bytes to resynchronize on a frame boundary
+    Resync,
+}
+
+/// Snapshot of link health over the rolling window
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkHealth {
+    /// Overall score from 0 (unusable) to 100 (perfect)
+    pub score: u8,
+    /// Fraction of heartbeats that went unanswered
+    pub heartbeat_loss: f32,
+    /// Fraction of sent frames that were rejected or never acknowledged
+    pub frame_loss: f32,
+    /// Fraction of verified frames that arrived corrupted
+    pub corruption: f32,
+    pub receive_errors: usize,
+    pub crc_failures: usize,
+    pub resyncs: usize,
+    /// Average heartbeat round trip time
+    pub round_trip: Option<Duration>,
+}
+
+impl LinkHealth {
+    /// Whether the score is low enough to warn about
+    pub fn is_degraded(&self) -> bool {
+        self.score < DEGRADED_SCORE
+    }
+
+    /// Describe the biggest problem with the link, e.g. "link degraded: 4% frame loss"
+    pub fn warning(&self) -> Option<String> {
+        if !self.is_degraded() {
+            return None;
+        }
+        let cause = if self.corruption > 0.0 {
+            format!("{:.0}% frame corruption", self.corruption * 100.0)
+        } else if self.frame_loss > 0.0 {
+            format!("{:.0}% frame loss", self.frame_loss * 100.0)
+        } else if self.heartbeat_loss > 0.0 {
+            format!("{:.0}% heartbeat loss", self.heartbeat_loss * 100.0)
+        } else if self.crc_failures > 0 {
+            format!("{} CRC failures", self.crc_failures)
+        } else if self.resyncs > 0 || self.receive_errors > 0 {
+            format!("{} receive errors, {} resyncs", self.receive_errors, self.resyncs)
+        } else {
+            format!("{:?} heartbeat round trip", self.round_trip.unwrap_or_default())
+        };
+        Some(format!("link degraded: {}", cause))
+    }
+}
+
+impl fmt::Display for LinkHealth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "link health {}/100 (heartbeat loss {:.1}%, frame loss {:.1}%, corruption {:.1}%, {} receive errors, {} CRC failures, {} resyncs",
+            self.score,
+            self.heartbeat_loss * 100.0,
+            self.frame_loss * 100.0,
+            self.corruption * 100.0,
+            self.receive_errors,
+            self.crc_failures,
+            self.resyncs
+        )?;
+        match self.round_trip {
+            Some(round_trip) => write!(f, ", round trip {:?})", round_trip),
+            None => write!(f, ")"),
+        }
+    }
+}
+
+/// Maintains a rolling health score for the serial link from observed events
+pub struct LinkMonitor {
+    events: VecDeque<(Instant, LinkEvent)>,
+    round_trips: VecDeque<(Instant, Duration)>,
+    heartbeat_sent_at: Option<Instant>,
+}
+
+impl LinkMonitor {
+    /// Create a new LinkMonitor with no history
+    pub fn new() -> Self {
+        Self {
+            events: VecDeque::new(),
+            round_trips: VecDeque::new(),
+            heartbeat_sent_at: None,
+        }
+    }
+
+    /// Record an event on the link
+    pub fn record(&mut self, event: LinkEvent) {
+        let now = Instant::now();
+        match event {
+            LinkEvent::HeartbeatSent => self.heartbeat_sent_at = Some(now),
+            LinkEvent::HeartbeatReceived => {
+                if let Some(sent_at) = self.heartbeat_sent_at.take() {
+                    self.round_trips.push_back((now, now.duration_since(sent_at)));
+                }
+            }
+            _ => {}
+        }
+        self.events.push_back((now, event));
+        self.expire(now);
+    }
+
+    /// Record several events of the same kind at once
+    pub fn record_many(&mut self, event: LinkEvent, count: usize) {
+        for _ in 0..count {
+            self.record(event);
+        }
+    }
+
+    /// Compute the health of the link over the rolling window
+    pub fn health(&mut self) -> LinkHealth {
+        self.expire(Instant::now());
+
+        let count = |kind: LinkEvent| self.events.iter().filter(|(_, event)| *event == kind).count();
+        // The latest heartbeat may still be in flight, so it doesn't count as lost yet
+        let heartbeats_sent = count(LinkEvent::HeartbeatSent).saturating_sub(self.heartbeat_sent_at.is_some() as usize);
+        let heartbeats_received = count(LinkEvent::HeartbeatReceived);
+        let frames_acked = count(LinkEvent::FrameAcked);
+        let frames_dropped = count(LinkEvent::FrameNacked) + count(LinkEvent::FrameLost);
+        let frames_verified = count(LinkEvent::FrameVerified);
+        let frames_corrupted = count(LinkEvent::FrameCorrupted);
+        let receive_errors = count(LinkEvent::ReceiveError);
+        let crc_failures = count(LinkEvent::CrcFailure);
+        let resyncs = count(LinkEvent::Resync);
+
+        let heartbeat_loss = ratio(heartbeats_sent.saturating_sub(heartbeats_received), heartbeats_sent);
+        let frame_loss = ratio(frames_dropped, frames_acked + frames_dropped);
+        let corruption = ratio(frames_corrupted, frames_verified + frames_corrupted);
+        let round_trip = (!self.round_trips.is_empty()).then(|| {
+            self.round_trips.iter().map(|(_, round_trip)| *round_trip).sum::<Duration>()
+                / self.round_trips.len() as u32
+        });
+
+        // Each problem costs up to a fixed share of the score
+        let round_trip_ms = round_trip.unwrap_or_default().as_millis() as f32;
+        let penalty = (heartbeat_loss * 50.0)
+            + (frame_loss * 200.0).min(50.0)
+            + (corruption * 200.0).min(50.0)
+            + (receive_errors as f32 * 5.0).min(20.0)
+            + (crc_failures as f32 * 5.0).min(20.0)
+            + (resyncs as f32 * 2.0).min(20.0)
+            + ((round_trip_ms - 100.0) / 10.0).clamp(0.0, 20.0);
+
+        LinkHealth {
+            score: (100.0 - penalty).clamp(0.0, 100.0) as u8,
+            heartbeat_loss,
+            frame_loss,
+            corruption,
+            receive_errors,
+            crc_failures,
+            resyncs,
+            round_trip,
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((at, _)) = self.events.front() {
+            if now.duration_since(*at) <= WINDOW {
+                break;
+            }
+            self.events.pop_front();
+        }
+        while let Some((at, _)) = self.round_trips.front() {
+            if now.duration_since(*at) <= WINDOW {
+                break;
+            }
+            self.round_trips.pop_front();
+        }
+    }
+}
+
+impl From<FrameOutcome> for LinkEvent {
+    fn from(outcome: FrameOutcome) -> Self {
+        match outcome {
+            FrameOutcome::Acked => LinkEvent::FrameAcked,
+            FrameOutcome::Nacked => LinkEvent::FrameNacked,
+            FrameOutcome::Lost => LinkEvent::FrameLost,
+        }
+    }
+}
+
+fn ratio(part: usize, total: usize) -> f32 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f32 / total as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acked_frames_are_perfect() {
+        let mut monitor = LinkMonitor::new();
+        monitor.record_many(LinkEvent::FrameAcked, 100);
+        let health = monitor.health();
+        assert_eq!(health.score, 100);
+        assert_eq!(health.warning(), None);
+    }
+
+    #[test]
+    fn nacked_and_lost_frames_cost_the_score() {
+        let mut monitor = LinkMonitor::new();
+        monitor.record_many(LinkEvent::FrameAcked, 95);
+        monitor.record_many(LinkEvent::FrameNacked, 3);
+        monitor.record_many(LinkEvent::FrameLost, 2);
+        let health = monitor.health();
+        assert_eq!(health.frame_loss, 0.05);
+        assert_eq!(health.score, 90);
+        assert!(!health.is_degraded());
+
+        monitor.record_many(LinkEvent::FrameLost, 10);
+        let health = monitor.health();
+        assert_eq!(health.score, 72);
+        assert_eq!(health.warning().as_deref(), Some("link degraded: 14% frame loss"));
+    }
+
+    #[test]
+    fn unanswered_heartbeats_count_once_the_next_is_sent() {
+        let mut monitor = LinkMonitor::new();
+        monitor.record(LinkEvent::HeartbeatSent);
+        monitor.record(LinkEvent::HeartbeatReceived);
+        monitor.record(LinkEvent::HeartbeatSent);
+        assert_eq!(monitor.health().heartbeat_loss, 0.0);
+
+        // Two went unanswered, the last is still in flight
+        monitor.record_many(LinkEvent::HeartbeatSent, 2);
+        let health = monitor.health();
+        assert_eq!(health.score, 66);
+        assert_eq!(health.warning().as_deref(), Some("link degraded: 67% heartbeat loss"));
+    }
+
+    #[test]
+    fn degrades_below_the_threshold() {
+        let mut monitor = LinkMonitor::new();
+        monitor.record_many(LinkEvent::CrcFailure, 4);
+        let health = monitor.health();
+        assert_eq!(health.score, DEGRADED_SCORE);
+        assert!(!health.is_degraded());
+
+        monitor.record(LinkEvent::Resync);
+        let health = monitor.health();
+        assert_eq!(health.score, DEGRADED_SCORE - 2);
+        assert_eq!(health.warning().as_deref(), Some("link degraded: 4 CRC failures"));
+    }
+}
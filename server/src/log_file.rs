@@ -0,0 +1,201 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// When and how the log file is rotated
+#[derive(Debug, Clone, Copy)]
+pub struct RotationPolicy {
+    /// Rotate once the current file reaches this many bytes
+    pub max_size: u64,
+    /// Rotate once the current file has been open this long
+    pub max_age: Duration,
+    /// Number of rotated files to keep, older ones are deleted
+    pub retention: usize,
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        Self {
+            max_size: 10 * 1024 * 1024,
+            max_age: Duration::from_secs(24 * 60 * 60),
+            retention: 7,
+        }
+    }
+}
+
+/// Combined log file for server events and forwarded firmware logs
+///
+/// Each line is tagged with its source. Rotated files are renamed `<name>.1`, `<name>.2`, ...
+/// with `.1` being the most recent.
+pub struct RotatingLog {
+    path: PathBuf,
+    policy: RotationPolicy,
+    file: File,
+    size: u64,
+    opened: SystemTime,
+}
+
+impl RotatingLog {
+    /// Open or create the log file at path, appending to existing content
+    pub fn open(path: impl Into<PathBuf>, policy: RotationPolicy) -> io::Result<Self> {
+        let path = path.into();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        Ok(Self {
+            opened: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+            size: metadata.len(),
+            path,
+            policy,
+            file,
+        })
+    }
+
+    /// Append a line tagged with its source, rotating first if the policy requires it
+    pub fn write_line(&mut self, source: &str, line: &str) -> io::Result<()> {
+        let age = SystemTime::now().duration_since(self.opened).unwrap_or_default();
+        if self.size >= self.policy.max_size || age >= self.policy.max_age {
+            self.rotate()?;
+        }
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let entry = format!(
+            "{}.{:03} [{}] {}\n",
+            timestamp.as_secs(),
+            timestamp.subsec_millis(),
+            source,
+            line
+        );
+        self.file.write_all(entry.as_bytes())?;
+        self.size += entry.len() as u64;
+        Ok(())
+    }
+
+    /// Rotate the current file out and start a new one
+    pub fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        // Shift older files up by one, dropping the oldest beyond retention
+        if self.policy.retention > 0 {
+            let oldest = rotated_path(&self.path, self.policy.retention);
+            if oldest.exists() {
+                fs::remove_file(&oldest)?;
+            }
+            for index in (1..self.policy.retention).rev() {
+                let from = rotated_path(&self.path, index);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, index + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.opened = SystemTime::now();
+        Ok(())
+    }
+}
+
+/// Path of the rotated file with the given index
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+/// Print the last lines of a log file, then keep printing new lines as they are written if follow is set
+pub fn tail(path: &Path, lines: usize, follow: bool) -> io::Result<()> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut last = std::collections::VecDeque::with_capacity(lines);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 {
+        if last.len() == lines {
+            last.pop_front();
+        }
+        if lines > 0 {
+            last.push_back(std::mem::take(&mut line));
+        }
+        line.clear();
+    }
+    last.iter().for_each(|line| print!("{}", line));
+
+    if !follow {
+        return Ok(());
+    }
+
+    let mut position = reader.stream_position()?;
+    loop {
+        // Start over from the beginning if the file was rotated out from under us
+        if fs::metadata(path)?.len() < position {
+            reader = BufReader::new(File::open(path)?);
+            position = 0;
+        }
+        reader.seek(SeekFrom::Start(position))?;
+        while reader.read_line(&mut line)? > 0 {
+            print!("{}", line);
+            line.clear();
+        }
+        position = reader.stream_position()?;
+        std::thread::sleep(Duration::from_millis(250));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty directory of its own for a test's log files
+    fn log_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tree-log-{}-{}", std::process::id(), name));
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    #[test]
+    fn rotates_at_the_size_limit_and_prunes_old_files() {
+        let path = log_dir("size").join("tree.log");
+        let policy = RotationPolicy {
+            max_size: 20,
+            max_age: Duration::MAX,
+            retention: 2,
+        };
+        let mut log = RotatingLog::open(&path, policy).unwrap();
+        // Each entry is over the limit on its own, so every write after the first rotates
+        for line in ["first", "second", "third", "fourth"] {
+            log.write_line("test", line).unwrap();
+        }
+
+        let read = |path: &Path| fs::read_to_string(path).unwrap();
+        assert!(read(&path).ends_with("[test] fourth\n"));
+        assert!(read(&rotated_path(&path, 1)).ends_with("[test] third\n"));
+        assert!(read(&rotated_path(&path, 2)).ends_with("[test] second\n"));
+        assert!(!rotated_path(&path, 3).exists());
+    }
+
+    #[test]
+    fn rotates_at_the_age_limit() {
+        let path = log_dir("age").join("tree.log");
+        let policy = RotationPolicy {
+            max_size: u64::MAX,
+            max_age: Duration::ZERO,
+            retention: 1,
+        };
+        let mut log = RotatingLog::open(&path, policy).unwrap();
+        log.write_line("test", "first").unwrap();
+        log.write_line("test", "second").unwrap();
+
+        let read = |path: &Path| fs::read_to_string(path).unwrap();
+        assert!(read(&path).ends_with("[test] second\n"));
+        assert!(read(&rotated_path(&path, 1)).ends_with("[test] first\n"));
+        assert!(!rotated_path(&path, 2).exists());
+    }
+}
@@ -0,0 +1,64 @@
+use common::message::LogPayload;
+use std::time::{Duration, Instant};
+
+/// How long firmware logs are held back so bunched messages can be put in order
+const REORDER_WINDOW: Duration = Duration::from_millis(50);
+
+/// Buffers firmware log messages briefly and releases them ordered by firmware timestamp
+///
+/// Messages can arrive bunched and out of order when the firmware's TX channel backs up,
+/// which makes interleaved firmware/host output hard to follow.
+pub struct LogReorderBuffer {
+    pending: Vec<(Instant, LogPayload)>,
+}
+
+impl LogReorderBuffer {
+    /// Create a new empty LogReorderBuffer
+    pub fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+        }
+    }
+
+    /// Add a received log message
+    pub fn push(&mut self, payload: LogPayload) {
+        let position = self
+            .pending
+            .partition_point(|(_, pending)| pending.timestamp_ms <= payload.timestamp_ms);
+        self.pending.insert(position, (Instant::now(), payload));
+    }
+
+    /// Take the messages that have waited out the reorder window, in timestamp order
+    pub fn drain_ready(&mut self) -> Vec<LogPayload> {
+        let now = Instant::now();
+        let ready = self
+            .pending
+            .iter()
+            .take_while(|(received, _)| now.duration_since(*received) >= REORDER_WINDOW)
+            .count();
+        self.pending.drain(..ready).map(|(_, payload)| payload).collect()
+    }
+}
+
+/// Format a firmware log message for display, e.g. `[   12.345s INFO  firmware::messages] content`
+/// Messages with a source location are followed by ` (at src/main.rs:42)`
+pub fn render(payload: &LogPayload) -> String {
+    let seconds = payload.timestamp_ms / 1000;
+    let millis = payload.timestamp_ms % 1000;
+    let mut rendered = if payload.target.is_empty() {
+        format!("[{:>6}.{:03}s {:<5}] {}", seconds, millis, payload.level(), payload.content)
+    } else {
+        format!(
+            "[{:>6}.{:03}s {:<5} {}] {}",
+            seconds,
+            millis,
+            payload.level(),
+            payload.target,
+            payload.content
+        )
+    };
+    if let Some(location) = &payload.location {
+        rendered.push_str(&format!(" (at {}:{})", location.file, location.line));
+    }
+    rendered
+}
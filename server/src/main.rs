@@ -1,18 +1,304 @@
-mod messages;
-
-use common::message::{Message, Rgb, SetLedsPayload};
-use messages::MessageHandler;
-use std::time::Duration;
-
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Connecting to serial port /dev/ttyS3 at 115200 baud...");
-    
-    // Create message handler connected to /dev/ttyS3 at 115200 baud
-    let message_handler = MessageHandler::new("/dev/ttyACM0", 115200)?;
-    
+mod accessibility;
+#[cfg_attr(not(feature = "audio"), allow(dead_code, unused_imports))]
+mod audio;
+mod bench;
+mod bridge;
+mod bus;
+#[cfg_attr(not(feature = "camera"), allow(dead_code, unused_imports))]
+mod calibration;
+mod cli;
+mod commands;
+mod config;
+mod correction;
+mod effects;
+mod flow;
+mod frame;
+mod fseq;
+mod hdr;
+mod http;
+mod link;
+mod log_file;
+mod logs;
+mod mapping;
+mod media;
+mod metrics;
+mod notify;
+mod pipeline;
+mod presets;
+mod recording;
+mod scheduler;
+#[cfg(feature = "script")]
+mod script;
+#[cfg_attr(not(feature = "simulator"), allow(dead_code, unused_imports))]
+mod simulator;
+mod sinks;
+mod smoothing;
+#[cfg(feature = "tui")]
+mod tui;
+mod verify;
+#[cfg(feature = "weather")]
+mod weather;
+
+use christmas_tree_client::{connect, MessageError, MessageHandler, DEFAULT_BAUD_RATE};
+use clap::Parser;
+use cli::{Cli, Command, LogsCommand, PresetCommand};
+use commands::describe_panic;
+use config::{Config, ConfigWatcher};
+use common::message::{
+    frame_checksum, GetLedsPayload, Message, NackReason, VerboseDiagnosticsPayload, VerificationPayload,
+};
+use effects::{EffectRegistry, Params};
+use flow::{DEFAULT_ACK_TIMEOUT, DEFAULT_WINDOW_SIZE, SendWindow};
+use frame::Frame;
+use link::{LinkEvent, LinkMonitor};
+use log_file::{RotatingLog, RotationPolicy};
+use logs::LogReorderBuffer;
+#[cfg(feature = "camera")]
+use mapping::DEFAULT_MAP_FILE;
+use mapping::TreeMap;
+use metrics::Metrics;
+use pipeline::{OutputPipeline, load_correction};
+use presets::PresetStore;
+use scheduler::Scheduler;
+use sinks::Sinks;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use verify::{FrameVerifier, first_mismatch};
+
+/// Combined log file for server events and firmware logs, rotated according to RotationPolicy::default
+const LOG_FILE: &str = "logs/tree.log";
+
+/// Number of LEDs assumed if the firmware doesn't report its DeviceInfo
+const DEFAULT_NUM_LEDS: usize = 513;
+
+/// How long to wait for the firmware to answer the version handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Size of the firmware image chunks sent by `tree update`
+const UPDATE_CHUNK_SIZE: usize = 1024;
+
+/// How long to wait for the firmware to confirm each step of a firmware update,
+/// erasing flash for a chunk can take a while
+const UPDATE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+
+    // `tree effects [tag]`, `tree logs tail`, `tree preset list`, `tree preset delete` and `tree simulate` don't
+    // need the tree
+    match &cli.command {
+        Some(Command::Effects { tag }) => {
+            let registry = EffectRegistry::with_builtin();
+            match tag {
+                Some(tag) => registry.with_tag(tag).for_each(|effect| print!("{}", effect)),
+                None => registry.list().for_each(|effect| print!("{}", effect)),
+            }
+            return Ok(());
+        }
+        Some(Command::Logs {
+            command: Some(LogsCommand::Tail { lines, follow }),
+        }) => {
+            log_file::tail(Path::new(LOG_FILE), *lines, *follow)?;
+            return Ok(());
+        }
+        Some(Command::Preset {
+            command: PresetCommand::List,
+        }) => {
+            let store = PresetStore::new(Config::load_or_default(&cli.config)?.presets_file);
+            for (name, preset) in store.list()? {
+                println!("{}: {}", name, preset);
+            }
+            return Ok(());
+        }
+        Some(Command::Preset {
+            command: PresetCommand::Delete { name },
+        }) => {
+            PresetStore::new(Config::load_or_default(&cli.config)?.presets_file).delete(name)?;
+            println!("Deleted preset {}", name);
+            return Ok(());
+        }
+        #[cfg(feature = "simulator")]
+        Some(Command::Simulate { leds }) => {
+            return simulator::run(*leds, &Config::load_or_default(&cli.config)?);
+        }
+        #[cfg(not(feature = "simulator"))]
+        Some(Command::Simulate { .. }) => {
+            return Err("tree simulate needs the server built with the simulator feature".into());
+        }
+        #[cfg(not(feature = "audio"))]
+        Some(Command::Audio { .. }) => return Err("tree audio needs the server built with the audio feature".into()),
+        #[cfg(not(feature = "script"))]
+        Some(Command::Script { .. }) => return Err("tree script needs the server built with the script feature".into()),
+        #[cfg(not(feature = "weather"))]
+        Some(Command::Weather) => return Err("tree weather needs the server built with the weather feature".into()),
+        #[cfg(not(feature = "tui"))]
+        Some(Command::Tui) => return Err("tree tui needs the server built with the tui feature".into()),
+        #[cfg(not(feature = "camera"))]
+        Some(Command::Calibrate { .. }) => {
+            return Err("tree calibrate needs the server built with the camera feature".into());
+        }
+        _ => {}
+    }
+
+    // Check the effect and its parameters before waiting on the tree
+    let effect = match &cli.command {
+        Some(Command::Run {
+            effect: Some(id),
+            params,
+        }) => Some((id.clone(), EffectRegistry::with_builtin().params(id, params)?)),
+        _ => None,
+    };
+
+    let mut log_file = RotatingLog::open(LOG_FILE, RotationPolicy::default())?;
+
+    // Watch the config file from before it's read, so a change while connecting isn't missed
+    let watcher = ConfigWatcher::new(&cli.config);
+    let mut config = Config::load_or_default(&cli.config)?;
+    config.record_file = cli.record;
+    let port = cli.port.unwrap_or_else(|| config.port.clone());
+
+    println!("Connecting to serial port {} at {} baud...", port, DEFAULT_BAUD_RATE);
+
+    // Make sure the firmware speaks our protocol before sending anything else, waking it if it's asleep
+    let connection = connect(&port, config.baud_rate, config.hardware_flow_control, HANDSHAKE_TIMEOUT)?;
+    let message_handler = connection.handler;
+    match connection.hello {
+        Some(hello) => {
+            println!("Firmware version {} (protocol {})", hello.firmware_version, hello.protocol_version);
+            println!("Link running at {} baud", connection.baud_rate);
+        }
+        None => eprintln!("Warning: firmware did not answer the version handshake"),
+    }
+
+    match cli.command {
+        None | Some(Command::Run { .. }) => {
+            run(&message_handler, &mut log_file, config, watcher, &port, connection.baud_rate, effect)
+        }
+        Some(Command::Logs { command: None }) => follow_logs(&message_handler, &mut log_file),
+        Some(Command::Bridge { protocol }) => bridge::run(protocol, &message_handler, &mut log_file, &config),
+        #[cfg(feature = "audio")]
+        Some(Command::Audio { effect, device }) => {
+            audio::run(effect, device.as_deref(), &message_handler, &mut log_file, &config)
+        }
+        #[cfg(feature = "script")]
+        Some(Command::Script { name }) => script::run(&name, &message_handler, &mut log_file, &config),
+        Some(Command::Play { file, repeat, speed }) => {
+            recording::play(&file, repeat, speed, &message_handler, &mut log_file, &config)
+        }
+        Some(Command::Media {
+            file,
+            projection,
+            repeat,
+        }) => media::play(&file, projection, repeat, &message_handler, &mut log_file, &config),
+        #[cfg(feature = "weather")]
+        Some(Command::Weather) => weather::run(&message_handler, &mut log_file, &config),
+        #[cfg(feature = "tui")]
+        Some(Command::Tui) => tui::run(&message_handler, &mut log_file, &config),
+        Some(Command::Bench {
+            pings,
+            step_secs,
+            max_fps,
+        }) => bench::run(pings, step_secs, max_fps, connection.baud_rate, &message_handler, &mut log_file, &config),
+        #[cfg(feature = "camera")]
+        Some(Command::Calibrate { camera, output, flat }) => {
+            let output = output.or_else(|| config.map_file.clone()).unwrap_or_else(|| DEFAULT_MAP_FILE.into());
+            calibration::run(camera, &output, flat, &message_handler, &mut log_file, &config)
+        }
+        Some(Command::Serve { listen }) => http::serve(listen, message_handler, log_file, &config),
+        Some(command) => commands::execute(command, &message_handler, &mut log_file, &config),
+    }
+}
+
+/// Stream an effect's frames to the tree, displaying its messages and the link's health
+fn run(
+    message_handler: &MessageHandler,
+    log_file: &mut RotatingLog,
+    mut config: Config,
+    mut watcher: ConfigWatcher,
+    port: &str,
+    baud_rate: u32,
+    effect: Option<(String, Params)>,
+) -> Result<(), Box<dyn Error>> {
+    // Size frames to the strip the firmware is driving, unless the config file sets the LED count
+    let num_leds = match message_handler.device_info(HANDSHAKE_TIMEOUT) {
+        Ok(info) => {
+            println!(
+                "Device: {} with {} LEDs on strips of {:?}, firmware {}, max frame size {} bytes",
+                info.chip, info.num_leds, info.strips, info.fw_version, info.max_frame_size
+            );
+            config.num_leds.unwrap_or(info.num_leds as usize)
+        }
+        Err(e) => {
+            let num_leds = config.num_leds.unwrap_or(DEFAULT_NUM_LEDS);
+            eprintln!("Warning: failed to get device info ({}), assuming {} LEDs", e, num_leds);
+            num_leds
+        }
+    };
+
+    // Place the LEDs for effects, assuming they're wound in a spiral when the tree hasn't been mapped
+    let map = match &config.map_file {
+        Some(path) => TreeMap::load(path)?,
+        None => TreeMap::spiral(num_leds),
+    };
+    if map.len() != num_leds {
+        eprintln!("Warning: the tree map has {} LEDs but the tree has {}", map.len(), num_leds);
+    }
+    let mut effect = match effect {
+        Some((id, params)) => Some(EffectRegistry::with_builtin().create(&id, &params, &map)?),
+        None => None,
+    };
+
+    // Report a crash or watchdog reset that happened while nobody was watching
+    match message_handler.reset_report(HANDSHAKE_TIMEOUT) {
+        Ok(report) => {
+            for event in commands::reset_events(&report) {
+                eprintln!("{}", event);
+                log_file.write_line("firmware", &event)?;
+            }
+        }
+        Err(e) => eprintln!("Warning: failed to get reset report ({})", e),
+    }
+
+    // Read the firmware's settings, which the brightness schedule updates
+    let mut firmware_config = match message_handler.config(HANDSHAKE_TIMEOUT) {
+        Ok(firmware_config) => Some(firmware_config),
+        Err(e) => {
+            eprintln!("Warning: failed to get the firmware's settings ({}), ignoring the brightness schedule", e);
+            None
+        }
+    };
+    let mut scheduled_brightness = None;
+    let mut scheduler = Scheduler::new();
+
+    // Read the firmware's clock so frames can be paced on it
+    message_handler.request_time()?;
+
     println!("Connected! Starting main loop...");
+    log_file.write_line("server", &format!("Connected to {} at {} baud", port, baud_rate))?;
+
+    let mut verifier = FrameVerifier::new();
+    let mut firmware_logs = LogReorderBuffer::new();
+    let mut link = LinkMonitor::new();
+    let mut window = SendWindow::new(DEFAULT_WINDOW_SIZE, DEFAULT_ACK_TIMEOUT);
+    let metrics = Arc::new(Metrics::default());
+    if let Some(addr) = config.metrics_listen {
+        metrics::serve(addr, metrics.clone())?;
+    }
 
-    let mut red: u8 = 100;
+    // Read back whatever the firmware is currently displaying
+    message_handler.send(&Message::GetLeds(GetLedsPayload {
+        offset: 0,
+        count: u16::MAX,
+    }))?;
+
+    let mut frame: Frame = Frame::new(num_leds);
+    let mut pipeline = OutputPipeline::new(frame.len());
+    let mut correction_modified = config.correction_file.as_deref().and_then(config::modified);
+    let mut sinks = Sinks::open(&config.output_sinks());
+    apply_config(message_handler, &mut pipeline, &config, None)?;
+    let mut last_frame = Instant::now();
     let mut since_message: u8 = 0;
 
     // Main loop: continuously send and receive messages
@@ -24,10 +310,151 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 match message {
                     Message::Heartbeat => {
                         println!("Received heartbeat");
+                        link.record(LinkEvent::HeartbeatReceived);
+                        metrics.heartbeat_received();
+                    }
+                    Message::Status(status) => {
+                        println!(
+                            "Firmware up {} s, {} bytes free heap, {}/{} messages queued, {} frames rendered, {} LED write errors, {} logs dropped{}",
+                            status.uptime_ms / 1000,
+                            status.free_heap,
+                            status.rx_queued,
+                            status.rx_capacity,
+                            status.frames_rendered,
+                            status.led_write_errors,
+                            status.dropped_logs,
+                            status
+                                .ambient_light
+                                .map(|light| format!(", ambient light {}", light))
+                                .unwrap_or_default()
+                        );
+                        // A receive channel close to full means the firmware can't keep up with the frame rate
+                        if status.rx_queued as usize * 4 >= status.rx_capacity as usize * 3 {
+                            let warning = format!(
+                                "Firmware receive channel is {}/{} full",
+                                status.rx_queued, status.rx_capacity
+                            );
+                            eprintln!("{}", warning);
+                            log_file.write_line("server", &warning)?;
+                        }
+                    }
+                    Message::Telemetry(telemetry) => {
+                        metrics.telemetry(&telemetry);
+                        let line = format!(
+                            "Firmware heap {} bytes free (lowest {}, largest block {}), stack {}/{} bytes used at most",
+                            telemetry.free_heap,
+                            telemetry.min_free_heap,
+                            telemetry.largest_free_block,
+                            telemetry.stack_high_water,
+                            telemetry.stack_size
+                        );
+                        println!("{}", line);
+                        log_file.write_line("firmware", &line)?;
+                        // A largest block far below the free heap means it has fragmented
+                        if telemetry.largest_free_block * 2 < telemetry.free_heap {
+                            let warning = format!(
+                                "Firmware heap is fragmented, largest block {} of {} bytes free",
+                                telemetry.largest_free_block, telemetry.free_heap
+                            );
+                            eprintln!("{}", warning);
+                            log_file.write_line("server", &warning)?;
+                        }
+                    }
+                    Message::Panic(panic) => {
+                        let event = describe_panic(&panic);
+                        eprintln!("{}", event);
+                        log_file.write_line("firmware", &event)?;
                     }
                     Message::Log(payload) => {
-                        // Display log messages from the firmware
-                        println!("[{}] {}", payload.level(), payload.content);
+                        // Firmware logs are displayed once they can be put in order
+                        firmware_logs.push(payload);
+                    }
+                    Message::Leds(payload) => {
+                        println!(
+                            "Firmware is displaying {} LEDs from offset {} (checksum {:#010x})",
+                            payload.leds.len(),
+                            payload.offset,
+                            frame_checksum(&payload.leds)
+                        );
+                        // Frames still in flight would change what the firmware shows before this is compared
+                        if window.in_flight() == 0
+                            && let Some(index) =
+                                first_mismatch(pipeline.output(&frame), payload.offset as usize, &payload.leds)
+                        {
+                            let event = format!(
+                                "Firmware is displaying something other than the last sent frame from LED {}, \
+                                 resending it whole",
+                                index
+                            );
+                            eprintln!("{}", event);
+                            log_file.write_line("server", &event)?;
+                            pipeline.invalidate(&mut frame);
+                        }
+                    }
+                    Message::Ack(ack) => {
+                        for outcome in window.ack(ack.seq) {
+                            link.record(outcome.into());
+                        }
+                    }
+                    // Skipped in favor of a newer frame, which settles it once displayed
+                    Message::Nack(nack) if nack.reason == NackReason::Superseded => {
+                        if let Some(seq) = nack.seq {
+                            window.nack(seq);
+                        }
+                    }
+                    Message::Nack(nack) => {
+                        eprintln!("Firmware rejected frame {:?}: {:?}", nack.seq, nack.reason);
+                        if nack.reason == NackReason::CrcMismatch {
+                            link.record(LinkEvent::CrcFailure);
+                        }
+                        if let Some(outcome) = nack.seq.and_then(|seq| window.nack(seq)) {
+                            link.record(outcome.into());
+                            // Later updates were computed against the rejected one, so resend the whole frame
+                            pipeline.invalidate(&mut frame);
+                        }
+                    }
+                    Message::ButtonEvent(event) => {
+                        println!("Button {} {:?} press", event.button, event.press);
+                        log_file.write_line("server", &format!("Button {} {:?} press", event.button, event.press))?;
+                    }
+                    Message::ScheduleEvent(action) => {
+                        println!("Schedule turned the tree {:?}", action);
+                        log_file.write_line("server", &format!("Schedule turned the tree {:?}", action))?;
+                    }
+                    Message::Busy => {
+                        eprintln!("Firmware is busy, pausing frames");
+                        window.pause();
+                    }
+                    Message::Ready => {
+                        println!("Firmware is ready, resuming frames");
+                        window.resume();
+                    }
+                    Message::FrameChecksum(report) => match verifier.verify(&report) {
+                        Some(true) => link.record(LinkEvent::FrameVerified),
+                        Some(false) => {
+                            link.record(LinkEvent::FrameCorrupted);
+                            let event = format!(
+                                "Frame {} checksum {:#010x} does not match the sent frame, written to the strips as \
+                                 {:08x?} ({} matched, {} mismatched)",
+                                report.seq,
+                                report.checksum,
+                                report.output_checksums,
+                                verifier.matched(),
+                                verifier.mismatched()
+                            );
+                            eprintln!("{}", event);
+                            log_file.write_line("server", &event)?;
+                            // The tree is showing something that was never sent, so resend the whole frame
+                            pipeline.invalidate(&mut frame);
+                        }
+                        // Sent too long ago to compare
+                        None => {}
+                    },
+                    Message::Time(_) => {
+                        // Consumed by the message handler's frame pacer
+                    }
+                    Message::Config(stored) => {
+                        firmware_config = Some(stored);
                     }
                     msg => {
                         println!("Received unexpected message: {:?}", msg);
@@ -38,10 +465,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 // No message available, continue
             }
             Err(e) => {
+                link.record(LinkEvent::ReceiveError);
                 eprintln!("Error receiving message: {}", e);
+                log_file.write_line("server", &format!("Error receiving message: {}", e))?;
             }
         }
 
+        // Display log messages from the firmware
+        for payload in firmware_logs.drain_ready() {
+            let line = logs::render(&payload);
+            println!("{}", line);
+            log_file.write_line("firmware", &line)?;
+        }
+
         // Small delay to avoid busy waiting
         std::thread::sleep(Duration::from_millis(10));
         since_message += 1;
@@ -50,15 +486,163 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             let message = Message::Heartbeat;
             println!("Sending heartbeat");
             message_handler.send(&message)?;
+            message_handler.request_time()?;
+
+            // Check the link once per heartbeat, warning if it has degraded
+            link.record_many(LinkEvent::Resync, message_handler.take_resyncs());
+            link.record_many(LinkEvent::CrcFailure, message_handler.take_crc_failures());
+            let health = link.health();
+            if let Some(warning) = health.warning() {
+                eprintln!("{} ({})", warning, health);
+                log_file.write_line("server", &format!("{} ({})", warning, health))?;
+            }
+            link.record(LinkEvent::HeartbeatSent);
+            metrics.heartbeat_sent();
+            metrics.set_frames_dropped(window.nacked() + window.lost());
+
+            // Re-apply the config file once it changes
+            match watcher.poll() {
+                Some(Ok(mut reloaded)) => {
+                    // The recording from --record carries on
+                    reloaded.record_file = config.record_file.clone();
+                    if reloaded.needs_restart(&config) {
+                        eprintln!(
+                            "The port, baud rate, flow control, LED count and metrics address apply from the next start"
+                        );
+                    }
+                    if reloaded.sinks != config.sinks {
+                        sinks = Sinks::open(&reloaded.output_sinks());
+                    }
+                    apply_config(message_handler, &mut pipeline, &reloaded, Some(&config))?;
+                    println!("Reloaded the config file");
+                    log_file.write_line("server", "Reloaded the config file")?;
+                    config = reloaded;
+                    scheduled_brightness = None;
+                }
+                Some(Err(e)) => {
+                    eprintln!("{}, keeping the current settings", e);
+                    log_file.write_line("server", &format!("{}, keeping the current settings", e))?;
+                }
+                None => {}
+            }
+
+            // Re-apply the color correction file once it changes, so it can be tuned while watching the tree
+            let modified = config.correction_file.as_deref().and_then(config::modified);
+            if modified != correction_modified {
+                correction_modified = modified;
+                pipeline.set_correction(load_correction(&config));
+            }
+
+            // Follow the brightness schedule, storing each change in the firmware's settings
+            if let Some(percent) = config.brightness_now()
+                && scheduled_brightness != Some(percent)
+                && let Some(firmware_config) = &mut firmware_config
+            {
+                firmware_config.brightness_cap = commands::brightness_cap(percent);
+                message_handler.send(&Message::SetConfig(firmware_config.clone()))?;
+                scheduled_brightness = Some(percent);
+                println!("Schedule set the brightness to {}%", percent);
+                log_file.write_line("server", &format!("Schedule set the brightness to {}%", percent))?;
+            }
+
+            // Fire the rules whose time has come, a failed rule leaving the others to run
+            let presets = PresetStore::new(&config.presets_file);
+            let now = config.local_minute();
+            for rule in scheduler.due(&config.rules, now, config.location(), config.utc_offset_min) {
+                match scheduler::execute(&rule.action, message_handler, &presets) {
+                    Ok(line) => {
+                        println!("{}", line);
+                        log_file.write_line("server", &line)?;
+                    }
+                    Err(e) => {
+                        eprintln!("Rule at {} failed: {}", rule.at, e);
+                        log_file.write_line("server", &format!("Rule at {} failed: {}", rule.at, e))?;
+                    }
+                }
+            }
+        }
+
+        // Render the next frame once the firmware can take it, skipped if nothing changed
+        if let Some(effect) = &mut effect
+            && !window.is_paused()
+            && (!config.flow_control || window.can_send())
+        {
+            let dt = last_frame.elapsed();
+            last_frame = Instant::now();
+            effect.tick(dt, &mut frame);
+            if let Some(update) = pipeline.process(&mut frame, dt) {
+                let seq = window.send();
+                verifier.record_sent(seq, update.checksum());
+                let written = Instant::now();
+                message_handler.send(&update.into_message(seq))?;
+                metrics.frame_sent(written.elapsed());
+                for line in sinks.send(pipeline.output(&frame)) {
+                    eprintln!("{}", line);
+                    log_file.write_line("server", &line)?;
+                }
+            }
+        }
+    }
+}
+
+/// Apply the settings that take effect while running, those that changed from the previous config or all of them
+fn apply_config(
+    message_handler: &MessageHandler,
+    pipeline: &mut OutputPipeline,
+    config: &Config,
+    previous: Option<&Config>,
+) -> Result<(), MessageError> {
+    if config.location().is_none()
+        && config.rules.iter().any(|rule| rule.at.is_solar())
+        && previous.is_none_or(|previous| previous.rules != config.rules)
+    {
+        eprintln!("Warning: rules at sunrise or sunset won't fire without latitude and longitude in the config");
+    }
+    message_handler.set_presentation_delay(config.presentation_delay())?;
+    pipeline.configure(config);
+    if previous.is_none_or(|previous| previous.verify_interval != config.verify_interval) {
+        message_handler.send(&Message::SetVerification(VerificationPayload {
+            interval: config.verify_interval,
+        }))?;
+    }
+    if previous.is_none_or(|previous| previous.verbose_diagnostics != config.verbose_diagnostics) {
+        message_handler.send(&Message::SetVerboseDiagnostics(VerboseDiagnosticsPayload {
+            enabled: config.verbose_diagnostics,
+        }))?;
+    }
+    if previous.is_none_or(|previous| previous.default_effect != config.default_effect)
+        && let Some(default) = &config.default_effect
+    {
+        message_handler.send(&Message::StartEffect(commands::start_effect(default.effect, default.speed)))?;
+    }
+    Ok(())
+}
+
+/// Display the tree's logs as they arrive, sending heartbeats so it keeps the link up
+fn follow_logs(message_handler: &MessageHandler, log_file: &mut RotatingLog) -> Result<(), Box<dyn Error>> {
+    let mut firmware_logs = LogReorderBuffer::new();
+    let mut since_heartbeat: u8 = 0;
+    loop {
+        match message_handler.try_receive()? {
+            Some(Message::Log(payload)) => firmware_logs.push(payload),
+            Some(Message::Panic(panic)) => {
+                let event = describe_panic(&panic);
+                eprintln!("{}", event);
+                log_file.write_line("firmware", &event)?;
+            }
+            _ => {}
+        }
+        for payload in firmware_logs.drain_ready() {
+            let line = logs::render(&payload);
+            println!("{}", line);
+            log_file.write_line("firmware", &line)?;
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
+        since_heartbeat += 1;
+        if since_heartbeat >= 100 {
+            since_heartbeat = 0;
+            message_handler.send(&Message::Heartbeat)?;
         }
-        // if red >= 255 {
-        //     red = 0;
-        // } else {
-        //     red += 1;
-        // }
-        // // Send a SetLeds message with the red value
-        // let pixels: Vec<Rgb> = std::iter::repeat(Rgb::new(red, 0, 0)).take(513).collect();
-        // let message = Message::SetLeds(SetLedsPayload { leds: pixels });
-        // message_handler.send(&message)?;
     }
 }
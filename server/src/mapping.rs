@@ -0,0 +1,190 @@
+use std::f32::consts::TAU;
+use std::fmt;
+use std::path::Path;
+
+/// File `tree calibrate` writes the map to when neither it nor the config names one
+#[cfg(feature = "camera")]
+pub const DEFAULT_MAP_FILE: &str = "tree.csv";
+
+/// Turns of the spiral LEDs are assumed to be wound in without a map
+const SPIRAL_TURNS: f32 = 12.0;
+
+/// Height of the spiral LEDs are assumed to be wound in without a map, in tree radii like GIFT coordinates
+const SPIRAL_HEIGHT: f32 = 3.0;
+
+/// Position of an LED on the tree, in GIFT coordinates
+///
+/// x and y run across the tree from -1 to 1 at its widest, and z up from 0 at the bottom in the same units.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Point3 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Point3 {
+    pub fn new(x: f32, y: f32, z: f32) -> Self {
+        Point3 { x, y, z }
+    }
+}
+
+#[derive(Debug)]
+pub enum MappingError {
+    Io(std::io::Error),
+    /// A line of the file isn't an LED's coordinates, lines counted from 1
+    Parse { line: usize, message: String },
+}
+
+impl fmt::Display for MappingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MappingError::Io(e) => write!(f, "Failed to read the tree map: {}", e),
+            MappingError::Parse { line, message } => write!(f, "Invalid tree map on line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for MappingError {}
+
+/// Where each LED is on the tree, so effects can animate through its volume rather than along the strip
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TreeMap {
+    points: Vec<Point3>,
+}
+
+impl TreeMap {
+    /// Create a map from the position of each LED in strip order
+    pub fn new(points: Vec<Point3>) -> TreeMap {
+        TreeMap { points }
+    }
+
+    /// Read a map in GIFT CSV format, one `x,y,z` line per LED in strip order
+    pub fn load(path: &Path) -> Result<TreeMap, MappingError> {
+        let contents = std::fs::read_to_string(path).map_err(MappingError::Io)?;
+        TreeMap::parse(&contents)
+    }
+
+    /// Parse a map in GIFT CSV format, one `x,y,z` line per LED in strip order
+    ///
+    /// Lines may also give the LED's index first as `index,x,y,z`, and be wrapped in brackets. A header line,
+    /// blank lines and `#` comments are skipped.
+    pub fn parse(csv: &str) -> Result<TreeMap, MappingError> {
+        let mut points = Vec::new();
+        let mut header_skipped = false;
+        for (number, line) in csv.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: String| MappingError::Parse {
+                line: number + 1,
+                message,
+            };
+            let line = line.trim_start_matches('[').trim_end_matches(']');
+            let fields: Result<Vec<f32>, _> = line.split(',').map(|field| field.trim().parse::<f32>()).collect();
+            let fields = match fields {
+                Ok(fields) => fields,
+                // Only the first line can be a header
+                Err(_) if points.is_empty() && !header_skipped => {
+                    header_skipped = true;
+                    continue;
+                }
+                Err(_) => return Err(error(format!("{} is not a list of numbers", line))),
+            };
+            let point = match fields[..] {
+                [x, y, z] => Point3::new(x, y, z),
+                [index, x, y, z] if index == points.len() as f32 => Point3::new(x, y, z),
+                [index, _, _, _] => {
+                    return Err(error(format!("LED {} is out of order, expected {}", index, points.len())));
+                }
+                _ => return Err(error(format!("expected x,y,z but found {} fields", fields.len()))),
+            };
+            points.push(point);
+        }
+        Ok(TreeMap { points })
+    }
+
+    /// Write the map in GIFT CSV format
+    #[cfg(feature = "camera")]
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::write(path, self.to_csv())
+    }
+
+    /// The map in GIFT CSV format, one `x,y,z` line per LED in strip order
+    #[cfg(any(feature = "camera", test))]
+    pub fn to_csv(&self) -> String {
+        self.points.iter().map(|point| format!("{},{},{}\n", point.x, point.y, point.z)).collect()
+    }
+
+    /// Map of LEDs wound evenly in a spiral from the bottom of a cone to its tip, standing in for a real map
+    pub fn spiral(len: usize) -> TreeMap {
+        let last = len.saturating_sub(1).max(1) as f32;
+        let points = (0..len)
+            .map(|index| {
+                let t = index as f32 / last;
+                let (sin, cos) = (t * SPIRAL_TURNS * TAU).sin_cos();
+                Point3::new(cos * (1.0 - t), sin * (1.0 - t), t * SPIRAL_HEIGHT)
+            })
+            .collect();
+        TreeMap { points }
+    }
+
+    /// Position of each LED in strip order
+    pub fn points(&self) -> &[Point3] {
+        &self.points
+    }
+
+    /// Number of LEDs in the map
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    /// Lowest and highest corners of the box holding every LED, None for an empty map
+    pub fn bounds(&self) -> Option<(Point3, Point3)> {
+        let first = *self.points.first()?;
+        Some(self.points.iter().fold((first, first), |(min, max), point| {
+            (
+                Point3::new(min.x.min(point.x), min.y.min(point.y), min.z.min(point.z)),
+                Point3::new(max.x.max(point.x), max.y.max(point.y), max.z.max(point.z)),
+            )
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_gift_csv() {
+        let map = TreeMap::parse("X,Y,Z\n0.5, -0.25, 0\n\n# top\n[0.0,0.0,3.5]\n").unwrap();
+        assert_eq!(map.points(), &[Point3::new(0.5, -0.25, 0.0), Point3::new(0.0, 0.0, 3.5)]);
+        assert_eq!(map.bounds(), Some((Point3::new(0.0, -0.25, 0.0), Point3::new(0.5, 0.0, 3.5))));
+
+        let indexed = TreeMap::parse("0,0.5,-0.25,0\n1,0,0,3.5").unwrap();
+        assert_eq!(indexed, map);
+        assert!(matches!(
+            TreeMap::parse("0,0,0,0\n2,0,0,1"),
+            Err(MappingError::Parse { line: 2, .. })
+        ));
+        assert!(matches!(TreeMap::parse("0,0,0\n0,0"), Err(MappingError::Parse { line: 2, .. })));
+        assert!(matches!(TreeMap::parse("0,0,0\nx,y,z"), Err(MappingError::Parse { line: 2, .. })));
+        assert!(matches!(TreeMap::parse("x,y,z\nx,y,z"), Err(MappingError::Parse { line: 2, .. })));
+    }
+
+    #[test]
+    fn csv_round_trips() {
+        let map = TreeMap::spiral(20);
+        assert_eq!(TreeMap::parse(&map.to_csv()).unwrap(), map);
+    }
+
+    #[test]
+    fn spiral_climbs_a_cone() {
+        let map = TreeMap::spiral(101);
+        assert_eq!(map.len(), 101);
+        assert_eq!(map.points()[0], Point3::new(1.0, 0.0, 0.0));
+        let top = map.points()[100];
+        assert!(top.x.abs() < 1e-6 && top.y.abs() < 1e-6 && (top.z - SPIRAL_HEIGHT).abs() < 1e-6);
+        assert!(map.points().windows(2).all(|pair| pair[0].z < pair[1].z));
+    }
+}
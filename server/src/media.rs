@@ -0,0 +1,321 @@
+use christmas_tree_client::MessageHandler;
+use clap::ValueEnum;
+use common::message::Rgb;
+use image::codecs::gif::GifDecoder;
+use image::{AnimationDecoder, Frames};
+use std::error::Error;
+use std::f32::consts::TAU;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read};
+use std::path::Path;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::bridge::FrameStream;
+use crate::config::Config;
+use crate::log_file::RotatingLog;
+use crate::mapping::TreeMap;
+use crate::{DEFAULT_NUM_LEDS, HANDSHAKE_TIMEOUT};
+
+/// Side of the square videos are scaled to, letterboxed, before they're sampled
+const VIDEO_SIZE: usize = 128;
+
+/// Frame rate videos are decoded at
+const VIDEO_FPS: u32 = 30;
+
+/// Delay GIF frames asking for less are shown for, as browsers do, since many GIFs ask for none
+const MIN_GIF_DELAY: Duration = Duration::from_millis(20);
+const DEFAULT_GIF_DELAY: Duration = Duration::from_millis(100);
+
+/// Longest playback waits between servicing the link
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How images are laid over the tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Projection {
+    /// Seen from the front, the image stretched over the tree's width and height
+    Front,
+    /// Wrapped once around the tree, its middle at the front and its edges meeting at the back
+    Wrap,
+}
+
+#[derive(Debug)]
+pub enum MediaError {
+    Io(io::Error),
+    /// The file couldn't be decoded
+    Decode(String),
+}
+
+impl fmt::Display for MediaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MediaError::Io(e) => write!(f, "Failed to read the media: {}", e),
+            MediaError::Decode(message) => write!(f, "Failed to decode the media: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for MediaError {}
+
+impl From<io::Error> for MediaError {
+    fn from(e: io::Error) -> Self {
+        MediaError::Io(e)
+    }
+}
+
+impl From<image::ImageError> for MediaError {
+    fn from(e: image::ImageError) -> Self {
+        MediaError::Decode(e.to_string())
+    }
+}
+
+/// A frame of a GIF or video, row by row from the top left
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<Rgb>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, pixels: Vec<Rgb>) -> Image {
+        assert_eq!(pixels.len(), width * height, "an image has a pixel for each of its rows and columns");
+        Image { width, height, pixels }
+    }
+
+    /// Color at (u, v), from (0, 0) at the top left corner to (1, 1) at the bottom right, blended between pixels
+    pub fn sample(&self, u: f32, v: f32) -> Rgb {
+        if self.pixels.is_empty() {
+            return Rgb::default();
+        }
+        // Pixel centers are half a pixel in from the edges
+        let x = (u * self.width as f32 - 0.5).clamp(0.0, (self.width - 1) as f32);
+        let y = (v * self.height as f32 - 0.5).clamp(0.0, (self.height - 1) as f32);
+        let (x0, y0) = (x as usize, y as usize);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (fx, fy) = (x.fract(), y.fract());
+        let pixel = |x: usize, y: usize| self.pixels[y * self.width + x];
+        let channel = |channel: fn(Rgb) -> u8| {
+            let top = channel(pixel(x0, y0)) as f32 * (1.0 - fx) + channel(pixel(x1, y0)) as f32 * fx;
+            let bottom = channel(pixel(x0, y1)) as f32 * (1.0 - fx) + channel(pixel(x1, y1)) as f32 * fx;
+            (top * (1.0 - fy) + bottom * fy).round() as u8
+        };
+        Rgb::new(channel(|rgb| rgb.r), channel(|rgb| rgb.g), channel(|rgb| rgb.b))
+    }
+
+    /// Sample the image at each LED's coordinates
+    pub fn leds(&self, coordinates: &[(f32, f32)]) -> Vec<Rgb> {
+        coordinates.iter().map(|&(u, v)| self.sample(u, v)).collect()
+    }
+}
+
+/// Where on an image each LED of the map shows, as (u, v) from the top left corner to the bottom right
+pub fn coordinates(map: &TreeMap, projection: Projection) -> Vec<(f32, f32)> {
+    let Some((min, max)) = map.bounds() else {
+        return Vec::new();
+    };
+    let height = (max.z - min.z).max(f32::EPSILON);
+    let width = (max.x - min.x).max(f32::EPSILON);
+    map.points()
+        .iter()
+        .map(|point| {
+            let v = (max.z - point.z) / height;
+            let u = match projection {
+                Projection::Front => (point.x - min.x) / width,
+                // The front of the tree faces -y, as it does to `tree calibrate`'s camera
+                Projection::Wrap => 0.5 + point.x.atan2(-point.y) / TAU,
+            };
+            (u, v)
+        })
+        .collect()
+}
+
+/// Frames decoded from a GIF, or from a video by ffmpeg
+pub enum Media {
+    Gif(Frames<'static>),
+    Video {
+        ffmpeg: Child,
+        output: ChildStdout,
+    },
+}
+
+impl Media {
+    /// Open a GIF, or any other file as a video
+    pub fn open(path: &Path) -> Result<Media, MediaError> {
+        let is_gif = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("gif"));
+        if is_gif {
+            let decoder = GifDecoder::new(BufReader::new(File::open(path)?))?;
+            return Ok(Media::Gif(decoder.into_frames()));
+        }
+        let filter = format!(
+            "scale={size}:{size}:force_original_aspect_ratio=decrease,pad={size}:{size}:(ow-iw)/2:(oh-ih)/2",
+            size = VIDEO_SIZE
+        );
+        let fps = VIDEO_FPS.to_string();
+        let mut ffmpeg = Command::new("ffmpeg")
+            .args(["-v", "error", "-i"])
+            .arg(path)
+            .args(["-vf", filter.as_str(), "-r", fps.as_str(), "-f", "rawvideo", "-pix_fmt", "rgb24", "-"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| match e.kind() {
+                ErrorKind::NotFound => MediaError::Decode("playing videos needs ffmpeg on the PATH".to_string()),
+                _ => MediaError::Io(e),
+            })?;
+        let output = ffmpeg.stdout.take().ok_or_else(|| MediaError::Decode("ffmpeg has no output".to_string()))?;
+        Ok(Media::Video { ffmpeg, output })
+    }
+
+    /// The next frame with how long it's shown, None once the media ends
+    pub fn next_frame(&mut self) -> Result<Option<(Image, Duration)>, MediaError> {
+        match self {
+            Media::Gif(frames) => {
+                let Some(frame) = frames.next().transpose()? else {
+                    return Ok(None);
+                };
+                let delay = Duration::from(frame.delay());
+                let delay = if delay < MIN_GIF_DELAY { DEFAULT_GIF_DELAY } else { delay };
+                let buffer = frame.into_buffer();
+                let (width, height) = (buffer.width() as usize, buffer.height() as usize);
+                // Transparent pixels are shown as off
+                let pixels = buffer
+                    .pixels()
+                    .map(|&image::Rgba([r, g, b, a])| {
+                        let alpha = |channel: u8| (channel as u16 * a as u16 / 255) as u8;
+                        Rgb::new(alpha(r), alpha(g), alpha(b))
+                    })
+                    .collect();
+                Ok(Some((Image::new(width, height, pixels), delay)))
+            }
+            Media::Video { output, .. } => {
+                let mut bytes = vec![0; VIDEO_SIZE * VIDEO_SIZE * 3];
+                match output.read_exact(&mut bytes) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                    Err(e) => return Err(e.into()),
+                }
+                let pixels = bytes.chunks_exact(3).map(|rgb| Rgb::new(rgb[0], rgb[1], rgb[2])).collect();
+                let delay = Duration::from_secs(1) / VIDEO_FPS;
+                Ok(Some((Image::new(VIDEO_SIZE, VIDEO_SIZE, pixels), delay)))
+            }
+        }
+    }
+}
+
+impl Drop for Media {
+    fn drop(&mut self) {
+        if let Media::Video { ffmpeg, .. } = self {
+            ffmpeg.kill().ok();
+            ffmpeg.wait().ok();
+        }
+    }
+}
+
+/// Show a GIF or video on the tree until it ends, sampling each frame at the LEDs' mapped positions
+pub fn play(
+    path: &Path,
+    projection: Projection,
+    repeat: bool,
+    message_handler: &MessageHandler,
+    log_file: &mut RotatingLog,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let num_leds = match message_handler.device_info(HANDSHAKE_TIMEOUT) {
+        Ok(info) => config.num_leds.unwrap_or(info.num_leds as usize),
+        Err(e) => {
+            let num_leds = config.num_leds.unwrap_or(DEFAULT_NUM_LEDS);
+            eprintln!("Warning: failed to get device info ({}), assuming {} LEDs", e, num_leds);
+            num_leds
+        }
+    };
+    let map = match &config.map_file {
+        Some(path) => TreeMap::load(path)?,
+        None => TreeMap::spiral(num_leds),
+    };
+    let coordinates = coordinates(&map, projection);
+    let line = format!("Showing {} on {} LEDs", path.display(), num_leds);
+    println!("{}", line);
+    log_file.write_line("server", &line)?;
+
+    let mut media = Media::open(path)?;
+    let mut stream = FrameStream::new(message_handler, config);
+    let mut due = Instant::now();
+    let mut played = false;
+    loop {
+        let Some((image, delay)) = media.next_frame()? else {
+            if !played {
+                return Err(format!("{} has no frames", path.display()).into());
+            }
+            if !repeat {
+                let line = format!("Finished showing {}", path.display());
+                println!("{}", line);
+                log_file.write_line("server", &line)?;
+                return Ok(());
+            }
+            media = Media::open(path)?;
+            continue;
+        };
+        // The link is kept serviced while waiting for the frame's time
+        while Instant::now() < due {
+            stream.service(log_file)?;
+            std::thread::sleep(due.saturating_duration_since(Instant::now()).min(POLL_INTERVAL));
+        }
+        let mut leds = image.leds(&coordinates);
+        // A map of another size still lights every LED the tree has
+        leds.resize(num_leds, Rgb::default());
+        stream.send(leds)?;
+        stream.service(log_file)?;
+        due += delay;
+        played = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapping::Point3;
+    use image::codecs::gif::GifEncoder;
+    use image::{Delay, Frame, RgbaImage};
+
+    #[test]
+    fn samples_between_pixels() {
+        let image = Image::new(2, 1, vec![Rgb::new(0, 0, 0), Rgb::new(200, 100, 50)]);
+        assert_eq!(image.sample(0.0, 0.0), Rgb::new(0, 0, 0));
+        assert_eq!(image.sample(0.5, 0.5), Rgb::new(100, 50, 25));
+        assert_eq!(image.sample(1.0, 1.0), Rgb::new(200, 100, 50));
+    }
+
+    #[test]
+    fn projects_leds_onto_images() {
+        let map = TreeMap::new(vec![
+            Point3::new(-1.0, 0.0, 0.0),
+            Point3::new(0.0, -1.0, 2.0),
+            Point3::new(1.0, 0.0, 1.0),
+        ]);
+        assert_eq!(coordinates(&map, Projection::Front), vec![(0.0, 1.0), (0.5, 0.0), (1.0, 0.5)]);
+        assert_eq!(coordinates(&map, Projection::Wrap), vec![(0.25, 1.0), (0.5, 0.0), (0.75, 0.5)]);
+    }
+
+    #[test]
+    fn decodes_gif_frames() {
+        let path = std::env::temp_dir().join(format!("tree-media-{}.gif", std::process::id()));
+        {
+            let mut encoder = GifEncoder::new(File::create(&path).unwrap());
+            let frames = [[255, 0, 0, 255], [0, 0, 255, 255]].map(|color| {
+                let image = RgbaImage::from_pixel(2, 2, image::Rgba(color));
+                Frame::from_parts(image, 0, 0, Delay::from_numer_denom_ms(200, 1))
+            });
+            encoder.encode_frames(frames).unwrap();
+        }
+        let mut media = Media::open(&path).unwrap();
+        let (image, delay) = media.next_frame().unwrap().unwrap();
+        assert_eq!(image.sample(0.5, 0.5), Rgb::new(255, 0, 0));
+        assert_eq!(delay, Duration::from_millis(200));
+        let (image, _) = media.next_frame().unwrap().unwrap();
+        assert_eq!(image.sample(0.5, 0.5), Rgb::new(0, 0, 255));
+        assert!(media.next_frame().unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
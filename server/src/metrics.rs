@@ -0,0 +1,226 @@
+use axum::routing::get;
+use axum::Router;
+use common::message::TelemetryPayload;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Time the frame rate is averaged over
+const FRAME_RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Upper bounds in seconds of the serial write latency histogram's buckets
+const WRITE_BUCKETS: [f64; 9] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25];
+
+/// Heartbeats in a row going unanswered after which the link counts as down until one is answered
+const MISSED_HEARTBEATS: u32 = 3;
+
+/// Measurements of the link and the firmware, exposed to Prometheus
+#[derive(Debug, Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    frames_sent: u64,
+    /// When each frame within the frame rate window was sent
+    recent_frames: VecDeque<Instant>,
+    /// Frames written in each latency bucket, the last counting those slower than every bucket
+    write_buckets: [u64; WRITE_BUCKETS.len() + 1],
+    write_seconds: f64,
+    frames_dropped: u64,
+    reconnects: u64,
+    /// When the heartbeat waiting for an answer was sent
+    heartbeat_sent: Option<Instant>,
+    /// Heartbeats in a row that went unanswered
+    unanswered: u32,
+    round_trip: Option<Duration>,
+    telemetry: Option<TelemetryPayload>,
+}
+
+impl Metrics {
+    /// Record a frame written to the link, and how long the write took
+    pub fn frame_sent(&self, latency: Duration) {
+        let Ok(mut inner) = self.inner.lock() else {
+            return;
+        };
+        let now = Instant::now();
+        inner.frames_sent += 1;
+        inner.recent_frames.push_back(now);
+        while inner.recent_frames.front().is_some_and(|&sent| now - sent > FRAME_RATE_WINDOW) {
+            inner.recent_frames.pop_front();
+        }
+        let seconds = latency.as_secs_f64();
+        let bucket = WRITE_BUCKETS.iter().position(|&bound| seconds <= bound).unwrap_or(WRITE_BUCKETS.len());
+        inner.write_buckets[bucket] += 1;
+        inner.write_seconds += seconds;
+    }
+
+    /// Set the number of frames the firmware rejected, skipped or never acknowledged since the link came up
+    pub fn set_frames_dropped(&self, dropped: u64) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.frames_dropped = dropped;
+        }
+    }
+
+    pub fn heartbeat_sent(&self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            if inner.heartbeat_sent.is_some() {
+                inner.unanswered += 1;
+            }
+            inner.heartbeat_sent = Some(Instant::now());
+        }
+    }
+
+    /// Record the firmware's heartbeat, answering the last one sent, the link having reconnected if it was down
+    pub fn heartbeat_received(&self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            if let Some(sent) = inner.heartbeat_sent.take() {
+                inner.round_trip = Some(sent.elapsed());
+            }
+            if inner.unanswered >= MISSED_HEARTBEATS {
+                inner.reconnects += 1;
+            }
+            inner.unanswered = 0;
+        }
+    }
+
+    pub fn telemetry(&self, telemetry: &TelemetryPayload) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.telemetry = Some(*telemetry);
+        }
+    }
+
+    /// The metrics in Prometheus' text format
+    pub fn render(&self) -> String {
+        let Ok(inner) = self.inner.lock() else {
+            return String::new();
+        };
+        let mut out = String::new();
+        let now = Instant::now();
+        let recent = inner.recent_frames.iter().filter(|&&sent| now - sent <= FRAME_RATE_WINDOW).count();
+        let frame_rate = recent as f64 / FRAME_RATE_WINDOW.as_secs_f64();
+        metric(&mut out, "tree_frames_sent_total", "counter", "Frames written to the link", inner.frames_sent);
+        metric(&mut out, "tree_frame_rate", "gauge", "Frames written per second, over the last 5 s", frame_rate);
+        let help = "Frames the firmware rejected, skipped for a newer one or never acknowledged";
+        metric(&mut out, "tree_frames_dropped_total", "counter", help, inner.frames_dropped);
+        let help = "Times the link answered heartbeats again after missing several in a row";
+        metric(&mut out, "tree_link_reconnects_total", "counter", help, inner.reconnects);
+
+        let name = "tree_serial_write_seconds";
+        writeln!(out, "# HELP {} Time to write a frame to the serial link", name).ok();
+        writeln!(out, "# TYPE {} histogram", name).ok();
+        let mut count = 0;
+        for (bound, frames) in WRITE_BUCKETS.iter().zip(inner.write_buckets) {
+            count += frames;
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count).ok();
+        }
+        count += inner.write_buckets[WRITE_BUCKETS.len()];
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count).ok();
+        writeln!(out, "{}_sum {}", name, inner.write_seconds).ok();
+        writeln!(out, "{}_count {}", name, count).ok();
+
+        // Left out until they've been measured, rather than reported as zero
+        if let Some(round_trip) = inner.round_trip {
+            let help = "Time the firmware took to answer the last heartbeat";
+            metric(&mut out, "tree_heartbeat_round_trip_seconds", "gauge", help, round_trip.as_secs_f64());
+        }
+        if let Some(telemetry) = &inner.telemetry {
+            let heap = [
+                ("tree_firmware_free_heap_bytes", "Firmware heap free", telemetry.free_heap),
+                ("tree_firmware_min_free_heap_bytes", "Lowest firmware heap free since boot", telemetry.min_free_heap),
+                (
+                    "tree_firmware_largest_free_block_bytes",
+                    "Largest block the firmware heap can allocate",
+                    telemetry.largest_free_block,
+                ),
+            ];
+            for (name, help, bytes) in heap {
+                metric(&mut out, name, "gauge", help, bytes);
+            }
+        }
+        out
+    }
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: impl std::fmt::Display) {
+    writeln!(out, "# HELP {} {}", name, help).ok();
+    writeln!(out, "# TYPE {} {}", name, kind).ok();
+    writeln!(out, "{} {}", name, value).ok();
+}
+
+/// Serve the metrics at /metrics on addr, from a thread of its own
+pub fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> std::io::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    let listener = runtime.block_on(tokio::net::TcpListener::bind(addr))?;
+    println!("Serving metrics on http://{}/metrics", listener.local_addr()?);
+    let router = Router::new().route("/metrics", get(move || async move { metrics.render() }));
+    std::thread::spawn(move || {
+        if let Err(e) = runtime.block_on(async { axum::serve(listener, router).await }) {
+            eprintln!("Stopped serving metrics: {}", e);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = Metrics::default();
+        metrics.frame_sent(Duration::from_micros(800));
+        metrics.frame_sent(Duration::from_millis(3));
+        metrics.frame_sent(Duration::from_secs(1));
+        metrics.set_frames_dropped(2);
+        let text = metrics.render();
+        for line in [
+            "# TYPE tree_frames_sent_total counter",
+            "tree_frames_sent_total 3",
+            "tree_frame_rate 0.6",
+            "tree_frames_dropped_total 2",
+            "tree_link_reconnects_total 0",
+            "tree_serial_write_seconds_bucket{le=\"0.0005\"} 0",
+            "tree_serial_write_seconds_bucket{le=\"0.001\"} 1",
+            "tree_serial_write_seconds_bucket{le=\"0.005\"} 2",
+            "tree_serial_write_seconds_bucket{le=\"0.25\"} 2",
+            "tree_serial_write_seconds_bucket{le=\"+Inf\"} 3",
+            "tree_serial_write_seconds_count 3",
+        ] {
+            assert!(text.lines().any(|rendered| rendered == line), "{} missing from\n{}", line, text);
+        }
+        // Nothing has been heard from the firmware yet
+        assert!(!text.contains("tree_heartbeat") && !text.contains("tree_firmware"));
+
+        metrics.telemetry(&TelemetryPayload {
+            free_heap: 120_000,
+            largest_free_block: 90_000,
+            min_free_heap: 100_000,
+            stack_size: 8192,
+            stack_high_water: 4000,
+        });
+        metrics.heartbeat_sent();
+        metrics.heartbeat_received();
+        let text = metrics.render();
+        assert!(text.contains("\ntree_firmware_free_heap_bytes 120000\n"));
+        assert!(text.contains("\ntree_heartbeat_round_trip_seconds "));
+    }
+
+    #[test]
+    fn counts_the_link_coming_back() {
+        let metrics = Metrics::default();
+        // A heartbeat or two going missing isn't the link going down
+        metrics.heartbeat_sent();
+        metrics.heartbeat_sent();
+        metrics.heartbeat_received();
+        assert!(metrics.render().contains("\ntree_link_reconnects_total 0\n"));
+        for _ in 0..=MISSED_HEARTBEATS {
+            metrics.heartbeat_sent();
+        }
+        metrics.heartbeat_received();
+        assert!(metrics.render().contains("\ntree_link_reconnects_total 1\n"));
+    }
+}
@@ -0,0 +1,85 @@
+use christmas_tree_client::MessageHandler;
+use common::message::Rgb;
+use std::error::Error;
+use std::time::Duration;
+
+use crate::HANDSHAKE_TIMEOUT;
+use crate::config::Config;
+use crate::pipeline::OutputPipeline;
+
+/// Time each flash is lit, and dark before the next, making two flashes a second like the reduced flash cap
+const FLASH_ON: Duration = Duration::from_millis(250);
+const FLASH_OFF: Duration = Duration::from_millis(250);
+
+/// Most flashes a notification can ask for
+pub const MAX_FLASHES: u32 = 20;
+
+/// A brief attention pattern shown over whatever the tree is showing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Notification {
+    pub color: Rgb,
+    /// Number of flashes
+    pub times: u32,
+    /// Show what the tree showed before once the flashes end, rather than staying lit in the color
+    pub then_resume: bool,
+}
+
+impl Notification {
+    /// Frames of the flashes, with how long each is shown
+    /// The last frame is the final flash, which stays lit unless the notification resumes.
+    pub fn steps(&self, num_leds: usize) -> Vec<(Vec<Rgb>, Duration)> {
+        let lit = vec![self.color; num_leds];
+        let dark = vec![Rgb::default(); num_leds];
+        let mut steps = Vec::new();
+        for flash in 0..self.times {
+            if flash > 0 {
+                steps.push((dark.clone(), FLASH_OFF));
+            }
+            steps.push((lit.clone(), FLASH_ON));
+        }
+        steps
+    }
+}
+
+/// Flash the tree over the link through the config's output filters, waiting for the flashes to end
+///
+/// Resuming sends back the LEDs the firmware was displaying, so a built-in effect that was running is resumed as
+/// the frame it was showing. `tree serve`'s notify API resumes the effect itself.
+pub fn run(
+    notification: Notification,
+    message_handler: &MessageHandler,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let previous = message_handler.leds(HANDSHAKE_TIMEOUT)?;
+    let mut pipeline = OutputPipeline::from_config(previous.len(), config);
+    let steps = notification.steps(previous.len());
+    for (seq, (leds, duration)) in (0..).zip(steps) {
+        message_handler.send_frame(seq, pipeline.apply(leds))?;
+        std::thread::sleep(duration);
+    }
+    if notification.then_resume {
+        message_handler.send_frame(notification.times * 2, pipeline.apply(previous))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flashes_end_lit() {
+        let red = Rgb::new(255, 0, 0);
+        let notification = Notification {
+            color: red,
+            times: 3,
+            then_resume: true,
+        };
+        let steps = notification.steps(2);
+        let frames: Vec<Rgb> = steps.iter().map(|(leds, _)| leds[0]).collect();
+        assert_eq!(frames, vec![red, Rgb::default(), red, Rgb::default(), red]);
+        assert!(steps.iter().all(|(leds, _)| leds.len() == 2));
+        let duration: Duration = steps.iter().map(|(_, duration)| *duration).sum();
+        assert_eq!(duration, Duration::from_millis(1250));
+    }
+}
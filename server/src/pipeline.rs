@@ -0,0 +1,178 @@
+use crate::accessibility::{DEFAULT_MAX_FLASHES_PER_SECOND, ReducedFlashFilter};
+use crate::config::Config;
+use crate::correction::ColorCorrection;
+use crate::frame::{Frame, FrameUpdate};
+use crate::hdr::Quantizer;
+use crate::smoothing::SmoothingFilter;
+use common::message::{Rgb, Rgb16};
+use std::time::{Duration, Instant};
+
+/// Output stage between rendered frames and the transmitter
+///
+/// Filters run on 16-bit colors and the result is only quantized to 8 bits, with dithering,
+/// at the very end. Without any filters enabled, an 8-bit frame's dirty regions are passed
+/// straight through. Filters keep evolving after the source stops changing, so otherwise
+/// the result is tracked in a separate output frame.
+///
+/// Every frame bound for the tree or a sink goes through one, so the filters the config enables apply to all of them.
+pub struct OutputPipeline {
+    output: Frame,
+    quantizer: Quantizer,
+    correction: Option<ColorCorrection>,
+    smoothing: Option<SmoothingFilter>,
+    reduced_flash: Option<ReducedFlashFilter>,
+    /// When apply was last given a frame, None before the first
+    last_applied: Option<Instant>,
+}
+
+impl OutputPipeline {
+    /// Create a new OutputPipeline for frames of the given length with no filters enabled
+    pub fn new(len: usize) -> Self {
+        Self {
+            output: Frame::new(len),
+            quantizer: Quantizer::new(),
+            correction: None,
+            smoothing: None,
+            reduced_flash: None,
+            last_applied: None,
+        }
+    }
+
+    /// Create a new OutputPipeline for frames of the given length with the filters the config enables
+    pub fn from_config(len: usize, config: &Config) -> Self {
+        let mut pipeline = Self::new(len);
+        pipeline.configure(config);
+        pipeline
+    }
+
+    /// Enable the filters the config asks for and disable the rest
+    pub fn configure(&mut self, config: &Config) {
+        self.set_correction(load_correction(config));
+        self.set_smoothing(config.smoothing_time_constant());
+        self.set_reduced_flash(config.reduced_flash.then_some(DEFAULT_MAX_FLASHES_PER_SECOND));
+    }
+
+    /// Enable per-LED color correction, or disable it with None
+    pub fn set_correction(&mut self, correction: Option<ColorCorrection>) {
+        self.correction = correction;
+    }
+
+    /// Enable temporal smoothing with the given time constant, or disable it with None
+    pub fn set_smoothing(&mut self, time_constant: Option<Duration>) {
+        self.smoothing = time_constant.map(SmoothingFilter::new);
+    }
+
+    /// Enable reduced flash mode with the given flash frequency cap, or disable it with None
+    pub fn set_reduced_flash(&mut self, max_flashes_per_second: Option<f32>) {
+        self.reduced_flash = max_flashes_per_second.map(ReducedFlashFilter::new);
+    }
+
+    /// Run a rendered 8-bit frame through the enabled filters
+    /// Returns the update to transmit, or None if the output didn't change
+    pub fn process(&mut self, frame: &mut Frame, dt: Duration) -> Option<FrameUpdate> {
+        if !self.has_filters() {
+            return frame.take_update();
+        }
+
+        frame.take_dirty();
+        let leds = frame.leds().iter().map(|&rgb| Rgb16::from(rgb)).collect();
+        self.quantize(leds, dt)
+    }
+
+    /// Run a whole 8-bit frame through the enabled filters, for outputs that send every frame whole
+    /// The filters advance by the time since the last frame given to apply, so frames sent now and then are shown
+    /// nearly as they are while a rapid run of them is filtered like a stream. The first frame counts as coming after
+    /// a long gap.
+    pub fn apply(&mut self, leds: Vec<Rgb>) -> Vec<Rgb> {
+        let dt = self.last_applied.map_or(Duration::MAX, |last| last.elapsed());
+        self.last_applied = Some(Instant::now());
+        if !self.has_filters() {
+            return leds;
+        }
+
+        let mut leds: Vec<Rgb16> = leds.into_iter().map(Rgb16::from).collect();
+        self.filter(&mut leds, dt);
+        self.quantizer.quantize(&leds)
+    }
+
+    /// Colors of the last 8-bit frame given to process as they're sent to the tree
+    pub fn output<'a>(&'a self, frame: &'a Frame) -> &'a [Rgb] {
+        if self.has_filters() {
+            self.output.leds()
+        } else {
+            frame.leds()
+        }
+    }
+
+    /// Make the next update carry the whole frame, after the firmware rejected an update
+    pub fn invalidate(&mut self, frame: &mut Frame) {
+        frame.mark_dirty(0..frame.len());
+        self.output.mark_dirty(0..self.output.len());
+    }
+
+    fn has_filters(&self) -> bool {
+        self.correction.is_some() || self.smoothing.is_some() || self.reduced_flash.is_some()
+    }
+
+    fn filter(&mut self, leds: &mut [Rgb16], dt: Duration) {
+        // Correction runs first so the later stages limit what the LEDs actually show
+        if let Some(correction) = &mut self.correction {
+            correction.apply(leds);
+        }
+        if let Some(smoothing) = &mut self.smoothing {
+            smoothing.apply(leds, dt);
+        }
+        // Reduced flash runs last so no other stage can reintroduce flashes
+        if let Some(reduced_flash) = &mut self.reduced_flash {
+            reduced_flash.apply(leds, dt);
+        }
+    }
+
+    fn quantize(&mut self, mut leds: Vec<Rgb16>, dt: Duration) -> Option<FrameUpdate> {
+        self.filter(&mut leds, dt);
+        let quantized = self.quantizer.quantize(&leds);
+        update(&mut self.output, quantized);
+        self.output.take_update()
+    }
+}
+
+/// The config's per-LED color correction, None without one or when it can't be loaded
+pub fn load_correction(config: &Config) -> Option<ColorCorrection> {
+    let path = config.correction_file.as_deref()?;
+    ColorCorrection::load(path)
+        .inspect_err(|e| eprintln!("Warning: {}, leaving the LEDs uncorrected", e))
+        .ok()
+}
+
+/// Copy leds into an output frame, resizing it if needed, so only changed LEDs are marked dirty
+fn update(output: &mut Frame, leds: Vec<Rgb>) {
+    if output.len() != leds.len() {
+        *output = Frame::new(leds.len());
+    }
+    for (index, color) in leds.into_iter().enumerate() {
+        output.set(index, color);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applied_frames_pass_through_without_filters() {
+        let mut pipeline = OutputPipeline::new(2);
+        let leds = vec![Rgb::new(1, 2, 3), Rgb::new(255, 0, 0)];
+        assert_eq!(pipeline.apply(leds.clone()), leds);
+    }
+
+    #[test]
+    fn applied_frames_are_flash_limited_after_the_first() {
+        let mut pipeline = OutputPipeline::new(1);
+        pipeline.set_reduced_flash(Some(DEFAULT_MAX_FLASHES_PER_SECOND));
+        let white = vec![Rgb::new(255, 255, 255)];
+        // The first frame counts as coming after a long gap, so it's shown as it is
+        assert_eq!(pipeline.apply(white.clone()), white);
+        // Going dark straight away would be a flash
+        assert!(pipeline.apply(vec![Rgb::default()])[0].r > 200);
+    }
+}
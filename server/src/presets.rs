@@ -0,0 +1,154 @@
+use christmas_tree_client::{MessageError, MessageHandler, Transport};
+use common::message::{Message, Segment, SegmentsPayload, StartEffectPayload};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::commands;
+use crate::HANDSHAKE_TIMEOUT;
+
+/// A look of the tree worth coming back to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Preset {
+    /// Firmware effect lighting the LEDs outside the segments
+    pub effect: StartEffectPayload,
+    /// Brightness in percent
+    pub brightness: u8,
+    /// Segments running their own effects
+    #[serde(default)]
+    pub segments: Vec<Segment>,
+}
+
+impl Preset {
+    /// Show the preset on the tree
+    pub fn apply<T: Transport>(&self, tree: &MessageHandler<T>) -> Result<(), MessageError> {
+        self.apply_settings(tree)?;
+        tree.send(&Message::StartEffect(self.effect.clone()))
+    }
+
+    /// Set the preset's brightness and segments without starting its effect
+    pub fn apply_settings<T: Transport>(&self, tree: &MessageHandler<T>) -> Result<(), MessageError> {
+        let mut config = tree.config(HANDSHAKE_TIMEOUT)?;
+        config.brightness_cap = commands::brightness_cap(self.brightness);
+        tree.set_config(config, HANDSHAKE_TIMEOUT)?;
+        let segments = SegmentsPayload {
+            segments: self.segments.clone(),
+        };
+        tree.set_segments(segments, HANDSHAKE_TIMEOUT).map(|_| ())
+    }
+}
+
+impl fmt::Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} at {}% speed, {}% brightness, {} segments",
+            self.effect.effect,
+            self.effect.speed,
+            self.brightness,
+            self.segments.len()
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum PresetError {
+    Io(std::io::Error),
+    Parse(serde_json::Error),
+    NotFound(String),
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresetError::Io(e) => write!(f, "Failed to access the presets file: {}", e),
+            PresetError::Parse(e) => write!(f, "Invalid presets file: {}", e),
+            PresetError::NotFound(name) => write!(f, "No preset named {}", name),
+        }
+    }
+}
+
+impl std::error::Error for PresetError {}
+
+/// Named presets kept in a JSON file, read and written on every change so several processes can share it
+pub struct PresetStore {
+    path: PathBuf,
+}
+
+impl PresetStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        PresetStore { path: path.into() }
+    }
+
+    /// Every preset by name, none if the file doesn't exist yet
+    pub fn list(&self) -> Result<BTreeMap<String, Preset>, PresetError> {
+        match std::fs::read(&self.path) {
+            Ok(contents) => serde_json::from_slice(&contents).map_err(PresetError::Parse),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(PresetError::Io(e)),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Result<Preset, PresetError> {
+        self.list()?.remove(name).ok_or_else(|| PresetError::NotFound(name.to_string()))
+    }
+
+    /// Save a preset, replacing any with the same name
+    pub fn save(&self, name: &str, preset: Preset) -> Result<(), PresetError> {
+        let mut presets = self.list()?;
+        presets.insert(name.to_string(), preset);
+        self.write(&presets)
+    }
+
+    pub fn delete(&self, name: &str) -> Result<(), PresetError> {
+        let mut presets = self.list()?;
+        presets.remove(name).ok_or_else(|| PresetError::NotFound(name.to_string()))?;
+        self.write(&presets)
+    }
+
+    /// Replace the file through a temporary one, so it's never left half written
+    fn write(&self, presets: &BTreeMap<String, Preset>) -> Result<(), PresetError> {
+        let contents = serde_json::to_vec_pretty(presets).map_err(PresetError::Parse)?;
+        let temporary = self.path.with_extension("json.tmp");
+        std::fs::write(&temporary, contents).map_err(PresetError::Io)?;
+        std::fs::rename(&temporary, &self.path).map_err(PresetError::Io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::message::{Effect, SegmentMode};
+
+    #[test]
+    fn presets_are_saved_listed_and_deleted() {
+        let path = std::env::temp_dir().join(format!("tree-presets-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store = PresetStore::new(&path);
+        assert!(store.list().unwrap().is_empty());
+
+        let christmas_eve = Preset {
+            effect: commands::start_effect(Effect::Twinkle, 0.5),
+            brightness: 60,
+            segments: vec![Segment {
+                start: 0,
+                len: 20,
+                mode: SegmentMode::Frozen,
+            }],
+        };
+        store.save("christmas eve", christmas_eve.clone()).unwrap();
+        let calm = Preset {
+            brightness: 20,
+            ..christmas_eve.clone()
+        };
+        store.save("calm", calm).unwrap();
+        assert_eq!(store.get("christmas eve").unwrap(), christmas_eve);
+        assert_eq!(store.list().unwrap().keys().collect::<Vec<_>>(), vec!["calm", "christmas eve"]);
+
+        store.delete("calm").unwrap();
+        assert!(matches!(store.get("calm"), Err(PresetError::NotFound(_))));
+        assert!(matches!(store.delete("calm"), Err(PresetError::NotFound(_))));
+        assert_eq!(PresetStore::new(&path).list().unwrap().len(), 1);
+    }
+}
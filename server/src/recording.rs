@@ -0,0 +1,355 @@
+use christmas_tree_client::MessageHandler;
+use common::message::Rgb;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::bridge::FrameStream;
+use crate::config::Config;
+use crate::log_file::RotatingLog;
+use crate::{DEFAULT_NUM_LEDS, HANDSHAKE_TIMEOUT};
+
+/// Bytes a recording starts with
+const MAGIC: &[u8; 4] = b"TREC";
+
+/// Version of the format after the magic bytes, bumped when it changes
+const VERSION: u8 = 1;
+
+/// Longest playback waits between servicing the link
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug)]
+pub enum RecordingError {
+    Io(io::Error),
+    /// The file isn't a recording, or one of a newer version
+    Format(String),
+}
+
+impl fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordingError::Io(e) => write!(f, "Failed to read the recording: {}", e),
+            RecordingError::Format(message) => write!(f, "Invalid recording: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for RecordingError {}
+
+impl From<io::Error> for RecordingError {
+    fn from(e: io::Error) -> Self {
+        RecordingError::Io(e)
+    }
+}
+
+/// Writes frames as they're sent, each with the time since recording started
+///
+/// After the magic bytes and version, each frame is stored as what changed since the frame before it, starting from
+/// every LED off. A frame is LEB128 varints of the milliseconds since the previous frame, its LED count and the number
+/// of runs of changed LEDs, then each run as varints of the unchanged LEDs before it and its length followed by 3
+/// bytes of RGB per LED. A frame that didn't change takes 3 bytes.
+pub struct Recorder<W: Write = File> {
+    writer: W,
+    started: Instant,
+    /// Milliseconds from the start the previous frame was recorded at
+    last_ms: u64,
+    previous: Vec<Rgb>,
+}
+
+impl Recorder {
+    /// Start a recording, replacing the file if it exists
+    pub fn create(path: &Path) -> io::Result<Recorder> {
+        Recorder::new(File::create(path)?)
+    }
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(mut writer: W) -> io::Result<Recorder<W>> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        Ok(Recorder {
+            writer,
+            started: Instant::now(),
+            last_ms: 0,
+            previous: Vec::new(),
+        })
+    }
+
+    /// Append a frame, timestamped now
+    pub fn record(&mut self, leds: &[Rgb]) -> io::Result<()> {
+        let ms = self.started.elapsed().as_millis() as u64;
+        self.record_at(ms, leds)
+    }
+
+    /// Append a frame at milliseconds from the start, which must not be before the previous frame's
+    fn record_at(&mut self, ms: u64, leds: &[Rgb]) -> io::Result<()> {
+        // Timestamps are rounded before they're differenced, so rounding doesn't add up over a long recording
+        let mut record = Vec::new();
+        write_varint(&mut record, ms.saturating_sub(self.last_ms));
+        write_varint(&mut record, leds.len() as u64);
+        self.previous.resize(leds.len(), Rgb::default());
+        let runs = changed_runs(&self.previous, leds);
+        write_varint(&mut record, runs.len() as u64);
+        let mut end = 0;
+        for (start, len) in runs {
+            write_varint(&mut record, (start - end) as u64);
+            write_varint(&mut record, len as u64);
+            record.extend(leds[start..start + len].iter().flat_map(|rgb| [rgb.r, rgb.g, rgb.b]));
+            end = start + len;
+        }
+        // Written in one go so a recording cut off by the server stopping ends on a whole frame
+        self.writer.write_all(&record)?;
+        self.last_ms = ms;
+        self.previous.copy_from_slice(leds);
+        Ok(())
+    }
+}
+
+/// Reads back the frames of a recording in order
+pub struct Player<R: Read = BufReader<File>> {
+    reader: R,
+    /// Milliseconds from the start of the last frame read
+    ms: u64,
+    leds: Vec<Rgb>,
+}
+
+impl Player {
+    pub fn open(path: &Path) -> Result<Player, RecordingError> {
+        Player::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> Player<R> {
+    pub fn new(mut reader: R) -> Result<Player<R>, RecordingError> {
+        let mut header = [0; 5];
+        reader.read_exact(&mut header).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => RecordingError::Format("the file is too short".to_string()),
+            _ => RecordingError::Io(e),
+        })?;
+        if header[..4] != MAGIC[..] {
+            return Err(RecordingError::Format("the file isn't a tree recording".to_string()));
+        }
+        if header[4] != VERSION {
+            return Err(RecordingError::Format(format!("version {} isn't supported", header[4])));
+        }
+        Ok(Player {
+            reader,
+            ms: 0,
+            leds: Vec::new(),
+        })
+    }
+
+    /// The next frame and when it was recorded from the start, None at the end of the recording
+    /// A frame cut off by the recording stopping partway through writing it ends the recording.
+    pub fn next_frame(&mut self) -> Result<Option<(Duration, &[Rgb])>, RecordingError> {
+        match self.read_frame() {
+            Ok(()) => Ok(Some((Duration::from_millis(self.ms), &self.leds))),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) if e.kind() == ErrorKind::InvalidData => Err(RecordingError::Format(e.to_string())),
+            Err(e) => Err(RecordingError::Io(e)),
+        }
+    }
+
+    fn read_frame(&mut self) -> io::Result<()> {
+        let ms = self.ms + read_varint(&mut self.reader)?;
+        let len = read_varint(&mut self.reader)?;
+        if len > u16::MAX as u64 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("a frame of {} LEDs is too long", len),
+            ));
+        }
+        let runs = read_varint(&mut self.reader)?;
+        let mut leds = self.leds.clone();
+        leds.resize(len as usize, Rgb::default());
+        let mut end = 0usize;
+        for _ in 0..runs {
+            let start = end.saturating_add(read_varint(&mut self.reader)? as usize);
+            end = start.saturating_add(read_varint(&mut self.reader)? as usize);
+            let past_end = || io::Error::new(ErrorKind::InvalidData, "a run of LEDs is past the end of its frame");
+            let run = leds.get_mut(start..end).ok_or_else(past_end)?;
+            for led in run {
+                let mut rgb = [0; 3];
+                self.reader.read_exact(&mut rgb)?;
+                *led = Rgb::new(rgb[0], rgb[1], rgb[2]);
+            }
+        }
+        // Only a whole frame replaces the last one
+        self.ms = ms;
+        self.leds = leds;
+        Ok(())
+    }
+}
+
+/// Replay a recording on the tree with the timing it was recorded with, speed times as fast, from the start again
+/// each time it ends if repeat is set
+pub fn play(
+    path: &Path,
+    repeat: bool,
+    speed: f32,
+    message_handler: &MessageHandler,
+    log_file: &mut RotatingLog,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    if !speed.is_finite() || speed <= 0.0 {
+        return Err(format!("{} is not a playback speed, use a number above 0", speed).into());
+    }
+    let mut player = Player::open(path)?;
+    let num_leds = match message_handler.device_info(HANDSHAKE_TIMEOUT) {
+        Ok(info) => config.num_leds.unwrap_or(info.num_leds as usize),
+        Err(e) => {
+            let num_leds = config.num_leds.unwrap_or(DEFAULT_NUM_LEDS);
+            eprintln!("Warning: failed to get device info ({}), assuming {} LEDs", e, num_leds);
+            num_leds
+        }
+    };
+    let line = format!("Playing {} on {} LEDs", path.display(), num_leds);
+    println!("{}", line);
+    log_file.write_line("server", &line)?;
+
+    let mut stream = FrameStream::new(message_handler, config);
+    let mut started = Instant::now();
+    let mut played = false;
+    loop {
+        let Some((at, mut leds)) = player.next_frame()?.map(|(at, leds)| (at, leds.to_vec())) else {
+            if !played {
+                return Err(format!("{} has no frames", path.display()).into());
+            }
+            if !repeat {
+                let line = format!("Finished playing {}", path.display());
+                println!("{}", line);
+                log_file.write_line("server", &line)?;
+                return Ok(());
+            }
+            player = Player::open(path)?;
+            started = Instant::now();
+            continue;
+        };
+        // The link is kept serviced while waiting for the frame's time
+        let due = started + at.div_f32(speed);
+        while Instant::now() < due {
+            stream.service(log_file)?;
+            std::thread::sleep(due.saturating_duration_since(Instant::now()).min(POLL_INTERVAL));
+        }
+        // Frames are sized to the tree, which may not be the one they were recorded on
+        leds.resize(num_leds, Rgb::default());
+        stream.send(leds)?;
+        stream.service(log_file)?;
+        played = true;
+    }
+}
+
+/// Start and length of each run of LEDs that differ between two frames of the same length
+fn changed_runs(previous: &[Rgb], leds: &[Rgb]) -> Vec<(usize, usize)> {
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for (index, (before, after)) in previous.iter().zip(leds).enumerate() {
+        if before == after {
+            continue;
+        }
+        match runs.last_mut() {
+            Some((start, len)) if *start + *len == index => *len += 1,
+            _ => runs.push((index, 1)),
+        }
+    }
+    runs
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(reader: &mut impl Read) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(ErrorKind::InvalidData, "a number is too long"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(bytes: &[u8]) -> Vec<(u64, Vec<Rgb>)> {
+        let mut player = Player::new(bytes).unwrap();
+        let mut frames = Vec::new();
+        while let Some((at, leds)) = player.next_frame().unwrap() {
+            frames.push((at.as_millis() as u64, leds.to_vec()));
+        }
+        frames
+    }
+
+    #[test]
+    fn frames_play_back_as_recorded() {
+        let red = Rgb::new(255, 0, 0);
+        let frames = vec![
+            (0, vec![Rgb::default(); 4]),
+            (16, vec![red, Rgb::default(), red, red]),
+            (33, vec![red, Rgb::default(), red, red]),
+            (1_000_000, vec![Rgb::new(1, 2, 3); 2]),
+            (1_000_016, vec![Rgb::new(1, 2, 3); 5]),
+        ];
+        let mut recorder = Recorder::new(Vec::new()).unwrap();
+        for (ms, leds) in &frames {
+            recorder.record_at(*ms, leds).unwrap();
+        }
+        let bytes = recorder.writer;
+        assert_eq!(play(&bytes), frames);
+
+        // A frame that didn't change takes 3 bytes
+        let mut unchanged = Recorder::new(Vec::new()).unwrap();
+        unchanged.record_at(0, &[red; 100]).unwrap();
+        let len = unchanged.writer.len();
+        unchanged.record_at(20, &[red; 100]).unwrap();
+        assert_eq!(unchanged.writer.len() - len, 3);
+
+        // A recording cut off partway through a frame ends with the frame before it
+        assert_eq!(play(&bytes[..bytes.len() - 1]), frames[..4]);
+    }
+
+    #[test]
+    fn runs_read_back_into_the_last_frame() {
+        let (off, on) = (Rgb::default(), Rgb::new(0, 0, 9));
+        let frames = vec![
+            (0, vec![off; 8]),
+            // Runs at the start, in the middle and at the end of the frame
+            (20, vec![on, on, off, off, on, off, on, on]),
+            // Only the LEDs that changed are recorded, the rest carry over from the frame before
+            (40, vec![on, off, off, Rgb::new(1, 2, 3), on, off, on, Rgb::new(4, 5, 6)]),
+        ];
+        let mut recorder = Recorder::new(Vec::new()).unwrap();
+        for (ms, leds) in &frames {
+            recorder.record_at(*ms, leds).unwrap();
+        }
+        assert_eq!(play(&recorder.writer), frames);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(matches!(Player::new(&b"GIF89a"[..]), Err(RecordingError::Format(_))));
+        assert!(matches!(Player::new(&b"TREC\x09"[..]), Err(RecordingError::Format(_))));
+        assert!(matches!(Player::new(&b"TR"[..]), Err(RecordingError::Format(_))));
+    }
+
+    #[test]
+    fn finds_changed_runs() {
+        let (off, on) = (Rgb::default(), Rgb::new(0, 0, 9));
+        assert_eq!(
+            changed_runs(&[off; 6], &[on, on, off, off, on, off]),
+            vec![(0, 2), (4, 1)]
+        );
+        assert!(changed_runs(&[on; 3], &[on; 3]).is_empty());
+    }
+}
@@ -0,0 +1,169 @@
+use christmas_tree_client::MessageHandler;
+use common::message::{Message, PowerOffPayload};
+use common::schedule::sun_times;
+use std::error::Error;
+
+use crate::config::{Rule, RuleAction, RuleTime, Weekday};
+use crate::presets::PresetStore;
+
+const MINUTES_PER_DAY: i64 = 24 * 60;
+
+/// Fires the config's rules as their times pass, like cron
+pub struct Scheduler {
+    /// Local minute, counted from the Unix epoch, up to which rules have fired
+    last_poll: Option<i64>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler { last_poll: None }
+    }
+
+    /// Rules that came due since the last poll up to the local minute now, counted from the Unix epoch, in order
+    ///
+    /// The first poll gives the rule that came due last within the past day, so a restart puts the tree back
+    /// into the state the rules want. Rules missed while polls stopped are only fired as far back as a day.
+    /// Rules at sunrise or sunset need the tree's latitude and longitude, and skip days the sun doesn't rise or set.
+    pub fn due<'a>(
+        &mut self,
+        rules: &'a [Rule],
+        now: i64,
+        location: Option<(f64, f64)>,
+        utc_offset_min: i16,
+    ) -> Vec<&'a Rule> {
+        let since = self.last_poll.unwrap_or(now - MINUTES_PER_DAY).max(now - MINUTES_PER_DAY);
+        // Starting a day early catches rules pushed past midnight by their offset from the sun
+        let mut due: Vec<(i64, &Rule)> = (since.div_euclid(MINUTES_PER_DAY) - 1..=now.div_euclid(MINUTES_PER_DAY))
+            .flat_map(|day| {
+                rules
+                    .iter()
+                    .filter(move |rule| rule.days.is_empty() || rule.days.contains(&Weekday::of_day(day)))
+                    .filter_map(move |rule| Some((due_minute(rule.at, day, location, utc_offset_min)?, rule)))
+            })
+            .filter(|&(minute, _)| since < minute && minute <= now)
+            .collect();
+        due.sort_by_key(|&(minute, _)| minute);
+        if self.last_poll.is_none() {
+            due.drain(..due.len().saturating_sub(1));
+        }
+        self.last_poll = Some(now);
+        due.into_iter().map(|(_, rule)| rule).collect()
+    }
+}
+
+/// Local minute, counted from the Unix epoch, at which a rule comes due on a local day
+fn due_minute(time: RuleTime, day: i64, location: Option<(f64, f64)>, utc_offset_min: i16) -> Option<i64> {
+    let sun = || {
+        let (latitude, longitude) = location?;
+        let (rise, set) = sun_times(day, latitude, longitude)?;
+        let local = |unix: i64| unix.div_euclid(60) + utc_offset_min as i64;
+        Some((local(rise), local(set)))
+    };
+    match time {
+        RuleTime::At(time) => Some(day * MINUTES_PER_DAY + time.0 as i64),
+        RuleTime::Sunrise(after) => Some(sun()?.0 + after as i64),
+        RuleTime::Sunset(after) => Some(sun()?.1 + after as i64),
+    }
+}
+
+/// Carry out a rule's action on the tree
+/// Returns a description of what was done
+pub fn execute(
+    action: &RuleAction,
+    message_handler: &MessageHandler,
+    presets: &PresetStore,
+) -> Result<String, Box<dyn Error>> {
+    match action {
+        RuleAction::On => {
+            message_handler.send(&Message::PowerOn)?;
+            Ok("Rule turned the tree on".to_string())
+        }
+        RuleAction::Off => {
+            message_handler.send(&Message::PowerOff(PowerOffPayload { light_sleep: false }))?;
+            Ok("Rule turned the tree off".to_string())
+        }
+        RuleAction::Preset(name) => {
+            presets.get(name)?.apply(message_handler)?;
+            Ok(format!("Rule loaded preset {}", name))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TimeOfDay;
+
+    /// 2024-12-23, a Monday
+    const MONDAY: i64 = 20_080 * MINUTES_PER_DAY;
+
+    /// Greenwich, where local time is UTC in December
+    const GREENWICH: Option<(f64, f64)> = Some((51.48, 0.0));
+
+    fn rule(at: u16, days: &[Weekday], action: RuleAction) -> Rule {
+        Rule {
+            at: RuleTime::At(TimeOfDay(at)),
+            days: days.to_vec(),
+            action,
+        }
+    }
+
+    #[test]
+    fn rules_fire_once_as_their_time_passes() {
+        let rules = [
+            rule(16 * 60 + 30, &[], RuleAction::On),
+            rule(22 * 60, &[], RuleAction::Preset("calm".to_string())),
+            rule(23 * 60 + 30, &[], RuleAction::Off),
+        ];
+        let mut scheduler = Scheduler::new();
+        // Starting in the evening restores the on rule
+        assert_eq!(scheduler.due(&rules, MONDAY + 17 * 60, None, 0), vec![&rules[0]]);
+        assert!(scheduler.due(&rules, MONDAY + 17 * 60 + 1, None, 0).is_empty());
+        assert_eq!(scheduler.due(&rules, MONDAY + 22 * 60, None, 0), vec![&rules[1]]);
+        assert!(scheduler.due(&rules, MONDAY + 22 * 60, None, 0).is_empty());
+        // Both rules passed while polls stopped fire in order
+        let tuesday = MONDAY + MINUTES_PER_DAY;
+        assert_eq!(scheduler.due(&rules, tuesday + 17 * 60, None, 0), vec![&rules[2], &rules[0]]);
+    }
+
+    #[test]
+    fn rules_only_fire_on_their_days() {
+        let rules = [
+            rule(16 * 60, &[Weekday::Sat, Weekday::Sun], RuleAction::On),
+            rule(18 * 60, &[], RuleAction::On),
+        ];
+        let mut scheduler = Scheduler::new();
+        scheduler.due(&rules, MONDAY, None, 0);
+        let saturday = MONDAY + 5 * MINUTES_PER_DAY;
+        assert_eq!(scheduler.due(&rules, saturday - 60, None, 0), vec![&rules[1]]);
+        assert_eq!(scheduler.due(&rules, saturday + 17 * 60, None, 0), vec![&rules[0]]);
+    }
+
+    #[test]
+    fn rules_follow_the_sun() {
+        // The sun rises around 08:05 and sets around 15:55 in Greenwich on the 23rd of December
+        let rules = [
+            Rule {
+                at: RuleTime::Sunset(0),
+                days: Vec::new(),
+                action: RuleAction::On,
+            },
+            Rule {
+                at: RuleTime::Sunrise(60),
+                days: Vec::new(),
+                action: RuleAction::Off,
+            },
+        ];
+        let mut scheduler = Scheduler::new();
+        scheduler.due(&rules, MONDAY + 8 * 60 + 50, GREENWICH, 0);
+        assert_eq!(scheduler.due(&rules, MONDAY + 9 * 60 + 20, GREENWICH, 0), vec![&rules[1]]);
+        assert!(scheduler.due(&rules, MONDAY + 15 * 60 + 45, GREENWICH, 0).is_empty());
+        assert_eq!(scheduler.due(&rules, MONDAY + 16 * 60 + 5, GREENWICH, 0), vec![&rules[0]]);
+        // An hour east of UTC, the same sunset is an hour later on the local clock
+        let mut scheduler = Scheduler::new();
+        scheduler.due(&rules, MONDAY + 16 * 60 + 45, GREENWICH, 60);
+        assert_eq!(scheduler.due(&rules, MONDAY + 17 * 60 + 5, GREENWICH, 60), vec![&rules[0]]);
+        // Without a location the sun's rules never fire
+        assert!(Scheduler::new().due(&rules, MONDAY + 17 * 60, None, 0).is_empty());
+    }
+}
@@ -0,0 +1,315 @@
+use christmas_tree_client::MessageHandler;
+use common::message::Rgb;
+use rhai::{AST, Dynamic, Engine, Scope};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::bridge::FrameStream;
+use crate::config::{self, Config};
+use crate::log_file::RotatingLog;
+use crate::{DEFAULT_NUM_LEDS, HANDSHAKE_TIMEOUT};
+
+/// Extension of effect scripts
+const SCRIPT_EXTENSION: &str = "rhai";
+
+/// Function each script defines, called with (time, led_index, position) for every LED of every frame
+const COLOR_FN: &str = "color";
+
+/// Operations a script may run per LED before it's stopped, so a runaway loop can't freeze the tree
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// Frames rendered per second
+const FRAME_RATE: u32 = 60;
+
+/// Time between checks of the script directory for changes
+const RELOAD_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Why a script couldn't render a frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptError {
+    /// No script has the name
+    NotFound(String),
+    /// The script couldn't be read or compiled
+    Load(String),
+    /// The script failed while running
+    Run(String),
+    /// The script returned something that isn't a color
+    Color(String),
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::NotFound(name) => write!(f, "No script named {}", name),
+            ScriptError::Load(e) => write!(f, "Script failed to load: {}", e),
+            ScriptError::Run(e) => write!(f, "Script failed: {}", e),
+            ScriptError::Color(value) => {
+                write!(f, "Script returned {}, not [r, g, b] from 0 to 255 or 0xRRGGBB", value)
+            }
+        }
+    }
+}
+
+impl Error for ScriptError {}
+
+struct Script {
+    modified: Option<SystemTime>,
+    ast: Result<AST, String>,
+}
+
+/// Effect scripts in a directory, each `<name>.rhai` defining `fn color(time, led_index, position)`
+///
+/// time is in seconds from when the effect started, position runs from 0 at the first LED to 1 at the last,
+/// and the color is returned as [r, g, b] from 0 to 255 or a 0xRRGGBB integer.
+pub struct ScriptLibrary {
+    dir: PathBuf,
+    engine: Engine,
+    scripts: BTreeMap<String, Script>,
+}
+
+impl ScriptLibrary {
+    /// Create a ScriptLibrary for a directory, without loading any scripts until the first reload
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        ScriptLibrary {
+            dir: dir.into(),
+            engine,
+            scripts: BTreeMap::new(),
+        }
+    }
+
+    /// Load the scripts added or changed since the last reload, and forget those removed
+    /// Returns a line describing each change
+    pub fn reload(&mut self) -> Vec<String> {
+        let mut changes = Vec::new();
+        let mut found = BTreeSet::new();
+        // A missing directory just has no scripts
+        for entry in std::fs::read_dir(&self.dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            if path.extension() != Some(OsStr::new(SCRIPT_EXTENSION)) {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(OsStr::to_str) else {
+                continue;
+            };
+            found.insert(name.to_string());
+            let modified = config::modified(&path);
+            if self.scripts.get(name).is_some_and(|script| script.modified == modified) {
+                continue;
+            }
+            let ast = std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|source| self.compile(&source));
+            changes.push(match &ast {
+                Ok(_) => format!("Loaded script {}", name),
+                Err(e) => format!("Script {} failed to load: {}", name, e),
+            });
+            self.scripts.insert(name.to_string(), Script { modified, ast });
+        }
+        self.scripts.retain(|name, _| {
+            let kept = found.contains(name);
+            if !kept {
+                changes.push(format!("Script {} was removed", name));
+            }
+            kept
+        });
+        changes
+    }
+
+    /// Names of the loaded scripts, in alphabetical order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.scripts.keys().map(String::as_str)
+    }
+
+    /// Render a script's frame at a time from when its effect started
+    pub fn render(&self, name: &str, time: Duration, leds: &mut [Rgb]) -> Result<(), ScriptError> {
+        let script = self.scripts.get(name).ok_or_else(|| ScriptError::NotFound(name.to_string()))?;
+        let ast = script.ast.as_ref().map_err(|e| ScriptError::Load(e.clone()))?;
+        let mut scope = Scope::new();
+        let last = leds.len().saturating_sub(1).max(1) as f64;
+        for (index, led) in leds.iter_mut().enumerate() {
+            let args = (time.as_secs_f64(), index as i64, index as f64 / last);
+            let value: Dynamic = self
+                .engine
+                .call_fn(&mut scope, ast, COLOR_FN, args)
+                .map_err(|e| ScriptError::Run(e.to_string()))?;
+            *led = color(&value).ok_or_else(|| ScriptError::Color(value.to_string()))?;
+        }
+        Ok(())
+    }
+
+    fn compile(&self, source: &str) -> Result<AST, String> {
+        let ast = self.engine.compile(source).map_err(|e| e.to_string())?;
+        if !ast.iter_functions().any(|function| function.name == COLOR_FN && function.params.len() == 3) {
+            return Err(format!("it doesn't define fn {}(time, led_index, position)", COLOR_FN));
+        }
+        Ok(ast)
+    }
+}
+
+/// A color returned by a script, channels out of range being clamped and fractional ones rounded
+fn color(value: &Dynamic) -> Option<Rgb> {
+    if let Ok(packed) = value.as_int() {
+        let [_, r, g, b] = u32::try_from(packed).ok().filter(|&packed| packed <= 0xff_ffff)?.to_be_bytes();
+        return Some(Rgb::new(r, g, b));
+    }
+    let channels = value.clone().into_array().ok()?;
+    let channel = |value: &Dynamic| {
+        let value = value.as_int().map(|value| value as f64).or_else(|_| value.as_float()).ok()?;
+        Some(value.round().clamp(0.0, 255.0) as u8)
+    };
+    match channels.as_slice() {
+        [r, g, b] => Some(Rgb::new(channel(r)?, channel(g)?, channel(b)?)),
+        _ => None,
+    }
+}
+
+/// Run an effect script on the tree until the process is stopped, reloading it whenever it changes
+pub fn run(
+    name: &str,
+    message_handler: &MessageHandler,
+    log_file: &mut RotatingLog,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let num_leds = match message_handler.device_info(HANDSHAKE_TIMEOUT) {
+        Ok(info) => config.num_leds.unwrap_or(info.num_leds as usize),
+        Err(e) => {
+            let num_leds = config.num_leds.unwrap_or(DEFAULT_NUM_LEDS);
+            eprintln!("Warning: failed to get device info ({}), assuming {} LEDs", e, num_leds);
+            num_leds
+        }
+    };
+    let mut library = ScriptLibrary::new(&config.script_dir);
+    library.reload();
+    if library.names().all(|script| script != name) {
+        let available: Vec<&str> = library.names().collect();
+        return Err(format!(
+            "No script {}.{} in {} (found {:?})",
+            name,
+            SCRIPT_EXTENSION,
+            config.script_dir.display(),
+            available
+        )
+        .into());
+    }
+    let line = format!("Running script {} on {} LEDs", name, num_leds);
+    println!("{}", line);
+    log_file.write_line("server", &line)?;
+
+    let mut stream = FrameStream::new(message_handler, config);
+    let mut leds = vec![Rgb::default(); num_leds];
+    let frame_interval = Duration::from_secs(1) / FRAME_RATE;
+    let started = Instant::now();
+    let mut last_reload = Instant::now();
+    // Errors are reported when they first happen rather than on every frame
+    let mut last_error = None;
+    loop {
+        if last_reload.elapsed() >= RELOAD_INTERVAL {
+            last_reload = Instant::now();
+            for line in library.reload() {
+                println!("{}", line);
+                log_file.write_line("server", &line)?;
+            }
+        }
+
+        let frame_start = Instant::now();
+        match library.render(name, started.elapsed(), &mut leds) {
+            Ok(()) => {
+                last_error = None;
+                stream.send(leds.clone())?;
+            }
+            // The tree keeps showing the last good frame until the script is fixed
+            Err(e) if last_error.as_ref() != Some(&e) => {
+                eprintln!("{}", e);
+                log_file.write_line("server", &e.to_string())?;
+                last_error = Some(e);
+            }
+            Err(_) => {}
+        }
+        stream.service(log_file)?;
+        std::thread::sleep(frame_interval.saturating_sub(frame_start.elapsed()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+
+    fn library(test: &str, scripts: &[(&str, &str)]) -> (PathBuf, ScriptLibrary) {
+        let dir = std::env::temp_dir().join(format!("tree-scripts-{}-{}", std::process::id(), test));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, source) in scripts {
+            std::fs::write(dir.join(name), source).unwrap();
+        }
+        let mut library = ScriptLibrary::new(&dir);
+        library.reload();
+        (dir, library)
+    }
+
+    #[test]
+    fn scripts_color_each_led() {
+        let (_, library) = library(
+            "color",
+            &[
+                ("gradient.rhai", "fn color(time, index, position) { [index * 10, position * 255.0, time] }"),
+                ("green.rhai", "fn color(time, index, position) { 0x00ff00 }"),
+                ("notes.txt", "not a script"),
+            ],
+        );
+        assert_eq!(library.names().collect::<Vec<_>>(), vec!["gradient", "green"]);
+
+        let mut leds = vec![Rgb::default(); 3];
+        library.render("gradient", Duration::from_secs(2), &mut leds).unwrap();
+        assert_eq!(leds, vec![Rgb::new(0, 0, 2), Rgb::new(10, 128, 2), Rgb::new(20, 255, 2)]);
+        library.render("green", Duration::ZERO, &mut leds).unwrap();
+        assert_eq!(leds, vec![Rgb::new(0, 255, 0); 3]);
+        assert_eq!(
+            library.render("missing", Duration::ZERO, &mut leds),
+            Err(ScriptError::NotFound("missing".to_string()))
+        );
+    }
+
+    #[test]
+    fn broken_scripts_report_errors() {
+        let (_, library) = library(
+            "errors",
+            &[
+                ("syntax.rhai", "fn color(time, index, position) {"),
+                ("unnamed.rhai", "fn colour(time, index, position) { 0 }"),
+                ("runaway.rhai", "fn color(time, index, position) { loop {} }"),
+                ("text.rhai", "fn color(time, index, position) { \"red\" }"),
+            ],
+        );
+        let mut leds = vec![Rgb::default(); 2];
+        assert!(matches!(library.render("syntax", Duration::ZERO, &mut leds), Err(ScriptError::Load(_))));
+        assert!(matches!(library.render("unnamed", Duration::ZERO, &mut leds), Err(ScriptError::Load(_))));
+        assert!(matches!(library.render("runaway", Duration::ZERO, &mut leds), Err(ScriptError::Run(_))));
+        assert!(matches!(library.render("text", Duration::ZERO, &mut leds), Err(ScriptError::Color(_))));
+    }
+
+    #[test]
+    fn reloads_changed_scripts() {
+        let (dir, mut library) = library("reload", &[("blink.rhai", "fn color(time, index, position) { 0xff0000 }")]);
+        assert!(library.reload().is_empty());
+
+        let mut file = File::create(dir.join("blink.rhai")).unwrap();
+        file.write_all(b"fn color(time, index, position) { 0x0000ff }").unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(1)).unwrap();
+        assert_eq!(library.reload(), vec!["Loaded script blink"]);
+        let mut leds = vec![Rgb::default(); 1];
+        library.render("blink", Duration::ZERO, &mut leds).unwrap();
+        assert_eq!(leds, vec![Rgb::new(0, 0, 255)]);
+
+        std::fs::remove_file(dir.join("blink.rhai")).unwrap();
+        assert_eq!(library.reload(), vec!["Script blink was removed"]);
+        assert_eq!(library.names().count(), 0);
+    }
+}
@@ -0,0 +1,175 @@
+use common::message::Rgb;
+use std::error::Error;
+
+use crate::config::Config;
+use crate::mapping::TreeMap;
+
+/// Size the window opens at, tall like a tree
+const WINDOW_WIDTH: usize = 480;
+const WINDOW_HEIGHT: usize = 720;
+
+/// Frames drawn per second
+const FRAME_RATE: usize = 60;
+
+/// Color behind the tree, dark so the LEDs stand out
+const BACKGROUND: u32 = 0x10_10_18;
+
+/// Color LEDs that are off are drawn in, so the shape of the tree shows
+const OFF: u32 = 0x30_30_30;
+
+/// Radius LEDs are drawn with in pixels
+const LED_RADIUS: i32 = 3;
+
+/// Turns per second the tree spins at, so its depth shows
+const SPIN_SPEED: f32 = 0.1;
+
+/// Fraction of the window the tree fills, leaving a margin around it
+const FILL: f32 = 0.9;
+
+/// Draw LEDs at their mapped positions, seen from an angle around the tree, nearer LEDs over farther ones
+///
+/// The buffer holds the window's pixels row by row as 0RGB. At an angle of 0 the tree is seen from the front,
+/// looking along y like `tree calibrate`'s camera.
+pub fn render(leds: &[Rgb], map: &TreeMap, angle: f32, buffer: &mut [u32], width: usize, height: usize) {
+    buffer.fill(BACKGROUND);
+    let Some((min, max)) = map.bounds() else {
+        return;
+    };
+    // Fit the tree in the window whichever way it's turned
+    let radius = map.points().iter().map(|point| point.x.hypot(point.y)).fold(f32::EPSILON, f32::max);
+    let middle = (min.z + max.z) / 2.0;
+    let scale = (width as f32 * FILL / (2.0 * radius)).min(height as f32 * FILL / (max.z - min.z).max(f32::EPSILON));
+
+    let (sin, cos) = angle.sin_cos();
+    let mut projected: Vec<(f32, i32, i32, u32)> = map
+        .points()
+        .iter()
+        .zip(leds)
+        .map(|(point, rgb)| {
+            let across = point.x * cos - point.y * sin;
+            let depth = point.x * sin + point.y * cos;
+            let x = width as f32 / 2.0 + across * scale;
+            let y = height as f32 / 2.0 + (middle - point.z) * scale;
+            let color = match (u32::from(rgb.r) << 16) | (u32::from(rgb.g) << 8) | u32::from(rgb.b) {
+                0 => OFF,
+                color => color,
+            };
+            (depth, x.round() as i32, y.round() as i32, color)
+        })
+        .collect();
+    // Farthest first, so nearer LEDs are drawn over them
+    projected.sort_by(|a, b| b.0.total_cmp(&a.0));
+    for (_, x, y, color) in projected {
+        for dy in -LED_RADIUS..=LED_RADIUS {
+            for dx in -LED_RADIUS..=LED_RADIUS {
+                let (px, py) = (x + dx, y + dy);
+                if dx * dx + dy * dy <= LED_RADIUS * LED_RADIUS
+                    && (0..width as i32).contains(&px)
+                    && (0..height as i32).contains(&py)
+                {
+                    buffer[py as usize * width + px as usize] = color;
+                }
+            }
+        }
+    }
+}
+
+/// Simulate the tree behind a pseudo-terminal, showing its LEDs in a window until it's closed
+///
+/// Any command can drive the simulated tree by giving the pseudo-terminal as its `--port`.
+#[cfg(all(unix, feature = "simulator"))]
+pub fn run(num_leds: Option<u16>, config: &Config) -> Result<(), Box<dyn Error>> {
+    use christmas_tree_client::Simulator;
+    use minifb::{Key, KeyRepeat, Window, WindowOptions};
+    use serialport::{SerialPort, TTYPort};
+    use std::f32::consts::TAU;
+    use std::time::{Duration, Instant};
+
+    let map = config.map_file.as_deref().map(TreeMap::load).transpose()?;
+    let num_leds = num_leds
+        .or(config.num_leds.and_then(|num_leds| num_leds.try_into().ok()))
+        .or(map.as_ref().and_then(|map| map.len().try_into().ok()))
+        .unwrap_or(crate::DEFAULT_NUM_LEDS as u16);
+    let map = map.unwrap_or_else(|| TreeMap::spiral(num_leds as usize));
+
+    // The host opens the pseudo-terminal's other end, which has to stay open meanwhile
+    let (mut device, host) = TTYPort::pair()?;
+    device.set_timeout(Duration::from_millis(10))?;
+    let port = host.name().ok_or("The pseudo-terminal has no name")?;
+    let simulator = Simulator::with_transport(Box::new(device) as Box<dyn SerialPort>, num_leds);
+    println!("Simulating a tree of {} LEDs on {}, drive it with e.g. `tree --port {} run`", num_leds, port, port);
+    println!("Space pauses the tree's spin, escape closes the window");
+
+    let options = WindowOptions {
+        resize: true,
+        ..WindowOptions::default()
+    };
+    let mut window = Window::new(&format!("tree simulator on {}", port), WINDOW_WIDTH, WINDOW_HEIGHT, options)?;
+    window.set_target_fps(FRAME_RATE);
+    let mut buffer = Vec::new();
+    let mut angle: f32 = 0.0;
+    let mut spinning = true;
+    let mut last_frame = Instant::now();
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        let dt = last_frame.elapsed();
+        last_frame = Instant::now();
+        if window.is_key_pressed(Key::Space, KeyRepeat::No) {
+            spinning = !spinning;
+        }
+        if spinning {
+            angle = (angle + dt.as_secs_f32() * SPIN_SPEED * TAU) % TAU;
+        }
+        let (width, height) = window.get_size();
+        buffer.resize(width * height, 0);
+        render(&simulator.state().leds, &map, angle, &mut buffer, width, height);
+        window.update_with_buffer(&buffer, width, height)?;
+    }
+    Ok(())
+}
+
+#[cfg(all(not(unix), feature = "simulator"))]
+pub fn run(_num_leds: Option<u16>, _config: &Config) -> Result<(), Box<dyn Error>> {
+    Err("tree simulate needs a pseudo-terminal, which is only available on Unix".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapping::Point3;
+    use std::f32::consts::TAU;
+
+    const SIZE: usize = 100;
+
+    fn pixel(buffer: &[u32], x: usize, y: usize) -> u32 {
+        buffer[y * SIZE + x]
+    }
+
+    #[test]
+    fn draws_leds_where_they_are_on_the_tree() {
+        let map = TreeMap::new(vec![Point3::new(0.0, 0.0, 0.0), Point3::new(0.0, 0.0, 2.0)]);
+        let leds = [Rgb::new(255, 0, 0), Rgb::default()];
+        let mut buffer = vec![0; SIZE * SIZE];
+        render(&leds, &map, 0.0, &mut buffer, SIZE, SIZE);
+        // The tree is scaled to fill 90 pixels of its height
+        assert_eq!(pixel(&buffer, 50, 95), 0xff_00_00);
+        assert_eq!(pixel(&buffer, 50, 5), OFF);
+        assert_eq!(pixel(&buffer, 10, 50), BACKGROUND);
+    }
+
+    #[test]
+    fn nearer_leds_hide_farther_ones() {
+        // One LED at the back of the tree and one in front of it, seen from the front and then from behind,
+        // and one at the top giving the tree its height
+        let map = TreeMap::new(vec![
+            Point3::new(0.0, 1.0, 0.0),
+            Point3::new(0.0, -1.0, 0.0),
+            Point3::new(1.0, 0.0, 2.0),
+        ]);
+        let leds = [Rgb::new(0, 0, 255), Rgb::new(0, 255, 0), Rgb::new(255, 255, 255)];
+        let mut buffer = vec![0; SIZE * SIZE];
+        render(&leds, &map, 0.0, &mut buffer, SIZE, SIZE);
+        assert_eq!(pixel(&buffer, 50, 95), 0x00_ff_00);
+        render(&leds, &map, TAU / 2.0, &mut buffer, SIZE, SIZE);
+        assert_eq!(pixel(&buffer, 50, 95), 0x00_00_ff);
+    }
+}
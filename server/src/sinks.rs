@@ -0,0 +1,226 @@
+use christmas_tree_client::{connect, MessageHandler, DEFAULT_BAUD_RATE};
+use common::ddp::{self, DDP_PORT};
+use common::message::{Message, Rgb, next_host_seq};
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::config::SinkConfig;
+use crate::recording::Recorder;
+use crate::HANDSHAKE_TIMEOUT;
+
+/// Time between heartbeats keeping a serial sink's link up
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Somewhere frames are copied to besides the tree
+pub trait OutputSink {
+    /// Send a whole frame
+    fn send(&mut self, leds: &[Rgb]) -> Result<(), Box<dyn Error>>;
+}
+
+/// Another tree, or the pseudo-terminal of `tree simulate`, sent frames without waiting for them to be displayed
+pub struct SerialSink {
+    message_handler: MessageHandler,
+    seq: u32,
+    last_heartbeat: Instant,
+}
+
+impl SerialSink {
+    pub fn open(port: &str) -> Result<SerialSink, Box<dyn Error>> {
+        let connection = connect(port, DEFAULT_BAUD_RATE, false, HANDSHAKE_TIMEOUT)?;
+        Ok(SerialSink {
+            message_handler: connection.handler,
+            seq: 0,
+            last_heartbeat: Instant::now(),
+        })
+    }
+}
+
+impl OutputSink for SerialSink {
+    fn send(&mut self, leds: &[Rgb]) -> Result<(), Box<dyn Error>> {
+        self.seq = next_host_seq(self.seq);
+        self.message_handler.send_frame(self.seq, leds.to_vec())?;
+        // Nothing waits on its messages, they're only read so they don't pile up
+        while self.message_handler.try_receive()?.is_some() {}
+        if self.last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL {
+            self.last_heartbeat = Instant::now();
+            self.message_handler.send(&Message::Heartbeat)?;
+        }
+        Ok(())
+    }
+}
+
+/// A DDP receiver such as a WLED device
+pub struct DdpSink {
+    socket: UdpSocket,
+    target: SocketAddr,
+    /// Sequence number of the last frame, cycling from 1 to 15
+    sequence: u8,
+}
+
+impl DdpSink {
+    /// Send to an address given as `host` or `host:port`, DDP_PORT being used without a port
+    pub fn open(address: &str) -> Result<DdpSink, Box<dyn Error>> {
+        let target = address
+            .to_socket_addrs()
+            .or_else(|_| (address, DDP_PORT).to_socket_addrs())?
+            .next()
+            .ok_or_else(|| format!("{} has no addresses", address))?;
+        let local: IpAddr = match target {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        Ok(DdpSink {
+            socket: UdpSocket::bind((local, 0))?,
+            target,
+            sequence: 0,
+        })
+    }
+}
+
+impl OutputSink for DdpSink {
+    fn send(&mut self, leds: &[Rgb]) -> Result<(), Box<dyn Error>> {
+        self.sequence = self.sequence % 15 + 1;
+        let data: Vec<u8> = leds.iter().flat_map(|rgb| [rgb.r, rgb.g, rgb.b]).collect();
+        for packet in ddp::encode(&data, self.sequence) {
+            self.socket.send_to(&packet, self.target)?;
+        }
+        Ok(())
+    }
+}
+
+impl OutputSink for Recorder {
+    fn send(&mut self, leds: &[Rgb]) -> Result<(), Box<dyn Error>> {
+        Ok(self.record(leds)?)
+    }
+}
+
+/// Open the sink a `[[sinks]]` table describes
+pub fn open(config: &SinkConfig) -> Result<Box<dyn OutputSink>, Box<dyn Error>> {
+    Ok(match config {
+        SinkConfig::Serial { port } => Box::new(SerialSink::open(port)?),
+        SinkConfig::Ddp { address } => Box::new(DdpSink::open(address)?),
+        SinkConfig::Record { path } => Box::new(Recorder::create(path)?),
+    })
+}
+
+/// Sink frames are copied to, with what it's called in messages
+struct Entry {
+    name: String,
+    sink: Box<dyn OutputSink>,
+    /// Whether the last frame failed to send, so a failing sink is only reported once
+    failing: bool,
+}
+
+/// Fans frames out to every sink, a sink that fails being tried again with the next frame
+#[derive(Default)]
+pub struct Sinks {
+    entries: Vec<Entry>,
+}
+
+impl Sinks {
+    /// Open the configured sinks, leaving out those that fail to open with a warning
+    pub fn open(configs: &[SinkConfig]) -> Sinks {
+        let mut sinks = Sinks::default();
+        for config in configs {
+            match open(config) {
+                Ok(sink) => {
+                    println!("Copying frames to {}", config);
+                    sinks.push(config.to_string(), sink);
+                }
+                Err(e) => eprintln!("Warning: failed to open {} ({}), leaving it out", config, e),
+            }
+        }
+        sinks
+    }
+
+    pub fn push(&mut self, name: String, sink: Box<dyn OutputSink>) {
+        self.entries.push(Entry {
+            name,
+            sink,
+            failing: false,
+        });
+    }
+
+    /// Send a frame to every sink
+    /// Returns a line for each sink that started or stopped failing
+    pub fn send(&mut self, leds: &[Rgb]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for entry in &mut self.entries {
+            match entry.sink.send(leds) {
+                Ok(()) if entry.failing => {
+                    entry.failing = false;
+                    lines.push(format!("Sending frames to {} again", entry.name));
+                }
+                Err(e) if !entry.failing => {
+                    entry.failing = true;
+                    lines.push(format!("Failed to send frames to {} ({})", entry.name, e));
+                }
+                _ => {}
+            }
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Sink keeping the frames sent to it, failing while told to
+    struct Collect {
+        frames: Arc<Mutex<Vec<Vec<Rgb>>>>,
+        fail: Arc<Mutex<bool>>,
+    }
+
+    impl OutputSink for Collect {
+        fn send(&mut self, leds: &[Rgb]) -> Result<(), Box<dyn Error>> {
+            if *self.fail.lock().unwrap() {
+                return Err("unplugged".into());
+            }
+            self.frames.lock().unwrap().push(leds.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn fans_frames_out_reporting_failures_once() {
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let fail = Arc::new(Mutex::new(false));
+        let mut sinks = Sinks::default();
+        for name in ["first", "second"] {
+            let sink = Collect {
+                frames: frames.clone(),
+                fail: fail.clone(),
+            };
+            sinks.push(name.to_string(), Box::new(sink));
+        }
+        let leds = [Rgb::new(1, 2, 3)];
+        assert!(sinks.send(&leds).is_empty());
+        assert_eq!(frames.lock().unwrap().len(), 2);
+
+        *fail.lock().unwrap() = true;
+        assert_eq!(sinks.send(&leds).len(), 2);
+        assert!(sinks.send(&leds).is_empty());
+        *fail.lock().unwrap() = false;
+        assert_eq!(
+            sinks.send(&leds),
+            vec!["Sending frames to first again", "Sending frames to second again"]
+        );
+    }
+
+    #[test]
+    fn sends_ddp_to_the_receiver() {
+        let receiver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut sink = DdpSink::open(&receiver.local_addr().unwrap().to_string()).unwrap();
+        sink.send(&[Rgb::new(1, 2, 3), Rgb::new(4, 5, 6)]).unwrap();
+
+        let mut packet = [0; 1500];
+        let len = receiver.recv(&mut packet).unwrap();
+        let data = ddp::parse(&packet[..len]).unwrap();
+        assert_eq!((data.sequence, data.offset, data.push), (1, 0, true));
+        assert_eq!(data.data, &[1, 2, 3, 4, 5, 6]);
+    }
+}
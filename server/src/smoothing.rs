@@ -0,0 +1,78 @@
+use common::message::Rgb16;
+use std::time::Duration;
+
+/// Per-pixel exponential smoothing filter for the output pipeline
+///
+/// Each channel moves towards its target by `1 - e^(-dt / time_constant)` of the remaining
+/// distance per tick, softening harsh transitions from low frame rate sources.
+/// A zero time constant passes frames through unchanged.
+pub struct SmoothingFilter {
+    time_constant: Duration,
+    state: Vec<[f32; 3]>,
+}
+
+impl SmoothingFilter {
+    /// Create a new SmoothingFilter with the given time constant
+    pub fn new(time_constant: Duration) -> Self {
+        Self {
+            time_constant,
+            state: Vec::new(),
+        }
+    }
+
+    /// Advance the filter by dt towards the colors in leds, replacing them with the filtered output
+    pub fn apply(&mut self, leds: &mut [Rgb16], dt: Duration) {
+        let alpha = if self.time_constant.is_zero() {
+            1.0
+        } else {
+            1.0 - (-dt.as_secs_f32() / self.time_constant.as_secs_f32()).exp()
+        };
+
+        // Start from the target when the frame size changes so nothing fades in from black
+        if self.state.len() != leds.len() {
+            self.state = leds
+                .iter()
+                .map(|rgb| [rgb.r as f32, rgb.g as f32, rgb.b as f32])
+                .collect();
+        }
+
+        for (state, rgb) in self.state.iter_mut().zip(leds.iter_mut()) {
+            for (channel, value) in state.iter_mut().zip([rgb.r, rgb.g, rgb.b]) {
+                *channel += (value as f32 - *channel) * alpha;
+            }
+            let [r, g, b] = state.map(|channel| channel.round() as u16);
+            *rgb = Rgb16::new(r, g, b);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_towards_the_target_exponentially() {
+        let mut filter = SmoothingFilter::new(Duration::from_millis(100));
+        filter.apply(&mut [Rgb16::new(0, 0, 0)], Duration::ZERO);
+
+        // One time constant covers 1 - 1/e of the distance
+        let mut leds = [Rgb16::new(10000, 0, 20000)];
+        filter.apply(&mut leds, Duration::from_millis(100));
+        assert_eq!(leds, [Rgb16::new(6321, 0, 12642)]);
+
+        // Half as long covers 1 - 1/sqrt(e) of what remains
+        let mut leds = [Rgb16::new(10000, 0, 20000)];
+        filter.apply(&mut leds, Duration::from_millis(50));
+        assert_eq!(leds, [Rgb16::new(7769, 0, 15537)]);
+    }
+
+    #[test]
+    fn zero_time_constant_passes_frames_through() {
+        let mut filter = SmoothingFilter::new(Duration::ZERO);
+        filter.apply(&mut [Rgb16::new(0, 0, 0)], Duration::ZERO);
+
+        let mut leds = [Rgb16::new(65535, 1234, 0)];
+        filter.apply(&mut leds, Duration::from_millis(1));
+        assert_eq!(leds, [Rgb16::new(65535, 1234, 0)]);
+    }
+}
@@ -0,0 +1,510 @@
+use christmas_tree_client::MessageHandler;
+use common::message::{Effect as Animation, Message, NackReason, Rgb, StatusPayload};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Gauge, List, ListItem, ListState, Paragraph};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use crate::commands::{brightness_cap, brightness_percent, describe_panic, start_effect};
+use crate::config::Config;
+use crate::effects::{Effect, EffectRegistry};
+use crate::flow::{DEFAULT_ACK_TIMEOUT, DEFAULT_WINDOW_SIZE, SendWindow};
+use crate::frame::Frame;
+use crate::link::{LinkEvent, LinkHealth, LinkMonitor};
+use crate::log_file::RotatingLog;
+use crate::logs::{self, LogReorderBuffer};
+use crate::mapping::TreeMap;
+use crate::pipeline::OutputPipeline;
+use crate::{DEFAULT_NUM_LEDS, HANDSHAKE_TIMEOUT};
+
+/// Rate host effects are rendered and the dashboard redrawn at
+const FRAME_RATE: u32 = 30;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Log lines kept, the oldest dropped first
+const LOG_LINES: usize = 500;
+
+/// Percent the brightness changes by with each key press
+const BRIGHTNESS_STEP: u8 = 5;
+
+/// Firmware animations offered after the host effects
+const ANIMATIONS: [Animation; 4] = [Animation::Rainbow, Animation::Chase, Animation::Twinkle, Animation::Breathe];
+
+/// An effect the picker can start
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Choice {
+    /// Host effect, by id
+    Host(&'static str),
+    Firmware(Animation),
+}
+
+/// Something asked for with a key press
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Start(Choice),
+    /// Stop the effect, turning the tree dark
+    Stop,
+    /// Set the brightness, in percent
+    Brightness(u8),
+    Quit,
+}
+
+/// What the dashboard shows, kept up to date from the tree's messages and the keys pressed
+struct Dashboard {
+    title: String,
+    /// Effects the picker offers, and their labels
+    choices: Vec<(Choice, String)>,
+    selected: usize,
+    /// Index of the choice running on the tree
+    running: Option<usize>,
+    /// Brightness in percent, unknown when the firmware's settings couldn't be read
+    brightness: Option<u8>,
+    status: Option<StatusPayload>,
+    /// Frames the firmware displayed per second, between its last two statuses
+    firmware_fps: Option<f32>,
+    /// Frames sent since the host frame rate was last measured
+    frames_sent: u32,
+    host_fps: f32,
+    health: Option<LinkHealth>,
+    logs: VecDeque<String>,
+}
+
+impl Dashboard {
+    fn new(title: String, registry: &EffectRegistry) -> Self {
+        let host = registry.list().map(|info| (Choice::Host(info.id), info.name.to_string()));
+        let firmware = ANIMATIONS.map(|animation| (Choice::Firmware(animation), format!("{:?} (firmware)", animation)));
+        Dashboard {
+            title,
+            choices: host.chain(firmware).collect(),
+            selected: 0,
+            running: None,
+            brightness: None,
+            status: None,
+            firmware_fps: None,
+            frames_sent: 0,
+            host_fps: 0.0,
+            health: None,
+            logs: VecDeque::new(),
+        }
+    }
+
+    fn log(&mut self, line: String) {
+        self.logs.push_back(line);
+        while self.logs.len() > LOG_LINES {
+            self.logs.pop_front();
+        }
+    }
+
+    /// Record the firmware's status, measuring its frame rate from the frames rendered since the last one and
+    /// following the firmware's effect when it changes on its own
+    fn status(&mut self, status: StatusPayload) {
+        self.firmware_fps = match &self.status {
+            // The firmware restarting resets its counters
+            Some(last) if status.uptime_ms > last.uptime_ms => {
+                let frames = status.frames_rendered.saturating_sub(last.frames_rendered);
+                Some(frames as f32 * 1000.0 / (status.uptime_ms - last.uptime_ms) as f32)
+            }
+            _ => None,
+        };
+        let host_running = self.running.is_some_and(|index| matches!(self.choices[index].0, Choice::Host(_)));
+        if !host_running {
+            self.running = status.effect.as_ref().and_then(|payload| {
+                self.choices.iter().position(|(choice, _)| *choice == Choice::Firmware(payload.effect))
+            });
+        }
+        self.status = Some(status);
+    }
+
+    /// Measure the rate frames were sent at since the last time
+    fn measure_host_fps(&mut self, elapsed: Duration) {
+        self.host_fps = self.frames_sent as f32 / elapsed.as_secs_f32().max(f32::EPSILON);
+        self.frames_sent = 0;
+    }
+
+    fn key(&mut self, code: KeyCode) -> Option<Action> {
+        match code {
+            KeyCode::Up => {
+                self.selected = (self.selected + self.choices.len() - 1) % self.choices.len();
+                None
+            }
+            KeyCode::Down => {
+                self.selected = (self.selected + 1) % self.choices.len();
+                None
+            }
+            KeyCode::Enter => {
+                self.running = Some(self.selected);
+                Some(Action::Start(self.choices[self.selected].0))
+            }
+            KeyCode::Char('s') => {
+                self.running = None;
+                Some(Action::Stop)
+            }
+            KeyCode::Left | KeyCode::Right | KeyCode::Char('-') | KeyCode::Char('+') => {
+                let current = self.brightness?;
+                let percent = match code {
+                    KeyCode::Left | KeyCode::Char('-') => current.saturating_sub(BRIGHTNESS_STEP),
+                    _ => (current + BRIGHTNESS_STEP).min(100),
+                };
+                self.brightness = Some(percent);
+                (percent != current).then_some(Action::Brightness(percent))
+            }
+            KeyCode::Char('q') | KeyCode::Esc => Some(Action::Quit),
+            _ => None,
+        }
+    }
+
+    fn draw(&self, frame: &mut ratatui::Frame) {
+        let [header, top, logs, help] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Length(12),
+            Constraint::Min(3),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [left, right] = Layout::horizontal([Constraint::Percentage(50); 2]).areas(top);
+        let [tree, link] = Layout::vertical([Constraint::Length(6), Constraint::Min(0)]).areas(left);
+        let [picker, brightness] = Layout::vertical([Constraint::Min(0), Constraint::Length(3)]).areas(right);
+
+        frame.render_widget(Line::styled(self.title.as_str(), Style::new().add_modifier(Modifier::BOLD)), header);
+
+        let running = self.running.map_or("none", |index| self.choices[index].1.as_str());
+        let mut lines = vec![
+            Line::from(format!("Effect: {}", running)),
+            Line::from(format!(
+                "FPS: {} displayed, {:.1} sent",
+                self.firmware_fps.map_or("?".to_string(), |fps| format!("{:.1}", fps)),
+                self.host_fps
+            )),
+        ];
+        if let Some(status) = &self.status {
+            lines.push(Line::from(format!(
+                "Up {} s, {} bytes free heap",
+                status.uptime_ms / 1000,
+                status.free_heap
+            )));
+            lines.push(Line::from(format!(
+                "{}/{} messages queued, {} LED write errors",
+                status.rx_queued, status.rx_capacity, status.led_write_errors
+            )));
+        }
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title("Tree")), tree);
+
+        let lines = match &self.health {
+            Some(health) => vec![
+                Line::styled(
+                    format!("Health {}/100", health.score),
+                    Style::new().fg(if health.is_degraded() { Color::Red } else { Color::Green }),
+                ),
+                Line::from(format!(
+                    "Heartbeat loss {:.1}%, frame loss {:.1}%",
+                    health.heartbeat_loss * 100.0,
+                    health.frame_loss * 100.0
+                )),
+                Line::from(format!(
+                    "{} receive errors, {} CRC failures, {} resyncs",
+                    health.receive_errors, health.crc_failures, health.resyncs
+                )),
+                Line::from(match health.round_trip {
+                    Some(round_trip) => format!("Round trip {:?}", round_trip),
+                    None => "No heartbeat answered".to_string(),
+                }),
+            ],
+            None => vec![Line::from("Measuring...")],
+        };
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title("Link")), link);
+
+        let items: Vec<ListItem> = self
+            .choices
+            .iter()
+            .enumerate()
+            .map(|(index, (_, label))| {
+                let style = if self.running == Some(index) {
+                    Style::new().fg(Color::Green)
+                } else {
+                    Style::new()
+                };
+                ListItem::new(label.as_str()).style(style)
+            })
+            .collect();
+        let list = List::new(items)
+            .block(Block::bordered().title("Effects"))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+            .highlight_symbol("> ");
+        frame.render_stateful_widget(list, picker, &mut ListState::default().with_selected(Some(self.selected)));
+
+        let block = Block::bordered().title("Brightness");
+        match self.brightness {
+            Some(percent) => frame.render_widget(
+                Gauge::default()
+                    .block(block)
+                    .gauge_style(Style::new().fg(Color::Yellow))
+                    .percent(percent as u16)
+                    .label(format!("{}%", percent)),
+                brightness,
+            ),
+            None => frame.render_widget(Paragraph::new("unknown").block(block), brightness),
+        }
+
+        // The latest lines that fit
+        let shown = logs.height.saturating_sub(2) as usize;
+        let skipped = self.logs.len().saturating_sub(shown);
+        let lines: Vec<Line> = self.logs.iter().skip(skipped).map(|line| Line::from(line.as_str())).collect();
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title("Logs")), logs);
+
+        let keys = "↑/↓ pick an effect, Enter start it, s stop, ←/→ brightness, q quit";
+        frame.render_widget(Line::styled(keys, Style::new().fg(Color::DarkGray)), help);
+    }
+}
+
+/// Puts the terminal back the way it was when the dashboard closes, however it closes
+struct RestoreTerminal;
+
+impl Drop for RestoreTerminal {
+    fn drop(&mut self) {
+        ratatui::restore();
+    }
+}
+
+/// Show the tree's logs, frame rate and link health in the terminal, with keys to pick an effect and set the
+/// brightness
+pub fn run(
+    message_handler: &MessageHandler,
+    log_file: &mut RotatingLog,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let (num_leds, device) = match message_handler.device_info(HANDSHAKE_TIMEOUT) {
+        Ok(info) => (
+            config.num_leds.unwrap_or(info.num_leds as usize),
+            format!("{} running firmware {}", info.chip, info.fw_version),
+        ),
+        Err(e) => {
+            let num_leds = config.num_leds.unwrap_or(DEFAULT_NUM_LEDS);
+            eprintln!("Warning: failed to get device info ({}), assuming {} LEDs", e, num_leds);
+            (num_leds, "Unknown device".to_string())
+        }
+    };
+    let map = match &config.map_file {
+        Some(path) => TreeMap::load(path)?,
+        None => TreeMap::spiral(num_leds),
+    };
+    let mut firmware_config = match message_handler.config(HANDSHAKE_TIMEOUT) {
+        Ok(firmware_config) => Some(firmware_config),
+        Err(e) => {
+            eprintln!("Warning: failed to get the firmware's settings ({}), the brightness can't be set", e);
+            None
+        }
+    };
+
+    let registry = EffectRegistry::with_builtin();
+    let mut dashboard = Dashboard::new(format!("{} with {} LEDs", device, num_leds), &registry);
+    dashboard.brightness = firmware_config.as_ref().map(|stored| brightness_percent(stored.brightness_cap));
+    log_file.write_line("server", "Opened the dashboard")?;
+
+    let mut terminal = ratatui::init();
+    let _restore = RestoreTerminal;
+    let mut window = SendWindow::new(DEFAULT_WINDOW_SIZE, DEFAULT_ACK_TIMEOUT);
+    let mut link = LinkMonitor::new();
+    let mut firmware_logs = LogReorderBuffer::new();
+    let mut effect: Option<Box<dyn Effect>> = None;
+    let mut frame = Frame::new(num_leds);
+    let mut pipeline = OutputPipeline::from_config(num_leds, config);
+    let frame_interval = Duration::from_secs(1) / FRAME_RATE;
+    let mut last_frame = Instant::now();
+    let mut last_heartbeat = Instant::now();
+    loop {
+        loop {
+            let message = match message_handler.try_receive() {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(e) => {
+                    link.record(LinkEvent::ReceiveError);
+                    let line = format!("Error receiving message: {}", e);
+                    log_file.write_line("server", &line)?;
+                    dashboard.log(line);
+                    break;
+                }
+            };
+            match message {
+                Message::Heartbeat => link.record(LinkEvent::HeartbeatReceived),
+                Message::Status(status) => dashboard.status(status),
+                Message::Log(payload) => firmware_logs.push(payload),
+                Message::Ack(ack) => {
+                    for outcome in window.ack(ack.seq) {
+                        link.record(outcome.into());
+                    }
+                }
+                // Skipped in favor of a newer frame, which settles it once displayed
+                Message::Nack(nack) if nack.reason == NackReason::Superseded => {
+                    if let Some(seq) = nack.seq {
+                        window.nack(seq);
+                    }
+                }
+                Message::Nack(nack) => {
+                    if nack.reason == NackReason::CrcMismatch {
+                        link.record(LinkEvent::CrcFailure);
+                    }
+                    if let Some(outcome) = nack.seq.and_then(|seq| window.nack(seq)) {
+                        link.record(outcome.into());
+                    }
+                }
+                Message::Busy => window.pause(),
+                Message::Ready => window.resume(),
+                Message::Config(stored) => {
+                    dashboard.brightness = Some(brightness_percent(stored.brightness_cap));
+                    firmware_config = Some(stored);
+                }
+                Message::Panic(panic) => {
+                    let event = describe_panic(&panic);
+                    log_file.write_line("firmware", &event)?;
+                    dashboard.log(event);
+                }
+                _ => {}
+            }
+        }
+        for payload in firmware_logs.drain_ready() {
+            let line = logs::render(&payload);
+            log_file.write_line("firmware", &line)?;
+            dashboard.log(line);
+        }
+
+        if last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL {
+            dashboard.measure_host_fps(last_heartbeat.elapsed());
+            last_heartbeat = Instant::now();
+            message_handler.send(&Message::Heartbeat)?;
+            link.record_many(LinkEvent::Resync, message_handler.take_resyncs());
+            link.record_many(LinkEvent::CrcFailure, message_handler.take_crc_failures());
+            dashboard.health = Some(link.health());
+            link.record(LinkEvent::HeartbeatSent);
+        }
+
+        // Frames the tree can't take yet are dropped, the effect carrying on regardless
+        if last_frame.elapsed() >= frame_interval {
+            let dt = last_frame.elapsed();
+            last_frame = Instant::now();
+            if let Some(effect) = &mut effect {
+                effect.tick(dt, &mut frame);
+                if !window.is_paused() && (!config.flow_control || window.can_send()) {
+                    message_handler.send_frame(window.send(), pipeline.apply(frame.leds().to_vec()))?;
+                    dashboard.frames_sent += 1;
+                }
+            }
+        }
+
+        terminal.draw(|terminal_frame| dashboard.draw(terminal_frame))?;
+        if !event::poll(frame_interval.saturating_sub(last_frame.elapsed()))? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        let line = match dashboard.key(key.code) {
+            None => continue,
+            Some(Action::Quit) => return Ok(()),
+            Some(Action::Start(Choice::Host(id))) => {
+                let params = registry.params(id, &[])?;
+                effect = Some(registry.create(id, &params, &map)?);
+                format!("Started {}", id)
+            }
+            Some(Action::Start(Choice::Firmware(animation))) => {
+                effect = None;
+                message_handler.send(&Message::StartEffect(start_effect(animation, 1.0)))?;
+                format!("Started the firmware's {:?}", animation)
+            }
+            Some(Action::Stop) => {
+                effect = None;
+                message_handler.send_frame(window.send(), pipeline.apply(vec![Rgb::default(); num_leds]))?;
+                "Stopped the effect".to_string()
+            }
+            Some(Action::Brightness(percent)) => {
+                // Answered with the stored settings, which update the gauge
+                if let Some(firmware_config) = &mut firmware_config {
+                    firmware_config.brightness_cap = brightness_cap(percent);
+                    message_handler.send(&Message::SetConfig(firmware_config.clone()))?;
+                }
+                format!("Set brightness to {}%", percent)
+            }
+        };
+        log_file.write_line("server", &line)?;
+        dashboard.log(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::message::StartEffectPayload;
+
+    fn status(uptime_ms: u64, frames_rendered: u32) -> StatusPayload {
+        StatusPayload {
+            uptime_ms,
+            free_heap: 100_000,
+            rx_queued: 0,
+            rx_capacity: 16,
+            frames_rendered,
+            led_write_errors: 0,
+            dropped_logs: 0,
+            ambient_light: None,
+            effect: None,
+        }
+    }
+
+    #[test]
+    fn keys_pick_effects_and_step_the_brightness() {
+        let mut dashboard = Dashboard::new("Tree".to_string(), &EffectRegistry::with_builtin());
+        assert_eq!(dashboard.key(KeyCode::Down), None);
+        assert_eq!(dashboard.key(KeyCode::Enter), Some(Action::Start(Choice::Host("ramp"))));
+        assert_eq!(dashboard.running, Some(1));
+        // Moving up past the top wraps around to the firmware's animations
+        dashboard.key(KeyCode::Up);
+        dashboard.key(KeyCode::Up);
+        assert_eq!(dashboard.key(KeyCode::Enter), Some(Action::Start(Choice::Firmware(Animation::Breathe))));
+        assert_eq!(dashboard.key(KeyCode::Char('s')), Some(Action::Stop));
+        assert_eq!(dashboard.running, None);
+
+        // Without the firmware's settings there's no brightness to change
+        assert_eq!(dashboard.key(KeyCode::Right), None);
+        dashboard.brightness = Some(98);
+        assert_eq!(dashboard.key(KeyCode::Right), Some(Action::Brightness(100)));
+        assert_eq!(dashboard.key(KeyCode::Right), None);
+        assert_eq!(dashboard.key(KeyCode::Left), Some(Action::Brightness(95)));
+        assert_eq!(dashboard.key(KeyCode::Char('q')), Some(Action::Quit));
+    }
+
+    #[test]
+    fn measures_the_firmware_frame_rate_between_statuses() {
+        let mut dashboard = Dashboard::new("Tree".to_string(), &EffectRegistry::with_builtin());
+        dashboard.status(status(1000, 10));
+        assert_eq!(dashboard.firmware_fps, None);
+        dashboard.status(status(3000, 70));
+        assert_eq!(dashboard.firmware_fps, Some(30.0));
+        // Restarted
+        dashboard.status(status(500, 5));
+        assert_eq!(dashboard.firmware_fps, None);
+    }
+
+    #[test]
+    fn follows_the_firmware_effect_unless_a_host_effect_runs() {
+        let mut dashboard = Dashboard::new("Tree".to_string(), &EffectRegistry::with_builtin());
+        let chase = StartEffectPayload { effect: Animation::Chase, speed: 100, palette: Vec::new() };
+        let firmware_chase =
+            dashboard.choices.iter().position(|(choice, _)| *choice == Choice::Firmware(Animation::Chase));
+        // Changed with the button
+        dashboard.status(StatusPayload { effect: Some(chase.clone()), ..status(1000, 10) });
+        assert_eq!(dashboard.running, firmware_chase);
+        dashboard.status(status(2000, 20));
+        assert_eq!(dashboard.running, None);
+
+        // Host effects send frames, which the firmware shows without an effect of its own
+        dashboard.key(KeyCode::Enter);
+        dashboard.status(StatusPayload { effect: Some(chase), ..status(3000, 30) });
+        assert_eq!(dashboard.running, Some(0));
+    }
+}
@@ -0,0 +1,115 @@
+use common::message::{FrameChecksumPayload, Rgb};
+use std::collections::VecDeque;
+
+/// Number of recently sent frame checksums kept for comparison
+const HISTORY_SIZE: usize = 64;
+
+/// Compares checksums reported by the firmware against the frames sent to it
+///
+/// Reports are matched to the sent frame with the same sequence number. A report that
+/// doesn't match it means the frame was corrupted somewhere between encoding on the
+/// server and being written to the LEDs.
+pub struct FrameVerifier {
+    /// Sequence number and checksum of each recently sent frame, oldest first
+    sent: VecDeque<(u32, u32)>,
+    matched: u64,
+    mismatched: u64,
+}
+
+impl FrameVerifier {
+    /// Create a new FrameVerifier with no sent frames
+    pub fn new() -> Self {
+        Self {
+            sent: VecDeque::with_capacity(HISTORY_SIZE),
+            matched: 0,
+            mismatched: 0,
+        }
+    }
+
+    /// Record the checksum of a frame sent to the firmware with the given sequence number
+    pub fn record_sent(&mut self, seq: u32, checksum: u32) {
+        if self.sent.len() == HISTORY_SIZE {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((seq, checksum));
+    }
+
+    /// Check a checksum reported by the firmware against the frame sent with the same sequence number
+    /// Returns whether it matches, otherwise the frame should be resent whole, or None if the frame is too old to
+    /// compare
+    pub fn verify(&mut self, report: &FrameChecksumPayload) -> Option<bool> {
+        let &(_, checksum) = self.sent.iter().rev().find(|(seq, _)| *seq == report.seq)?;
+        if checksum == report.checksum {
+            self.matched += 1;
+            Some(true)
+        } else {
+            self.mismatched += 1;
+            Some(false)
+        }
+    }
+
+    /// Number of reported frames that matched a sent frame
+    pub fn matched(&self) -> u64 {
+        self.matched
+    }
+
+    /// Number of reported frames that matched no sent frame
+    pub fn mismatched(&self) -> u64 {
+        self.mismatched
+    }
+}
+
+/// Index of the first LED read back from the firmware that differs from the colors sent to it, None if they all match
+/// LEDs read back beyond the end of the sent frame count as differing.
+pub fn first_mismatch(sent: &[Rgb], offset: usize, leds: &[Rgb]) -> Option<usize> {
+    let expected = sent.get(offset..).unwrap_or_default();
+    (0..leds.len())
+        .find(|&i| expected.get(i) != Some(&leds[i]))
+        .map(|i| offset + i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(seq: u32, checksum: u32) -> FrameChecksumPayload {
+        FrameChecksumPayload {
+            seq,
+            checksum,
+            output_checksums: vec![0],
+        }
+    }
+
+    #[test]
+    fn matches_the_frame_with_the_same_sequence_number() {
+        let mut verifier = FrameVerifier::new();
+        verifier.record_sent(1, 0x1111);
+        verifier.record_sent(2, 0x2222);
+        // Frames the firmware dropped are skipped over
+        assert_eq!(verifier.verify(&report(2, 0x2222)), Some(true));
+        // Matching an earlier frame's checksum isn't enough
+        assert_eq!(verifier.verify(&report(2, 0x1111)), Some(false));
+        assert_eq!((verifier.matched(), verifier.mismatched()), (1, 1));
+    }
+
+    #[test]
+    fn forgets_frames_beyond_the_history() {
+        let mut verifier = FrameVerifier::new();
+        for seq in 0..=HISTORY_SIZE as u32 {
+            verifier.record_sent(seq, 0x1111);
+        }
+        assert_eq!(verifier.verify(&report(0, 0x1111)), None);
+        assert_eq!(verifier.verify(&report(1, 0x1111)), Some(true));
+        assert_eq!((verifier.matched(), verifier.mismatched()), (1, 0));
+    }
+
+    #[test]
+    fn finds_the_first_read_back_led_that_differs() {
+        let sent = [Rgb::new(1, 1, 1), Rgb::new(2, 2, 2), Rgb::new(3, 3, 3)];
+        assert_eq!(first_mismatch(&sent, 1, &sent[1..]), None);
+        assert_eq!(first_mismatch(&sent, 1, &[Rgb::new(2, 2, 2), Rgb::new(0, 0, 0)]), Some(2));
+        // The firmware's frame is longer than the one sent to it
+        assert_eq!(first_mismatch(&sent, 2, &[Rgb::new(3, 3, 3), Rgb::new(0, 0, 0)]), Some(3));
+        assert_eq!(first_mismatch(&sent, 4, &[Rgb::new(0, 0, 0)]), Some(4));
+    }
+}
@@ -0,0 +1,197 @@
+use christmas_tree_client::MessageHandler;
+use common::message::Rgb;
+use std::error::Error;
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::time::{Duration, Instant};
+
+use crate::bridge::FrameStream;
+use crate::config::{Config, Reading, Sky};
+use crate::effects::{Effect, EffectRegistry};
+use crate::frame::Frame;
+use crate::log_file::RotatingLog;
+use crate::mapping::TreeMap;
+use crate::{DEFAULT_NUM_LEDS, HANDSHAKE_TIMEOUT};
+
+mod binding;
+#[cfg(feature = "weather")]
+mod client;
+
+#[cfg(feature = "weather")]
+use client::WeatherClient;
+
+/// Rate the picked effect is rendered at
+const FRAME_RATE: u32 = 30;
+
+/// The weather at the tree when it was last read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Weather {
+    pub sky: Sky,
+    pub temperature_c: f32,
+    pub wind_kmh: f32,
+    pub precipitation_mm: f32,
+    pub snowfall_cm: f32,
+    /// Percentage of the sky covered
+    pub cloud_cover: f32,
+}
+
+impl Weather {
+    pub fn reading(&self, reading: Reading) -> f32 {
+        match reading {
+            Reading::TemperatureC => self.temperature_c,
+            Reading::WindKmh => self.wind_kmh,
+            Reading::PrecipitationMm => self.precipitation_mm,
+            Reading::SnowfallCm => self.snowfall_cm,
+            Reading::CloudCover => self.cloud_cover,
+        }
+    }
+}
+
+impl fmt::Display for Weather {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?}, {} °C, wind {} km/h, {} mm of precipitation, {} cm of snow, {}% cloud cover",
+            self.sky, self.temperature_c, self.wind_kmh, self.precipitation_mm, self.snowfall_cm, self.cloud_cover
+        )
+    }
+}
+
+/// What the sky is doing for a WMO weather code, as Open-Meteo reports them
+pub fn sky(code: u8) -> Sky {
+    match code {
+        0 | 1 => Sky::Clear,
+        45 | 48 => Sky::Fog,
+        51..=67 | 80..=82 => Sky::Rain,
+        71..=77 | 85 | 86 => Sky::Snow,
+        95..=99 => Sky::Storm,
+        _ => Sky::Cloudy,
+    }
+}
+
+/// Show the host effect the config's weather rules pick for the weather, reading it again every poll interval
+///
+/// The tree keeps its display until the weather is first read, and when the weather can't be read it keeps the
+/// effect it has.
+#[cfg(feature = "weather")]
+pub fn run(
+    message_handler: &MessageHandler,
+    log_file: &mut RotatingLog,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let (latitude, longitude) = config
+        .location()
+        .ok_or("tree weather needs the tree's latitude and longitude in the config")?;
+    let registry = EffectRegistry::with_builtin();
+    binding::check(&config.weather.rules, &registry)?;
+    let num_leds = match message_handler.device_info(HANDSHAKE_TIMEOUT) {
+        Ok(info) => config.num_leds.unwrap_or(info.num_leds as usize),
+        Err(e) => {
+            let num_leds = config.num_leds.unwrap_or(DEFAULT_NUM_LEDS);
+            eprintln!("Warning: failed to get device info ({}), assuming {} LEDs", e, num_leds);
+            num_leds
+        }
+    };
+    let map = match &config.map_file {
+        Some(path) => TreeMap::load(path)?,
+        None => TreeMap::spiral(num_leds),
+    };
+
+    let line = format!("Reading the weather at {}, {} from {}", latitude, longitude, config.weather.url);
+    println!("{}", line);
+    log_file.write_line("server", &line)?;
+    let client = WeatherClient::new(&config.weather.url, latitude, longitude);
+    let interval = Duration::from_secs(config.weather.poll_interval_min.max(1) * 60);
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    let (sender, readings) = mpsc::channel();
+    std::thread::spawn(move || runtime.block_on(poll(client, interval, sender)));
+
+    let mut stream = FrameStream::new(message_handler, config);
+    let mut frame: Frame = Frame::new(num_leds);
+    // The effect shown, and the parameter values it was started with
+    let mut picked: Option<(String, Vec<(String, String)>)> = None;
+    let mut effect: Option<Box<dyn Effect>> = None;
+    let frame_interval = Duration::from_secs(1) / FRAME_RATE;
+    let mut last_frame = Instant::now();
+    loop {
+        if let Some(weather) = next_reading(&readings, log_file)? {
+            let rule = binding::select(&config.weather.rules, &weather);
+            let pick = rule.map(|rule| (rule.effect.clone(), binding::values(rule, &weather)));
+            if pick != picked {
+                let line = match &pick {
+                    Some((id, values)) => {
+                        let params = registry.params(id, values)?;
+                        effect = Some(registry.create(id, &params, &map)?);
+                        format!("Showing {} {:?} for the weather", id, values)
+                    }
+                    None => {
+                        effect = None;
+                        stream.send(vec![Rgb::default(); num_leds])?;
+                        "No weather rule matches the weather, turning the tree dark".to_string()
+                    }
+                };
+                println!("{}", line);
+                log_file.write_line("server", &line)?;
+                picked = pick;
+            }
+        }
+
+        let frame_start = Instant::now();
+        if let Some(effect) = &mut effect {
+            effect.tick(frame_start - last_frame, &mut frame);
+            stream.send(frame.leds().to_vec())?;
+        }
+        last_frame = frame_start;
+        stream.service(log_file)?;
+        std::thread::sleep(frame_interval.saturating_sub(frame_start.elapsed()));
+    }
+}
+
+/// The latest reading of the weather if there's a new one, logging readings that failed
+#[cfg(feature = "weather")]
+fn next_reading(
+    readings: &Receiver<Result<Weather, reqwest::Error>>,
+    log_file: &mut RotatingLog,
+) -> Result<Option<Weather>, Box<dyn Error>> {
+    match readings.try_recv() {
+        Ok(Ok(weather)) => {
+            let line = format!("Weather: {}", weather);
+            println!("{}", line);
+            log_file.write_line("server", &line)?;
+            Ok(Some(weather))
+        }
+        Ok(Err(e)) => {
+            let line = format!("Failed to read the weather ({}), trying again at the next poll", e);
+            eprintln!("{}", line);
+            log_file.write_line("server", &line)?;
+            Ok(None)
+        }
+        Err(TryRecvError::Empty) => Ok(None),
+        Err(TryRecvError::Disconnected) => Err("stopped reading the weather".into()),
+    }
+}
+
+/// Read the weather every interval until the readings stop being received
+#[cfg(feature = "weather")]
+async fn poll(client: WeatherClient, interval: Duration, sender: Sender<Result<Weather, reqwest::Error>>) {
+    loop {
+        if sender.send(client.current().await).is_err() {
+            return;
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weather_codes_describe_the_sky() {
+        let skies: Vec<Sky> = [0, 3, 45, 61, 73, 81, 86, 96].into_iter().map(sky).collect();
+        assert_eq!(
+            skies,
+            vec![Sky::Clear, Sky::Cloudy, Sky::Fog, Sky::Rain, Sky::Snow, Sky::Rain, Sky::Snow, Sky::Storm]
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use super::Weather;
+use crate::config::{ParamBinding, Sky, WeatherRule};
+use crate::effects::EffectRegistry;
+
+/// The first rule the weather matches
+pub fn select<'a>(rules: &'a [WeatherRule], weather: &Weather) -> Option<&'a WeatherRule> {
+    rules.iter().find(|rule| matches(rule, weather))
+}
+
+/// Whether the weather is what a rule is for
+pub fn matches(rule: &WeatherRule, weather: &Weather) -> bool {
+    rule.when.is_none_or(|sky| sky == weather.sky)
+        && rule.below_c.is_none_or(|below| weather.temperature_c < below)
+        && rule.above_c.is_none_or(|above| weather.temperature_c >= above)
+}
+
+/// A rule's effect parameters in the weather, as (id, value) text for the effect registry
+pub fn values(rule: &WeatherRule, weather: &Weather) -> Vec<(String, String)> {
+    rule.params
+        .iter()
+        .map(|(id, binding)| (id.clone(), value(binding, weather)))
+        .collect()
+}
+
+fn value(binding: &ParamBinding, weather: &Weather) -> String {
+    match binding {
+        ParamBinding::Reading { reading, from, to } => {
+            let reading = weather.reading(*reading);
+            let fraction = if from[0] == from[1] {
+                if reading >= from[0] { 1.0 } else { 0.0 }
+            } else {
+                ((reading - from[0]) / (from[1] - from[0])).clamp(0.0, 1.0)
+            };
+            (to[0] + fraction * (to[1] - to[0])).to_string()
+        }
+        ParamBinding::Fixed(toml::Value::String(value)) => value.clone(),
+        ParamBinding::Fixed(value) => value.to_string(),
+    }
+}
+
+/// Check every rule names an effect and parameters it takes, so a mistake shows before the weather turns
+pub fn check(rules: &[WeatherRule], registry: &EffectRegistry) -> Result<(), String> {
+    let calm = Weather {
+        sky: Sky::Clear,
+        temperature_c: 0.0,
+        wind_kmh: 0.0,
+        precipitation_mm: 0.0,
+        snowfall_cm: 0.0,
+        cloud_cover: 0.0,
+    };
+    for (index, rule) in rules.iter().enumerate() {
+        registry
+            .params(&rule.effect, &values(rule, &calm))
+            .map_err(|e| format!("Weather rule {}: {}", index + 1, e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WeatherConfig;
+
+    fn weather(sky: Sky, temperature_c: f32, wind_kmh: f32, snowfall_cm: f32) -> Weather {
+        Weather {
+            sky,
+            temperature_c,
+            wind_kmh,
+            precipitation_mm: 0.0,
+            snowfall_cm,
+            cloud_cover: 100.0,
+        }
+    }
+
+    fn expected(values: &[(&str, &str)]) -> Vec<(String, String)> {
+        values.iter().map(|(id, value)| (id.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn built_in_rules_follow_the_weather() {
+        let rules = WeatherConfig::default().rules;
+        check(&rules, &EffectRegistry::with_builtin()).unwrap();
+
+        let snowing = weather(Sky::Snow, -3.0, 10.0, 1.0);
+        let rule = select(&rules, &snowing).unwrap();
+        assert_eq!(rule.effect, "snowfall");
+        assert_eq!(values(rule, &snowing), expected(&[("density", "0.6")]));
+
+        // Windier weather below freezing shimmers faster, up to the end of the range
+        let freezing = weather(Sky::Clear, -0.5, 20.0, 0.0);
+        let rule = select(&rules, &freezing).unwrap();
+        assert_eq!(rule.effect, "shimmer");
+        assert_eq!(values(rule, &freezing), expected(&[("color", "4080ff"), ("speed", "1.75")]));
+        let gale = weather(Sky::Storm, -0.5, 90.0, 0.0);
+        assert_eq!(values(rule, &gale), expected(&[("color", "4080ff"), ("speed", "3")]));
+
+        let mild = weather(Sky::Rain, 8.0, 20.0, 0.0);
+        let rule = select(&rules, &mild).unwrap();
+        assert_eq!(values(rule, &mild), expected(&[("color", "ffa040"), ("depth", "0.3")]));
+    }
+
+    #[test]
+    fn rules_with_mistakes_are_caught() {
+        let mut rules = WeatherConfig::default().rules;
+        rules[1].effect = "sparkle".to_string();
+        assert!(check(&rules, &EffectRegistry::with_builtin()).unwrap_err().starts_with("Weather rule 2"));
+        let mut rules = WeatherConfig::default().rules;
+        rules[2].params.insert("depth".to_string(), ParamBinding::Fixed(toml::Value::Integer(3)));
+        assert!(check(&rules, &EffectRegistry::with_builtin()).is_err());
+        assert!(select(&[], &weather(Sky::Clear, 0.0, 0.0, 0.0)).is_none());
+    }
+}
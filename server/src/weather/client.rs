@@ -0,0 +1,120 @@
+use serde::Deserialize;
+use std::time::Duration;
+
+use super::{sky, Weather};
+
+/// Readings asked of Open-Meteo, in its default units of °C, km/h, mm, cm and percent
+const CURRENT: &str = "temperature_2m,weather_code,wind_speed_10m,precipitation,snowfall,cloud_cover";
+
+/// Time after which a reading is given up on, to be tried again at the next poll
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Reads the weather from Open-Meteo's forecast API
+#[derive(Debug, Clone)]
+pub struct WeatherClient {
+    http: reqwest::Client,
+    url: String,
+    latitude: f64,
+    longitude: f64,
+}
+
+impl WeatherClient {
+    /// Read the weather at a location from the forecast API at url
+    pub fn new(url: &str, latitude: f64, longitude: f64) -> Self {
+        WeatherClient {
+            http: reqwest::Client::new(),
+            url: url.to_string(),
+            latitude,
+            longitude,
+        }
+    }
+
+    /// The weather now
+    pub async fn current(&self) -> Result<Weather, reqwest::Error> {
+        let query = [
+            ("latitude", self.latitude.to_string()),
+            ("longitude", self.longitude.to_string()),
+            ("current", CURRENT.to_string()),
+        ];
+        let forecast: Forecast = self
+            .http
+            .get(&self.url)
+            .query(&query)
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(forecast.current.into())
+    }
+}
+
+/// The parts of Open-Meteo's forecast that are read
+#[derive(Debug, Deserialize)]
+struct Forecast {
+    current: Current,
+}
+
+#[derive(Debug, Deserialize)]
+struct Current {
+    temperature_2m: f32,
+    weather_code: u8,
+    wind_speed_10m: f32,
+    precipitation: f32,
+    snowfall: f32,
+    cloud_cover: f32,
+}
+
+impl From<Current> for Weather {
+    fn from(current: Current) -> Self {
+        Weather {
+            sky: sky(current.weather_code),
+            temperature_c: current.temperature_2m,
+            wind_kmh: current.wind_speed_10m,
+            precipitation_mm: current.precipitation,
+            snowfall_cm: current.snowfall,
+            cloud_cover: current.cloud_cover,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Sky;
+
+    #[test]
+    fn reads_open_meteo_forecasts() {
+        let forecast: Forecast = serde_json::from_str(
+            r#"{
+                "latitude": 51.5,
+                "longitude": 0.0,
+                "current_units": {"temperature_2m": "°C", "snowfall": "cm"},
+                "current": {
+                    "time": "2025-12-24T18:00",
+                    "interval": 900,
+                    "temperature_2m": -1.5,
+                    "weather_code": 73,
+                    "wind_speed_10m": 12.2,
+                    "precipitation": 0.3,
+                    "snowfall": 0.7,
+                    "cloud_cover": 100
+                }
+            }"#,
+        )
+        .unwrap();
+        let weather = Weather::from(forecast.current);
+        assert_eq!(
+            weather,
+            Weather {
+                sky: Sky::Snow,
+                temperature_c: -1.5,
+                wind_kmh: 12.2,
+                precipitation_mm: 0.3,
+                snowfall_cm: 0.7,
+                cloud_cover: 100.0,
+            }
+        );
+    }
+}